@@ -1,8 +1,11 @@
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use futures::Future;
 use warp::Filter;
 
+use crate::breaker::BreakerState;
+use crate::proxy_client::ClientInfo;
 use crate::Store;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -33,3 +36,79 @@ pub fn spawn_api(store: Arc<Store>, api_port: u16) -> impl Future<Output = ()> {
     );
     warp::serve(routes).run(([127, 0, 0, 1], api_port))
 }
+
+/// Returned by `GET /status` of the status endpoint.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ClientStatus {
+    /// `None` until the first handshake succeeds
+    pub client_info: Option<ClientInfo>,
+    pub active_streams: usize,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+    pub rtt_ms: Option<f64>,
+    /// breaker guarding requests to the token server
+    pub token_server: BreakerState,
+}
+
+impl ClientStatus {
+    pub fn from_store(store: &Store) -> Self {
+        let stats = store.stats();
+        Self {
+            client_info: store.client_info(),
+            active_streams: store.len_stream(),
+            bytes_up: stats.bytes_up(),
+            bytes_down: stats.bytes_down(),
+            reconnects: stats.reconnects(),
+            last_error: stats.last_error(),
+            rtt_ms: store.rtt().map(|rtt| rtt.as_secs_f64() * 1000.0),
+            token_server: store.token_breaker().state(),
+        }
+    }
+}
+
+pub fn status(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("status"))
+        .and(warp::path::end())
+        .map(move || warp::reply::json(&ClientStatus::from_store(&store)))
+}
+
+/// Serve `GET /status` on `host:port`. Pass a loopback `host` unless the status should be reachable from other machines.
+pub fn spawn_status(store: Arc<Store>, host: IpAddr, port: u16) -> impl Future<Output = ()> {
+    warp::serve(status(store)).run((host, port))
+}
+
+#[cfg(test)]
+mod client_status_test {
+    use super::*;
+    use futures::channel::mpsc::unbounded;
+    use ownserver_lib::{ClientId, StreamId};
+
+    #[tokio::test]
+    async fn report_active_streams() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default());
+        store.set_client_info(ClientInfo {
+            client_id: ClientId::new(),
+            host: "foo.local".to_string(),
+            public_host: "foo.local".to_string(),
+            endpoints: Vec::new(),
+            capabilities: Vec::new(),
+            expires_at: None,
+        });
+        let (tx, _rx) = unbounded();
+        store.add_stream(StreamId::new(), tx);
+        store.stats().add_bytes_up(42);
+
+        let response = warp::test::request().path("/status").reply(&status(store)).await;
+        let status: serde_json::Value = serde_json::from_slice(response.body())?;
+        assert_eq!(status["active_streams"], 1);
+        assert_eq!(status["bytes_up"], 42);
+        assert_eq!(status["bytes_down"], 0);
+        assert_eq!(status["client_info"]["host"], "foo.local");
+        assert!(status["last_error"].is_null());
+        assert_eq!(status["token_server"], "closed");
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+/// Consecutive failures that open the token server breaker.
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+/// How long an open breaker turns requests away before a probe is let through.
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// requests go out
+    Closed,
+    /// requests are turned away until the cooldown ends
+    Open,
+    /// one probe request may go out, its outcome closes or reopens the breaker
+    HalfOpen,
+}
+
+/// Stops calling a failing service for a while, so that retries don't pile onto it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    failures: u32,
+    opened_at: Option<Instant>,
+    // a probe that never reports back is given up after a cooldown
+    probe_started_at: Option<Instant>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURE_THRESHOLD, DEFAULT_COOLDOWN)
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.inner.lock().unwrap().opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether a request may go out now, how long until it may otherwise.
+    /// While half open only one probe goes out at a time.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let opened_at = match inner.opened_at {
+            None => return Ok(()),
+            Some(opened_at) => opened_at,
+        };
+        let elapsed = opened_at.elapsed();
+        if elapsed < self.cooldown {
+            return Err(self.cooldown - elapsed);
+        }
+        match inner.probe_started_at {
+            Some(started_at) if started_at.elapsed() < self.cooldown => Err(self.cooldown - started_at.elapsed()),
+            _ => {
+                inner.probe_started_at = Some(Instant::now());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.inner.lock().unwrap() = Inner::default();
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures += 1;
+        // a failed probe opens the breaker for another cooldown
+        if inner.failures >= self.failure_threshold || inner.opened_at.is_some() {
+            inner.opened_at = Some(Instant::now());
+            inner.probe_started_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod circuit_breaker_test {
+    use super::*;
+
+    #[test]
+    fn open_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(10));
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.try_acquire(), Ok(()));
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(matches!(breaker.try_acquire(), Err(wait) if wait > Duration::from_secs(9)));
+    }
+
+    #[tokio::test]
+    async fn let_one_probe_through_after_cooldown() {
+        let cooldown = Duration::from_millis(50);
+        let breaker = CircuitBreaker::new(1, cooldown);
+        breaker.record_failure();
+        tokio::time::sleep(cooldown).await;
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        assert_eq!(breaker.try_acquire(), Ok(()));
+        assert!(breaker.try_acquire().is_err());
+
+        // the probe failed, wait another cooldown
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        tokio::time::sleep(cooldown).await;
+        assert_eq!(breaker.try_acquire(), Ok(()));
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ownserver_lib::{EndpointClaim, EndpointClaims, DEFAULT_CONTROL_PATH, DEFAULT_H2_CONTROL_PORT};
+
+use crate::tls::TlsOptions;
+use crate::transport::Transport;
+
+pub const DEFAULT_CONTROL_PORT: u16 = 5000;
+pub const DEFAULT_TOKEN_SERVER: &str = "https://auth.ownserver.kumassy.com/v1/request_token";
+
+/// Config taken by `proxy_client::run_with_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConfig {
+    pub control_port: u16,
+    /// path of the server's WebSocket control endpoint
+    pub control_path: String,
+    pub token_server: String,
+    pub transport: Transport,
+    pub endpoint_claims: EndpointClaims,
+    /// times to retry establishing the tunnel after a transient failure
+    pub reconnect_attempts: u32,
+    pub reconnect_delay: Duration,
+    /// connect with wss, plain ws when `None`
+    pub tls: Option<TlsOptions>,
+    /// serve an echo server on every local port in place of the game server
+    pub loopback: bool,
+}
+
+impl ClientConfig {
+    /// Defaults match the command line defaults of `ownserver`.
+    pub fn builder() -> ClientConfigBuilder {
+        ClientConfigBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfigBuilder {
+    config: ClientConfig,
+    // picked by the transport when not set
+    control_port: Option<u16>,
+}
+
+impl Default for ClientConfigBuilder {
+    fn default() -> Self {
+        Self {
+            config: ClientConfig {
+                control_port: DEFAULT_CONTROL_PORT,
+                control_path: DEFAULT_CONTROL_PATH.to_string(),
+                token_server: DEFAULT_TOKEN_SERVER.to_string(),
+                transport: Transport::default(),
+                endpoint_claims: EndpointClaims::new(),
+                reconnect_attempts: 0,
+                reconnect_delay: Duration::from_secs(1),
+                tls: None,
+                loopback: false,
+            },
+            control_port: None,
+        }
+    }
+}
+
+impl ClientConfigBuilder {
+    /// `DEFAULT_CONTROL_PORT` by default, `DEFAULT_H2_CONTROL_PORT` with `Transport::H2`.
+    pub fn control_port(mut self, port: u16) -> Self {
+        self.control_port = Some(port);
+        self
+    }
+
+    pub fn control_path(mut self, path: impl Into<String>) -> Self {
+        self.config.control_path = path.into();
+        self
+    }
+
+    pub fn token_server(mut self, url: impl Into<String>) -> Self {
+        self.config.token_server = url.into();
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.config.transport = transport;
+        self
+    }
+
+    pub fn endpoint(mut self, claim: EndpointClaim) -> Self {
+        self.config.endpoint_claims.push(claim);
+        self
+    }
+
+    pub fn endpoints(mut self, claims: EndpointClaims) -> Self {
+        self.config.endpoint_claims.extend(claims);
+        self
+    }
+
+    /// Retry up to `attempts` times, waiting `delay` in between.
+    pub fn reconnect(mut self, attempts: u32, delay: Duration) -> Self {
+        self.config.reconnect_attempts = attempts;
+        self.config.reconnect_delay = delay;
+        self
+    }
+
+    /// Echo whatever players send instead of forwarding it to a game server, see `local::loopback`.
+    pub fn loopback(mut self, loopback: bool) -> Self {
+        self.config.loopback = loopback;
+        self
+    }
+
+    /// Check the server certificate against the CA in the pem file at `path`, enables TLS.
+    pub fn server_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.tls.get_or_insert_with(TlsOptions::default).server_ca = Some(path.into());
+        self
+    }
+
+    /// Present this certificate to servers requiring client certificates, enables TLS.
+    pub fn client_cert(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.config.tls.get_or_insert_with(TlsOptions::default).client_cert = Some((cert.into(), key.into()));
+        self
+    }
+
+    pub fn build(mut self) -> ClientConfig {
+        self.config.control_port = self.control_port.unwrap_or(match self.config.transport {
+            Transport::H2 => DEFAULT_H2_CONTROL_PORT,
+            Transport::WebSocket => DEFAULT_CONTROL_PORT,
+        });
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod client_config_builder_test {
+    use super::*;
+    use ownserver_lib::Protocol;
+
+    #[test]
+    fn build_with_defaults() {
+        let config = ClientConfig::builder().build();
+
+        assert_eq!(config.control_port, 5000);
+        assert_eq!(config.control_path, "/tunnel");
+        assert_eq!(config.token_server, DEFAULT_TOKEN_SERVER);
+        assert_eq!(config.transport, Transport::WebSocket);
+        assert!(config.endpoint_claims.is_empty());
+        assert_eq!(config.reconnect_attempts, 0);
+        assert_eq!(config.tls, None);
+        assert!(!config.loopback);
+    }
+
+    #[test]
+    fn dial_h2_control_port_with_h2_transport() {
+        let config = ClientConfig::builder().transport(Transport::H2).build();
+        assert_eq!(config.control_port, DEFAULT_H2_CONTROL_PORT);
+        // a port given explicitly wins regardless of the order
+        let config = ClientConfig::builder().control_port(6000).transport(Transport::H2).build();
+        assert_eq!(config.control_port, 6000);
+    }
+
+    #[test]
+    fn build_with_overrides() {
+        let claim = EndpointClaim {
+            protocol: Protocol::UDP,
+            local_port: 25565,
+            remote_port: 0,
+        };
+        let config = ClientConfig::builder()
+            .control_port(6000)
+            .control_path("/ownserver/tunnel")
+            .token_server("http://localhost:8888/v0/request_token")
+            .transport(Transport::H2)
+            .endpoint(claim.clone())
+            .reconnect(3, Duration::from_millis(100))
+            .client_cert("client.pem", "client_key.pem")
+            .build();
+
+        assert_eq!(config.control_port, 6000);
+        assert_eq!(config.control_path, "/ownserver/tunnel");
+        assert_eq!(config.token_server, "http://localhost:8888/v0/request_token");
+        assert_eq!(config.transport, Transport::H2);
+        assert_eq!(config.endpoint_claims, vec![claim]);
+        assert_eq!((config.reconnect_attempts, config.reconnect_delay), (3, Duration::from_millis(100)));
+        assert_eq!(config.tls, Some(TlsOptions {
+            server_ca: None,
+            client_cert: Some(("client.pem".into(), "client_key.pem".into())),
+        }));
+    }
+}
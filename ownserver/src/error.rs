@@ -1,3 +1,4 @@
+use ownserver_lib::CloseReason;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,6 +6,18 @@ pub enum Error {
     #[error("Failed to connect to control server: {0}.")]
     WebSocketError(#[from] tokio_tungstenite::tungstenite::error::Error),
 
+    #[error("Failed to open HTTP/2 tunnel to control server: {0}.")]
+    H2Error(#[from] h2::Error),
+
+    #[error("Failed to set up TLS: {0}")]
+    Tls(#[from] crate::tls::TlsError),
+
+    #[error("TLS is not supported over the h2 transport, use the websocket transport instead.")]
+    TlsOverH2,
+
+    #[error("The token server kept failing, not asking it again for {0:?}.")]
+    TokenServerCircuitOpen(std::time::Duration),
+
     #[error("The server responded with an invalid response.")]
     ServerReplyInvalid,
 
@@ -29,6 +42,15 @@ pub enum Error {
     #[error("Current client handshake version is not supported.")]
     ClientHandshakeVersionMismatch,
 
+    #[error("Server rejected our connection: {0}.")]
+    Rejected(CloseReason),
+
+    #[error("Server rejected our connection: {0}, asked to retry in {1:?}.")]
+    RejectedRetryAfter(CloseReason, std::time::Duration),
+
+    #[error("Server closed our connection: {0}.")]
+    Disconnected(CloseReason),
+
     #[error("Server sent a malformed message.")]
     MalformedMessageFromServer,
 
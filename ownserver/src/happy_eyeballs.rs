@@ -0,0 +1,124 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::*;
+use tokio::net::TcpStream;
+
+use crate::resolver::Resolve;
+
+/// How long an attempt runs alone before the next address is tried as well, as recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first of `host`'s addresses that answers, IPv6 and IPv4 alternately (RFC 8305).
+/// A new attempt starts every `delay` or as soon as the previous one fails, earlier attempts keep running.
+/// With addresses of only one family they are simply tried in turn.
+pub async fn connect(resolver: &dyn Resolve, host: &str, port: u16, delay: Duration) -> io::Result<TcpStream> {
+    let mut addrs = interleave_families(resolver.resolve(host, port).await?).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => break,
+            }
+        }
+
+        let timer = tokio::time::sleep(delay);
+        tokio::pin!(timer);
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(socket) => {
+                    debug!("connected to {} at {}", host, addr);
+                    return Ok(socket);
+                }
+                Err(e) => {
+                    debug!("failed to connect to {} at {}: {:?}", host, addr, e);
+                    last_error = Some(e);
+                    if let Some(addr) = addrs.next() {
+                        attempts.push(attempt(addr));
+                    }
+                }
+            },
+            _ = &mut timer, if !addrs.as_slice().is_empty() => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(attempt(addr));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host))))
+}
+
+async fn attempt(addr: SocketAddr) -> (SocketAddr, io::Result<TcpStream>) {
+    (addr, TcpStream::connect(addr).await)
+}
+
+// first family of the resolver first, then one address of each family in turn
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs.first().map_or(true, SocketAddr::is_ipv6);
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_is_v6);
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    first.reverse();
+    second.reverse();
+    while !first.is_empty() || !second.is_empty() {
+        interleaved.extend(first.pop());
+        interleaved.extend(second.pop());
+    }
+    interleaved
+}
+
+#[cfg(test)]
+mod happy_eyeballs_test {
+    use super::*;
+    use futures::future::BoxFuture;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Resolve for StaticResolver {
+        fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+
+    #[test]
+    fn alternate_families() {
+        let v6 = |n: u16| SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], n));
+        let v4 = |n: u16| SocketAddr::from(([127, 0, 0, 1], n));
+        assert_eq!(interleave_families(vec![v6(1), v6(2), v6(3), v4(4)]), vec![v6(1), v4(4), v6(2), v6(3)]);
+        assert_eq!(interleave_families(vec![v4(1), v6(2), v4(3)]), vec![v4(1), v6(2), v4(3)]);
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_ipv4_when_ipv6_is_dead() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let live = listener.local_addr()?;
+        // documentation prefix, never routed: the attempt either fails at once or hangs
+        let dead = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], live.port()));
+        let resolver = StaticResolver(vec![dead, live]);
+
+        let started = Instant::now();
+        let socket = connect(&resolver, "control.test", live.port(), CONNECTION_ATTEMPT_DELAY).await?;
+        assert_eq!(socket.peer_addr()?, live);
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn return_last_error_when_every_address_fails() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let refused = listener.local_addr()?;
+        drop(listener);
+
+        let resolver = StaticResolver(vec![refused]);
+        let result = connect(&resolver, "control.test", refused.port(), CONNECTION_ATTEMPT_DELAY).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        Ok(())
+    }
+}
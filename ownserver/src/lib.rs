@@ -1,35 +1,162 @@
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use dashmap::mapref::one::{Ref, RefMut};
 use futures::channel::mpsc::UnboundedSender;
-use ownserver_lib::{StreamId, EndpointId, Endpoint, Endpoints};
+use ownserver_lib::{Capability, ClientId, HeartbeatTracker, StreamId, EndpointId, Endpoint, Endpoints, INITIAL_STREAM_WINDOW};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::ToSocketAddrs;
+use tokio::sync::Semaphore;
+
+use crate::local::{pool::LocalPool, socks5::Socks5Proxy, SocketOptions, SocketTimeouts};
+use crate::proxy_client::ClientInfo;
+use crate::breaker::CircuitBreaker;
+use crate::stats::ClientStats;
 
 #[derive(Debug, Clone)]
 pub enum StreamMessage {
     Data(Vec<u8>),
     Close,
+    /// the remote peer is done sending, shut down the write half of the local connection
+    ShutdownWrite,
 }
+pub mod breaker;
+pub mod config;
 pub mod error;
+pub mod happy_eyeballs;
 pub mod local;
+pub mod logging;
 pub mod proxy_client;
+pub mod resolver;
+pub mod stats;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod tls;
+pub mod transport;
 pub mod api;
 
 pub type LocalStream = UnboundedSender<StreamMessage>;
 #[derive(Debug, Default)]
 pub struct Store {
     streams: DashMap<StreamId, LocalStream>,
+    windows: DashMap<StreamId, Arc<Semaphore>>,
     endpoints_map: DashMap<EndpointId, Endpoint>,
+    local_pool: Option<LocalPool>,
+    socket_timeouts: SocketTimeouts,
+    socket_options: SocketOptions,
+    local_socks5: Option<Socks5Proxy>,
+    capabilities: DashSet<Capability>,
+    heartbeat: Mutex<HeartbeatTracker>,
+    rtt: Mutex<Option<Duration>>,
+    // local port tried when the primary local port refuses a tcp connection
+    local_fallbacks: HashMap<u16, u16>,
+    // local port of http streams by lowercase Host without port
+    http_routes: HashMap<String, u16>,
+    stats: ClientStats,
+    token_breaker: CircuitBreaker,
+    happy_eyeballs: bool,
+    client_info: Mutex<Option<ClientInfo>>,
 }
 
 impl Store {
+    pub fn with_local_pool(local_pool: LocalPool) -> Self {
+        Self {
+            local_pool: Some(local_pool),
+            ..Default::default()
+        }
+    }
+
+    pub fn local_pool(&self) -> Option<&LocalPool> {
+        self.local_pool.as_ref()
+    }
+
+    pub fn with_socket_timeouts(mut self, socket_timeouts: SocketTimeouts) -> Self {
+        self.socket_timeouts = socket_timeouts;
+        self
+    }
+
+    pub fn socket_timeouts(&self) -> SocketTimeouts {
+        self.socket_timeouts
+    }
+
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    pub fn socket_options(&self) -> SocketOptions {
+        self.socket_options
+    }
+
+    /// Reach local services through a SOCKS5 proxy. Connections via the proxy are never pooled.
+    pub fn with_local_socks5(mut self, proxy: Socks5Proxy) -> Self {
+        self.local_socks5 = Some(proxy);
+        self
+    }
+
+    pub fn local_socks5(&self) -> Option<&Socks5Proxy> {
+        self.local_socks5.as_ref()
+    }
+
+    /// Connect tcp streams to `fallback` when `primary` refuses, e.g. a standby instance while the local server restarts.
+    pub fn with_local_fallback(mut self, primary: u16, fallback: u16) -> Self {
+        self.local_fallbacks.insert(primary, fallback);
+        self
+    }
+
+    pub fn local_fallback(&self, local_port: u16) -> Option<u16> {
+        self.local_fallbacks.get(&local_port).copied()
+    }
+
+    /// Connect http streams for `host` to `local_port` instead of the local port of their endpoint,
+    /// for servers that sniff http with `--sniff-http`. Raw tcp on the same endpoint is not affected.
+    pub fn with_http_route(mut self, host: &str, local_port: u16) -> Self {
+        self.http_routes.insert(host.to_ascii_lowercase(), local_port);
+        self
+    }
+
+    /// Local port routed for the Host header `host`, which may carry a port e.g.) `play.example.com:8080`.
+    pub fn http_route(&self, host: &str) -> Option<u16> {
+        let name = match host.strip_prefix('[') {
+            Some(v6) => v6.split(']').next().unwrap_or(v6),
+            None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+        };
+        self.http_routes.get(&name.to_ascii_lowercase()).copied()
+    }
+
+    /// The local pool applies only to direct connections.
+    pub fn pooled(&self) -> Option<&LocalPool> {
+        match self.local_socks5 {
+            Some(_) => None,
+            None => self.local_pool(),
+        }
+    }
+
     pub fn add_stream(&self, stream_id: StreamId, stream: LocalStream) {
+        self.stats.record_stream();
         self.streams.insert(stream_id, stream);
     }
 
     pub fn remove_stream(&self, stream_id: &StreamId) -> Option<(StreamId, LocalStream)> {
+        if let Some((_, window)) = self.windows.remove(stream_id) {
+            window.close();
+        }
         self.streams.remove(stream_id)
     }
 
+    /// Register the send window of a tcp stream. It is closed when the stream is removed.
+    pub fn add_window(&self, stream_id: StreamId, window: Arc<Semaphore>) {
+        self.windows.insert(stream_id, window);
+    }
+
+    pub fn update_window(&self, stream_id: &StreamId, n: u32) {
+        if let Some(window) = self.windows.get(stream_id) {
+            // never grow beyond the initial window even if the server sends bogus updates
+            let room = (INITIAL_STREAM_WINDOW as usize).saturating_sub(window.available_permits());
+            window.add_permits((n as usize).min(room));
+        }
+    }
+
     pub fn has_stream(&self, stream_id: &StreamId) -> bool {
         self.streams.contains_key(stream_id)
     }
@@ -56,6 +183,86 @@ impl Store {
         }
     }
 
+    /// Capabilities negotiated with the server in the handshake.
+    pub fn register_capabilities(&self, capabilities: Vec<Capability>) {
+        self.capabilities.clear();
+        for capability in capabilities {
+            self.capabilities.insert(capability);
+        }
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Nonce of the next heartbeat to the server, `None` once the server missed too many.
+    pub fn ping_server(&self) -> Option<u64> {
+        let mut heartbeat = self.heartbeat.lock().unwrap();
+        let nonce = heartbeat.ping();
+        (!heartbeat.is_dead()).then_some(nonce)
+    }
+
+    /// Match a heartbeat answered by the server and keep its round trip time.
+    pub fn ack_heartbeat(&self, nonce: u64) -> Option<Duration> {
+        let rtt = self.heartbeat.lock().unwrap().pong(nonce)?;
+        *self.rtt.lock().unwrap() = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Latest round trip time to the server.
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().unwrap()
+    }
+
+    pub fn stats(&self) -> &ClientStats {
+        &self.stats
+    }
+
+    /// Guard token requests with `breaker` instead of the default one.
+    pub fn with_token_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.token_breaker = breaker;
+        self
+    }
+
+    pub fn token_breaker(&self) -> &CircuitBreaker {
+        &self.token_breaker
+    }
+
+    /// Race the IPv6 and IPv4 addresses of the proxy server when opening the control connection.
+    pub fn with_happy_eyeballs(mut self, enabled: bool) -> Self {
+        self.happy_eyeballs = enabled;
+        self
+    }
+
+    pub fn happy_eyeballs(&self) -> bool {
+        self.happy_eyeballs
+    }
+
+    /// Remember what the server assigned in the latest handshake.
+    pub fn set_client_info(&self, client_info: ClientInfo) {
+        *self.client_info.lock().unwrap() = Some(client_info);
+    }
+
+    /// Apply what the server reported in `WhoAmIResp`. Nothing changes before the handshake set the client info.
+    pub fn refresh_client_info(&self, client_id: ClientId, public_host: String, endpoints: Endpoints, expires_at: Option<u64>) {
+        {
+            let mut client_info = self.client_info.lock().unwrap();
+            let info = match client_info.as_mut() {
+                Some(info) => info,
+                None => return,
+            };
+            info.client_id = client_id;
+            info.public_host = public_host;
+            info.endpoints = endpoints.clone();
+            info.expires_at = expires_at;
+        }
+        self.register_endpoints(endpoints);
+    }
+
+    pub fn client_info(&self) -> Option<ClientInfo> {
+        self.client_info.lock().unwrap().clone()
+    }
+
     pub fn get_local_addr_by_endpoint_id(&self, eid: EndpointId) -> Option<impl ToSocketAddrs + std::fmt::Debug + Clone> {
         let endpoint = self.endpoints_map.get(&eid)?;
 
@@ -0,0 +1,46 @@
+use std::io;
+
+use log::*;
+use ownserver_lib::{EndpointClaim, Protocol};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Serve an echo server on the local port of `claim` in place of the game server,
+/// so that a round trip through the public port only depends on the tunnel.
+pub async fn spawn_echo(claim: &EndpointClaim) -> io::Result<()> {
+    let addr = ("127.0.0.1", claim.local_port);
+    match claim.protocol {
+        Protocol::TCP => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("loopback tcp echo server listening on {:?}", listener.local_addr());
+            tokio::spawn(async move {
+                while let Ok((mut socket, peer)) = listener.accept().await {
+                    debug!("loopback accepted {}", peer);
+                    tokio::spawn(async move {
+                        let mut buf = [0; 4 * 1024];
+                        loop {
+                            let n = match socket.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => n,
+                            };
+                            if socket.write_all(&buf[..n]).await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+            });
+        }
+        Protocol::UDP => {
+            let socket = UdpSocket::bind(addr).await?;
+            info!("loopback udp echo server listening on {:?}", socket.local_addr());
+            tokio::spawn(async move {
+                let mut buf = [0; 64 * 1024];
+                while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                    let _ = socket.send_to(&buf[..n], peer).await;
+                }
+            });
+        }
+    }
+    Ok(())
+}
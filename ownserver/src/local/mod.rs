@@ -1,2 +1,41 @@
 pub mod udp;
-pub mod tcp;
\ No newline at end of file
+pub mod tcp;
+pub mod loopback;
+pub mod pool;
+pub mod socks5;
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+
+pub use ownserver_lib::socket::{with_timeout, SocketTimeouts, TcpOptions};
+
+/// Options set on every local tcp connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub tcp: TcpOptions,
+    /// source address of direct local tcp connections and local udp sockets, chosen by the route when `None`
+    pub bind_addr: Option<IpAddr>,
+}
+
+/// Connect to `host:port` from `bind_addr`, trying only the addresses of `host` in the same family.
+pub async fn connect_tcp(host: &str, port: u16, bind_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+    let bind_addr = match bind_addr {
+        Some(bind_addr) => bind_addr,
+        None => return TcpStream::connect((host, port)).await,
+    };
+
+    let mut last_err = None;
+    for addr in lookup_host((host, port)).await?.filter(|addr| addr.is_ipv4() == bind_addr.is_ipv4()) {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        socket.bind(SocketAddr::new(bind_addr, 0))?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(ErrorKind::AddrNotAvailable, format!("{} has no address to reach from {}", host, bind_addr))
+    }))
+}
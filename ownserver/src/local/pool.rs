@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::sync::Mutex;
+
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Bounds the open local tcp connections per local port. A connection is never handed to another stream,
+/// the pool knows only the local port and a request one remote peer left half sent would reach the next peer.
+#[derive(Debug)]
+pub struct LocalPool {
+    in_use: Mutex<HashMap<u16, usize>>,
+    max_size: usize,
+}
+
+impl LocalPool {
+    /// `max_size` bounds the open connections per local port, checkouts over it fail.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            in_use: Default::default(),
+            max_size,
+        }
+    }
+
+    /// Connect to `addr` unless `max_size` connections to `local_port` are open.
+    /// Every successful checkout must be followed by `release` once the connection is closed.
+    pub async fn checkout<A: ToSocketAddrs>(&self, local_port: u16, addr: A) -> io::Result<TcpStream> {
+        self.checkout_with(local_port, TcpStream::connect(addr)).await
+    }
+
+    /// Same as `checkout` but opens the connection with `connect`, which is not polled over `max_size`.
+    pub async fn checkout_with<F: Future<Output = io::Result<TcpStream>>>(&self, local_port: u16, connect: F) -> io::Result<TcpStream> {
+        {
+            let mut in_use = self.in_use.lock().unwrap();
+            let in_use = in_use.entry(local_port).or_default();
+            if *in_use >= self.max_size {
+                return Err(io::Error::new(ErrorKind::ConnectionRefused, format!("{} connections to local port {} are in use", in_use, local_port)));
+            }
+            // taken before connecting so that concurrent checkouts don't go over `max_size`
+            *in_use += 1;
+        }
+
+        // also frees the slot when the checkout is dropped before connecting e.g.) by a connect timeout
+        let slot = Slot { pool: self, local_port };
+        let conn = connect.await?;
+        std::mem::forget(slot);
+        Ok(conn)
+    }
+
+    /// Free the slot of a checked out connection that was closed.
+    pub fn release(&self, local_port: u16) {
+        let mut in_use = self.in_use.lock().unwrap();
+        if let Some(n) = in_use.get_mut(&local_port) {
+            *n = n.saturating_sub(1);
+            if *n == 0 {
+                in_use.remove(&local_port);
+            }
+        }
+    }
+
+    pub fn len_in_use(&self, local_port: u16) -> usize {
+        self.in_use.lock().unwrap().get(&local_port).copied().unwrap_or_default()
+    }
+}
+
+// slot of a checkout that is still connecting
+struct Slot<'a> {
+    pool: &'a LocalPool,
+    local_port: u16,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.pool.release(self.local_port);
+    }
+}
+
+#[cfg(test)]
+mod local_pool_test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    async fn launch_listener() -> Result<(std::net::SocketAddr, Arc<AtomicUsize>), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let accepted_ = accepted.clone();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                accepted_.fetch_add(1, Ordering::SeqCst);
+                sockets.push(socket);
+            }
+        });
+        Ok((addr, accepted))
+    }
+
+    #[tokio::test]
+    async fn refuse_checkout_over_max_size() -> Result<(), Box<dyn std::error::Error>> {
+        let (addr, accepted) = launch_listener().await?;
+        let pool = LocalPool::new(2);
+
+        let first = pool.checkout(addr.port(), addr).await?;
+        let _second = pool.checkout(addr.port(), addr).await?;
+        assert!(pool.checkout(addr.port(), addr).await.is_err());
+
+        // a closed connection frees its slot, the next stream gets a connection of its own
+        let first_addr = first.local_addr()?;
+        drop(first);
+        pool.release(addr.port());
+        let third = pool.checkout(addr.port(), addr).await?;
+        assert_ne!(third.local_addr()?, first_addr);
+        assert!(pool.checkout(addr.port(), addr).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn free_slot_when_connect_fails() -> Result<(), Box<dyn std::error::Error>> {
+        let (addr, _accepted) = launch_listener().await?;
+        let pool = LocalPool::new(1);
+
+        let refused = async { Err(io::Error::from(ErrorKind::ConnectionRefused)) };
+        assert!(pool.checkout_with(addr.port(), refused).await.is_err());
+        assert_eq!(pool.len_in_use(addr.port()), 0);
+        pool.checkout(addr.port(), addr).await?;
+        assert_eq!(pool.len_in_use(addr.port()), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn free_slot_when_checkout_is_dropped() -> Result<(), Box<dyn std::error::Error>> {
+        let (addr, _accepted) = launch_listener().await?;
+        let pool = LocalPool::new(1);
+
+        let hanging = std::future::pending::<io::Result<TcpStream>>();
+        assert!(tokio::time::timeout(Duration::from_millis(10), pool.checkout_with(addr.port(), hanging)).await.is_err());
+        pool.checkout(addr.port(), addr).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+
+use log::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 proxy used to reach local services instead of connecting to them directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    pub addr: String,
+    /// username and password, no authentication when `None`
+    pub auth: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    pub fn new(addr: String, auth: Option<(String, String)>) -> Self {
+        Self { addr, auth }
+    }
+
+    /// Open a connection to `target_host:target_port` through the proxy.
+    pub async fn connect(&self, target_host: &str, target_port: u16) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        self.handshake(&mut stream).await?;
+        request_connect(&mut stream, target_host, target_port).await?;
+        debug!("connected to {}:{} via socks5 proxy {}", target_host, target_port, self.addr);
+        Ok(stream)
+    }
+
+    async fn handshake(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let methods: &[u8] = match self.auth {
+            Some(_) => &[METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+            None => &[METHOD_NO_AUTH],
+        };
+        let mut greeting = vec![VERSION, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting).await?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(invalid_data("socks5 proxy replied with unknown version"));
+        }
+
+        match (reply[1], &self.auth) {
+            (METHOD_NO_AUTH, _) => Ok(()),
+            (METHOD_USERNAME_PASSWORD, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, "socks5 username or password is too long"));
+                }
+                let mut request = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+
+                let mut reply = [0; 2];
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0x00 {
+                    return Err(io::Error::new(ErrorKind::PermissionDenied, "socks5 proxy rejected username or password"));
+                }
+                Ok(())
+            }
+            (METHOD_NOT_ACCEPTABLE, _) => Err(io::Error::new(ErrorKind::PermissionDenied, "socks5 proxy accepts none of our auth methods")),
+            _ => Err(invalid_data("socks5 proxy selected unsupported auth method")),
+        }
+    }
+}
+
+async fn request_connect(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "socks5 target host is too long"));
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid_data("socks5 proxy replied with unknown version"));
+    }
+    if reply[1] != 0x00 {
+        return Err(io::Error::new(ErrorKind::ConnectionRefused, format!("socks5 proxy failed to connect: reply {}", reply[1])));
+    }
+
+    // skip the bound address
+    let len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(invalid_data("socks5 proxy replied with unknown address type")),
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod socks5_test {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::local::tcp::LOCAL_HOST;
+
+    async fn launch_echo_server() -> io::Result<u16> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        Ok(port)
+    }
+
+    // minimal SOCKS5 server supporting CONNECT to domain names
+    async fn serve_socks5(mut socket: TcpStream, auth: Option<(&str, &str)>) -> io::Result<()> {
+        let mut header = [0; 2];
+        socket.read_exact(&mut header).await?;
+        let mut methods = vec![0; header[1] as usize];
+        socket.read_exact(&mut methods).await?;
+
+        match auth {
+            Some((username, password)) => {
+                socket.write_all(&[VERSION, METHOD_USERNAME_PASSWORD]).await?;
+                let mut header = [0; 2];
+                socket.read_exact(&mut header).await?;
+                let mut u = vec![0; header[1] as usize];
+                socket.read_exact(&mut u).await?;
+                let mut p = vec![0; socket.read_u8().await? as usize];
+                socket.read_exact(&mut p).await?;
+
+                let ok = u == username.as_bytes() && p == password.as_bytes();
+                socket.write_all(&[USERNAME_PASSWORD_VERSION, if ok { 0x00 } else { 0x01 }]).await?;
+                if !ok {
+                    return Ok(());
+                }
+            }
+            None => socket.write_all(&[VERSION, METHOD_NO_AUTH]).await?,
+        }
+
+        let mut request = [0; 5];
+        socket.read_exact(&mut request).await?;
+        assert_eq!(request[3], ATYP_DOMAIN);
+        let mut host = vec![0; request[4] as usize];
+        socket.read_exact(&mut host).await?;
+        let port = socket.read_u16().await?;
+
+        let mut target = TcpStream::connect((String::from_utf8_lossy(&host).to_string(), port)).await?;
+        socket.write_all(&[VERSION, 0x00, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await?;
+        tokio::io::copy_bidirectional(&mut socket, &mut target).await?;
+        Ok(())
+    }
+
+    async fn launch_socks5_server(auth: Option<(&'static str, &'static str)>) -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = serve_socks5(socket, auth).await;
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn assert_echo(mut stream: TcpStream) -> io::Result<()> {
+        stream.write_all(b"foobar").await?;
+        let mut buf = [0; 6];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"foobar");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forward_through_socks5_without_auth() -> Result<(), Box<dyn std::error::Error>> {
+        let target_port = launch_echo_server().await?;
+        let proxy = Socks5Proxy::new(launch_socks5_server(None).await?, None);
+
+        let stream = proxy.connect(LOCAL_HOST, target_port).await?;
+        assert_echo(stream).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forward_through_socks5_with_username_password() -> Result<(), Box<dyn std::error::Error>> {
+        let target_port = launch_echo_server().await?;
+        let addr = launch_socks5_server(Some(("alice", "secret"))).await?;
+
+        let proxy = Socks5Proxy::new(addr.clone(), Some(("alice".to_string(), "secret".to_string())));
+        let stream = proxy.connect(LOCAL_HOST, target_port).await?;
+        assert_echo(stream).await?;
+
+        let proxy = Socks5Proxy::new(addr, Some(("alice".to_string(), "wrong".to_string())));
+        let err = proxy.connect(LOCAL_HOST, target_port).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_local_port_through_socks5() -> Result<(), Box<dyn std::error::Error>> {
+        use ownserver_lib::{Endpoint, EndpointId, Protocol};
+        use crate::{local::tcp::connect_local, Store};
+
+        let endpoint = Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::TCP,
+            local_port: launch_echo_server().await?,
+            remote_port: 10000,
+        };
+        let store = Store::default().with_local_socks5(Socks5Proxy::new(launch_socks5_server(None).await?, None));
+        store.register_endpoints(vec![endpoint.clone()]);
+
+        let (stream, _) = connect_local(&store, endpoint.id, None).await?;
+        assert_echo(stream).await?;
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::{self, ErrorKind};
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::{SinkExt, StreamExt};
@@ -7,23 +8,33 @@ use futures::{SinkExt, StreamExt};
 use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 
 use crate::{StreamMessage, Store};
+use crate::local::{connect_tcp, with_timeout};
 use log::*;
-use ownserver_lib::{StreamId, EndpointId, ControlPacketV2};
+use ownserver_lib::{Capability, StreamId, EndpointId, ControlPacketV2, INITIAL_STREAM_WINDOW};
+
+/// Host of local services, also asked to the SOCKS5 proxy when one is configured.
+pub const LOCAL_HOST: &str = "localhost";
 
 /// Establish a new local stream and start processing messages to it
+/// `http_host` is the Host header the server sniffed, it picks the local port of a matching http route.
 pub async fn setup_new_stream(
     store: Arc<Store>,
     mut tunnel_tx: UnboundedSender<ControlPacketV2>,
     stream_id: StreamId,
     endpoint_id: EndpointId,
+    http_host: Option<&str>,
 ) -> io::Result<()> {
     info!("sid={} eid={} setting up local tcp stream", stream_id, endpoint_id);
-    let local_addr = store.get_local_addr_by_endpoint_id(endpoint_id).ok_or(io::Error::from(ErrorKind::Other))?;
 
-    let local_tcp = match TcpStream::connect(local_addr).await {
-        Ok(s) => s,
+    let (local_tcp, local_port) = match connect_local(&store, endpoint_id, http_host).await {
+        Ok((s, local_port)) => {
+            info!("sid={} eid={} local port {} serves the stream", stream_id, endpoint_id, local_port);
+            (s, local_port)
+        }
         Err(e) => {
             warn!("sid={} eid={} failed to connect to local service: {:?}", stream_id, endpoint_id, e);
             let _ = tunnel_tx.send(ControlPacketV2::Refused(stream_id)).await;
@@ -32,71 +43,218 @@ pub async fn setup_new_stream(
     };
     let (stream, sink) = split(local_tcp);
 
-    // Read local tcp bytes, send them tunnel
-    let store_ = store.clone();
-    tokio::spawn(async move {
-        let _ = process_local_tcp(stream, tunnel_tx, stream_id).await;
-        store_.remove_stream(&stream_id);
-        info!("sid={} remove stream to active_streams. len={}", &stream_id, store_.len_stream());
-    });
-
     // Forward remote packets to local tcp
     let (tx, rx) = unbounded();
     store.add_stream(stream_id, tx);
     info!("sid={} insert stream to active_streams. len={}", &stream_id, store.len_stream());
 
+    let window = if store.supports(Capability::FlowControl) {
+        let window = Arc::new(Semaphore::new(INITIAL_STREAM_WINDOW as usize));
+        store.add_window(stream_id, window.clone());
+        Some(window)
+    } else {
+        None
+    };
+
     tokio::spawn(async move {
-        forward_to_local_tcp(stream_id, sink, rx).await;
-        info!("sid={} end forward to local", &stream_id);
+        let half_close = store.supports(Capability::HalfClose);
+        let timeouts = store.socket_timeouts();
+        let ct = CancellationToken::new();
+
+        // Read local tcp bytes, send them tunnel
+        let read = async {
+            let end = process_local_tcp(stream, tunnel_tx.clone(), stream_id, window, timeouts.read, half_close, ct.clone()).await;
+            // a half-closed stream still forwards to the local service until the server shuts it down too
+            if !matches!(end, LocalReadEnd::HalfClosed) {
+                store.remove_stream(&stream_id);
+                info!("sid={} remove stream to active_streams. len={}", &stream_id, store.len_stream());
+            }
+            end
+        };
+        let write = async {
+            forward_to_local_tcp(stream_id, sink, rx, tunnel_tx.clone(), timeouts.write, ct.clone()).await;
+            info!("sid={} end forward to local", &stream_id);
+        };
+        let (end, ()) = tokio::join!(read, write);
+        if let LocalReadEnd::HalfClosed = end {
+            store.remove_stream(&stream_id);
+            info!("sid={} remove half-closed stream to active_streams. len={}", &stream_id, store.len_stream());
+        }
+
+        if let Some(pool) = store.pooled() {
+            pool.release(local_port);
+        }
     });
 
     Ok(())
 }
 
+/// Connect to the local service of `endpoint_id` with the socket options of `store`, trying the
+/// fallback local port when the primary one fails. Returns the local port that accepted the connection.
+pub async fn connect_local(store: &Store, endpoint_id: EndpointId, http_host: Option<&str>) -> io::Result<(TcpStream, u16)> {
+    let endpoint_port = store.get_endpoint_by_endpoint_id(endpoint_id).ok_or(io::Error::from(ErrorKind::Other))?.local_port;
+    // raw tcp and http for hosts without a route go to the local port of the endpoint
+    let local_port = http_host.and_then(|host| store.http_route(host)).unwrap_or(endpoint_port);
+
+    let (stream, local_port) = match connect_local_port(store, local_port).await {
+        Ok(stream) => (stream, local_port),
+        Err(e) => match store.local_fallback(local_port) {
+            Some(fallback) => {
+                warn!("eid={} failed to connect to local port {}: {:?}, try fallback {}", endpoint_id, local_port, e, fallback);
+                (connect_local_port(store, fallback).await?, fallback)
+            }
+            None => return Err(e),
+        },
+    };
+    store.socket_options().tcp.apply(&stream)?;
+    Ok((stream, local_port))
+}
+
+async fn connect_local_port(store: &Store, local_port: u16) -> io::Result<TcpStream> {
+    let connect = connect_tcp(LOCAL_HOST, local_port, store.socket_options().bind_addr);
+    let connect = async {
+        match (store.local_socks5(), store.pooled()) {
+            (Some(proxy), _) => proxy.connect(LOCAL_HOST, local_port).await,
+            (None, Some(pool)) => pool.checkout_with(local_port, connect).await,
+            (None, None) => connect.await,
+        }
+    };
+    with_timeout(store.socket_timeouts().connect, connect).await
+}
+
+/// Why `process_local_tcp` stopped reading.
+#[derive(Debug)]
+pub enum LocalReadEnd {
+    /// stopped by the cancellation token
+    Cancelled,
+    /// the local service is done sending and the server was told with `ShutdownWrite`,
+    /// data from the server is still written to it
+    HalfClosed,
+    Closed,
+}
+
+/// Largest read from a local tcp service, each read is sent as one `Data` packet.
+pub const LOCAL_READ_BUFFER: usize = 16 * 1024;
+
+/// Stops when `ct` is cancelled.
+/// Reads take from `window` before they happen and never read more than it has, so a local service that sends
+/// a large burst at once is read only as fast as the server acknowledges the data with `WindowUpdate`.
+/// The rest of the burst waits in the socket buffers, pushing back on the local service.
+/// Without a window, for servers that don't support flow control, reads never pause.
+/// The stream is closed on both ends when the local service sends nothing for `read_timeout`.
+/// The server is told with `End` when the local service resets the connection, and when it closes the connection
+/// unless `half_close` is set, then with `ShutdownWrite`.
 pub async fn process_local_tcp(
     mut stream: ReadHalf<TcpStream>,
     mut tunnel: UnboundedSender<ControlPacketV2>,
     stream_id: StreamId,
-) {
-    let mut buf = [0; 4 * 1024];
+    window: Option<Arc<Semaphore>>,
+    read_timeout: Option<Duration>,
+    half_close: bool,
+    ct: CancellationToken,
+) -> LocalReadEnd {
+    let mut buf = vec![0; LOCAL_READ_BUFFER];
 
     loop {
-        let n = match stream.read(&mut buf).await {
+        let granted = match window {
+            Some(ref window) => {
+                let acquired = tokio::select! {
+                    acquired = window.acquire() => acquired,
+                    _ = ct.cancelled() => {
+                        debug!("sid={} stop reading from local service", &stream_id);
+                        return LocalReadEnd::Cancelled;
+                    }
+                };
+                match acquired {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => {
+                        info!("sid={} stream window was closed", &stream_id);
+                        return LocalReadEnd::Closed;
+                    }
+                }
+                // only this loop takes from the window, the permits seen are still there
+                let more = window.available_permits().min(buf.len() - 1);
+                match window.try_acquire_many(more as u32) {
+                    Ok(permit) => {
+                        permit.forget();
+                        1 + more
+                    }
+                    Err(_) => 1,
+                }
+            }
+            None => buf.len(),
+        };
+
+        let read = tokio::select! {
+            read = with_timeout(read_timeout, stream.read(&mut buf[..granted])) => read,
+            _ = ct.cancelled() => {
+                debug!("sid={} stop reading from local service", &stream_id);
+                return LocalReadEnd::Cancelled;
+            }
+        };
+        let n = match read {
             Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                warn!("sid={} read from local service timed out, closing stream", &stream_id);
+                // unlike End, Refused also closes the remote socket on servers that ignore End from the client
+                let _ = tunnel.send(ControlPacketV2::Refused(stream_id)).await;
+                return LocalReadEnd::Closed;
+            }
             Err(e) => {
                 error!("sid={} failed to read data from socket: {:?}", &stream_id, e);
-                return;
+                // e.g. reset by the local service, the remote peer should not wait for its idle timeout
+                let _ = tunnel.send(ControlPacketV2::End(stream_id)).await;
+                return LocalReadEnd::Closed;
             }
         };
 
         if n == 0 {
             info!("sid={} done reading from client stream", &stream_id);
-            return;
+            if half_close {
+                if tunnel.send(ControlPacketV2::ShutdownWrite(stream_id)).await.is_ok() {
+                    return LocalReadEnd::HalfClosed;
+                }
+                return LocalReadEnd::Closed;
+            }
+            let _ = tunnel.send(ControlPacketV2::End(stream_id)).await;
+            return LocalReadEnd::Closed;
         }
 
-        let data = buf[..n].to_vec();
-        debug!(
-            "sid={} read from local service: {}",
-            &stream_id,
-            data.len(),
-        );
+        // give back what the read didn't use
+        if let Some(ref window) = window {
+            window.add_permits(granted - n);
+        }
 
-        let packet = ControlPacketV2::Data(stream_id, data.clone());
+        debug!("sid={} read from local service: {}", &stream_id, n);
+        let packet = ControlPacketV2::Data(stream_id, buf[..n].to_vec());
         if let Err(e) = tunnel.send(packet).await {
             error!("sid={} failed to tunnel packet from local tcp to tunnel: {:?}", &stream_id, e);
-            return;
+            return LocalReadEnd::Closed;
         }
     }
 }
 
+/// Shuts the write half down on `Close`, and on `ShutdownWrite` without closing the read half.
+/// Every write is acknowledged to the server with `WindowUpdate`.
+/// A write that does not complete within `write_timeout` closes the stream and cancels `ct`.
 pub async fn forward_to_local_tcp(
     stream_id: StreamId,
     mut sink: WriteHalf<TcpStream>,
     mut queue: UnboundedReceiver<StreamMessage>,
+    mut tunnel: UnboundedSender<ControlPacketV2>,
+    write_timeout: Option<Duration>,
+    ct: CancellationToken,
 ) {
     loop {
         let data = match queue.next().await {
             Some(StreamMessage::Data(data)) => data,
+            Some(StreamMessage::ShutdownWrite) => {
+                debug!("sid={} remote peer is done sending, shut down write half", &stream_id);
+                let _ = sink.shutdown().await.map_err(|e| {
+                    error!("sid={} failed to shutdown: {:?}", &stream_id, e);
+                });
+                return;
+            }
             None | Some(StreamMessage::Close) => {
                 warn!("sid={} closing stream", &stream_id);
                 let _ = sink.shutdown().await.map_err(|e| {
@@ -106,10 +264,320 @@ pub async fn forward_to_local_tcp(
             }
         };
 
-        if let Err(e) = sink.write_all(&data).await {
-            error!("sid={} failed to write packet data to local tcp socket: {:?}", &stream_id, e);
-            return;
+        match with_timeout(write_timeout, sink.write_all(&data)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                warn!("sid={} write to local service timed out, closing stream", &stream_id);
+                let _ = tunnel.send(ControlPacketV2::Refused(stream_id)).await;
+                ct.cancel();
+                return;
+            }
+            Err(e) => {
+                error!("sid={} failed to write packet data to local tcp socket: {:?}", &stream_id, e);
+                return;
+            }
         }
         debug!("sid={} wrote to local service: {}", &stream_id, data.len());
+
+        let _ = tunnel.send(ControlPacketV2::WindowUpdate(stream_id, data.len() as u32)).await;
+    }
+}
+
+#[cfg(test)]
+mod local_tcp_timeout_test {
+    use super::*;
+    use tokio::net::{TcpListener, TcpSocket};
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn close_stream_when_local_write_times_out() -> Result<(), Box<dyn std::error::Error>> {
+        // accept and never read
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let socket = TcpSocket::new_v4()?;
+        socket.set_send_buffer_size(4096)?;
+        let (_stream, sink) = split(socket.connect(addr).await?);
+
+        let stream_id = StreamId::new();
+        let (queue_tx, queue_rx) = unbounded();
+        let (tunnel_tx, mut tunnel_rx) = unbounded();
+        for _ in 0..1024 {
+            queue_tx.unbounded_send(StreamMessage::Data(vec![0; 64 * 1024]))?;
+        }
+
+        let ct = CancellationToken::new();
+        let start = Instant::now();
+        forward_to_local_tcp(stream_id, sink, queue_rx, tunnel_tx, Some(Duration::from_millis(200)), ct.clone()).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(ct.is_cancelled());
+
+        let mut refused = false;
+        while let Ok(Some(packet)) = tunnel_rx.try_next() {
+            refused |= packet == ControlPacketV2::Refused(stream_id);
+        }
+        assert!(refused);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod local_tcp_burst_test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn read_initial_burst_within_window() -> Result<(), Box<dyn std::error::Error>> {
+        let burst: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let burst_ = burst.clone();
+        tokio::spawn(async move {
+            if let Ok((mut socket, _)) = listener.accept().await {
+                let _ = socket.write_all(&burst_).await;
+            }
+        });
+
+        let (stream, _sink) = split(TcpStream::connect(addr).await?);
+        let stream_id = StreamId::new();
+        let window = Arc::new(Semaphore::new(INITIAL_STREAM_WINDOW as usize));
+        let (tunnel_tx, mut tunnel_rx) = unbounded();
+        tokio::spawn(process_local_tcp(stream, tunnel_tx, stream_id, Some(window.clone()), None, false, CancellationToken::new()));
+
+        // acknowledge only once the reader has gone quiet, i.e. has used up the window
+        let mut received = Vec::with_capacity(burst.len());
+        let mut unacked = 0;
+        loop {
+            match tokio::time::timeout(Duration::from_millis(50), tunnel_rx.next()).await {
+                Ok(Some(ControlPacketV2::Data(sid, data))) => {
+                    assert_eq!(sid, stream_id);
+                    assert!(data.len() <= LOCAL_READ_BUFFER);
+                    unacked += data.len();
+                    assert!(unacked <= INITIAL_STREAM_WINDOW as usize, "{} bytes are in flight", unacked);
+                    received.extend_from_slice(&data);
+                }
+                Ok(Some(ControlPacketV2::End(sid))) if sid == stream_id => break,
+                Ok(other) => panic!("unexpected packet {:?}", other),
+                Err(_) => {
+                    window.add_permits(unacked);
+                    unacked = 0;
+                }
+            }
+        }
+        assert_eq!(received.len(), burst.len());
+        assert!(received == burst, "the burst arrived out of order");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod local_tcp_socket_options_test {
+    use super::*;
+    use crate::local::{SocketOptions, TcpOptions};
+    use ownserver_lib::{Endpoint, Protocol};
+    use tokio::net::TcpListener;
+
+    async fn connect_with(options: SocketOptions) -> io::Result<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::TCP,
+            local_port: listener.local_addr()?.port(),
+            remote_port: 10000,
+        };
+        let store = Store::default().with_socket_options(options);
+        store.register_endpoints(vec![endpoint.clone()]);
+        connect_local(&store, endpoint.id, None).await.map(|(stream, _)| stream)
+    }
+
+    #[tokio::test]
+    async fn set_nodelay_by_default() -> io::Result<()> {
+        let stream = connect_with(SocketOptions::default()).await?;
+        assert!(stream.nodelay()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_nagle_when_nodelay_is_off() -> io::Result<()> {
+        let stream = connect_with(SocketOptions { tcp: TcpOptions { nodelay: false, keepalive: None }, ..Default::default() }).await?;
+        assert!(!stream.nodelay()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_from_bind_addr() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::TCP,
+            local_port: listener.local_addr()?.port(),
+            remote_port: 10000,
+        };
+        let bind_addr = "127.0.0.2".parse().unwrap();
+        let store = Store::default().with_socket_options(SocketOptions { bind_addr: Some(bind_addr), ..Default::default() });
+        store.register_endpoints(vec![endpoint.clone()]);
+
+        let (stream, _) = connect_local(&store, endpoint.id, None).await?;
+        assert_eq!(stream.local_addr()?.ip(), bind_addr);
+        let (_, peer_addr) = listener.accept().await?;
+        assert_eq!(peer_addr.ip(), bind_addr);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod local_tcp_connect_timeout_test {
+    use super::*;
+    use crate::local::{pool::LocalPool, SocketTimeouts};
+    use ownserver_lib::{Endpoint, Protocol};
+    use std::time::Instant;
+    use tokio::net::TcpSocket;
+
+    #[tokio::test]
+    async fn give_up_on_local_service_not_accepting() -> Result<(), Box<dyn std::error::Error>> {
+        // nobody accepts, once the backlog is full the kernel drops further SYNs and connects hang
+        let socket = TcpSocket::new_v4()?;
+        socket.bind("127.0.0.1:0".parse()?)?;
+        let listener = socket.listen(1)?;
+        let addr = listener.local_addr()?;
+        let mut backlog = Vec::new();
+        for _ in 0..8 {
+            backlog.push(tokio::spawn(TcpStream::connect(addr)));
+        }
+
+        let endpoint = Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::TCP,
+            local_port: addr.port(),
+            remote_port: 10000,
+        };
+        let store = Store::default().with_socket_timeouts(SocketTimeouts {
+            connect: Some(Duration::from_millis(300)),
+            ..Default::default()
+        });
+        store.register_endpoints(vec![endpoint.clone()]);
+
+        let started = Instant::now();
+        let err = connect_local(&store, endpoint.id, None).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+
+        let (tx, mut rx) = unbounded();
+        assert!(setup_new_stream(Arc::new(store), tx, StreamId::new(), endpoint.id, None).await.is_err());
+        assert!(matches!(rx.next().await, Some(ControlPacketV2::Refused(_))));
+
+        backlog.iter().for_each(|connect| connect.abort());
+        Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn free_pool_slots_of_timed_out_connects() -> Result<(), Box<dyn std::error::Error>> {
+        let socket = TcpSocket::new_v4()?;
+        socket.bind("127.0.0.1:0".parse()?)?;
+        let listener = socket.listen(1)?;
+        let addr = listener.local_addr()?;
+        let mut backlog = Vec::new();
+        for _ in 0..8 {
+            backlog.push(tokio::spawn(TcpStream::connect(addr)));
+        }
+
+        let endpoint = Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::TCP,
+            local_port: addr.port(),
+            remote_port: 10000,
+        };
+        let max_size = 2;
+        let store = Store::with_local_pool(LocalPool::new(max_size)).with_socket_timeouts(SocketTimeouts {
+            connect: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        store.register_endpoints(vec![endpoint.clone()]);
+
+        // a slot kept by a timed out connect would refuse the last checkout as over `max_size`
+        for _ in 0..max_size + 1 {
+            let err = connect_local(&store, endpoint.id, None).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+        }
+
+        backlog.iter().for_each(|connect| connect.abort());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod local_tcp_fallback_test {
+    use super::*;
+    use ownserver_lib::{Endpoint, Protocol};
+    use tokio::net::TcpListener;
+
+    async fn unused_port() -> io::Result<u16> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        listener.local_addr().map(|addr| addr.port())
+    }
+
+    #[tokio::test]
+    async fn connect_to_fallback_when_primary_is_down() -> Result<(), Box<dyn std::error::Error>> {
+        let primary = unused_port().await?;
+        let standby = TcpListener::bind("127.0.0.1:0").await?;
+        let fallback = standby.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = standby.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let endpoint = Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::TCP,
+            local_port: primary,
+            remote_port: 10000,
+        };
+        let store = Store::default();
+        store.register_endpoints(vec![endpoint.clone()]);
+        assert!(connect_local(&store, endpoint.id, None).await.is_err());
+
+        let store = Store::default().with_local_fallback(primary, fallback);
+        store.register_endpoints(vec![endpoint.clone()]);
+        let (mut stream, served_by) = connect_local(&store, endpoint.id, None).await?;
+        assert_eq!(served_by, fallback);
+
+        stream.write_all(b"foobar").await?;
+        let mut buf = [0; 6];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"foobar");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn route_http_streams_by_host() -> Result<(), Box<dyn std::error::Error>> {
+        let raw = TcpListener::bind("127.0.0.1:0").await?;
+        let web = TcpListener::bind("127.0.0.1:0").await?;
+        let (raw_port, web_port) = (raw.local_addr()?.port(), web.local_addr()?.port());
+        let endpoint = Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::TCP,
+            local_port: raw_port,
+            remote_port: 10000,
+        };
+        let store = Store::default().with_http_route("web.example.com", web_port);
+        store.register_endpoints(vec![endpoint.clone()]);
+
+        let (_, served_by) = connect_local(&store, endpoint.id, Some("Web.Example.com:8080")).await?;
+        assert_eq!(served_by, web_port);
+        let (_, served_by) = connect_local(&store, endpoint.id, Some("other.example.com")).await?;
+        assert_eq!(served_by, raw_port);
+        let (_, served_by) = connect_local(&store, endpoint.id, None).await?;
+        assert_eq!(served_by, raw_port);
+        Ok(())
+    }
+}
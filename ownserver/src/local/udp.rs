@@ -1,4 +1,5 @@
 use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
@@ -10,7 +11,9 @@ use crate::{StreamMessage, Store};
 use log::*;
 use ownserver_lib::{StreamId, EndpointId, ControlPacketV2};
 
-/// Establish a new local stream and start processing messages to it
+/// Establish a new local stream and start processing messages to it.
+/// Each stream, i.e. each remote peer, sends from its own ephemeral socket for as long as the stream lives,
+/// so the local server tells peers apart by source port.
 pub async fn setup_new_stream(
     store: Arc<Store>,
     tunnel_tx: UnboundedSender<ControlPacketV2>,
@@ -20,7 +23,8 @@ pub async fn setup_new_stream(
     info!("sid={} eid={} setting up local udp stream", stream_id, endpoint_id);
     let local_addr = store.get_local_addr_by_endpoint_id(endpoint_id).ok_or(io::Error::from(ErrorKind::Other))?;
 
-    let local_udp = match UdpSocket::bind("127.0.0.1:0").await {
+    let bind_addr = store.socket_options().bind_addr.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let local_udp = match UdpSocket::bind((bind_addr, 0)).await {
         Ok(s) => s,
         Err(e) => {
             warn!("sid={} eid={} failed to bind socket: {:?}", stream_id, endpoint_id, e);
@@ -105,6 +109,8 @@ pub async fn forward_to_local_udp(
     loop {
         let data = match queue.next().await {
             Some(StreamMessage::Data(data)) => data,
+            // datagrams have no direction to close
+            Some(StreamMessage::ShutdownWrite) => continue,
             None | Some(StreamMessage::Close) => {
                 warn!("sid={} closing stream", &stream_id);
                 // let _ = sink.shutdown().await.map_err(|e| {
@@ -121,4 +127,72 @@ pub async fn forward_to_local_udp(
         }
         debug!("sid={} wrote to local service: {}", &stream_id, data.len());
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod local_udp_test {
+    use super::*;
+    use crate::local::SocketOptions;
+    use ownserver_lib::{Endpoint, Protocol};
+    use std::time::Duration;
+
+    // local udp server and the endpoint forwarding to it
+    async fn local_server() -> io::Result<(UdpSocket, Endpoint)> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let endpoint = Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::UDP,
+            local_port: server.local_addr()?.port(),
+            remote_port: 10000,
+        };
+        Ok((server, endpoint))
+    }
+
+    #[tokio::test]
+    async fn send_from_bind_addr() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, endpoint) = local_server().await?;
+        let bind_addr: IpAddr = "127.0.0.2".parse()?;
+        let store = Arc::new(Store::default().with_socket_options(SocketOptions { bind_addr: Some(bind_addr), ..Default::default() }));
+        store.register_endpoints(vec![endpoint.clone()]);
+
+        let (tunnel_tx, _tunnel_rx) = unbounded();
+        let stream_id = StreamId::new();
+        setup_new_stream(store.clone(), tunnel_tx, stream_id, endpoint.id).await?;
+        store.get_stream(&stream_id).expect("stream was not added").unbounded_send(StreamMessage::Data(b"ping".to_vec()))?;
+
+        let mut buf = [0; 16];
+        let (n, peer_addr) = tokio::time::timeout(Duration::from_secs(2), server.recv_from(&mut buf)).await??;
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(peer_addr.ip(), bind_addr);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_each_peer_from_its_own_port() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, endpoint) = local_server().await?;
+        let store = Arc::new(Store::default());
+        store.register_endpoints(vec![endpoint.clone()]);
+
+        let (tunnel_tx, mut tunnel_rx) = unbounded();
+        let alice = StreamId::new();
+        let bob = StreamId::new();
+        let mut buf = [0; 16];
+        let mut ports = Vec::new();
+        for stream_id in [alice, bob, alice] {
+            if store.get_stream(&stream_id).is_none() {
+                setup_new_stream(store.clone(), tunnel_tx.clone(), stream_id, endpoint.id).await?;
+            }
+            store.get_stream(&stream_id).expect("stream was not added").unbounded_send(StreamMessage::Data(b"ping".to_vec()))?;
+            let (_, peer_addr) = tokio::time::timeout(Duration::from_secs(2), server.recv_from(&mut buf)).await??;
+            ports.push(peer_addr.port());
+        }
+        assert_ne!(ports[0], ports[1]);
+        assert_eq!(ports[0], ports[2]);
+
+        // replies go back to the stream of the port they are sent to
+        server.send_to(b"pong", ("127.0.0.1", ports[1])).await?;
+        let packet = tokio::time::timeout(Duration::from_secs(2), tunnel_rx.next()).await?;
+        assert_eq!(packet, Some(ControlPacketV2::Data(bob, b"pong".to_vec())));
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+pub use ownserver_lib::LogFormat;
+
+/// Install a global subscriber writing to stderr. `log` records are forwarded to it as well.
+/// Verbosity is read from `RUST_LOG` and defaults to errors only.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    let registry = tracing_subscriber::registry().with(filter);
+    let result = match format {
+        LogFormat::Pretty => registry.with(fmt::layer().with_writer(std::io::stderr)).try_init(),
+        LogFormat::Json => registry.with(fmt::layer().json().with_writer(std::io::stderr)).try_init(),
+    };
+    result.expect("failed to register tracer with registry");
+}
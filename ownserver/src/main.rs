@@ -1,29 +1,95 @@
-use std::{sync::Arc, ops::RangeInclusive};
+use std::{fmt, net::IpAddr, path::PathBuf, sync::Arc, ops::RangeInclusive, time::Duration};
 use anyhow::Result;
 use log::*;
-use ownserver_lib::{EndpointClaim, Protocol};
+use ownserver_lib::{parse_control_path, EndpointClaim, Protocol, DEFAULT_CONTROL_PATH};
 use tokio_util::sync::CancellationToken;
 use clap::Parser;
 
-use ownserver::{proxy_client::run, api, Store};
+use ownserver::{config::{ClientConfig, DEFAULT_TOKEN_SERVER}, proxy_client::run_with_config, api, logging::{self, LogFormat}, stats, local::{pool::LocalPool, socks5::Socks5Proxy, SocketOptions, SocketTimeouts, TcpOptions}, transport::Transport, Store};
 
+/// Every option can also be set by the `OWNSERVER_` environment variable of its name, e.g. `OWNSERVER_TOKEN_SERVER`.
+/// The command line wins over the environment, which wins over the default.
 #[derive(Parser, Debug)]
 #[command(name = "ownserver")]
 #[command(author, version, about, long_about = None)] 
 struct Cli {
-    #[arg(long, required = true, help = "Port and protocol of your local game server e.g.) `25565/tcp` for Minecraft", value_parser = parse_endpoint)]
+    #[arg(long, env = "OWNSERVER_ENDPOINT", value_delimiter = ',', required = true, help = "Port and protocol of your local game server e.g.) `25565/tcp` for Minecraft, `25565/tcp/30000` to ask for remote port 30000", value_parser = parse_endpoint)]
     endpoint: Vec<EndpointClaim>,
 
-    #[arg(long, help = "Advanced settings. You can inspect client's internal state at localhost:<api_port>.")]
+    #[arg(long, env = "OWNSERVER_API_PORT", help = "Advanced settings. You can inspect client's internal state at localhost:<api_port>.")]
     api_port: Option<u16>,
-    #[arg(long, default_value_t = 5000, help = "Advanced settings")]
-    control_port: u16,
-    #[arg(long, default_value = "https://auth.ownserver.kumassy.com/v1/request_token", help = "Advanced settings")]
+    #[arg(long, env = "OWNSERVER_CLIENT_STATUS_PORT", help = "Advanced settings. Report tunnel info, active streams, traffic and the last error as json at <client_status_host>:<client_status_port>/status")]
+    client_status_port: Option<u16>,
+    #[arg(long, env = "OWNSERVER_CLIENT_STATUS_HOST", default_value = "127.0.0.1", help = "Advanced settings. Address the status endpoint listens on")]
+    client_status_host: IpAddr,
+    #[arg(long, env = "OWNSERVER_CONTROL_PORT", help = "Advanced settings. 5000 by default, 5001 with `--transport h2` to match the port the server is usually given as `--h2-control-port`")]
+    control_port: Option<u16>,
+    #[arg(long, env = "OWNSERVER_CONTROL_PATH", default_value = DEFAULT_CONTROL_PATH, value_parser = parse_control_path, help = "Advanced settings. Path of the server's WebSocket endpoint when it is served behind a reverse proxy e.g.) `/ownserver/tunnel`")]
+    control_path: String,
+    #[arg(long, env = "OWNSERVER_TRANSPORT", value_enum, default_value_t = Transport::WebSocket, help = "Advanced settings. Carrier of the control channel. Use `h2` if WebSockets are blocked on your network, it does not support TLS")]
+    transport: Transport,
+    #[arg(long, env = "OWNSERVER_TOKEN_SERVER", default_value = DEFAULT_TOKEN_SERVER, help = "Advanced settings")]
     token_server: String,
+    #[arg(long, env = "OWNSERVER_RECONNECT_ATTEMPTS", default_value_t = 0, help = "Advanced settings. Times to retry establishing the tunnel when the server is down or refuses for a transient reason")]
+    reconnect_attempts: u32,
+    #[arg(long, env = "OWNSERVER_RECONNECT_DELAY", default_value_t = 1, help = "Advanced settings. Seconds to wait between retries, longer while the server is full or asks for a longer wait")]
+    reconnect_delay: u64,
+    #[arg(long, env = "OWNSERVER_HAPPY_EYEBALLS", help = "Advanced settings. Try the IPv6 and IPv4 addresses of the proxy server side by side and keep the first that connects, for networks with broken IPv6")]
+    happy_eyeballs: bool,
+    #[arg(long, env = "OWNSERVER_SERVER_CA", help = "Advanced settings. Connect with TLS and check the server certificate against the CA in this pem file")]
+    server_ca: Option<PathBuf>,
+    #[arg(long, env = "OWNSERVER_CLIENT_CERT", requires = "client_key", help = "Advanced settings. Connect with TLS and present the certificate in this pem file to servers requiring client certificates")]
+    client_cert: Option<PathBuf>,
+    #[arg(long, env = "OWNSERVER_CLIENT_KEY", requires = "client_cert", help = "Advanced settings. Private key of --client-cert")]
+    client_key: Option<PathBuf>,
+    #[arg(long, env = "OWNSERVER_LOCAL_POOL", help = "Advanced settings. Limit the open local tcp connections per local port to --local-pool-max-size. Connections are not reused across streams")]
+    local_pool: bool,
+    #[arg(long, env = "OWNSERVER_LOCAL_POOL_MAX_SIZE", default_value_t = 32, help = "Advanced settings. Maximum open connections per local port with --local-pool. Streams over it are refused")]
+    local_pool_max_size: usize,
+    #[arg(long, env = "OWNSERVER_LOCAL_CONNECT_TIMEOUT", default_value_t = 5, help = "Advanced settings. Close a stream when the local server does not accept the connection within this many seconds, 0 to wait as long as the OS does")]
+    local_connect_timeout: u64,
+    #[arg(long, env = "OWNSERVER_READ_TIMEOUT", help = "Advanced settings. Close a stream when the local server sends nothing for this many seconds")]
+    read_timeout: Option<u64>,
+    #[arg(long, env = "OWNSERVER_WRITE_TIMEOUT", help = "Advanced settings. Close a stream when the local server does not accept data for this many seconds")]
+    write_timeout: Option<u64>,
+    #[arg(long, env = "OWNSERVER_LOCAL_SOCKS5", help = "Advanced settings. Reach your local game server through this SOCKS5 proxy e.g.) `127.0.0.1:1080`")]
+    local_socks5: Option<String>,
+    #[arg(long, env = "OWNSERVER_LOCAL_SOCKS5_USERNAME", requires = "local_socks5", requires = "local_socks5_password", help = "Advanced settings. Username for the SOCKS5 proxy")]
+    local_socks5_username: Option<String>,
+    #[arg(long, env = "OWNSERVER_LOCAL_SOCKS5_PASSWORD", hide_env_values = true, requires = "local_socks5_username", help = "Advanced settings. Password for the SOCKS5 proxy", value_parser = parse_secret)]
+    local_socks5_password: Option<Secret>,
+    #[arg(long, env = "OWNSERVER_NO_NODELAY", help = "Advanced settings. Keep Nagle's algorithm on local tcp connections")]
+    no_nodelay: bool,
+    #[arg(long, env = "OWNSERVER_LOCAL_BIND_ADDR", help = "Advanced settings. Source address of connections to your local game server, to pick the interface on multi-homed machines e.g.) `192.168.1.10`", value_parser = parse_local_bind_addr)]
+    local_bind_addr: Option<IpAddr>,
+    #[arg(long, env = "OWNSERVER_LOCAL_PORT_FALLBACK", value_delimiter = ',', help = "Advanced settings. Connect tcp streams to a standby local server when the primary refuses e.g.) `25565:25566`", value_parser = parse_local_fallback)]
+    local_port_fallback: Vec<(u16, u16)>,
+    #[arg(long, env = "OWNSERVER_HTTP_ROUTE", value_delimiter = ',', help = "Advanced settings. Connect http requests for a host to another local port when the server runs with `--sniff-http`, raw tcp keeps the endpoint's port e.g.) `map.example.com:8123`", value_parser = parse_http_route)]
+    http_route: Vec<(String, u16)>,
+    #[arg(long, env = "OWNSERVER_LOOPBACK", help = "Run a built-in echo server on each local port instead of your game server, to check that bytes sent to the public port come back")]
+    loopback: bool,
+    #[arg(long, env = "OWNSERVER_STATS_INTERVAL", help = "Advanced settings. Log active streams, bytes up/down and uptime every this many seconds. Shown at `RUST_LOG=info`")]
+    stats_interval: Option<u64>,
+    #[arg(long, env = "OWNSERVER_LOG_FORMAT", default_value_t = LogFormat::Pretty, help = "Advanced settings. `pretty` or `json`, use `json` for structured logs")]
+    log_format: LogFormat,
 }
 
 const PORT_RANGE: RangeInclusive<usize> = 1..=65535;
 
+/// Option value kept out of the debug log of `Cli`.
+#[derive(Clone)]
+struct Secret(String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+fn parse_secret(s: &str) -> Result<Secret, String> {
+    Ok(Secret(s.to_string()))
+}
+
 fn parse_endpoint(s: &str) -> Result<EndpointClaim, String> {
     let mut parts = s.split('/');
 
@@ -48,47 +114,247 @@ fn parse_endpoint(s: &str) -> Result<EndpointClaim, String> {
         _ => return Err(format!("`{s}` isn't a valid protocol")),
     };
 
+    // only granted by servers running with --fixed-ports
+    let remote_port = match parts.next() {
+        Some(p) => match p.parse::<usize>() {
+            Ok(remote_port) if PORT_RANGE.contains(&remote_port) => remote_port as u16,
+            _ => return Err(format!("`{p}` isn't a valid remote port")),
+        },
+        None => 0,
+    };
+
     Ok(EndpointClaim {
         protocol,
         local_port: port,
-        remote_port: 0,
+        remote_port,
     })
 }
 
+fn parse_local_fallback(s: &str) -> Result<(u16, u16), String> {
+    let (primary, fallback) = s.split_once(':').ok_or(format!("`{s}` isn't a valid fallback, expected `<local_port>:<fallback_port>`"))?;
+    let parse_port = |p: &str| match p.parse::<usize>() {
+        Ok(port) if PORT_RANGE.contains(&port) => Ok(port as u16),
+        _ => Err(format!("`{p}` isn't a valid port")),
+    };
+    Ok((parse_port(primary)?, parse_port(fallback)?))
+}
+
+fn parse_http_route(s: &str) -> Result<(String, u16), String> {
+    let (host, port) = s.rsplit_once(':').ok_or(format!("`{s}` isn't a valid http route, expected `<host>:<local_port>`"))?;
+    if host.is_empty() {
+        return Err(format!("`{s}` has no host"));
+    }
+    match port.parse::<usize>() {
+        Ok(port) if PORT_RANGE.contains(&port) => Ok((host.to_string(), port as u16)),
+        _ => Err(format!("`{port}` isn't a valid port")),
+    }
+}
+
+// an address no interface has fails here instead of on every local connection
+fn parse_local_bind_addr(s: &str) -> Result<IpAddr, String> {
+    let addr: IpAddr = s.parse().map_err(|_| format!("`{s}` isn't a valid ip address"))?;
+    if addr.is_unspecified() || addr.is_multicast() {
+        return Err(format!("`{s}` isn't an address of this machine"));
+    }
+    std::net::UdpSocket::bind((addr, 0)).map_err(|e| format!("can't bind to `{s}`: {e}"))?;
+    Ok(addr)
+}
+
+// a connect timeout of 0 would time out every connection at once, so it means none
+fn socket_timeouts(cli: &Cli) -> SocketTimeouts {
+    SocketTimeouts {
+        connect: Some(Duration::from_secs(cli.local_connect_timeout)).filter(|timeout| !timeout.is_zero()),
+        read: cli.read_timeout.map(Duration::from_secs),
+        write: cli.write_timeout.map(Duration::from_secs),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    pretty_env_logger::init();
-
     let cli = Cli::parse();
+    logging::init(cli.log_format);
     debug!("{:?}", cli);
 
-    let store: Arc<Store> = Default::default();
+    let store = if cli.local_pool {
+        Store::with_local_pool(LocalPool::new(cli.local_pool_max_size))
+    } else {
+        Store::default()
+    };
+    let mut store = store.with_socket_timeouts(socket_timeouts(&cli)).with_socket_options(SocketOptions {
+        tcp: TcpOptions { nodelay: !cli.no_nodelay, keepalive: None },
+        bind_addr: cli.local_bind_addr,
+    }).with_happy_eyeballs(cli.happy_eyeballs);
+    for (primary, fallback) in cli.local_port_fallback.iter() {
+        store = store.with_local_fallback(*primary, *fallback);
+    }
+    for (host, local_port) in cli.http_route.iter() {
+        store = store.with_http_route(host, *local_port);
+    }
+    if let Some(addr) = cli.local_socks5.clone() {
+        let auth = cli.local_socks5_username.clone().zip(cli.local_socks5_password.clone().map(|password| password.0));
+        store = store.with_local_socks5(Socks5Proxy::new(addr, auth));
+    }
+    let store = Arc::new(store);
     let cancellation_token = CancellationToken::new();
 
+    if cli.loopback {
+        for endpoint in cli.endpoint.iter() {
+            println!("loopback mode: echoing {}/{}", endpoint.local_port, endpoint.protocol);
+        }
+    }
+
+
+    if let Some(port) = cli.client_status_port {
+        info!("client status is available at {}:{}/status", cli.client_status_host, port);
+        tokio::spawn(api::spawn_status(store.clone(), cli.client_status_host, port));
+    }
 
+    if let Some(secs) = cli.stats_interval.filter(|secs| *secs > 0) {
+        tokio::spawn(stats::log_periodically(store.clone(), Duration::from_secs(secs), cancellation_token.clone()));
+    }
+
+    let mut config = ClientConfig::builder()
+        .control_path(cli.control_path)
+        .token_server(cli.token_server)
+        .transport(cli.transport)
+        .endpoints(cli.endpoint)
+        .reconnect(cli.reconnect_attempts, Duration::from_secs(cli.reconnect_delay))
+        .loopback(cli.loopback);
+    if let Some(port) = cli.control_port {
+        config = config.control_port(port);
+    }
+    if let Some(path) = cli.server_ca {
+        config = config.server_ca(path);
+    }
+    if let Some((cert, key)) = cli.client_cert.zip(cli.client_key) {
+        config = config.client_cert(cert, key);
+    }
+    let config = config.build();
     let store_ = store.clone();
-    let (client_info, mut set) =
-        run(store_, cli.control_port, &cli.token_server, cancellation_token, cli.endpoint).await?;
-    info!("client is running under configuration: {:?}", client_info);
+    let mut handle = run_with_config(store_, config, cancellation_token).await?;
+    info!("client is running under configuration: {:?}", handle.client_info());
+    for addr in handle.client_info().remote_addrs() {
+        println!("Your server is available at {} ({} localhost:{})", addr, addr.protocol, addr.local_port);
+    }
 
     if let Some(api_port) = cli.api_port {
         info!("client side api is available at localhost:{}", api_port);
-        set.spawn(async move {
+        handle.spawn(async move {
             api::spawn_api(store, api_port).await;
             Ok(())
         });
     }
 
-    while let Some(res) = set.join_next().await {
-        match res {
-            Err(join_error) => {
-                error!("join error {:?} for client", join_error);
+    let summary = handle.wait().await;
+    info!("client terminated: {}", summary);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod cli_env_test {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    // every option falls back to the process environment, which the tests share while running in parallel
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        let _env = lock_env();
+        Cli::try_parse_from(args.iter().copied())
+    }
+
+    #[test]
+    fn read_options_from_env() -> Result<(), clap::Error> {
+        let vars = [
+            ("OWNSERVER_ENDPOINT", "25565/tcp,19132/udp"),
+            ("OWNSERVER_CONTROL_PORT", "6000"),
+            ("OWNSERVER_LOOPBACK", "true"),
+            ("OWNSERVER_NO_NODELAY", "false"),
+        ];
+        let cli = {
+            let _env = lock_env();
+            for (name, value) in vars {
+                std::env::set_var(name, value);
             }
-            Ok(_) => {
-                info!("client successfully terminated");
+            let cli = Cli::try_parse_from(["ownserver", "--control-port", "7000"]);
+            for (name, _) in vars {
+                std::env::remove_var(name);
             }
-        }
+            cli?
+        };
+
+        assert_eq!(cli.endpoint.len(), 2);
+        assert_eq!(cli.endpoint[1].protocol, Protocol::UDP);
+        assert_eq!(cli.endpoint[1].local_port, 19132);
+        // the command line wins over the environment
+        assert_eq!(cli.control_port, Some(7000));
+        assert!(cli.loopback);
+        assert!(!cli.no_nodelay);
+        // the default applies without either
+        assert_eq!(cli.token_server, DEFAULT_TOKEN_SERVER);
+        Ok(())
     }
 
-    Ok(())
+    #[test]
+    fn accept_only_local_bind_addrs() {
+        assert_eq!(parse_local_bind_addr("127.0.0.2"), Ok("127.0.0.2".parse().unwrap()));
+        assert!(parse_local_bind_addr("0.0.0.0").is_err());
+        assert!(parse_local_bind_addr("localhost").is_err());
+        // TEST-NET-1, assigned to no interface
+        assert!(parse_local_bind_addr("192.0.2.123").is_err());
+    }
+
+    #[test]
+    fn keep_reconnect_off_by_default() -> Result<(), clap::Error> {
+        let cli = parse(&["ownserver", "--endpoint", "25565/tcp"])?;
+        assert_eq!((cli.reconnect_attempts, cli.reconnect_delay), (0, 1));
+        let cli = parse(&["ownserver", "--endpoint", "25565/tcp", "--reconnect-attempts", "5", "--reconnect-delay", "3"])?;
+        assert_eq!((cli.reconnect_attempts, cli.reconnect_delay), (5, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_log_format() -> Result<(), clap::Error> {
+        let cli = parse(&["ownserver", "--endpoint", "25565/tcp"])?;
+        assert_eq!(cli.log_format, LogFormat::Pretty);
+        let cli = parse(&["ownserver", "--endpoint", "25565/tcp", "--log-format", "json"])?;
+        assert_eq!(cli.log_format, LogFormat::Json);
+        assert!(parse(&["ownserver", "--endpoint", "25565/tcp", "--log-format", "xml"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn take_zero_connect_timeout_as_none() -> Result<(), clap::Error> {
+        let cli = parse(&["ownserver", "--endpoint", "25565/tcp"])?;
+        assert_eq!(socket_timeouts(&cli).connect, Some(Duration::from_secs(5)));
+        let cli = parse(&["ownserver", "--endpoint", "25565/tcp", "--local-connect-timeout", "0"])?;
+        assert_eq!(socket_timeouts(&cli).connect, None);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_socks5_password_out_of_debug_log() -> Result<(), clap::Error> {
+        let cli = parse(&[
+            "ownserver", "--endpoint", "25565/tcp",
+            "--local-socks5", "127.0.0.1:1080", "--local-socks5-username", "alice", "--local-socks5-password", "hunter2",
+        ])?;
+        assert_eq!(cli.local_socks5_password.as_ref().map(|password| password.0.as_str()), Some("hunter2"));
+        let logged = format!("{:?}", cli);
+        assert!(logged.contains("alice"));
+        assert!(!logged.contains("hunter2"), "{}", logged);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_http_routes() {
+        assert_eq!(parse_http_route("map.example.com:8123"), Ok(("map.example.com".to_string(), 8123)));
+        assert!(parse_http_route("map.example.com").is_err());
+        assert!(parse_http_route(":8123").is_err());
+        assert!(parse_http_route("map.example.com:0").is_err());
+    }
 }
@@ -5,42 +5,199 @@ use futures::{Sink, SinkExt, Stream, StreamExt};
 use log::*;
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Encoder, Decoder};
-use std::sync::Arc;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
-use tokio::task::JoinSet;
+use tokio::net::TcpStream;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Instant;
 use tokio_tungstenite::{
-    connect_async,
+    client_async,
     tungstenite::{Error as WsError, Message},
 };
 use tokio_util::sync::CancellationToken;
 use url::Url;
 
+use crate::breaker::CircuitBreaker;
+use crate::config::ClientConfig;
 use crate::error::Error;
+use crate::{happy_eyeballs, tls};
+use crate::resolver::{CachingResolver, Resolve, SystemResolver, DEFAULT_DNS_CACHE_TTL};
+use crate::transport::{self, Transport};
 use crate::{local, Store};
 use crate::StreamMessage;
 use ownserver_lib::{
-    ClientId, CLIENT_HELLO_VERSION, ControlPacketV2, ControlPacketV2Codec, ClientHelloV2, EndpointClaims, Endpoints, ServerHelloV2, Protocol,
+    capability_names, negotiate_capabilities, Capability, ClientId, CloseReason, CLIENT_HELLO_VERSION, ControlPacketV2, ControlPacketV2Codec, ClientHelloV2, EndpointClaims, EndpointId, Endpoints, ServerHelloV2, Protocol, StreamId, SUPPORTED_CAPABILITIES,
 };
 
+/// How often the client checks that the server is alive when `Capability::Heartbeat` is negotiated.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
 pub async fn run(
     store: Arc<Store>,
     control_port: u16,
     token_server: &str,
     cancellation_token: CancellationToken,
     endpoint_claims: EndpointClaims,
-) -> Result<(ClientInfo, JoinSet<Result<(), Error>>)> {
+) -> Result<RunHandle> {
+    run_with_transport(store, control_port, token_server, Transport::WebSocket, cancellation_token, endpoint_claims).await
+}
+
+pub async fn run_with_transport(
+    store: Arc<Store>,
+    control_port: u16,
+    token_server: &str,
+    transport: Transport,
+    cancellation_token: CancellationToken,
+    endpoint_claims: EndpointClaims,
+) -> Result<RunHandle> {
+    let config = ClientConfig::builder()
+        .control_port(control_port)
+        .token_server(token_server)
+        .transport(transport)
+        .endpoints(endpoint_claims)
+        .build();
+    run_with_config(store, config, cancellation_token).await
+}
+
+/// Establish the tunnel, retrying transient failures as `config.reconnect_attempts` allows.
+pub async fn run_with_config(
+    store: Arc<Store>,
+    config: ClientConfig,
+    cancellation_token: CancellationToken,
+) -> Result<RunHandle> {
+    // the h2 tunnel is plaintext, so refuse rather than silently skip the certificates
+    if config.transport == Transport::H2 && config.tls.is_some() {
+        return Err(Error::TlsOverH2.into());
+    }
+    let tls = config.tls.as_ref().map(tls::client_config).transpose().map_err(Error::from)?;
+    if config.loopback {
+        for claim in config.endpoint_claims.iter() {
+            local::loopback::spawn_echo(claim).await?;
+        }
+    }
+    let mut attempt = 0;
+    loop {
+        let ClientConfig { control_port, ref control_path, ref token_server, transport, ref endpoint_claims, .. } = config;
+        match connect(store.clone(), control_port, control_path, tls.clone(), token_server, transport, cancellation_token.clone(), endpoint_claims.clone()).await {
+            Err(e) if attempt < config.reconnect_attempts && is_transient(&e) => {
+                store.stats().record_error(format!("{:#}", e));
+                store.stats().record_reconnect();
+                let delay = retry_delay(&e, attempt, config.reconnect_delay);
+                attempt += 1;
+                warn!("failed to establish tunnel: {:?}, retry {}/{} in {:?}", e, attempt, config.reconnect_attempts, delay);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = cancellation_token.cancelled() => return Err(e),
+                }
+            }
+            Err(e) => {
+                store.stats().record_error(format!("{:#}", e));
+                return Err(e);
+            }
+            result => return result,
+        }
+    }
+}
+
+// the server turned us down for a reason that retrying won't fix
+fn is_transient(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<Error>() {
+        Some(Error::BadRequest | Error::IllegalHost | Error::ClientHandshakeVersionMismatch) => false,
+        Some(Error::Rejected(reason) | Error::Disconnected(reason) | Error::RejectedRetryAfter(reason, _)) => reason.is_retryable(),
+        _ => true,
+    }
+}
+
+/// Bounds of the wait a server asks for in `ServerHelloV2::Rejected`.
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+// ports only free up as other clients leave, so back off exponentially while the server is full
+fn retry_delay(e: &anyhow::Error, attempt: u32, delay: Duration) -> Duration {
+    match e.downcast_ref::<Error>() {
+        Some(Error::Rejected(CloseReason::NoPortsAvailable)) => delay * 2u32.pow(attempt.min(6)),
+        // retrying earlier would be turned away again
+        Some(Error::TokenServerCircuitOpen(retry_in)) => delay.max(*retry_in),
+        // the server knows its load better than our backoff does, within reason
+        Some(Error::RejectedRetryAfter(_, retry_after)) => (*retry_after).clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER),
+        _ => delay,
+    }
+}
+
+async fn connect(
+    store: Arc<Store>,
+    control_port: u16,
+    control_path: &str,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    token_server: &str,
+    transport: Transport,
+    cancellation_token: CancellationToken,
+    endpoint_claims: EndpointClaims,
+) -> Result<RunHandle> {
     println!("Connecting to auth server: {}", token_server);
-    let (token, host) = fetch_token(token_server).await?;
+    let (token, host) = fetch_token_guarded(store.token_breaker(), token_server).await?;
     info!("got token: {}, host: {}", token, host);
     println!("Your proxy server: {}", host);
 
     println!("Connecting to proxy server: {}:{}", host, control_port);
-    let url = Url::parse(&format!("ws://{}:{}/tunnel", host, control_port))?;
-    let (mut websocket, _) = connect_async(url).await.map_err(|_| Error::ServerDown)?;
-    info!("WebSocket handshake has been successfully completed");
+    match (transport, tls) {
+        (Transport::WebSocket, Some(tls)) => {
+            let url = Url::parse(&format!("wss://{}:{}{}", host, control_port, control_path))?;
+            let socket = connect_control_socket(&store, &host, control_port).await?;
+            let socket = tls::connect(&host, socket, tls).await?;
+            let (websocket, _) = client_async(url, socket).await.map_err(|_| Error::ServerDown)?;
+            info!("WebSocket handshake over TLS has been successfully completed");
+
+            let (ws_sink, ws_stream) = websocket.split();
+            run_tunnel(store, token, cancellation_token, endpoint_claims, ws_sink, ws_stream).await
+        }
+        (Transport::WebSocket, None) => {
+            let url = Url::parse(&format!("ws://{}:{}{}", host, control_port, control_path))?;
+            let socket = connect_control_socket(&store, &host, control_port).await?;
+            let (websocket, _) = client_async(url, socket).await.map_err(|_| Error::ServerDown)?;
+            info!("WebSocket handshake has been successfully completed");
+
+            let (ws_sink, ws_stream) = websocket.split();
+            run_tunnel(store, token, cancellation_token, endpoint_claims, ws_sink, ws_stream).await
+        }
+        (Transport::H2, Some(_)) => Err(Error::TlsOverH2.into()),
+        (Transport::H2, None) => {
+            let (ws_sink, ws_stream) = transport::connect_h2(&host, control_port).await?;
+            info!("HTTP/2 tunnel has been successfully established");
+
+            run_tunnel(store, token, cancellation_token, endpoint_claims, ws_sink, ws_stream).await
+        }
+    }
+}
+
+async fn connect_control_socket(store: &Store, host: &str, control_port: u16) -> Result<TcpStream, Error> {
+    let socket = if store.happy_eyeballs() {
+        happy_eyeballs::connect(&SystemResolver, host, control_port, happy_eyeballs::CONNECTION_ATTEMPT_DELAY).await
+    } else {
+        TcpStream::connect((host, control_port)).await
+    };
+    socket.map_err(|e| {
+        error!("failed to connect to {}:{}: {:?}", host, control_port, e);
+        Error::ServerDown
+    })
+}
 
-    send_client_hello(&mut websocket, token, endpoint_claims).await?;
-    let client_info = verify_server_hello(&mut websocket).await?;
+async fn run_tunnel<Si, St>(
+    store: Arc<Store>,
+    token: String,
+    cancellation_token: CancellationToken,
+    endpoint_claims: EndpointClaims,
+    mut ws_sink: Si,
+    mut ws_stream: St,
+) -> Result<RunHandle>
+where
+    Si: Sink<Message, Error = WsError> + Unpin + Send + 'static,
+    St: Stream<Item = Result<Message, WsError>> + Unpin + Send + 'static,
+{
+    send_client_hello(&mut ws_sink, token, endpoint_claims).await?;
+    let client_info = verify_server_hello(&mut ws_stream).await?;
     info!(
         "cid={} got client_info from server: {:?}",
         client_info.client_id, client_info
@@ -48,22 +205,23 @@ pub async fn run(
     println!("Your Client ID: {}", client_info.client_id);
     println!("Endpoint Info:");
     for endpoint in client_info.endpoints.iter() {
-        let message = format!("{}://localhost:{} <--> {}://{}:{}", endpoint.protocol, endpoint.local_port, endpoint.protocol, client_info.host, endpoint.remote_port);
+        let message = format!("{}://localhost:{} <--> {}://{}:{}", endpoint.protocol, endpoint.local_port, endpoint.protocol, client_info.public_host, endpoint.remote_port);
         println!("+{}+", "-".repeat(message.len() + 2));
         println!("| {} |", message);
         println!("+{}+", "-".repeat(message.len() + 2));
     }
     store.register_endpoints(client_info.endpoints.clone());
-
-    // split reading and writing
-    let (mut ws_sink, mut ws_stream) = websocket.split();
+    store.register_capabilities(client_info.capabilities.clone());
+    store.set_client_info(client_info.clone());
 
     // tunnel channel
     let (mut tunnel_tx, mut tunnel_rx) = unbounded::<ControlPacketV2>();
 
     let mut set = JoinSet::new();
     let client_id = client_info.client_id;
+    let run_store = store.clone();
     let ct = cancellation_token.child_token();
+    let store_ = store.clone();
     // continuously write to websocket tunnel
     set.spawn(async move {
         loop {
@@ -76,6 +234,9 @@ pub async fn run(
                             return Ok(());
                         }
                     };
+                    if let ControlPacketV2::Data(_, ref data) = packet {
+                        store_.stats().add_bytes_up(data.len());
+                    }
 
                     let mut codec = ControlPacketV2Codec::new();
                     let mut bytes = BytesMut::new();
@@ -96,49 +257,113 @@ pub async fn run(
         }
     });
 
+    // ends the heartbeat once the reader stops
+    let reader_done = CancellationToken::new();
+    if store.supports(Capability::Heartbeat) {
+        let store = store.clone();
+        let mut tunnel_tx = tunnel_tx.clone();
+        let ct = cancellation_token.child_token();
+        let reader_done = reader_done.clone();
+        set.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => {},
+                    _ = ct.cancelled() => return Ok(()),
+                    _ = reader_done.cancelled() => return Ok(()),
+                }
+                match store.ping_server() {
+                    Some(nonce) => {
+                        let _ = tunnel_tx.send(ControlPacketV2::Heartbeat(nonce)).await;
+                    }
+                    None => {
+                        warn!("cid={} server missed heartbeats", client_id);
+                        return Err(Error::Timeout);
+                    }
+                }
+            }
+        });
+    }
+
+    // the server hello does not tell when the session expires, `WhoAmIResp` does
+    if store.supports(Capability::WhoAmI) {
+        let _ = tunnel_tx.unbounded_send(ControlPacketV2::WhoAmI);
+    }
+
     let ct = cancellation_token.child_token();
     set.spawn(async move {
+        let _reader_done = reader_done.drop_guard();
         // continuously read from websocket tunnel
-        loop {
-            tokio::select! {
-                v = ws_stream.next() => {
-                    match v {
-                        Some(Ok(message)) if message.is_close() => {
-                            debug!("cid={} got close message", client_id);
-                            return Ok(());
-                        }
-                        Some(Ok(message)) => {
-                            let packet = process_control_flow_message(
-                                store.clone(),
-                                &mut tunnel_tx,
-                                message.into_data(),
-                            )
-                            .await
-                            .map_err(|e| {
-                                error!("cid={} Malformed protocol control packet: {:?}", client_id, e);
-                                Error::MalformedMessageFromServer
-                            })?;
-                            debug!("cid={} Processed data packet: {}", client_id, packet);
-                        }
-                        Some(Err(e)) => {
-                            warn!("cid={} websocket read error: {:?}", client_id, e);
-                            return Err(Error::Timeout);
-                        }
-                        None => {
-                            warn!("cid={} websocket sent none", client_id);
-                            return Err(Error::Timeout);
+        let result = async {
+            loop {
+                tokio::select! {
+                    v = ws_stream.next() => {
+                        match v {
+                            Some(Ok(Message::Close(frame))) => {
+                                let reason = CloseReason::from_close_frame(frame.as_ref().map(|f| (u16::from(f.code), f.reason.as_ref())));
+                                return closed(client_id, reason);
+                            }
+                            Some(Ok(message)) => {
+                                let packet = process_control_flow_message(
+                                    store.clone(),
+                                    &mut tunnel_tx,
+                                    message.into_data(),
+                                )
+                                .await
+                                .map_err(|e| {
+                                    error!("cid={} Malformed protocol control packet: {:?}", client_id, e);
+                                    Error::MalformedMessageFromServer
+                                })?;
+                                debug!("cid={} Processed data packet: {}", client_id, packet);
+                                if let ControlPacketV2::Data(_, ref data) = packet {
+                                    store.stats().add_bytes_down(data.len());
+                                }
+                                if let ControlPacketV2::Disconnect(reason) = packet {
+                                    println!("Server closed the connection: {}", reason);
+                                    return Err(Error::Disconnected(reason));
+                                }
+                            }
+                            Some(Err(e)) => {
+                                warn!("cid={} websocket read error: {:?}", client_id, e);
+                                return closed(client_id, CloseReason::Abnormal);
+                            }
+                            None => {
+                                warn!("cid={} websocket sent none", client_id);
+                                return closed(client_id, CloseReason::Abnormal);
+                            }
                         }
+                    },
+                    _ = ct.cancelled() => {
+                        return Ok(());
                     }
-                },
-                _ = ct.cancelled() => {
-                    return Ok(());
                 }
             }
+        }.await;
+        if let Err(ref e) = result {
+            store.stats().record_error(e);
         }
+        result
     });
 
 
-    Ok((client_info, set))
+    Ok(RunHandle { client_info, set, store: run_store, cancellation_token, started_at: Instant::now() })
+}
+
+// a normal closure ends the tunnel quietly, anything else is reported to the caller
+fn closed(client_id: ClientId, reason: CloseReason) -> Result<(), Error> {
+    match reason {
+        CloseReason::Normal => {
+            info!("cid={} server closed the connection", client_id);
+            Ok(())
+        }
+        CloseReason::Abnormal => {
+            warn!("cid={} connection to the server dropped abnormally", client_id);
+            Err(Error::Disconnected(reason))
+        }
+        _ => {
+            warn!("cid={} server closed the connection: {}", client_id, reason);
+            Err(Error::Disconnected(reason))
+        }
+    }
 }
 
 pub async fn send_client_hello<T>(websocket: &mut T, token: String, endpoint_claims: EndpointClaims) -> Result<(), T::Error>
@@ -149,6 +374,8 @@ where
         version: CLIENT_HELLO_VERSION,
         token,
         endpoint_claims,
+        capabilities: capability_names(SUPPORTED_CAPABILITIES),
+        reservation: None,
     };
     debug!("Sent client hello: {:?}", hello);
     let hello_data = serde_json::to_vec(&hello).unwrap_or_default();
@@ -162,7 +389,143 @@ where
 pub struct ClientInfo {
     pub client_id: ClientId,
     pub host: String,
+    /// host players connect to, the control connection's host unless the server advertises one
+    #[serde(default)]
+    pub public_host: String,
     pub endpoints: Endpoints,
+    /// empty for servers that predate capability negotiation
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// unix time in seconds the server ends the session, known once the server answered `WhoAmI`
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl ClientInfo {
+    /// Where players reach each endpoint, as assigned in the server hello.
+    pub fn remote_addrs(&self) -> Vec<RemoteAddr> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| RemoteAddr {
+                host: self.public_host.clone(),
+                port: endpoint.remote_port,
+                protocol: endpoint.protocol,
+                local_port: endpoint.local_port,
+            })
+            .collect()
+    }
+}
+
+/// Why `RunHandle::wait` returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    /// the cancellation token passed to `run` was cancelled
+    Cancelled,
+    /// every tunnel task returned by itself, e.g. the server closed the connection normally
+    Finished,
+    /// a tunnel task returned an error, e.g. the server went away, the first one is reported
+    Error(String),
+    /// a tunnel task panicked or was aborted, the first one is reported
+    TaskFailed(String),
+}
+
+/// End of session report returned by `RunHandle::wait`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub streams: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub reconnects: u64,
+    pub uptime: Duration,
+    pub exit_reason: ExitReason,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "streams={} bytes_up={} bytes_down={} reconnects={} uptime={}s exit_reason={:?}",
+            self.streams, self.bytes_up, self.bytes_down, self.reconnects, self.uptime.as_secs(), self.exit_reason,
+        )
+    }
+}
+
+/// Tunnel tasks started by `run`. More tasks may be added with `spawn`, e.g. the client api.
+#[derive(Debug)]
+pub struct RunHandle {
+    client_info: ClientInfo,
+    set: JoinSet<Result<(), Error>>,
+    store: Arc<Store>,
+    cancellation_token: CancellationToken,
+    started_at: Instant,
+}
+
+impl RunHandle {
+    /// As assigned in the server hello.
+    pub fn client_info(&self) -> &ClientInfo {
+        &self.client_info
+    }
+
+    pub fn spawn<F>(&mut self, task: F)
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.set.spawn(task);
+    }
+
+    pub async fn join_next(&mut self) -> Option<Result<Result<(), Error>, JoinError>> {
+        self.set.join_next().await
+    }
+
+    /// Wait until every task returns and report the session.
+    pub async fn wait(mut self) -> RunSummary {
+        let mut error = None;
+        let mut failure = None;
+        while let Some(res) = self.set.join_next().await {
+            match res {
+                Err(join_error) => {
+                    error!("join error {:?} for client", join_error);
+                    failure.get_or_insert_with(|| join_error.to_string());
+                }
+                Ok(Err(e)) => {
+                    warn!("client task failed: {}", e);
+                    error.get_or_insert_with(|| e.to_string());
+                }
+                Ok(Ok(())) => info!("client task successfully terminated"),
+            }
+        }
+
+        let exit_reason = match (failure, error) {
+            (Some(failure), _) => ExitReason::TaskFailed(failure),
+            (None, Some(error)) => ExitReason::Error(error),
+            (None, None) if self.cancellation_token.is_cancelled() => ExitReason::Cancelled,
+            (None, None) => ExitReason::Finished,
+        };
+        let stats = self.store.stats();
+        RunSummary {
+            streams: stats.streams(),
+            bytes_up: stats.bytes_up(),
+            bytes_down: stats.bytes_down(),
+            reconnects: stats.reconnects(),
+            uptime: self.started_at.elapsed(),
+            exit_reason,
+        }
+    }
+}
+
+/// Public address of one endpoint, displayed as `host:port`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteAddr {
+    pub host: String,
+    pub port: u16,
+    pub protocol: Protocol,
+    pub local_port: u16,
+}
+
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
 }
 
 pub async fn verify_server_hello<T>(websocket: &mut T) -> Result<ClientInfo, Error>
@@ -180,14 +543,19 @@ where
     })?;
     debug!("Got server hello: {:?}", server_hello);
 
-    let (client_id, host, endpoints) = match server_hello {
+    let (client_id, host, public_host, endpoints, capabilities) = match server_hello {
         ServerHelloV2::Success {
             client_id,
             endpoints,
             host,
+            version,
+            capabilities,
+            public_host,
         } => {
-            info!("cid={} Server accepted our connection.", client_id);
-            (client_id, host, endpoints)
+            let capabilities = negotiate_capabilities(&capabilities, SUPPORTED_CAPABILITIES);
+            info!("cid={} Server accepted our connection. version={} capabilities={:?}", client_id, version, capabilities);
+            let public_host = public_host.unwrap_or_else(|| host.clone());
+            (client_id, host, public_host, endpoints, capabilities)
         }
         ServerHelloV2::BadRequest => {
             error!("Server send an error: {:?}", Error::BadRequest);
@@ -215,15 +583,93 @@ where
             error!("Server send an error: {:?}", Error::InternalServerError);
             return Err(Error::InternalServerError);
         }
+        ServerHelloV2::Rejected { reason, retry_after } => {
+            if let CloseReason::VersionUnsupported { min, max } = reason {
+                error!(
+                    "This client speaks protocol version {} but the server supports {}-{}. Please upgrade ownserver.",
+                    CLIENT_HELLO_VERSION, min, max
+                );
+            }
+            if let Some(retry_after) = retry_after {
+                warn!("Server rejected our connection: {}, retry in {:?}", reason, retry_after);
+                return Err(Error::RejectedRetryAfter(reason, retry_after));
+            }
+            error!("Server rejected our connection: {}", reason);
+            return Err(Error::Rejected(reason));
+        }
     };
 
     Ok(ClientInfo {
         client_id,
         host,
+        public_host,
         endpoints,
+        capabilities,
+        expires_at: None,
     })
 }
 
+// `http_host` is the Host header of the request the stream opens with, when the server sniffed one
+async fn init_stream(
+    store: Arc<Store>,
+    tunnel_tx: &mut UnboundedSender<ControlPacketV2>,
+    stream_id: StreamId,
+    endpoint_id: EndpointId,
+    http_host: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("sid={} eid={} init stream, http host: {:?}", stream_id, endpoint_id, http_host);
+
+    let endpoint = match store.get_endpoint_by_endpoint_id(endpoint_id) {
+        Some(e) => e,
+        None => {
+            warn!(
+                "sid={} eid={} endpoint is not registered",
+                stream_id, endpoint_id
+            );
+            return Err(format!("eid={} is not registered", endpoint_id).into())
+        }
+    };
+
+    if store.has_stream(&stream_id) {
+        warn!(
+            "sid={} already exist at init process",
+            stream_id
+        );
+        return Err(format!("sid={} is already exist", stream_id).into())
+    }
+
+    match endpoint.protocol {
+        Protocol::TCP => {
+            local::tcp::setup_new_stream(
+                store.clone(),
+                tunnel_tx.clone(),
+                stream_id,
+                endpoint_id,
+                http_host,
+            )
+            .await?;
+            let _ = tunnel_tx.send(ControlPacketV2::InitAck(stream_id)).await;
+            match http_host {
+                Some(host) => println!("new tcp stream arrived: sid={}, eid={}, host={}", stream_id, endpoint_id, host),
+                None => println!("new tcp stream arrived: sid={}, eid={}", stream_id, endpoint_id),
+            }
+        }
+        Protocol::UDP => {
+            local::udp::setup_new_stream(
+                store.clone(),
+                tunnel_tx.clone(),
+                stream_id,
+                endpoint_id,
+            )
+            .await?;
+            let _ = tunnel_tx.send(ControlPacketV2::InitAck(stream_id)).await;
+            println!("new udp stream arrived: sid={}, eid={}", stream_id, endpoint_id);
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn process_control_flow_message(
     store: Arc<Store>,
     tunnel_tx: &mut UnboundedSender<ControlPacketV2>,
@@ -236,55 +682,36 @@ pub async fn process_control_flow_message(
 
     match control_packet {
         ControlPacketV2::Init(stream_id, endpoint_id) => {
-            debug!("sid={} eid={} init stream", stream_id, endpoint_id);
-
-            let endpoint = match store.get_endpoint_by_endpoint_id(endpoint_id) {
-                Some(e) => e,
-                None => {
-                    warn!(
-                        "sid={} eid={} endpoint is not registered",
-                        stream_id, endpoint_id
-                    );
-                    return Err(format!("eid={} is not registered", endpoint_id).into())
-                }
-            };
-
-            if store.has_stream(&stream_id) {
-                warn!(
-                    "sid={} already exist at init process",
-                    stream_id
-                );
-                return Err(format!("sid={} is already exist", stream_id).into())
-            }
-
-            match endpoint.protocol {
-                Protocol::TCP => {
-                    local::tcp::setup_new_stream(
-                        store.clone(),
-                        tunnel_tx.clone(),
-                        stream_id,
-                        endpoint_id,
-                    )
-                    .await?;
-                    println!("new tcp stream arrived: sid={}, eid={}", stream_id, endpoint_id);
-                }
-                Protocol::UDP => {
-                    local::udp::setup_new_stream(
-                        store.clone(),
-                        tunnel_tx.clone(),
-                        stream_id,
-                        endpoint_id,
-                    )
-                    .await?;
-                    println!("new udp stream arrived: sid={}, eid={}", stream_id, endpoint_id);
-                }
-            }
+            init_stream(store, tunnel_tx, stream_id, endpoint_id, None).await?;
+        }
+        ControlPacketV2::InitHttp(stream_id, endpoint_id, ref host) => {
+            init_stream(store, tunnel_tx, stream_id, endpoint_id, Some(host)).await?;
         }
         ControlPacketV2::Ping => {
             debug!("got ping");
             let _ = tunnel_tx.send(ControlPacketV2::Ping).await;
         }
-        ControlPacketV2::Refused(_) => return Err("unexpected control packet".into()),
+        ControlPacketV2::Heartbeat(nonce) => {
+            debug!("got heartbeat nonce={}", nonce);
+            let _ = tunnel_tx.send(ControlPacketV2::HeartbeatAck(nonce)).await;
+        }
+        ControlPacketV2::HeartbeatAck(nonce) => {
+            if let Some(rtt) = store.ack_heartbeat(nonce) {
+                debug!("heartbeat rtt={:?}", rtt);
+            }
+        }
+        ControlPacketV2::WindowUpdate(stream_id, n) => {
+            debug!("sid={} window update: {}", stream_id, n);
+            store.update_window(&stream_id, n);
+        }
+        ControlPacketV2::WhoAmIResp { client_id, host, endpoints, expires_at } => {
+            debug!("cid={} server reported host={} endpoints={:?} expires_at={:?}", client_id, host, endpoints, expires_at);
+            store.refresh_client_info(client_id, host, endpoints, expires_at);
+        }
+        ControlPacketV2::Refused(_) | ControlPacketV2::InitAck(_) | ControlPacketV2::WhoAmI => return Err("unexpected control packet".into()),
+        ControlPacketV2::Disconnect(ref reason) => {
+            warn!("server closed the connection: {}", reason);
+        }
         ControlPacketV2::End(stream_id) => {
             debug!("sid={} end stream", stream_id);
             // proxy server try to close control stream and local stream
@@ -303,6 +730,14 @@ pub async fn process_control_flow_message(
                 }
             });
         }
+        ControlPacketV2::ShutdownWrite(stream_id) => {
+            debug!("sid={} remote peer is done sending", stream_id);
+            if let Some(mut tx) = store.get_mut_stream(&stream_id) {
+                let _ = tx.send(StreamMessage::ShutdownWrite).await.map_err(|e| {
+                    error!("sid={} failed to send stream shutdown: {:?}", stream_id, e);
+                });
+            }
+        }
         ControlPacketV2::Data(stream_id, ref data) => {
             debug!("sid={} new data: {}", stream_id, data.len());
 
@@ -334,8 +769,57 @@ pub enum TokenResponse {
     Err { message: String },
 }
 
+static TOKEN_SERVER_RESOLVER: OnceLock<CachingResolver<SystemResolver>> = OnceLock::new();
+
 pub async fn fetch_token(url: &str) -> Result<(String, String)> {
-    let client = reqwest::Client::new();
+    let resolver = TOKEN_SERVER_RESOLVER.get_or_init(|| CachingResolver::new(SystemResolver, DEFAULT_DNS_CACHE_TTL));
+    fetch_token_with(url, resolver).await
+}
+
+/// `fetch_token` unless `breaker` turns the request away, recording the outcome in it.
+pub async fn fetch_token_guarded(breaker: &CircuitBreaker, url: &str) -> Result<(String, String)> {
+    if let Err(retry_in) = breaker.try_acquire() {
+        warn!("token server circuit is open, not requesting a token for {:?}", retry_in);
+        return Err(Error::TokenServerCircuitOpen(retry_in).into());
+    }
+    let result = fetch_token(url).await;
+    match result {
+        Ok(_) => breaker.record_success(),
+        Err(_) => breaker.record_failure(),
+    }
+    result
+}
+
+/// Same as `fetch_token` but tries every address `resolver` returns for the token server, in order,
+/// until one accepts the connection.
+pub async fn fetch_token_with(url: &str, resolver: &dyn Resolve) -> Result<(String, String)> {
+    let parsed = Url::parse(url)?;
+    let host = match parsed.host() {
+        Some(url::Host::Domain(host)) => host,
+        // ip addresses need no resolution
+        _ => return request_token(reqwest::Client::new(), url).await,
+    };
+    let port = parsed.port_or_known_default().unwrap_or(80);
+
+    let mut last_error = None;
+    for addr in resolver.resolve(host, port).await? {
+        let client = reqwest::Client::builder().resolve(host, addr).build()?;
+        match request_token(client, url).await {
+            Err(e) if is_connect_error(&e) => {
+                warn!("failed to connect to token server at {}: {:?}", addr, e);
+                last_error = Some(e);
+            }
+            result => return result,
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("token server {} resolved to no address", host)))
+}
+
+fn is_connect_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>().map(|e| e.is_connect()).unwrap_or(false)
+}
+
+async fn request_token(client: reqwest::Client, url: &str) -> Result<(String, String)> {
     let resp = client
         .post(url)
         .send()
@@ -351,9 +835,40 @@ pub async fn fetch_token(url: &str) -> Result<(String, String)> {
 
 #[cfg(test)]
 mod fetch_token_test {
-    use super::fetch_token;
+    use super::{fetch_token, fetch_token_guarded, fetch_token_with};
+    use crate::breaker::{BreakerState, CircuitBreaker};
+    use crate::error::Error;
+    use crate::resolver::Resolve;
+    use futures::future::BoxFuture;
+    use std::net::SocketAddr;
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+    use std::time::Duration;
     use warp::{http::StatusCode, Filter};
 
+    // resolves every host to 127.0.0.2 first, where nothing listens, then to 127.0.0.1
+    struct MultiHomedResolver;
+
+    impl Resolve for MultiHomedResolver {
+        fn resolve<'a>(&'a self, _host: &'a str, port: u16) -> BoxFuture<'a, std::io::Result<Vec<SocketAddr>>> {
+            Box::pin(async move { Ok(vec![SocketAddr::from(([127, 0, 0, 2], port)), SocketAddr::from(([127, 0, 0, 1], port))]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn try_next_address_when_first_is_down() -> Result<(), Box<dyn std::error::Error>> {
+        let response = r#"{ "token": "json.web.token", "host": "foo.local" }"#;
+        let routes = warp::any().map(move || response);
+        tokio::spawn(async move {
+            warp::serve(routes).run(([127, 0, 0, 1], 11115)).await;
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let (token, host) = fetch_token_with("http://token.test:11115/v0/request_token", &MultiHomedResolver).await?;
+        assert_eq!(token, "json.web.token".to_string());
+        assert_eq!(host, "foo.local".to_string());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn parse_ok_response() -> Result<(), Box<dyn std::error::Error>> {
         let response = r#"
@@ -372,6 +887,43 @@ mod fetch_token_test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn stop_requesting_while_token_server_fails() -> Result<(), Box<dyn std::error::Error>> {
+        // fails the first two requests, then recovers
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_ = requests.clone();
+        let routes = warp::any().map(move || {
+            if requests_.fetch_add(1, Ordering::SeqCst) < 2 {
+                warp::reply::with_status(r#"{ "message": "unavailable" }"#, StatusCode::SERVICE_UNAVAILABLE)
+            } else {
+                warp::reply::with_status(r#"{ "token": "json.web.token", "host": "foo.local" }"#, StatusCode::OK)
+            }
+        });
+        tokio::spawn(async move {
+            warp::serve(routes).run(([127, 0, 0, 1], 11116)).await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let url = "http://localhost:11116/v0/request_token";
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(200));
+        assert!(fetch_token_guarded(&breaker, url).await.is_err());
+        assert!(fetch_token_guarded(&breaker, url).await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // turned away without reaching the token server
+        let error = fetch_token_guarded(&breaker, url).await.expect_err("breaker is open");
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::TokenServerCircuitOpen(_))));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        let (token, _) = fetch_token_guarded(&breaker, url).await?;
+        assert_eq!(token, "json.web.token");
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn returns_error_when_token_server_internal_error(
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -395,11 +947,150 @@ mod fetch_token_test {
 }
 
 
+#[cfg(test)]
+mod run_with_config_test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use warp::Filter;
+
+    // token server that always fails and counts requests
+    fn launch_failing_token_server(port: u16) -> Arc<AtomicUsize> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_ = requests.clone();
+        let routes = warp::any().map(move || {
+            requests_.fetch_add(1, Ordering::SeqCst);
+            r#"{ "message": "failed to generate token" }"#
+        });
+        tokio::spawn(async move {
+            warp::serve(routes).run(([127, 0, 0, 1], port)).await;
+        });
+        requests
+    }
+
+    #[tokio::test]
+    async fn retry_when_reconnect_is_enabled() -> Result<(), Box<dyn std::error::Error>> {
+        let requests = launch_failing_token_server(11113);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let config = ClientConfig::builder()
+            .token_server("http://localhost:11113/v0/request_token")
+            .reconnect(2, Duration::from_millis(10))
+            .build();
+        let result = run_with_config(Arc::new(Store::default()), config, CancellationToken::new()).await;
+        assert!(result.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn give_up_at_once_by_default() -> Result<(), Box<dyn std::error::Error>> {
+        let requests = launch_failing_token_server(11114);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let config = ClientConfig::builder()
+            .token_server("http://localhost:11114/v0/request_token")
+            .build();
+        let result = run_with_config(Arc::new(Store::default()), config, CancellationToken::new()).await;
+        assert!(result.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    // token and control server in one, rejecting every client hello with `retry_after`
+    fn launch_full_server(port: u16, retry_after: Duration) -> Arc<std::sync::Mutex<Vec<tokio::time::Instant>>> {
+        let hellos = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hellos_ = hellos.clone();
+        let token = warp::path!("v0" / "request_token").map(|| r#"{ "token": "token", "host": "127.0.0.1" }"#);
+        let tunnel = warp::path("tunnel").and(warp::ws()).map(move |ws: warp::ws::Ws| {
+            let hellos = hellos_.clone();
+            ws.on_upgrade(move |mut websocket| async move {
+                if websocket.next().await.is_some() {
+                    hellos.lock().unwrap().push(tokio::time::Instant::now());
+                    let hello = ServerHelloV2::Rejected { reason: CloseReason::TooManyClients, retry_after: Some(retry_after) };
+                    let _ = websocket.send(warp::ws::Message::binary(serde_json::to_vec(&hello).unwrap_or_default())).await;
+                }
+            })
+        });
+        tokio::spawn(async move {
+            warp::serve(token.or(tunnel)).run(([127, 0, 0, 1], port)).await;
+        });
+        hellos
+    }
+
+    #[tokio::test]
+    async fn wait_as_long_as_the_server_asks() -> Result<(), Box<dyn std::error::Error>> {
+        let retry_after = Duration::from_millis(1500);
+        let hellos = launch_full_server(11117, retry_after);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // our own delay would take far longer
+        let config = ClientConfig::builder()
+            .token_server("http://127.0.0.1:11117/v0/request_token")
+            .control_port(11117)
+            .reconnect(1, Duration::from_secs(30))
+            .build();
+        let result = tokio::time::timeout(Duration::from_secs(10), run_with_config(Arc::new(Store::default()), config, CancellationToken::new())).await?;
+        let error = result.err().expect("the server is full");
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::RejectedRetryAfter(CloseReason::TooManyClients, d)) if *d == retry_after));
+
+        let hellos = hellos.lock().unwrap().clone();
+        assert_eq!(hellos.len(), 2);
+        let waited = hellos[1] - hellos[0];
+        assert!(waited >= retry_after && waited < Duration::from_secs(3), "waited {:?}", waited);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_tls_over_h2() -> Result<(), Box<dyn std::error::Error>> {
+        let requests = launch_failing_token_server(11118);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let config = ClientConfig::builder()
+            .token_server("http://localhost:11118/v0/request_token")
+            .transport(Transport::H2)
+            .server_ca("ca.pem")
+            .build();
+        let result = run_with_config(Arc::new(Store::default()), config, CancellationToken::new()).await;
+        assert!(matches!(result.err().and_then(|e| e.downcast::<Error>().ok()), Some(Error::TlsOverH2)));
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn back_off_while_server_has_no_ports() {
+        let delay = Duration::from_secs(1);
+        let exhausted = anyhow::Error::new(Error::Rejected(CloseReason::NoPortsAvailable));
+        assert!(is_transient(&exhausted));
+        assert_eq!(retry_delay(&exhausted, 0, delay), Duration::from_secs(1));
+        assert_eq!(retry_delay(&exhausted, 3, delay), Duration::from_secs(8));
+        assert_eq!(retry_delay(&exhausted, 10, delay), Duration::from_secs(64));
+
+        let rejected = anyhow::Error::new(Error::Rejected(CloseReason::AlreadyConnected));
+        assert!(!is_transient(&rejected));
+        let dropped = anyhow::Error::new(Error::Disconnected(CloseReason::Abnormal));
+        assert_eq!(retry_delay(&dropped, 3, delay), delay);
+
+        let hinted = anyhow::Error::new(Error::RejectedRetryAfter(CloseReason::NoPortsAvailable, Duration::from_secs(5)));
+        assert!(is_transient(&hinted));
+        assert_eq!(retry_delay(&hinted, 3, delay), Duration::from_secs(5));
+        // a hint does not make a permanent rejection worth retrying
+        let hinted = anyhow::Error::new(Error::RejectedRetryAfter(CloseReason::AlreadyConnected, Duration::from_secs(5)));
+        assert!(!is_transient(&hinted));
+
+        let eager = anyhow::Error::new(Error::RejectedRetryAfter(CloseReason::TooManyClients, Duration::ZERO));
+        assert_eq!(retry_delay(&eager, 0, delay), MIN_RETRY_AFTER);
+        let endless = anyhow::Error::new(Error::RejectedRetryAfter(CloseReason::TooManyClients, Duration::MAX));
+        assert_eq!(retry_delay(&endless, 0, delay), MAX_RETRY_AFTER);
+    }
+}
+
+
 #[cfg(test)]
 mod client_verify_server_hello_test {
     use super::*;
     use futures::{channel::mpsc, SinkExt};
     use ownserver_lib::{ClientId, ServerHelloV2, EndpointId, Endpoint};
+    use crate::test_support::server_hello;
 
     #[tokio::test]
     async fn it_accept_server_hello() -> Result<(), Box<dyn std::error::Error>> {
@@ -416,6 +1107,9 @@ mod client_verify_server_hello_test {
                 local_port: 1234,
                 remote_port: 1234,
             }],
+            version: CLIENT_HELLO_VERSION,
+            capabilities: vec!["flow-control".to_string(), "teleport".to_string()],
+            public_host: Some("play.example.com".to_string()),
         })
         .unwrap_or_default();
         tx.send(Ok(Message::binary(hello))).await?;
@@ -423,11 +1117,24 @@ mod client_verify_server_hello_test {
         let client_info = verify_server_hello(&mut rx)
             .await
             .expect("unexpected server hello error");
+        let remote_addrs = client_info.remote_addrs();
+        assert_eq!(remote_addrs, vec![RemoteAddr {
+            host: "play.example.com".to_string(),
+            port: 1234,
+            protocol: Protocol::TCP,
+            local_port: 1234,
+        }]);
+        assert_eq!(remote_addrs[0].to_string(), "play.example.com:1234");
         let ClientInfo {
             client_id,
             host,
+            public_host,
             endpoints,
+            capabilities,
+            ..
         } = client_info;
+        assert_eq!(public_host, "play.example.com");
+        assert_eq!(capabilities, vec![Capability::FlowControl]);
         assert_eq!(client_id, cid);
         assert_eq!(host, "foo.bar.local".to_string());
         assert_eq!(endpoints, vec![Endpoint {
@@ -440,6 +1147,19 @@ mod client_verify_server_hello_test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn fall_back_to_control_host_without_public_host() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut tx, mut rx) = mpsc::unbounded();
+
+        tx.send(Ok(Message::binary(server_hello(Vec::new(), Vec::new())?))).await?;
+
+        let client_info = verify_server_hello(&mut rx)
+            .await
+            .expect("unexpected server hello error");
+        assert_eq!(client_info.public_host, "foo.bar.local");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn returns_errors_when_websocket_yields_nothing() -> Result<(), Box<dyn std::error::Error>>
     {
@@ -471,6 +1191,25 @@ mod client_verify_server_hello_test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn returns_errors_when_version_unsupported() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut tx, mut rx) = mpsc::unbounded();
+
+        let hello = serde_json::to_vec(&ServerHelloV2::Rejected {
+            reason: CloseReason::VersionUnsupported { min: 4, max: 5 },
+            retry_after: None,
+        })
+        .unwrap_or_default();
+        tx.send(Ok(Message::binary(hello))).await?;
+
+        let server_hello = verify_server_hello(&mut rx)
+            .await
+            .expect_err("server hello is unexpectedly correct");
+        assert!(matches!(server_hello, Error::Rejected(CloseReason::VersionUnsupported { min: 4, max: 5 })));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn returns_errors_when_websocket_error() -> Result<(), Box<dyn std::error::Error>> {
         let (mut tx, mut rx) = mpsc::unbounded();
@@ -484,4 +1223,220 @@ mod client_verify_server_hello_test {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod close_frame_test {
+    use super::*;
+    use futures::channel::mpsc;
+    use ownserver_lib::{Endpoint, EndpointId};
+    use std::convert::Infallible;
+    use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+    use crate::test_support::server_hello;
+
+    async fn close_tunnel_with(frame: Option<CloseFrame<'static>>) -> Result<Vec<Result<(), Error>>, Box<dyn std::error::Error>> {
+        let (mut tx, rx) = mpsc::unbounded();
+        let endpoints = vec![Endpoint { id: EndpointId::new(), protocol: Protocol::TCP, local_port: 1234, remote_port: 1234 }];
+        tx.send(Ok(Message::binary(server_hello(endpoints, Vec::new())?))).await?;
+        tx.send(Ok(Message::Close(frame))).await?;
+
+        let sink = futures::sink::drain::<Message>().sink_map_err(|e: Infallible| -> WsError { match e {} });
+        let mut handle = run_tunnel(Arc::new(Store::default()), "token".to_string(), CancellationToken::new(), Vec::new(), sink, rx).await?;
+
+        let mut results = Vec::new();
+        while let Some(result) = handle.join_next().await {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+
+    #[tokio::test]
+    async fn report_close_code_as_reason() -> Result<(), Box<dyn std::error::Error>> {
+        let results = close_tunnel_with(Some(CloseFrame {
+            code: CloseCode::Library(4000),
+            reason: "maintenance".into(),
+        })).await?;
+
+        assert!(results.iter().any(|r| matches!(
+            r,
+            Err(Error::Disconnected(CloseReason::Other { code: 4000, reason })) if reason == "maintenance"
+        )));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn end_quietly_on_normal_close() -> Result<(), Box<dyn std::error::Error>> {
+        let results = close_tunnel_with(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "".into(),
+        })).await?;
+
+        assert!(results.iter().all(|r| r.is_ok()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod run_summary_test {
+    use super::*;
+    use futures::channel::mpsc;
+    use ownserver_lib::{Endpoint, EndpointId};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::test_support::{encode, server_hello};
+
+    #[tokio::test]
+    async fn report_forwarded_traffic_after_cancel() -> Result<(), Box<dyn std::error::Error>> {
+        let local = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = Endpoint { id: EndpointId::new(), protocol: Protocol::TCP, local_port: local.local_addr()?.port(), remote_port: 10000 };
+        tokio::spawn(async move {
+            let (mut socket, _) = local.accept().await?;
+            let mut buf = [0; 5];
+            socket.read_exact(&mut buf).await?;
+            socket.write_all(b"world!").await?;
+            Ok::<_, std::io::Error>(())
+        });
+
+        let (mut tx, rx) = mpsc::unbounded();
+        let stream_id = StreamId::new();
+        tx.send(Ok(Message::binary(server_hello(vec![endpoint.clone()], Vec::new())?))).await?;
+        tx.send(Ok(Message::binary(encode(ControlPacketV2::Init(stream_id, endpoint.id))?))).await?;
+        tx.send(Ok(Message::binary(encode(ControlPacketV2::Data(stream_id, b"hello".to_vec()))?))).await?;
+        let (sink, mut sent) = mpsc::unbounded::<Message>();
+        let sink = sink.sink_map_err(|_| WsError::AlreadyClosed);
+
+        let cancellation_token = CancellationToken::new();
+        let handle = run_tunnel(Arc::new(Store::default()), "token".to_string(), cancellation_token.clone(), Vec::new(), sink, rx).await?;
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(2), sent.next()).await?.expect("client sent no reply");
+            let mut bytes = BytesMut::from(&message.into_data()[..]);
+            if let Ok(Some(ControlPacketV2::Data(_, data))) = ControlPacketV2Codec::new().decode(&mut bytes) {
+                assert_eq!(data, b"world!");
+                break;
+            }
+        }
+
+        cancellation_token.cancel();
+        let summary = tokio::time::timeout(Duration::from_secs(2), handle.wait()).await?;
+        assert_eq!(summary.streams, 1);
+        assert_eq!(summary.bytes_down, 5);
+        assert_eq!(summary.bytes_up, 6);
+        assert_eq!(summary.exit_reason, ExitReason::Cancelled);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_server_disconnect() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut tx, rx) = mpsc::unbounded();
+        tx.send(Ok(Message::binary(server_hello(Vec::new(), Vec::new())?))).await?;
+        tx.send(Ok(Message::binary(encode(ControlPacketV2::Disconnect(CloseReason::QuotaExceeded))?))).await?;
+        let sink = futures::sink::drain::<Message>().sink_map_err(|e: std::convert::Infallible| -> WsError { match e {} });
+
+        let handle = run_tunnel(Arc::new(Store::default()), "token".to_string(), CancellationToken::new(), Vec::new(), sink, rx).await?;
+        let summary = tokio::time::timeout(Duration::from_secs(2), handle.wait()).await?;
+        assert_eq!(summary.exit_reason, ExitReason::Error(Error::Disconnected(CloseReason::QuotaExceeded).to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod heartbeat_test {
+    use super::*;
+    use crate::test_support::encode;
+
+    #[tokio::test]
+    async fn answer_heartbeat_with_same_nonce() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default());
+        let (mut tunnel_tx, mut tunnel_rx) = unbounded();
+
+        process_control_flow_message(store, &mut tunnel_tx, encode(ControlPacketV2::Heartbeat(42))?).await?;
+        assert_eq!(tunnel_rx.next().await, Some(ControlPacketV2::HeartbeatAck(42)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_rtt_of_answered_heartbeat() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default());
+        let (mut tunnel_tx, _tunnel_rx) = unbounded();
+
+        let nonce = store.ping_server().expect("server is considered dead");
+        process_control_flow_message(store.clone(), &mut tunnel_tx, encode(ControlPacketV2::HeartbeatAck(nonce + 1))?).await?;
+        assert_eq!(store.rtt(), None);
+
+        process_control_flow_message(store.clone(), &mut tunnel_tx, encode(ControlPacketV2::HeartbeatAck(nonce))?).await?;
+        assert!(store.rtt().is_some());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod who_am_i_test {
+    use super::*;
+    use futures::channel::mpsc::UnboundedReceiver;
+    use ownserver_lib::{Endpoint, EndpointId};
+    use crate::test_support::{encode, server_hello};
+
+    #[tokio::test]
+    async fn refresh_client_info_from_response() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default());
+        let (mut tunnel_tx, _tunnel_rx) = unbounded();
+        let client_id = ClientId::new();
+        store.set_client_info(ClientInfo {
+            client_id,
+            host: "foo.local".to_string(),
+            public_host: "foo.local".to_string(),
+            endpoints: Vec::new(),
+            capabilities: Vec::new(),
+            expires_at: None,
+        });
+
+        let endpoint = Endpoint { id: EndpointId::new(), protocol: Protocol::TCP, local_port: 25565, remote_port: 10000 };
+        let response = ControlPacketV2::WhoAmIResp {
+            client_id,
+            host: "play.foo.local".to_string(),
+            endpoints: vec![endpoint.clone()],
+            expires_at: Some(1700000000),
+        };
+        process_control_flow_message(store.clone(), &mut tunnel_tx, encode(response)?).await?;
+
+        let client_info = store.client_info().expect("client info is not set");
+        assert_eq!(client_info.host, "foo.local");
+        assert_eq!(client_info.public_host, "play.foo.local");
+        assert_eq!(client_info.endpoints, vec![endpoint.clone()]);
+        assert_eq!(client_info.expires_at, Some(1700000000));
+        assert!(store.get_endpoint_by_endpoint_id(endpoint.id).is_some());
+        Ok(())
+    }
+
+    // runs the tunnel against a server that accepts with `capabilities`, returns what the client sent after its hello
+    async fn packets_after_hello(capabilities: &[Capability]) -> Result<UnboundedReceiver<Message>, Box<dyn std::error::Error>> {
+        let hello = server_hello(Vec::new(), capability_names(capabilities))?;
+        let stream = futures::stream::iter(vec![Ok(Message::binary(hello))]).chain(futures::stream::pending());
+        let (sink, mut sent) = unbounded::<Message>();
+        let sink = sink.sink_map_err(|_| WsError::AlreadyClosed);
+
+        let _handle = run_tunnel(Arc::new(Store::default()), "token".to_string(), CancellationToken::new(), Vec::new(), sink, stream).await?;
+        sent.next().await.expect("client sent no hello");
+        Ok(sent)
+    }
+
+    fn decode(message: Message) -> Result<Option<ControlPacketV2>, std::io::Error> {
+        let mut bytes = BytesMut::from(&message.into_data()[..]);
+        ControlPacketV2Codec::new().decode(&mut bytes)
+    }
+
+    #[tokio::test]
+    async fn ask_who_am_i_when_negotiated() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sent = packets_after_hello(&[Capability::WhoAmI]).await?;
+        let message = tokio::time::timeout(Duration::from_secs(2), sent.next()).await?.expect("client sent nothing");
+        assert_eq!(decode(message)?, Some(ControlPacketV2::WhoAmI));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn do_not_ask_servers_without_who_am_i() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sent = packets_after_hello(&[]).await?;
+        assert!(tokio::time::timeout(Duration::from_millis(200), sent.next()).await.is_err());
+        Ok(())
+    }
+}
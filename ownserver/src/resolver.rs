@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use log::*;
+
+/// How long resolved addresses of the token server are reused.
+pub const DEFAULT_DNS_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Resolves a host to every address it has, in the order they should be tried.
+pub trait Resolve: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// Resolves with the system resolver.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+/// Keeps the addresses resolved by `inner` for `ttl`.
+#[derive(Debug)]
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    cache: Mutex<HashMap<(String, u16), (Instant, Vec<SocketAddr>)>>,
+}
+
+impl<R: Resolve> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Default::default(),
+        }
+    }
+}
+
+impl<R: Resolve> Resolve for CachingResolver<R> {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let key = (host.to_string(), port);
+            if let Some((resolved_at, addrs)) = self.cache.lock().unwrap().get(&key) {
+                if resolved_at.elapsed() < self.ttl {
+                    return Ok(addrs.clone());
+                }
+            }
+
+            let addrs = self.inner.resolve(host, port).await?;
+            debug!("resolved {} to {:?}", host, addrs);
+            self.cache.lock().unwrap().insert(key, (Instant::now(), addrs.clone()));
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod caching_resolver_test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingResolver {
+        lookups: AtomicUsize,
+    }
+
+    impl Resolve for CountingResolver {
+        fn resolve<'a>(&'a self, _host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn reuse_addresses_within_ttl() -> io::Result<()> {
+        let resolver = CachingResolver::new(CountingResolver::default(), Duration::from_millis(100));
+        resolver.resolve("token.test", 80).await?;
+        resolver.resolve("token.test", 80).await?;
+        assert_eq!(resolver.inner.lookups.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        resolver.resolve("token.test", 80).await?;
+        assert_eq!(resolver.inner.lookups.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
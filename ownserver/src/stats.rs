@@ -0,0 +1,156 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::info;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::Store;
+
+/// Counters of the running client, reported by the status endpoint.
+#[derive(Debug, Default)]
+pub struct ClientStats {
+    streams: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    reconnects: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl ClientStats {
+    pub fn record_stream(&self) {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Bytes sent from local services to the server.
+    pub fn add_bytes_up(&self, n: usize) {
+        self.bytes_up.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes received from the server for local services.
+    pub fn add_bytes_down(&self, n: usize) {
+        self.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, error: impl ToString) {
+        *self.last_error.lock().unwrap() = Some(error.to_string());
+    }
+
+    /// Streams opened since the client started.
+    pub fn streams(&self) -> u64 {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_up(&self) -> u64 {
+        self.bytes_up.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_down(&self) -> u64 {
+        self.bytes_down.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+}
+
+/// Running totals logged every `--stats-interval` seconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsSummary {
+    pub active_streams: usize,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub uptime: Duration,
+}
+
+impl StatsSummary {
+    pub fn from_store(store: &Store, uptime: Duration) -> Self {
+        let stats = store.stats();
+        Self {
+            active_streams: store.len_stream(),
+            bytes_up: stats.bytes_up(),
+            bytes_down: stats.bytes_down(),
+            uptime,
+        }
+    }
+}
+
+impl fmt::Display for StatsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "active_streams={} bytes_up={} bytes_down={} uptime={}s",
+            self.active_streams,
+            self.bytes_up,
+            self.bytes_down,
+            self.uptime.as_secs()
+        )
+    }
+}
+
+/// Log a `StatsSummary` at info level every `interval` until `cancellation_token` is cancelled.
+pub async fn log_periodically(store: Arc<Store>, interval: Duration, cancellation_token: CancellationToken) {
+    report_periodically(store, interval, cancellation_token, |summary| info!("client stats: {}", summary)).await
+}
+
+/// Same as `log_periodically` but hands each summary to `report`. Uptime counts from the call.
+pub async fn report_periodically(
+    store: Arc<Store>,
+    interval: Duration,
+    cancellation_token: CancellationToken,
+    mut report: impl FnMut(StatsSummary),
+) {
+    let started_at = Instant::now();
+    let mut ticker = tokio::time::interval_at(started_at + interval, interval);
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => return,
+            _ = ticker.tick() => report(StatsSummary::from_store(&store, started_at.elapsed())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod stats_summary_test {
+    use super::*;
+    use futures::channel::mpsc::unbounded;
+    use ownserver_lib::StreamId;
+
+    #[tokio::test]
+    async fn report_counts_after_forwarding() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default());
+        let ct = CancellationToken::new();
+        let (tx, mut summaries) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(report_periodically(store.clone(), Duration::from_millis(100), ct.clone(), move |summary| {
+            let _ = tx.send(summary);
+        }));
+
+        let (stream, _rx) = unbounded();
+        store.add_stream(StreamId::new(), stream);
+        store.stats().add_bytes_up(1200);
+        store.stats().add_bytes_down(34);
+
+        let summary = tokio::time::timeout(Duration::from_secs(2), summaries.recv()).await?.expect("no summary");
+        assert_eq!(summary.active_streams, 1);
+        assert_eq!(summary.bytes_up, 1200);
+        assert_eq!(summary.bytes_down, 34);
+        assert!(summary.uptime >= Duration::from_millis(100));
+        assert_eq!(
+            StatsSummary { uptime: Duration::from_secs(61), ..summary }.to_string(),
+            "active_streams=1 bytes_up=1200 bytes_down=34 uptime=61s"
+        );
+
+        ct.cancel();
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+//! Fixtures shared by the unit tests.
+use std::io;
+
+use bytes::BytesMut;
+use ownserver_lib::{ClientId, ControlPacketV2, ControlPacketV2Codec, Endpoints, ServerHelloV2, CLIENT_HELLO_VERSION};
+use tokio_util::codec::Encoder;
+
+/// `packet` as the server sends it.
+pub fn encode(packet: ControlPacketV2) -> io::Result<Vec<u8>> {
+    let mut bytes = BytesMut::new();
+    ControlPacketV2Codec::new().encode(packet, &mut bytes)?;
+    Ok(bytes.to_vec())
+}
+
+/// `ServerHelloV2::Success` of the current version for `endpoints` at `foo.bar.local`, as the server sends it.
+pub fn server_hello(endpoints: Endpoints, capabilities: Vec<String>) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&ServerHelloV2::Success {
+        client_id: ClientId::new(),
+        host: "foo.bar.local".to_string(),
+        endpoints,
+        version: CLIENT_HELLO_VERSION,
+        capabilities,
+        public_host: None,
+    })
+}
@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::*;
+use rustls::{Certificate, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile::Item;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::error::Error;
+
+/// TLS settings of the control channel. Setting any of them switches the WebSocket to wss.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    /// pem file of the CA the server certificate is checked against, the webpki roots when `None`
+    pub server_ca: Option<PathBuf>,
+    /// pem files of the certificate and private key presented to servers requiring client certificates
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read {}: {1}.", .0.display())]
+    Io(PathBuf, #[source] io::Error),
+
+    #[error("No certificate found in {}.", .0.display())]
+    NoCertificate(PathBuf),
+
+    #[error("No private key found in {}.", .0.display())]
+    NoPrivateKey(PathBuf),
+
+    #[error("Invalid TLS configuration: {0}.")]
+    Rustls(#[from] rustls::Error),
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path).map(BufReader::new).map_err(|e| TlsError::Io(path.to_path_buf(), e))
+}
+
+/// Every certificate in the pem file at `path`, leaf first.
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// The first PKCS#8, PKCS#1 or SEC1 private key in the pem file at `path`.
+pub fn load_private_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| TlsError::Io(path.to_path_buf(), e))? {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(TlsError::NoPrivateKey(path.to_path_buf())),
+        }
+    }
+}
+
+/// Certificates of the pem file at `path` as trust anchors.
+pub fn load_roots(path: &Path) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+pub fn client_config(options: &TlsOptions) -> Result<Arc<rustls::ClientConfig>, TlsError> {
+    let roots = match options.server_ca {
+        Some(ref path) => load_roots(path)?,
+        None => {
+            let mut roots = RootCertStore::empty();
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+            }));
+            roots
+        }
+    };
+
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match options.client_cert {
+        Some((ref cert, ref key)) => builder.with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Start TLS with `host` on `socket`, checking its certificate against the roots of `config`.
+pub async fn connect(host: &str, socket: TcpStream, config: Arc<rustls::ClientConfig>) -> Result<TlsStream<TcpStream>, Error> {
+    let server_name = ServerName::try_from(host).map_err(|_| Error::BadRequest)?;
+    let peer_addr = socket.peer_addr().map_err(|_| Error::ServerDown)?;
+    TlsConnector::from(config)
+        .connect(server_name, socket)
+        .await
+        .map_err(|e| {
+            error!("TLS handshake with {} at {} failed: {:?}", host, peer_addr, e);
+            Error::ServerDown
+        })
+}
+
+#[cfg(test)]
+mod tls_test {
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../ownserver_server/testdata").join(name)
+    }
+
+    #[test]
+    fn build_client_config_with_client_cert() -> Result<(), TlsError> {
+        let options = TlsOptions {
+            server_ca: Some(testdata("mtls_ca.pem")),
+            client_cert: Some((testdata("mtls_client.pem"), testdata("mtls_client_key.pem"))),
+        };
+        let config = client_config(&options)?;
+        assert!(config.client_auth_cert_resolver.has_certs());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_pem_without_key() {
+        assert!(matches!(load_private_key(&testdata("mtls_client.pem")), Err(TlsError::NoPrivateKey(_))));
+        assert!(matches!(load_certs(&testdata("missing.pem")), Err(TlsError::Io(..))));
+    }
+}
@@ -0,0 +1,115 @@
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use clap::ValueEnum;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use http::{Method, Request, StatusCode};
+use log::*;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+use crate::error::Error;
+
+/// Carrier of the control channel. Control packets are encoded the same way on every transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Transport {
+    /// WebSocket at ws://<host>:<control_port><control_path>, `/tunnel` by default
+    #[default]
+    #[value(name = "ws")]
+    WebSocket,
+    /// HTTP/2 CONNECT to <host>:<control_port>, for networks that block WebSockets
+    #[value(name = "h2")]
+    H2,
+}
+
+/// Open an HTTP/2 CONNECT tunnel and expose it as a stream of messages.
+/// Each message is framed with a length prefix over the tunnel.
+pub async fn connect_h2(
+    host: &str,
+    control_port: u16,
+) -> Result<
+    (
+        impl Sink<Message, Error = WsError> + Unpin + Send + 'static,
+        impl Stream<Item = Result<Message, WsError>> + Unpin + Send + 'static,
+    ),
+    Error,
+> {
+    let socket = TcpStream::connect((host, control_port))
+        .await
+        .map_err(|_| Error::ServerDown)?;
+    let (send_request, connection) = h2::client::handshake(socket).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("h2 connection closed with error: {:?}", e);
+        }
+    });
+
+    let mut send_request = send_request.ready().await?;
+    let request = Request::builder()
+        .method(Method::CONNECT)
+        .uri(format!("{}:{}", host, control_port))
+        .body(())
+        .map_err(|_| Error::BadRequest)?;
+    let (response, mut send) = send_request.send_request(request, false)?;
+    let response = response.await?;
+    if response.status() != StatusCode::OK {
+        error!("h2 CONNECT was refused: {}", response.status());
+        return Err(Error::ServerDown);
+    }
+    let mut recv = response.into_body();
+
+    let (sink_tx, mut sink_rx) = unbounded::<Message>();
+    tokio::spawn(async move {
+        // the connection lives as long as a request handle exists
+        let _send_request = send_request;
+        let mut codec = LengthDelimitedCodec::new();
+        while let Some(message) = sink_rx.next().await {
+            let mut frame = BytesMut::new();
+            if let Err(e) = codec.encode(Bytes::from(message.into_data()), &mut frame) {
+                warn!("failed to frame message: {:?}", e);
+                break;
+            }
+            if let Err(e) = send.send_data(frame.freeze(), false) {
+                warn!("failed to write to h2 stream: {:?}", e);
+                break;
+            }
+        }
+        let _ = send.send_data(Bytes::new(), true);
+    });
+
+    let (stream_tx, stream_rx) = unbounded::<Result<Message, WsError>>();
+    tokio::spawn(async move {
+        let mut codec = LengthDelimitedCodec::new();
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = recv.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = stream_tx.unbounded_send(Err(WsError::Io(io::Error::new(io::ErrorKind::Other, e))));
+                    return;
+                }
+            };
+            let _ = recv.flow_control().release_capacity(chunk.len());
+            buf.extend_from_slice(&chunk);
+
+            loop {
+                match codec.decode(&mut buf) {
+                    Ok(Some(frame)) => {
+                        if stream_tx.unbounded_send(Ok(Message::binary(frame.to_vec()))).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = stream_tx.unbounded_send(Err(WsError::Io(e)));
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    Ok((sink_tx.sink_map_err(|_| WsError::AlreadyClosed), stream_rx))
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+/// Consecutive unanswered `ControlPacketV2::Heartbeat`s after which the peer is considered dead.
+pub const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// Matches `Heartbeat`s sent to a peer with the `HeartbeatAck`s it sends back.
+#[derive(Debug, Clone, Default)]
+pub struct HeartbeatTracker {
+    next_nonce: u64,
+    outstanding: Option<(u64, Instant)>,
+    missed: u32,
+}
+
+impl HeartbeatTracker {
+    /// Nonce for the next `Heartbeat`. The previous one counts as missed if it is still unanswered.
+    pub fn ping(&mut self) -> u64 {
+        self.ping_at(Instant::now())
+    }
+
+    fn ping_at(&mut self, now: Instant) -> u64 {
+        if self.outstanding.is_some() {
+            self.missed += 1;
+        }
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        self.outstanding = Some((nonce, now));
+        nonce
+    }
+
+    /// Round trip time when `nonce` answers the latest `Heartbeat`, `None` for stale or unknown nonces.
+    pub fn pong(&mut self, nonce: u64) -> Option<Duration> {
+        self.pong_at(nonce, Instant::now())
+    }
+
+    fn pong_at(&mut self, nonce: u64, now: Instant) -> Option<Duration> {
+        match self.outstanding {
+            Some((outstanding, sent_at)) if outstanding == nonce => {
+                self.outstanding = None;
+                self.missed = 0;
+                Some(now.duration_since(sent_at))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.missed >= MAX_MISSED_HEARTBEATS
+    }
+}
+
+#[cfg(test)]
+mod heartbeat_tracker_test {
+    use super::*;
+
+    #[test]
+    fn measure_round_trip_of_matching_nonce() {
+        let mut heartbeat = HeartbeatTracker::default();
+        let now = Instant::now();
+
+        let nonce = heartbeat.ping_at(now);
+        assert_eq!(heartbeat.pong_at(nonce + 1, now), None);
+        assert_eq!(heartbeat.pong_at(nonce, now + Duration::from_millis(30)), Some(Duration::from_millis(30)));
+        assert_eq!(heartbeat.pong_at(nonce, now + Duration::from_millis(40)), None);
+    }
+
+    #[test]
+    fn dead_after_missed_heartbeats() {
+        let mut heartbeat = HeartbeatTracker::default();
+        let now = Instant::now();
+
+        for _ in 0..MAX_MISSED_HEARTBEATS {
+            heartbeat.ping_at(now);
+        }
+        assert!(!heartbeat.is_dead());
+
+        let nonce = heartbeat.ping_at(now);
+        assert!(heartbeat.is_dead());
+
+        heartbeat.pong_at(nonce, now);
+        assert!(!heartbeat.is_dead());
+    }
+}
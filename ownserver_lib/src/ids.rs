@@ -0,0 +1,71 @@
+use uuid::Uuid;
+
+/// Where `StreamId::new`, `ClientId::new` and `EndpointId::new` take their uuid from.
+pub trait IdGenerator {
+    fn next_uuid(&self) -> Uuid;
+}
+
+/// Random v4 uuids, the only generator outside tests.
+#[derive(Debug, Default)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn next_uuid(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+#[cfg(feature = "test-ids")]
+pub use sequential::{set_id_generator, IdGeneratorGuard, SequentialIds};
+
+#[cfg(feature = "test-ids")]
+mod sequential {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    /// 1, 2, 3, ... in the order ids are made, e.g. the second one is `StreamId::from_u128(2)`.
+    #[derive(Debug, Default)]
+    pub struct SequentialIds {
+        last: Cell<u128>,
+    }
+
+    impl IdGenerator for SequentialIds {
+        fn next_uuid(&self) -> Uuid {
+            self.last.set(self.last.get() + 1);
+            Uuid::from_u128(self.last.get())
+        }
+    }
+
+    thread_local! {
+        pub(super) static GENERATOR: RefCell<Option<Box<dyn IdGenerator>>> = RefCell::new(None);
+    }
+
+    /// Ids made on this thread come from `generator` until the guard is dropped.
+    /// A current thread runtime, the default of `#[tokio::test]`, makes them all on one thread.
+    pub fn set_id_generator(generator: impl IdGenerator + 'static) -> IdGeneratorGuard {
+        let previous = GENERATOR.with(|cell| cell.borrow_mut().replace(Box::new(generator)));
+        IdGeneratorGuard { previous }
+    }
+
+    #[must_use]
+    pub struct IdGeneratorGuard {
+        previous: Option<Box<dyn IdGenerator>>,
+    }
+
+    impl Drop for IdGeneratorGuard {
+        fn drop(&mut self) {
+            let previous = self.previous.take();
+            GENERATOR.with(|cell| *cell.borrow_mut() = previous);
+        }
+    }
+}
+
+pub(crate) fn next_uuid() -> Uuid {
+    #[cfg(feature = "test-ids")]
+    {
+        if let Some(uuid) = sequential::GENERATOR.with(|cell| cell.borrow().as_ref().map(|g| g.next_uuid())) {
+            return uuid;
+        }
+    }
+    RandomIds.next_uuid()
+}
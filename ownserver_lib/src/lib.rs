@@ -1,11 +1,44 @@
 use std::io;
+use std::time::Duration;
 
 use bytes::BytesMut;
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Encoder, Decoder};
 use uuid::Uuid;
 
-pub const CLIENT_HELLO_VERSION: u16 = 2;
+mod heartbeat;
+pub use heartbeat::{HeartbeatTracker, MAX_MISSED_HEARTBEATS};
+pub mod ids;
+mod log_format;
+pub use log_format::LogFormat;
+pub mod socket;
+
+pub const CLIENT_HELLO_VERSION: u16 = 4;
+/// Oldest client handshake version the server accepts. Clients up to `CLIENT_HELLO_VERSION` are supported.
+pub const MIN_CLIENT_HELLO_VERSION: u16 = 3;
+/// First client handshake version that knows `CloseReason::TooManyClients`, `ReservationInvalid` and `PortBindFailed`.
+pub const REJECT_REASONS_VERSION: u16 = 4;
+
+/// Bytes a peer may send on a tcp stream before it has to wait for `ControlPacketV2::WindowUpdate`.
+pub const INITIAL_STREAM_WINDOW: u32 = 256 * 1024;
+
+/// Path of the WebSocket control endpoint unless configured otherwise.
+pub const DEFAULT_CONTROL_PATH: &str = "/tunnel";
+
+/// Port clients of the HTTP/2 CONNECT transport dial unless configured otherwise.
+/// The server listens on it only when given it as `--h2-control-port`.
+pub const DEFAULT_H2_CONTROL_PORT: u16 = 5001;
+
+/// Accept a control path such as `/tunnel` or `/ownserver/tunnel`.
+pub fn parse_control_path(path: &str) -> Result<String, String> {
+    if !path.starts_with('/') {
+        return Err(format!("control path `{}` must start with `/`", path));
+    }
+    if path.contains(|c: char| c == '?' || c == '#' || c.is_whitespace()) {
+        return Err(format!("control path `{}` must be a plain path", path));
+    }
+    Ok(path.to_string())
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 #[serde(transparent)]
@@ -19,7 +52,13 @@ impl std::fmt::Display for StreamId {
 
 impl StreamId {
     pub fn new() -> Self {
-        Self(Uuid::new_v4())
+        Self(ids::next_uuid())
+    }
+
+    /// The `n`th id of `SequentialIds`.
+    #[cfg(feature = "test-ids")]
+    pub fn from_u128(n: u128) -> Self {
+        Self(Uuid::from_u128(n))
     }
 }
 
@@ -33,9 +72,24 @@ impl std::fmt::Display for ClientId {
     }
 }
 
+/// Parses both the `client_<uuid>` of `Display` and a bare uuid.
+impl std::str::FromStr for ClientId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.strip_prefix("client_").unwrap_or(s).parse()?))
+    }
+}
+
 impl ClientId {
     pub fn new() -> Self {
-        Self(Uuid::new_v4())
+        Self(ids::next_uuid())
+    }
+
+    /// The `n`th id of `SequentialIds`.
+    #[cfg(feature = "test-ids")]
+    pub fn from_u128(n: u128) -> Self {
+        Self(Uuid::from_u128(n))
     }
 }
 
@@ -49,7 +103,13 @@ impl std::fmt::Display for EndpointId {
 }
 impl EndpointId {
     pub fn new() -> Self {
-        Self(Uuid::new_v4())
+        Self(ids::next_uuid())
+    }
+
+    /// The `n`th id of `SequentialIds`.
+    #[cfg(feature = "test-ids")]
+    pub fn from_u128(n: u128) -> Self {
+        Self(Uuid::from_u128(n))
     }
 }
 
@@ -78,20 +138,120 @@ pub struct EndpointClaim {
 
 pub type EndpointClaims = Vec<EndpointClaim>;
 
+/// Optional feature negotiated in the handshake. Check the negotiated set before using one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    Compression,
+    Encryption,
+    /// tcp streams wait for `ControlPacketV2::WindowUpdate`
+    FlowControl,
+    MultiPort,
+    /// liveness is checked with `ControlPacketV2::Heartbeat` instead of `Ping`
+    Heartbeat,
+    /// tcp streams are half-closed with `ControlPacketV2::ShutdownWrite` instead of ending at the first EOF
+    HalfClose,
+    /// tcp streams the server sniffed as http open with `ControlPacketV2::InitHttp`
+    HttpHost,
+    /// the client asks for `ControlPacketV2::WhoAmIResp` once the handshake is done
+    WhoAmI,
+}
+
+/// Capabilities implemented by this version of ownserver.
+pub const SUPPORTED_CAPABILITIES: &[Capability] = &[Capability::FlowControl, Capability::MultiPort, Capability::Heartbeat, Capability::HalfClose, Capability::HttpHost, Capability::WhoAmI];
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Compression => "compression",
+            Capability::Encryption => "encryption",
+            Capability::FlowControl => "flow-control",
+            Capability::MultiPort => "multi-port",
+            Capability::Heartbeat => "heartbeat",
+            Capability::HalfClose => "half-close",
+            Capability::HttpHost => "http-host",
+            Capability::WhoAmI => "who-am-i",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "compression" => Some(Capability::Compression),
+            "encryption" => Some(Capability::Encryption),
+            "flow-control" => Some(Capability::FlowControl),
+            "multi-port" => Some(Capability::MultiPort),
+            "heartbeat" => Some(Capability::Heartbeat),
+            "half-close" => Some(Capability::HalfClose),
+            "http-host" => Some(Capability::HttpHost),
+            "who-am-i" => Some(Capability::WhoAmI),
+            _ => None,
+        }
+    }
+}
+
+/// Names the peer `offered` that are also `supported`. Names unknown to this version are ignored.
+pub fn negotiate_capabilities(offered: &[String], supported: &[Capability]) -> Vec<Capability> {
+    let mut negotiated = Vec::new();
+    for capability in offered.iter().filter_map(|name| Capability::from_name(name)) {
+        if supported.contains(&capability) && !negotiated.contains(&capability) {
+            negotiated.push(capability);
+        }
+    }
+    negotiated
+}
+
+pub fn capability_names(capabilities: &[Capability]) -> Vec<String> {
+    capabilities.iter().map(|c| c.as_str().to_string()).collect()
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientHelloV2 {
     pub version: u16,
     pub token: String,
     pub endpoint_claims: EndpointClaims,
+    /// names of `Capability`, kept as strings so that newer peers can offer more
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// token of a port the server reserved for this client out of band
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reservation: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ControlPacketV2 {
+    /// Open a stream for the port mapping `EndpointId`. Later packets of the stream are routed by `StreamId`,
+    /// so one connection carries several mappings without labelling every packet.
     Init(StreamId, EndpointId),
     Data(StreamId, Vec<u8>),
     Refused(StreamId),
     End(StreamId),
+    /// Echoed back unchanged. Peers that negotiated `Capability::Heartbeat` use `Heartbeat` instead
     Ping,
+    WindowUpdate(StreamId, u32),
+    /// Server closes the whole control connection
+    Disconnect(CloseReason),
+    /// Client has set up the local side of a stream opened by `Init`
+    InitAck(StreamId),
+    /// Either side checks the peer is alive, answered by `HeartbeatAck` with the same nonce
+    Heartbeat(u64),
+    HeartbeatAck(u64),
+    /// Client asks what the server assigned to it, answered by `WhoAmIResp`
+    WhoAmI,
+    WhoAmIResp {
+        client_id: ClientId,
+        /// host players connect to
+        host: String,
+        /// every port mapping with its remote port
+        endpoints: Endpoints,
+        /// unix time in seconds the session reaches the server's maximum duration
+        expires_at: Option<u64>,
+    },
+    /// Sender is done writing to the stream but still reads from it. The peer shuts down only the write half of its socket.
+    /// Sent only when `Capability::HalfClose` is negotiated, `End` otherwise
+    ShutdownWrite(StreamId),
+    /// `Init` of a stream whose remote peer opened with an http request, with the Host header as the peer sent it.
+    /// The request itself reaches the local server unchanged. Sent only when `Capability::HttpHost` is negotiated
+    InitHttp(StreamId, EndpointId, String),
 }
 
 impl std::fmt::Display for ControlPacketV2 {
@@ -102,6 +262,15 @@ impl std::fmt::Display for ControlPacketV2 {
             ControlPacketV2::Refused(sid)  => write!(f, "ControlPacket::Refused(sid={})", sid),
             ControlPacketV2::End(sid) => write!(f, "ControlPacket::End(sid={})", sid),
             ControlPacketV2::Ping => write!(f, "ControlPacket::Ping"),
+            ControlPacketV2::WindowUpdate(sid, n) => write!(f, "ControlPacket::WindowUpdate(sid={}, n={})", sid, n),
+            ControlPacketV2::Disconnect(reason) => write!(f, "ControlPacket::Disconnect(reason={})", reason),
+            ControlPacketV2::InitAck(sid) => write!(f, "ControlPacket::InitAck(sid={})", sid),
+            ControlPacketV2::Heartbeat(nonce) => write!(f, "ControlPacket::Heartbeat(nonce={})", nonce),
+            ControlPacketV2::HeartbeatAck(nonce) => write!(f, "ControlPacket::HeartbeatAck(nonce={})", nonce),
+            ControlPacketV2::WhoAmI => write!(f, "ControlPacket::WhoAmI"),
+            ControlPacketV2::WhoAmIResp { client_id, host, endpoints, .. } => write!(f, "ControlPacket::WhoAmIResp(cid={}, host={}, endpoints_len={})", client_id, host, endpoints.len()),
+            ControlPacketV2::ShutdownWrite(sid) => write!(f, "ControlPacket::ShutdownWrite(sid={})", sid),
+            ControlPacketV2::InitHttp(sid, eid, host) => write!(f, "ControlPacket::InitHttp(sid={}, eid={}, host={})", sid, eid, host),
         }
     }
 }
@@ -116,6 +285,74 @@ pub struct Endpoint {
 
 pub type Endpoints = Vec<Endpoint>;
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    VersionUnsupported { min: u16, max: u16 },
+    QuotaExceeded,
+    AlreadyConnected,
+    /// the peer closed the websocket with 1000
+    Normal,
+    /// the peer is shutting down or restarting, 1001
+    GoingAway,
+    /// the connection dropped without a close frame, 1006
+    Abnormal,
+    /// any other websocket close code
+    Other { code: u16, reason: String },
+    /// the session reached the server's maximum duration
+    SessionExpired,
+    /// the server has no free remote port left
+    NoPortsAvailable,
+    /// the client kept sending packets the server does not accept
+    ProtocolViolation,
+    /// the server has as many clients as it accepts
+    TooManyClients,
+    /// the port reservation of the client hello is unknown or has expired
+    ReservationInvalid,
+    /// no remote port of the server could be bound, other processes hold them
+    PortBindFailed,
+}
+
+impl CloseReason {
+    /// Map a websocket close frame. A close message without a frame counts as `Normal`.
+    pub fn from_close_frame(frame: Option<(u16, &str)>) -> Self {
+        match frame {
+            None | Some((1000, _)) => CloseReason::Normal,
+            Some((1001, _)) => CloseReason::GoingAway,
+            Some((1006, _)) => CloseReason::Abnormal,
+            Some((code, reason)) => CloseReason::Other {
+                code,
+                reason: reason.to_string(),
+            },
+        }
+    }
+
+    /// Whether connecting again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, CloseReason::GoingAway | CloseReason::Abnormal | CloseReason::Other { .. } | CloseReason::SessionExpired | CloseReason::NoPortsAvailable | CloseReason::TooManyClients | CloseReason::PortBindFailed)
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::VersionUnsupported { min, max } => write!(f, "protocol version is not supported, server supports {}-{}", min, max),
+            CloseReason::QuotaExceeded => write!(f, "traffic quota exceeded"),
+            CloseReason::AlreadyConnected => write!(f, "another client is connected with the same token"),
+            CloseReason::Normal => write!(f, "connection closed normally"),
+            CloseReason::GoingAway => write!(f, "peer is going away"),
+            CloseReason::Abnormal => write!(f, "connection closed abnormally"),
+            CloseReason::Other { code, reason } => write!(f, "connection closed with code {}: {}", code, reason),
+            CloseReason::SessionExpired => write!(f, "session reached the maximum duration"),
+            CloseReason::NoPortsAvailable => write!(f, "no remote port is available on the server"),
+            CloseReason::ProtocolViolation => write!(f, "client sent packets the server does not accept"),
+            CloseReason::TooManyClients => write!(f, "the server has too many clients"),
+            CloseReason::ReservationInvalid => write!(f, "the port reservation is unknown or has expired"),
+            CloseReason::PortBindFailed => write!(f, "the server could not listen on a remote port"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum ServerHelloV2 {
@@ -123,12 +360,44 @@ pub enum ServerHelloV2 {
         client_id: ClientId,
         host: String,
         endpoints: Endpoints,
+        #[serde(default)]
+        version: u16,
+        /// capabilities offered by the client that the server supports as well
+        #[serde(default)]
+        capabilities: Vec<String>,
+        /// host players connect to, `host` when `None`
+        #[serde(default)]
+        public_host: Option<String>,
     },
     BadRequest,
     ServiceTemporaryUnavailable,
     IllegalHost,
     InternalServerError,
     VersionMismatch,
+    Rejected {
+        reason: CloseReason,
+        /// how long the client should wait before connecting again, its own backoff when `None`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<Duration>,
+    },
+}
+
+impl ServerHelloV2 {
+    /// Swap rejections a client of handshake `version` can't decode for the responses it got before them.
+    pub fn for_client_version(self, version: u16) -> Self {
+        if version >= REJECT_REASONS_VERSION {
+            return self;
+        }
+        match self {
+            ServerHelloV2::Rejected { reason: CloseReason::TooManyClients, .. } => ServerHelloV2::ServiceTemporaryUnavailable,
+            ServerHelloV2::Rejected { reason: CloseReason::ReservationInvalid, .. } => ServerHelloV2::BadRequest,
+            ServerHelloV2::Rejected { reason: CloseReason::PortBindFailed, retry_after } => ServerHelloV2::Rejected {
+                reason: CloseReason::NoPortsAvailable,
+                retry_after,
+            },
+            other => other,
+        }
+    }
 }
 
 
@@ -185,4 +454,186 @@ mod control_packet_test {
         assert_eq!(ControlPacketV2::Init(stream_id, endpoint_id), deserialized_packet);
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_control_packet_window_update() -> Result<(), Box<dyn std::error::Error>> {
+        let stream_id = StreamId::default();
+        let expected_packet = ControlPacketV2::WindowUpdate(stream_id, 4096);
+
+        let mut encoded = BytesMut::new();
+        ControlPacketV2Codec::new().encode(expected_packet, &mut encoded)?;
+
+        let deserialized_packet = ControlPacketV2Codec::new().decode(&mut encoded)?.unwrap();
+        assert_eq!(ControlPacketV2::WindowUpdate(stream_id, 4096), deserialized_packet);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_packet_disconnect() -> Result<(), Box<dyn std::error::Error>> {
+        let expected_packet = ControlPacketV2::Disconnect(CloseReason::QuotaExceeded);
+
+        let mut encoded = BytesMut::new();
+        ControlPacketV2Codec::new().encode(expected_packet, &mut encoded)?;
+
+        let deserialized_packet = ControlPacketV2Codec::new().decode(&mut encoded)?.unwrap();
+        assert_eq!(ControlPacketV2::Disconnect(CloseReason::QuotaExceeded), deserialized_packet);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_packet_init_ack() -> Result<(), Box<dyn std::error::Error>> {
+        let stream_id = StreamId::default();
+        let expected_packet = ControlPacketV2::InitAck(stream_id);
+
+        let mut encoded = BytesMut::new();
+        ControlPacketV2Codec::new().encode(expected_packet, &mut encoded)?;
+
+        let deserialized_packet = ControlPacketV2Codec::new().decode(&mut encoded)?.unwrap();
+        assert_eq!(ControlPacketV2::InitAck(stream_id), deserialized_packet);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_packet_heartbeat() -> Result<(), Box<dyn std::error::Error>> {
+        let mut encoded = BytesMut::new();
+        ControlPacketV2Codec::new().encode(ControlPacketV2::Heartbeat(42), &mut encoded)?;
+
+        let deserialized_packet = ControlPacketV2Codec::new().decode(&mut encoded)?.unwrap();
+        assert_eq!(ControlPacketV2::Heartbeat(42), deserialized_packet);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_packet_shutdown_write() -> Result<(), Box<dyn std::error::Error>> {
+        let stream_id = StreamId::default();
+        let mut encoded = BytesMut::new();
+        ControlPacketV2Codec::new().encode(ControlPacketV2::ShutdownWrite(stream_id), &mut encoded)?;
+
+        let deserialized_packet = ControlPacketV2Codec::new().decode(&mut encoded)?.unwrap();
+        assert_eq!(ControlPacketV2::ShutdownWrite(stream_id), deserialized_packet);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_packet_init_http() -> Result<(), Box<dyn std::error::Error>> {
+        let packet = ControlPacketV2::InitHttp(StreamId::default(), EndpointId::default(), "Play.Example.com:8080".to_string());
+        let mut encoded = BytesMut::new();
+        ControlPacketV2Codec::new().encode(packet.clone(), &mut encoded)?;
+
+        let deserialized_packet = ControlPacketV2Codec::new().decode(&mut encoded)?.unwrap();
+        assert_eq!(packet, deserialized_packet);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_packet_who_am_i_resp() -> Result<(), Box<dyn std::error::Error>> {
+        let expected_packet = ControlPacketV2::WhoAmIResp {
+            client_id: ClientId::new(),
+            host: "foo.local".to_string(),
+            endpoints: vec![Endpoint { id: EndpointId::new(), protocol: Protocol::TCP, local_port: 25565, remote_port: 10000 }],
+            expires_at: Some(1700000000),
+        };
+        let mut encoded = BytesMut::new();
+        ControlPacketV2Codec::new().encode(expected_packet.clone(), &mut encoded)?;
+
+        let deserialized_packet = ControlPacketV2Codec::new().decode(&mut encoded)?.unwrap();
+        assert_eq!(expected_packet, deserialized_packet);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod client_id_test {
+    use super::*;
+
+    #[test]
+    fn parse_displayed_and_bare_ids() {
+        let client_id = ClientId::new();
+        assert_eq!(client_id.to_string().parse::<ClientId>(), Ok(client_id));
+        assert_eq!(client_id.0.to_string().parse::<ClientId>(), Ok(client_id));
+        assert!("client_42".parse::<ClientId>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod capability_test {
+    use super::*;
+
+    #[test]
+    fn negotiate_intersection() {
+        let offered = capability_names(&[Capability::Compression, Capability::FlowControl, Capability::MultiPort]);
+        let supported = [Capability::FlowControl, Capability::MultiPort, Capability::Encryption];
+        assert_eq!(negotiate_capabilities(&offered, &supported), vec![Capability::FlowControl, Capability::MultiPort]);
+    }
+
+    #[test]
+    fn ignore_unknown_capabilities() {
+        let offered = vec!["teleport".to_string(), "flow-control".to_string(), "flow-control".to_string()];
+        assert_eq!(negotiate_capabilities(&offered, SUPPORTED_CAPABILITIES), vec![Capability::FlowControl]);
+    }
+}
+
+#[cfg(test)]
+mod close_reason_test {
+    use super::*;
+
+    #[test]
+    fn map_close_frames() {
+        assert_eq!(CloseReason::from_close_frame(Some((1000, ""))), CloseReason::Normal);
+        assert_eq!(CloseReason::from_close_frame(None), CloseReason::Normal);
+        assert_eq!(CloseReason::from_close_frame(Some((1001, "restart"))), CloseReason::GoingAway);
+        assert_eq!(CloseReason::from_close_frame(Some((1006, ""))), CloseReason::Abnormal);
+        assert_eq!(
+            CloseReason::from_close_frame(Some((4000, "bye"))),
+            CloseReason::Other { code: 4000, reason: "bye".to_string() }
+        );
+    }
+
+    #[test]
+    fn retry_only_transient_closures() {
+        assert!(!CloseReason::Normal.is_retryable());
+        assert!(!CloseReason::QuotaExceeded.is_retryable());
+        assert!(!CloseReason::ProtocolViolation.is_retryable());
+        assert!(CloseReason::Abnormal.is_retryable());
+        assert!(CloseReason::GoingAway.is_retryable());
+        assert!(CloseReason::SessionExpired.is_retryable());
+        assert!(CloseReason::NoPortsAvailable.is_retryable());
+        assert!(CloseReason::TooManyClients.is_retryable());
+        assert!(!CloseReason::ReservationInvalid.is_retryable());
+        assert!(CloseReason::PortBindFailed.is_retryable());
+    }
+}
+
+#[cfg(test)]
+mod server_hello_test {
+    use super::*;
+
+    fn rejected(reason: CloseReason) -> ServerHelloV2 {
+        ServerHelloV2::Rejected { reason, retry_after: None }
+    }
+
+    #[test]
+    fn keep_old_responses_for_old_clients() {
+        let old = REJECT_REASONS_VERSION - 1;
+        assert!(matches!(rejected(CloseReason::TooManyClients).for_client_version(old), ServerHelloV2::ServiceTemporaryUnavailable));
+        assert!(matches!(rejected(CloseReason::ReservationInvalid).for_client_version(old), ServerHelloV2::BadRequest));
+        assert!(matches!(
+            rejected(CloseReason::PortBindFailed).for_client_version(old),
+            ServerHelloV2::Rejected { reason: CloseReason::NoPortsAvailable, .. }
+        ));
+        assert!(matches!(
+            rejected(CloseReason::AlreadyConnected).for_client_version(old),
+            ServerHelloV2::Rejected { reason: CloseReason::AlreadyConnected, .. }
+        ));
+    }
+
+    #[test]
+    fn send_new_reasons_to_new_clients() {
+        for reason in [CloseReason::TooManyClients, CloseReason::ReservationInvalid, CloseReason::PortBindFailed] {
+            match rejected(reason.clone()).for_client_version(REJECT_REASONS_VERSION) {
+                ServerHelloV2::Rejected { reason: sent, .. } => assert_eq!(sent, reason),
+                other => panic!("unexpected server hello {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+/// Socket level timeouts applied to forwarded tcp connections. `None` waits forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketTimeouts {
+    /// give up connecting, a firewall dropping SYNs would hang the stream otherwise. Unused for accepted sockets
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+/// Options set on every forwarded tcp connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// disable Nagle's algorithm so that small game packets are sent at once
+    pub nodelay: bool,
+    /// probe idle peers after this long to detect dead connections
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self { nodelay: true, keepalive: None }
+    }
+}
+
+impl TcpOptions {
+    pub fn apply(&self, socket: &TcpStream) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            let params = TcpKeepalive::new().with_time(keepalive).with_interval(keepalive);
+            SockRef::from(socket).set_tcp_keepalive(&params)?;
+        }
+        Ok(())
+    }
+}
+
+/// Await `io`, failing with `ErrorKind::TimedOut` once `timeout` elapses.
+pub async fn with_timeout<T>(timeout: Option<Duration>, io: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, io)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut))),
+        None => io.await,
+    }
+}
+
+#[cfg(test)]
+mod tcp_options_test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn accepted_socket() -> io::Result<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, _) = listener.accept().await?;
+        Ok((socket, client))
+    }
+
+    #[tokio::test]
+    async fn set_nodelay_and_keepalive() -> io::Result<()> {
+        let (socket, _client) = accepted_socket().await?;
+        TcpOptions { nodelay: true, keepalive: Some(Duration::from_secs(30)) }.apply(&socket)?;
+
+        let sock = SockRef::from(&socket);
+        assert!(sock.nodelay()?);
+        assert!(sock.keepalive()?);
+        assert_eq!(sock.keepalive_time()?, Duration::from_secs(30));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leave_nagle_enabled_when_nodelay_is_off() -> io::Result<()> {
+        let (socket, _client) = accepted_socket().await?;
+        TcpOptions { nodelay: false, keepalive: None }.apply(&socket)?;
+
+        let sock = SockRef::from(&socket);
+        assert!(!sock.nodelay()?);
+        assert!(!sock.keepalive()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use async_trait::async_trait;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Pause of an accept loop out of file descriptors, so that it does not spin until one is closed.
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Where accept loops take connections from, a `TcpListener` outside of tests.
+#[async_trait]
+pub trait Accept: Send + Sync {
+    type Conn: Send;
+
+    async fn accept(&self) -> io::Result<(Self::Conn, SocketAddr)>;
+}
+
+#[async_trait]
+impl Accept for TcpListener {
+    type Conn = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
+
+/// `EMFILE` or `ENFILE`, accepting again only fails until a descriptor is closed.
+pub fn is_fd_exhausted(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = e;
+        false
+    }
+}
+
+/// Accept the next connection. Each failure is handed to `on_error` and retried, after `ACCEPT_BACKOFF`
+/// when out of file descriptors. Cancel by dropping the future, e.g. in a `select!`.
+pub async fn accept_with_backoff<L: Accept>(listener: &L, mut on_error: impl FnMut(&io::Error) + Send) -> (L::Conn, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(conn) => return conn,
+            Err(e) => {
+                on_error(&e);
+                if is_fd_exhausted(&e) {
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod accept_test {
+    use super::*;
+    use std::{collections::VecDeque, sync::Mutex};
+    use tokio::time::Instant;
+
+    // fails with `errors` in order and then accepts
+    struct FailingListener {
+        errors: Mutex<VecDeque<io::Error>>,
+    }
+
+    impl FailingListener {
+        fn new(errors: impl IntoIterator<Item = io::Error>) -> Self {
+            Self { errors: Mutex::new(errors.into_iter().collect()) }
+        }
+    }
+
+    #[async_trait]
+    impl Accept for FailingListener {
+        type Conn = ();
+
+        async fn accept(&self) -> io::Result<((), SocketAddr)> {
+            match self.errors.lock().unwrap().pop_front() {
+                Some(e) => Err(e),
+                None => Ok(((), SocketAddr::from(([127, 0, 0, 1], 10000)))),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(start_paused = true)]
+    async fn back_off_when_out_of_file_descriptors() {
+        let listener = FailingListener::new([io::Error::from_raw_os_error(libc::EMFILE), io::Error::from_raw_os_error(libc::ENFILE)]);
+        let mut failures = 0;
+        let started = Instant::now();
+        accept_with_backoff(&listener, |_| failures += 1).await;
+        assert_eq!(failures, 2);
+        assert_eq!(started.elapsed(), ACCEPT_BACKOFF * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accept_again_at_once_after_other_errors() {
+        let listener = FailingListener::new([io::Error::from(io::ErrorKind::ConnectionAborted)]);
+        let mut failures = 0;
+        let started = Instant::now();
+        accept_with_backoff(&listener, |_| failures += 1).await;
+        assert_eq!(failures, 1);
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,215 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AccessError {
+    #[error("Failed to read access list {}: {1}.", .0.display())]
+    Io(PathBuf, #[source] io::Error),
+
+    #[error("Invalid access list {}: {1}.", .0.display())]
+    Parse(PathBuf, #[source] serde_json::Error),
+
+    #[error("`{0}` isn't a valid CIDR.")]
+    InvalidCidr(String),
+
+    #[error("No access list is configured.")]
+    NotConfigured,
+}
+
+/// An IPv4 or IPv6 network e.g.) `192.0.2.0/24`. A bare address is a network of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+// IPv4 peers of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, width) = bits(self.network);
+        let (ip, ip_width) = bits(canonical(ip));
+        if width != ip_width {
+            return false;
+        }
+        if self.prefix == 0 {
+            return true;
+        }
+        let shift = width - self.prefix;
+        network >> shift == ip >> shift
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = AccessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AccessError::InvalidCidr(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network = canonical(addr.trim().parse().map_err(|_| invalid())?);
+        let width = bits(network).1;
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|p| *p <= width).ok_or_else(invalid)?,
+            None => width,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = AccessError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Which remote peers may connect e.g.) `{"allow": ["10.0.0.0/8"], "deny": ["10.0.0.13"]}`.
+/// Denied networks win over allowed ones, an empty allow list allows every peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessList {
+    #[serde(default)]
+    pub allow: Vec<Cidr>,
+    #[serde(default)]
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn load(path: &Path) -> Result<Self, AccessError> {
+        let data = std::fs::read(path).map_err(|e| AccessError::Io(path.to_path_buf(), e))?;
+        serde_json::from_slice(&data).map_err(|e| AccessError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
+
+/// The access list of a file, swapped as a whole by `reload`.
+#[derive(Debug)]
+pub struct AccessControl {
+    path: PathBuf,
+    list: RwLock<Arc<AccessList>>,
+    drop_denied: bool,
+}
+
+impl AccessControl {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, AccessError> {
+        let path = path.into();
+        let list = AccessList::load(&path)?;
+        Ok(Self {
+            path,
+            list: RwLock::new(Arc::new(list)),
+            drop_denied: false,
+        })
+    }
+
+    /// Close the streams of peers a reload denies. Otherwise only new connections are checked.
+    pub fn dropping_denied(mut self, drop_denied: bool) -> Self {
+        self.drop_denied = drop_denied;
+        self
+    }
+
+    pub fn drops_denied(&self) -> bool {
+        self.drop_denied
+    }
+
+    pub fn list(&self) -> Arc<AccessList> {
+        self.list.read().unwrap().clone()
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.list().permits(ip)
+    }
+
+    /// Read the file again. The current list stays in effect when it can't be read.
+    pub fn reload(&self) -> Result<Arc<AccessList>, AccessError> {
+        let list = Arc::new(AccessList::load(&self.path)?);
+        *self.list.write().unwrap() = list.clone();
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod access_test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn match_networks() -> Result<(), AccessError> {
+        let cidr: Cidr = "192.0.2.0/24".parse()?;
+        assert!(cidr.contains(ip("192.0.2.200")));
+        assert!(cidr.contains(ip("::ffff:192.0.2.1")));
+        assert!(!cidr.contains(ip("192.0.3.1")));
+        assert!(!cidr.contains(ip("2001:db8::1")));
+
+        assert!("2001:db8::/32".parse::<Cidr>()?.contains(ip("2001:db8:1::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>()?.contains(ip("203.0.113.1")));
+        assert!("203.0.113.1".parse::<Cidr>()?.contains(ip("203.0.113.1")));
+        assert!(!"203.0.113.1".parse::<Cidr>()?.contains(ip("203.0.113.2")));
+
+        assert!(matches!("192.0.2.0/33".parse::<Cidr>(), Err(AccessError::InvalidCidr(_))));
+        assert!(matches!("localhost".parse::<Cidr>(), Err(AccessError::InvalidCidr(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn deny_wins_over_allow() -> Result<(), serde_json::Error> {
+        let list: AccessList = serde_json::from_str(r#"{"allow": ["10.0.0.0/8"], "deny": ["10.0.0.13"]}"#)?;
+        assert!(list.permits(ip("10.1.2.3")));
+        assert!(!list.permits(ip("10.0.0.13")));
+        assert!(!list.permits(ip("192.0.2.1")));
+
+        let list: AccessList = serde_json::from_str(r#"{"deny": ["192.0.2.0/24"]}"#)?;
+        assert!(list.permits(ip("203.0.113.1")));
+        assert!(!list.permits(ip("192.0.2.1")));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_list_when_reload_fails() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("ownserver-access-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"deny": ["192.0.2.1"]}"#)?;
+        let access = AccessControl::load(&path)?;
+        assert!(!access.permits(ip("192.0.2.1")));
+
+        std::fs::write(&path, r#"{"deny": ["192.0.2.1", "#)?;
+        assert!(matches!(access.reload(), Err(AccessError::Parse(..))));
+        assert!(!access.permits(ip("192.0.2.1")));
+
+        std::fs::write(&path, r#"{"deny": []}"#)?;
+        access.reload()?;
+        assert!(access.permits(ip("192.0.2.1")));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
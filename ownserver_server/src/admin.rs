@@ -0,0 +1,115 @@
+use thiserror::Error;
+use warp::http::StatusCode;
+
+/// What an admin endpoint does to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminScope {
+    /// status and listings
+    Read,
+    /// disconnecting, draining and reloading
+    Write,
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminDenied {
+    #[error("Missing or invalid admin token.")]
+    Unauthorized,
+
+    #[error("The read-only admin token can't change the server.")]
+    Forbidden,
+
+    #[error("Set --admin-token to use admin endpoints that change the server.")]
+    WriteDisabled,
+}
+
+impl AdminDenied {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AdminDenied::Unauthorized => StatusCode::UNAUTHORIZED,
+            AdminDenied::Forbidden | AdminDenied::WriteDisabled => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl warp::reject::Reject for AdminDenied {}
+
+/// Bearer tokens of the admin endpoints. Without either token every admin endpoint is refused, the control port
+/// is public. Reads need one of the tokens and writes need the full token.
+#[derive(Debug, Clone, Default)]
+pub struct AdminTokens {
+    full: Option<String>,
+    readonly: Option<String>,
+}
+
+impl AdminTokens {
+    pub fn new(full: Option<String>, readonly: Option<String>) -> Self {
+        Self { full, readonly }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.full.is_none() && self.readonly.is_none()
+    }
+
+    /// `authorization` is the value of the `Authorization` header e.g.) `Bearer s3cret`.
+    pub fn authorize(&self, authorization: Option<&str>, scope: AdminScope) -> Result<(), AdminDenied> {
+        if self.is_open() {
+            return match scope {
+                AdminScope::Read => Err(AdminDenied::Unauthorized),
+                AdminScope::Write => Err(AdminDenied::WriteDisabled),
+            };
+        }
+        let token = authorization.and_then(|value| value.strip_prefix("Bearer ")).map(str::trim);
+        let matches = |expected: &Option<String>| match (expected, token) {
+            (Some(expected), Some(token)) => constant_time_eq(expected.as_bytes(), token.as_bytes()),
+            _ => false,
+        };
+
+        if matches(&self.full) {
+            return Ok(());
+        }
+        if matches(&self.readonly) {
+            return match scope {
+                AdminScope::Read => Ok(()),
+                AdminScope::Write => Err(AdminDenied::Forbidden),
+            };
+        }
+        Err(AdminDenied::Unauthorized)
+    }
+}
+
+// how long the comparison takes tells nothing about how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod admin_tokens_test {
+    use super::*;
+
+    #[test]
+    fn refuse_everything_without_tokens() {
+        let tokens = AdminTokens::default();
+        assert_eq!(tokens.authorize(None, AdminScope::Read), Err(AdminDenied::Unauthorized));
+        assert_eq!(tokens.authorize(Some("Bearer anything"), AdminScope::Read), Err(AdminDenied::Unauthorized));
+        assert_eq!(tokens.authorize(None, AdminScope::Write), Err(AdminDenied::WriteDisabled));
+        assert_eq!(tokens.authorize(Some("Bearer anything"), AdminScope::Write), Err(AdminDenied::WriteDisabled));
+    }
+
+    #[test]
+    fn refuse_writes_with_readonly_token_only() {
+        let tokens = AdminTokens::new(None, Some("readonly".to_string()));
+        assert_eq!(tokens.authorize(Some("Bearer readonly"), AdminScope::Read), Ok(()));
+        assert_eq!(tokens.authorize(Some("Bearer readonly"), AdminScope::Write), Err(AdminDenied::Forbidden));
+    }
+
+    #[test]
+    fn limit_readonly_token_to_reads() {
+        let tokens = AdminTokens::new(Some("full".to_string()), Some("readonly".to_string()));
+        assert_eq!(tokens.authorize(Some("Bearer full"), AdminScope::Write), Ok(()));
+        assert_eq!(tokens.authorize(Some("Bearer readonly"), AdminScope::Read), Ok(()));
+        assert_eq!(tokens.authorize(Some("Bearer readonly"), AdminScope::Write), Err(AdminDenied::Forbidden));
+        assert_eq!(tokens.authorize(Some("Bearer other"), AdminScope::Read), Err(AdminDenied::Unauthorized));
+        assert_eq!(tokens.authorize(Some("full"), AdminScope::Read), Err(AdminDenied::Unauthorized));
+        assert_eq!(tokens.authorize(None, AdminScope::Read), Err(AdminDenied::Unauthorized));
+    }
+}
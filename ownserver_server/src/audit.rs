@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use metrics::increment_counter;
+use ownserver_lib::{ClientId, EndpointId, Protocol, StreamId};
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+const AUDIT_LOG_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Connect {
+        client_id: ClientId,
+        token_subject: Option<String>,
+        peer_addr: SocketAddr,
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        labels: HashMap<String, String>,
+    },
+    Disconnect {
+        client_id: ClientId,
+        token_subject: Option<String>,
+        peer_addr: Option<SocketAddr>,
+    },
+    PortAllocated {
+        client_id: ClientId,
+        endpoint_id: EndpointId,
+        protocol: Protocol,
+        remote_port: u16,
+    },
+    StreamOpen {
+        client_id: ClientId,
+        stream_id: StreamId,
+        token_subject: Option<String>,
+        peer_addr: SocketAddr,
+    },
+    StreamClose {
+        client_id: ClientId,
+        stream_id: StreamId,
+        token_subject: Option<String>,
+        peer_addr: Option<SocketAddr>,
+    },
+}
+
+/// Events broadcast to embedders by `Store::subscribe`, the ones the audit log records.
+pub type ServerEvent = AuditEvent;
+
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    timestamp: String,
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+/// Writes audit events as newline-delimited JSON from a background task.
+/// Recording never waits on the writer; events are dropped when the buffer is full.
+#[derive(Debug)]
+pub struct AuditLog {
+    tx: mpsc::Sender<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Append to the file at `path`, or write to stdout when `path` is `-`.
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        if path == Path::new("-") {
+            return Ok(Self::from_writer(tokio::io::stdout()));
+        }
+
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self::from_writer(file))
+    }
+
+    pub fn from_writer<W: AsyncWrite + Unpin + Send + 'static>(writer: W) -> Self {
+        let (tx, mut rx) = mpsc::channel::<AuditRecord>(AUDIT_LOG_BUFFER);
+
+        tokio::spawn(async move {
+            let mut writer = BufWriter::new(writer);
+            while let Some(record) = rx.recv().await {
+                let mut record = Some(record);
+                // write everything queued so far, then flush once
+                while let Some(r) = record.take() {
+                    match serde_json::to_vec(&r) {
+                        Ok(mut line) => {
+                            line.push(b'\n');
+                            if let Err(e) = writer.write_all(&line).await {
+                                tracing::error!("failed to write audit log: {:?}", e);
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            tracing::warn!("failed to serialize audit event: {:?}", e);
+                        }
+                    }
+                    record = rx.try_recv().ok();
+                }
+                if let Err(e) = writer.flush().await {
+                    tracing::error!("failed to flush audit log: {:?}", e);
+                    return;
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let record = AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            event,
+        };
+        match self.tx.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(record)) => {
+                tracing::warn!(event = ?record.event, "audit log buffer is full, drop event");
+                increment_counter!("ownserver_server.audit.dropped");
+            }
+            Err(TrySendError::Closed(_)) => {
+                tracing::warn!("audit log writer has stopped");
+                increment_counter!("ownserver_server.audit.dropped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod audit_log_test {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn write_connect_event_as_json_line() -> Result<(), Box<dyn std::error::Error>> {
+        let (writer, reader) = tokio::io::duplex(4096);
+        let audit = AuditLog::from_writer(writer);
+
+        let client_id = ClientId::new();
+        audit.record(AuditEvent::Connect {
+            client_id,
+            token_subject: Some("alice".to_string()),
+            peer_addr: "192.0.2.1:40000".parse()?,
+            labels: HashMap::from([("plan".to_string(), "pro".to_string())]),
+        });
+
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await?;
+        assert!(line.ends_with('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(value["event"], "connect");
+        assert_eq!(value["client_id"], serde_json::to_value(client_id)?);
+        assert_eq!(value["token_subject"], "alice");
+        assert_eq!(value["peer_addr"], "192.0.2.1:40000");
+        assert_eq!(value["labels"]["plan"], "pro");
+        assert!(chrono::DateTime::parse_from_rfc3339(value["timestamp"].as_str().unwrap()).is_ok());
+        Ok(())
+    }
+}
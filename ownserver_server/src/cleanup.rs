@@ -0,0 +1,112 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::Store;
+
+/// Entries dropped by one cleanup after which the next one runs at the shortest interval.
+const BUSY_THRESHOLD: usize = 64;
+/// Delays are shifted randomly by up to this fraction so that servers started together don't clean up in lockstep.
+const JITTER: f64 = 0.1;
+
+/// Interval between `Store::cleanup` runs. It drops to `base / 8` while cleanups find many closed entries
+/// and grows up to `base * 4` while they find nothing.
+#[derive(Debug, Clone)]
+pub struct CleanupSchedule {
+    base: Duration,
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl CleanupSchedule {
+    pub fn new(base: Duration) -> Self {
+        Self {
+            base,
+            min: (base / 8).max(Duration::from_secs(1)).min(base),
+            max: base * 4,
+            current: base,
+        }
+    }
+
+    /// Adapt to the number of entries the last cleanup dropped.
+    pub fn record(&mut self, removed: usize) {
+        self.current = if removed >= BUSY_THRESHOLD {
+            self.min
+        } else if removed == 0 {
+            (self.current * 2).min(self.max)
+        } else {
+            self.base
+        };
+    }
+
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// `current` with jitter applied.
+    pub fn next_delay(&self, rng: &mut impl Rng) -> Duration {
+        self.current.mul_f64(rng.gen_range(1.0 - JITTER..=1.0 + JITTER))
+    }
+}
+
+/// Run `Store::cleanup` forever on `schedule`.
+pub async fn run_periodic_cleanup(store: Arc<Store>, mut schedule: CleanupSchedule) {
+    loop {
+        let delay = schedule.next_delay(&mut rand::thread_rng());
+        tokio::time::sleep(delay).await;
+        let removed = store.cleanup().await;
+        schedule.record(removed);
+        tracing::debug!(removed, "cleaned up store, next cleanup in about {:?}", schedule.current());
+    }
+}
+
+#[cfg(test)]
+mod cleanup_schedule_test {
+    use super::*;
+    use std::net::SocketAddr;
+    use ownserver_lib::ClientId;
+    use rand::{rngs::StdRng, SeedableRng};
+    use crate::{remote::stream::CloseCause, test_support::add_udp_stream};
+
+    #[tokio::test]
+    async fn clean_up_sooner_after_many_closed_streams() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        for port in 0..BUSY_THRESHOLD as u16 {
+            let peer_addr = SocketAddr::from(([127, 0, 0, 1], 40000 + port));
+            let stream_id = add_udp_stream(&store, ClientId::new(), peer_addr).await?;
+            store.disable_remote(stream_id, CloseCause::PeerClosed).await;
+        }
+
+        let mut schedule = CleanupSchedule::new(Duration::from_secs(15));
+        schedule.record(store.cleanup().await);
+        assert!(schedule.current() < Duration::from_secs(15));
+        assert_eq!(store.len_streams().await, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn lengthen_while_idle() {
+        let mut schedule = CleanupSchedule::new(Duration::from_secs(15));
+        schedule.record(0);
+        assert_eq!(schedule.current(), Duration::from_secs(30));
+        for _ in 0..10 {
+            schedule.record(0);
+        }
+        assert_eq!(schedule.current(), Duration::from_secs(60));
+
+        schedule.record(1);
+        assert_eq!(schedule.current(), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let schedule = CleanupSchedule::new(Duration::from_secs(10));
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let delay = schedule.next_delay(&mut rng);
+            assert!(delay >= Duration::from_secs(9) && delay <= Duration::from_secs(11));
+        }
+    }
+}
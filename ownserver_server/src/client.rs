@@ -1,21 +1,96 @@
-use std::sync::Arc;
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::{Arc, Mutex, Weak}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use bytes::BytesMut;
-use futures::{stream::SplitSink, StreamExt, SinkExt};
-use ownserver_lib::{ClientId, Endpoints, ControlPacketV2Codec, ControlPacketV2};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use metrics::{counter, increment_counter, Label};
+use ownserver_lib::{Capability, ClientId, CloseReason, StreamId, Endpoints, ControlPacketV2Codec, ControlPacketV2, HeartbeatTracker};
+use tokio::{sync::mpsc::{self, error::SendTimeoutError}, time::Instant};
 use tokio_util::{sync::CancellationToken, codec::{Encoder, Decoder}};
 use tracing::Instrument;
 use warp::ws::{Message, WebSocket};
 
-use crate::{Store, remote::stream::StreamMessage, ClientStreamError};
+use crate::{Store, audit::AuditEvent, health::ClientHealth, packet_filter::{FilterVerdict, PacketFilter, PacketKind}, quota::ByteQuota, remote::stream::{CloseCause, StreamMessage}, ClientStreamError};
 
+pub const DEFAULT_CLIENT_SEND_BUFFER: usize = 256;
+pub const DEFAULT_CLIENT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_DECODE_ERRORS: u32 = 10;
+pub const DEFAULT_DATA_SEND_RETRIES: u32 = 3;
+// pause before the first retry of a `Data` packet, doubled for each further one
+const DATA_SEND_BACKOFF: Duration = Duration::from_millis(50);
+// how long queued messages such as `Disconnect` may take to flush once the client is disabled
+const CLIENT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// messages queued for the client before `send_to_client` waits
+    pub send_buffer: usize,
+    /// how long `send_to_client` waits for room before giving up on the client
+    pub send_timeout: Duration,
+    /// bytes forwarded in either direction allowed per rolling window
+    pub quota: Option<ByteQuota>,
+    /// negotiated in the handshake
+    pub capabilities: Vec<Capability>,
+    /// the client is disconnected with `SessionExpired` this long after it connected
+    pub max_session_duration: Option<Duration>,
+    /// packets outside it are dropped, every packet is accepted when `None`
+    pub packet_filter: Option<PacketFilter>,
+    /// token subject, the next client of the same subject resumes the streams of this one
+    pub subject: Option<String>,
+    /// address the client connected from, reported by its `Disconnect` audit event
+    pub peer_addr: Option<SocketAddr>,
+    /// token labels, the ones named by `Store::with_metric_labels` also label its metrics
+    pub labels: HashMap<String, String>,
+    /// host players connect to, reported by `WhoAmIResp`
+    pub host: String,
+    /// undecodable packets in a row before the client is disconnected with `ProtocolViolation`
+    pub max_decode_errors: u32,
+    /// times a `Data` packet that found the queue full is retried before only its stream is closed.
+    /// each attempt waits up to `send_timeout`, with a growing pause in between
+    pub data_send_retries: u32,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            send_buffer: DEFAULT_CLIENT_SEND_BUFFER,
+            send_timeout: DEFAULT_CLIENT_SEND_TIMEOUT,
+            quota: None,
+            capabilities: Vec::new(),
+            max_session_duration: None,
+            packet_filter: None,
+            subject: None,
+            peer_addr: None,
+            labels: HashMap::new(),
+            host: String::new(),
+            max_decode_errors: DEFAULT_MAX_DECODE_ERRORS,
+            data_send_retries: DEFAULT_DATA_SEND_RETRIES,
+        }
+    }
+}
+
+type SharedQuota = Option<Arc<Mutex<ByteQuota>>>;
+
+// returns false when the client went over its quota
+fn record_bytes(quota: &SharedQuota, metric_labels: &[Label], n: usize) -> bool {
+    counter!("ownserver_server.store.bytes_total", n as u64, metric_labels.to_vec());
+    match quota {
+        Some(quota) => quota.lock().unwrap().add(n as u64),
+        None => true,
+    }
+}
 
 #[derive(Debug)]
 pub struct Client {
     pub client_id: ClientId,
     endpoints: Endpoints,
 
-    ws_tx: SplitSink<WebSocket, Message>,
+    sender: ClientSender,
+    heartbeat: HeartbeatTracker,
+    capabilities: Vec<Capability>,
+    connected_at: Instant,
+    subject: Option<String>,
+    peer_addr: Option<SocketAddr>,
+    labels: HashMap<String, String>,
     // ws_rx: SplitStream<WebSocket>,
     store: Arc<Store>,
     ct: CancellationToken,
@@ -24,12 +99,78 @@ pub struct Client {
 
 impl Client {
     pub fn new(store: Arc<Store>, client_id: ClientId, endpoints: Endpoints, ws: WebSocket) -> Self {
-        let (sink, mut stream) = ws.split();
+        let (sink, stream) = ws.split();
+        Self::with_transport(store, client_id, endpoints, sink, stream, ClientOptions::default())
+    }
+
+    /// Same as `new` but over any carrier that transfers one control packet per `Message`.
+    /// At most `options.send_buffer` messages are queued for the client. `send_to_client` waits for room
+    /// and gives up on the client once the queue stays full for `options.send_timeout`.
+    pub fn with_transport<Si, St, E>(
+        store: Arc<Store>,
+        client_id: ClientId,
+        endpoints: Endpoints,
+        sink: Si,
+        mut stream: St,
+        options: ClientOptions,
+    ) -> Self
+    where
+        Si: Sink<Message> + Send + 'static,
+        Si::Error: fmt::Debug,
+        St: Stream<Item = Result<Message, E>> + Unpin + Send + 'static,
+        E: Send + 'static,
+    {
+        let ClientOptions { send_buffer, send_timeout, quota, capabilities, max_session_duration, mut packet_filter, subject, peer_addr, labels, host, max_decode_errors, data_send_retries } = options;
+        let connected_at = Instant::now();
+        let expires_at = max_session_duration.map(|duration| (SystemTime::now() + duration).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        let quota: SharedQuota = quota.map(|q| Arc::new(Mutex::new(q)));
+        let metric_labels: Arc<[Label]> = std::iter::once(Label::new("client_id", client_id.to_string()))
+            .chain(store.metric_labels(&labels))
+            .collect();
         let token = CancellationToken::new();
+        let span = store.client_span(client_id);
+        let (tx, mut rx) = mpsc::channel::<Message>(send_buffer.max(1));
+
+        let ct = token.clone();
+        tokio::spawn(async move {
+            let mut sink = Box::pin(sink);
+            loop {
+                tokio::select! {
+                    _ = ct.cancelled() => {
+                        // deliver what is already queued, e.g. Disconnect, unless the client is stalled
+                        let drain = async {
+                            while let Ok(message) = rx.try_recv() {
+                                if sink.send(message).await.is_err() {
+                                    return;
+                                }
+                            }
+                            let _ = sink.close().await;
+                        };
+                        let _ = tokio::time::timeout(CLIENT_DRAIN_TIMEOUT, drain).await;
+                        break
+                    }
+                    message = rx.recv() => {
+                        let message = match message {
+                            Some(message) => message,
+                            None => break,
+                        };
+                        if let Err(e) = sink.send(message).await {
+                            tracing::debug!(cid = %client_id, error = ?e, "failed to write to client");
+                            break
+                        }
+                    }
+                }
+            }
+        }.instrument(tracing::info_span!(parent: &span, "client_write_loop")));
 
         let ct = token.clone();
         let store_ = store.clone();
+        let quota_ = quota.clone();
+        let metric_labels_ = metric_labels.clone();
+        let endpoints_ = endpoints.clone();
         tokio::spawn(async move {
+            // reset by every packet that decodes
+            let mut decode_errors = 0;
             loop {
                 tokio::select! {
                     _ = ct.cancelled() => {
@@ -41,9 +182,12 @@ impl Client {
                             Some(Ok(msg)) if (msg.is_binary() || msg.is_text()) && !msg.as_bytes().is_empty() => {
                                 msg.into_bytes()
                             }
-                            // handle close with reason
-                            Some(Ok(msg)) if msg.is_close() && !msg.as_bytes().is_empty() => {
-                                tracing::info!(cid = %client_id, close_reason=?msg, "client got close");
+                            Some(Ok(msg)) if msg.is_close() => {
+                                log_close(client_id, &CloseReason::from_close_frame(msg.close_frame()));
+                                break
+                            }
+                            None | Some(Err(_)) => {
+                                log_close(client_id, &CloseReason::Abnormal);
                                 break
                             }
                             _ => {
@@ -54,24 +198,59 @@ impl Client {
                 
                         let mut bytes = BytesMut::from(&message[..]);
                         let packet = match ControlPacketV2Codec::new().decode(&mut bytes) {
-                            Ok(Some(packet)) => packet,
-                            Ok(None) => {
-                                // TODO: should handle None case
-                                tracing::warn!(cid = %client_id, "failed to parse partial client message");
-                                continue;
+                            Ok(Some(packet)) => {
+                                decode_errors = 0;
+                                packet
                             }
-                            Err(e) => {
-                                tracing::warn!(cid = %client_id, error = ?e, "failed to parse client message");
+                            // every message carries a whole packet, a partial one is as broken as garbage
+                            result => {
+                                decode_errors += 1;
+                                match result {
+                                    Err(e) => tracing::warn!(cid = %client_id, error = ?e, decode_errors, "failed to parse client message"),
+                                    _ => tracing::warn!(cid = %client_id, decode_errors, "failed to parse partial client message"),
+                                }
+                                increment_counter!("ownserver_server.control.decode_errors");
+                                if decode_errors >= max_decode_errors {
+                                    tracing::warn!(cid = %client_id, decode_errors, "client keeps sending undecodable packets");
+                                    increment_counter!("ownserver_server.client.protocol_violation");
+                                    store_.close_client(client_id, CloseReason::ProtocolViolation).await;
+                                    break
+                                }
                                 continue;
                             }
                         };
 
-
-                
                         tracing::trace!(cid = %client_id, ?packet, "got control packet from client");
 
+                        if let Some(filter) = packet_filter.as_mut() {
+                            let kind = PacketKind::of(&packet);
+                            match filter.check(kind) {
+                                FilterVerdict::Accept => {}
+                                FilterVerdict::Drop => {
+                                    tracing::warn!(cid = %client_id, %kind, "dropped disallowed packet");
+                                    increment_counter!("ownserver_server.client.packet_rejected", "kind" => kind.as_str());
+                                    continue;
+                                }
+                                FilterVerdict::Disconnect => {
+                                    tracing::warn!(cid = %client_id, %kind, violations = filter.violations(), "client keeps sending disallowed packets");
+                                    increment_counter!("ownserver_server.client.packet_rejected", "kind" => kind.as_str());
+                                    increment_counter!("ownserver_server.client.protocol_violation");
+                                    store_.close_client(client_id, CloseReason::ProtocolViolation).await;
+                                    break
+                                }
+                            }
+                        }
+
                         let (stream_id, message) = match packet {
                             ControlPacketV2::Data(stream_id, data) => {
+                                if !record_bytes(&quota_, &metric_labels_, data.len()) {
+                                    tracing::info!(cid = %client_id, "client exceeded traffic quota");
+                                    increment_counter!("ownserver_server.client.quota_exceeded");
+                                    store_.close_client(client_id, CloseReason::QuotaExceeded).await;
+                                    break
+                                }
+                                // clients without InitAck acknowledge with their first data
+                                store_.ack_remote(stream_id);
                                 tracing::trace!(cid = %client_id, sid = %stream_id, "forwarding to stream: {}", data.len());
                                 (stream_id, StreamMessage::Data(data))
                             }
@@ -83,12 +262,53 @@ impl Client {
                                 tracing::trace!(cid = %client_id, "pong");
                                 continue;
                             }
-                            ControlPacketV2::Init(stream_id, endpoint_id) => {
+                            ControlPacketV2::Heartbeat(nonce) => {
+                                tracing::trace!(cid = %client_id, nonce, "heartbeat");
+                                if let Err(e) = store_.send_to_client(client_id, ControlPacketV2::HeartbeatAck(nonce)).await {
+                                    tracing::debug!(cid = %client_id, error = ?e, "failed to answer heartbeat");
+                                }
+                                continue;
+                            }
+                            ControlPacketV2::HeartbeatAck(nonce) => {
+                                store_.ack_heartbeat(client_id, nonce).await;
+                                continue;
+                            }
+                            ControlPacketV2::WhoAmI => {
+                                let packet = ControlPacketV2::WhoAmIResp { client_id, host: host.clone(), endpoints: endpoints_.clone(), expires_at };
+                                if let Err(e) = store_.send_to_client(client_id, packet).await {
+                                    tracing::debug!(cid = %client_id, error = ?e, "failed to answer who am i");
+                                }
+                                continue;
+                            }
+                            ControlPacketV2::WhoAmIResp { .. } => {
+                                tracing::error!(cid = %client_id, "invalid protocol ControlPacketV2::WhoAmIResp");
+                                continue;
+                            }
+                            ControlPacketV2::InitAck(stream_id) => {
+                                tracing::trace!(cid = %client_id, sid = %stream_id, "tunnel says: stream is ready");
+                                store_.ack_remote(stream_id);
+                                continue;
+                            }
+                            ControlPacketV2::WindowUpdate(stream_id, n) => {
+                                tracing::trace!(cid = %client_id, sid = %stream_id, "window update: {}", n);
+                                store_.update_window(stream_id, n).await;
+                                continue;
+                            }
+                            ControlPacketV2::Init(stream_id, endpoint_id) | ControlPacketV2::InitHttp(stream_id, endpoint_id, _) => {
                                 tracing::error!(cid = %client_id, sid = %stream_id, eid = %endpoint_id, "invalid protocol ControlPacketV2::Init");
                                 continue;
                             }
                             ControlPacketV2::End(stream_id) => {
-                                tracing::error!(cid = %client_id, sid = %stream_id, "invalid protocol ControlPacketV2::End");
+                                // the local connection is gone, close the remote socket once queued data is written
+                                tracing::debug!(cid = %client_id, sid = %stream_id, "tunnel says: local connection closed");
+                                (stream_id, StreamMessage::TunnelRefused)
+                            }
+                            ControlPacketV2::ShutdownWrite(stream_id) => {
+                                tracing::debug!(cid = %client_id, sid = %stream_id, "tunnel says: local service is done sending");
+                                (stream_id, StreamMessage::ShutdownWrite)
+                            }
+                            ControlPacketV2::Disconnect(reason) => {
+                                tracing::error!(cid = %client_id, %reason, "invalid protocol ControlPacketV2::Disconnect");
                                 continue;
                             }
                         };
@@ -97,15 +317,31 @@ impl Client {
                         if let Err(e) = store_.send_to_remote(stream_id, message).await {
                             tracing::debug!(cid = %client_id, sid = %stream_id, error = ?e, "Failed to send to remote stream");
 
-                            store_.disable_remote(stream_id).await;
+                            store_.disable_remote(stream_id, CloseCause::Error).await;
                         }
                     }
                 }
             }
             store_.disable_client(client_id).await;
-        }.instrument(tracing::info_span!("client_read_loop")));
+        }.instrument(tracing::info_span!(parent: &span, "client_read_loop")));
 
-        Self { client_id, endpoints, ws_tx: sink, store, ct: token, disabled: false }
+        if let Some(duration) = max_session_duration {
+            let ct = token.clone();
+            let store_ = store.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = ct.cancelled() => {}
+                    _ = tokio::time::sleep_until(connected_at + duration) => {
+                        tracing::info!(cid = %client_id, "session reached the maximum duration {:?}", duration);
+                        increment_counter!("ownserver_server.client.session_expired");
+                        store_.close_client(client_id, CloseReason::SessionExpired).await;
+                    }
+                }
+            });
+        }
+
+        let sender = ClientSender { client_id, ws_tx: tx, send_timeout, data_send_retries, quota, metric_labels, health: Default::default() };
+        Self { client_id, endpoints, sender, heartbeat: HeartbeatTracker::default(), capabilities, connected_at, subject, peer_addr, labels, store, ct: token, disabled: false }
     }
 
     // pub async fn send_to_stream(&self, stream_id: StreamId, message: StreamMessage) -> Result<(), Box<dyn std::error::Error>> {
@@ -114,33 +350,90 @@ impl Client {
     // }
 
     pub async fn send_to_client(&mut self, packet: ControlPacketV2) -> Result<(), ClientStreamError> {
-        let mut codec = ControlPacketV2Codec::new();
-        let mut bytes = BytesMut::new();
-        if let Err(e) = codec.encode(packet, &mut bytes) {
-            tracing::warn!(cid = %self.client_id, error = ?e, "failed to encode message");
-            return Err(ClientStreamError::ClientError(format!("packet is invalid {}", e)))
+        match self.sender.send(packet).await {
+            Ok(()) => Ok(()),
+            Err(failure) => Err(self.handle_send_failure(failure).await),
+        }
+    }
+
+    /// Queues packets for this client without borrowing it, see `ClientSender`.
+    pub fn sender(&self) -> ClientSender {
+        self.sender.clone()
+    }
+
+    /// Close or disable the client as `failure` requires.
+    pub async fn handle_send_failure(&mut self, failure: SendFailure) -> ClientStreamError {
+        match failure {
+            SendFailure::QuotaExceeded => self.close(CloseReason::QuotaExceeded).await,
+            SendFailure::Unresponsive(_) => self.disable().await,
+            SendFailure::Congested(_) | SendFailure::Invalid(_) => {}
         }
+        failure.into_error()
+    }
 
-        if let Err(e) =  self.ws_tx.send(Message::binary(bytes.to_vec())).await {
-            tracing::debug!(cid = %self.client_id, error = ?e, "client disconnected: aborting");
-            self.disable().await;
-            return Err(ClientStreamError::ClientError(format!("failed to communicate with client {:?}", e)))
+    /// Tell the client why it is disconnected, then disable it.
+    pub async fn close(&mut self, reason: CloseReason) {
+        let cause = match reason {
+            CloseReason::QuotaExceeded => CloseCause::QuotaExceeded,
+            _ => CloseCause::ClientDisconnected,
+        };
+        let mut bytes = BytesMut::new();
+        match ControlPacketV2Codec::new().encode(ControlPacketV2::Disconnect(reason), &mut bytes) {
+            Ok(()) => {
+                if let Err(e) = self.sender.ws_tx.try_send(Message::binary(bytes.to_vec())) {
+                    tracing::debug!(cid = %self.client_id, error = ?e, "failed to queue disconnect");
+                }
+            }
+            Err(e) => tracing::warn!(cid = %self.client_id, error = ?e, "failed to encode message"),
         }
-        Ok(())
+        self.disable_for(cause).await;
     }
 
     pub async fn disable(&mut self) {
+        self.disable_for(CloseCause::ClientDisconnected).await
+    }
+
+    // `cause` is counted for the streams closed with the client
+    async fn disable_for(&mut self, cause: CloseCause) {
+        if !self.disabled {
+            self.store.audit(AuditEvent::Disconnect { client_id: self.client_id, token_subject: self.subject.clone(), peer_addr: self.peer_addr });
+        }
         tracing::info!(cid = %self.client_id, "client was disabled");
         self.ct.cancel();
         self.disabled = true;
 
-        self.store.disable_remote_by_client(self.client_id).await;
+        self.store.detach_remote_by_client(self.client_id, self.subject.as_deref(), cause).await;
     }
 
     pub fn disabled(&self) -> bool {
         self.disabled
     }
 
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    pub fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    pub fn health(&self) -> &ClientHealth {
+        &self.sender.health
+    }
+
+    /// `client_id` and the token labels picked by the store
+    pub fn metric_labels(&self) -> &[Label] {
+        &self.sender.metric_labels
+    }
+
+    pub fn heartbeat_mut(&mut self) -> &mut HeartbeatTracker {
+        &mut self.heartbeat
+    }
+
     pub fn cancellation_token(&self) -> CancellationToken {
         self.ct.clone()
     }
@@ -149,4 +442,538 @@ impl Client {
         &self.endpoints
     }
 
-}
\ No newline at end of file
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+}
+
+/// Why `ClientSender::send` failed, `Client::handle_send_failure` acts on it.
+#[derive(Debug)]
+pub enum SendFailure {
+    /// the data of the stream was dropped, the client is kept
+    Congested(StreamId),
+    /// the client goes away with `CloseReason::QuotaExceeded`
+    QuotaExceeded,
+    Invalid(String),
+    /// the send buffer stayed full or the client went away, the client is disabled
+    Unresponsive(ClientStreamError),
+}
+
+impl SendFailure {
+    /// `true` when the client has to be closed or disabled.
+    pub fn ends_client(&self) -> bool {
+        matches!(self, SendFailure::QuotaExceeded | SendFailure::Unresponsive(_))
+    }
+
+    pub fn into_error(self) -> ClientStreamError {
+        match self {
+            SendFailure::Congested(stream_id) => ClientStreamError::Congested(stream_id),
+            SendFailure::QuotaExceeded => ClientStreamError::ClientError("client exceeded traffic quota".to_string()),
+            SendFailure::Invalid(e) => ClientStreamError::ClientError(format!("packet is invalid {}", e)),
+            SendFailure::Unresponsive(e) => e,
+        }
+    }
+}
+
+/// Queues packets for a client. The store clones it out of its client map,
+/// so that waiting for a slow client does not hold up the others.
+#[derive(Debug, Clone)]
+pub struct ClientSender {
+    client_id: ClientId,
+    ws_tx: mpsc::Sender<Message>,
+    send_timeout: Duration,
+    data_send_retries: u32,
+    quota: SharedQuota,
+    metric_labels: Arc<[Label]>,
+    health: Arc<ClientHealth>,
+}
+
+impl ClientSender {
+    /// Wait up to `send_timeout` for room in the send buffer. Send results are recorded in the client's health.
+    pub async fn send(&self, packet: ControlPacketV2) -> Result<(), SendFailure> {
+        let result = self.send_packet(packet).await;
+        match result {
+            Ok(()) => self.health.record_success(),
+            Err(_) => self.health.record_failure(),
+        }
+        result
+    }
+
+    async fn send_packet(&self, packet: ControlPacketV2) -> Result<(), SendFailure> {
+        if let ControlPacketV2::Data(_, ref data) = packet {
+            if !record_bytes(&self.quota, &self.metric_labels, data.len()) {
+                tracing::info!(cid = %self.client_id, "client exceeded traffic quota");
+                increment_counter!("ownserver_server.client.quota_exceeded");
+                return Err(SendFailure::QuotaExceeded)
+            }
+        }
+
+        let data_stream_id = match packet {
+            ControlPacketV2::Data(stream_id, _) => Some(stream_id),
+            _ => None,
+        };
+        let mut codec = ControlPacketV2Codec::new();
+        let mut bytes = BytesMut::new();
+        if let Err(e) = codec.encode(packet, &mut bytes) {
+            tracing::warn!(cid = %self.client_id, error = ?e, "failed to encode message");
+            return Err(SendFailure::Invalid(e.to_string()))
+        }
+        if let Some(stream_id) = data_stream_id {
+            return self.send_data(stream_id, Message::binary(bytes.to_vec())).await;
+        }
+
+        match self.ws_tx.send_timeout(Message::binary(bytes.to_vec()), self.send_timeout).await {
+            Ok(()) => Ok(()),
+            Err(SendTimeoutError::Timeout(_)) => {
+                tracing::warn!(cid = %self.client_id, "client send buffer stayed full for {:?}: aborting", self.send_timeout);
+                Err(SendFailure::Unresponsive(ClientStreamError::ClientError("client does not keep up with sent data".to_string())))
+            }
+            Err(SendTimeoutError::Closed(_)) => {
+                tracing::debug!(cid = %self.client_id, "client disconnected: aborting");
+                Err(SendFailure::Unresponsive(ClientStreamError::ClientError("failed to communicate with client".to_string())))
+            }
+        }
+    }
+
+    // a congested queue costs the stream its data, not the client its other streams
+    async fn send_data(&self, stream_id: StreamId, mut message: Message) -> Result<(), SendFailure> {
+        let attempts = self.data_send_retries + 1;
+        let mut backoff = DATA_SEND_BACKOFF;
+        for attempt in 1..=attempts {
+            match self.ws_tx.send_timeout(message, self.send_timeout).await {
+                Ok(()) => return Ok(()),
+                Err(SendTimeoutError::Timeout(retained)) => {
+                    message = retained;
+                    if attempt < attempts {
+                        tracing::debug!(cid = %self.client_id, sid = %stream_id, attempt, "client send buffer is full, retry data in {:?}", backoff);
+                        increment_counter!("ownserver_server.client.data_send_retry");
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                }
+                Err(SendTimeoutError::Closed(_)) => {
+                    tracing::debug!(cid = %self.client_id, "client disconnected: aborting");
+                    return Err(SendFailure::Unresponsive(ClientStreamError::ClientError("failed to communicate with client".to_string())))
+                }
+            }
+        }
+        tracing::warn!(cid = %self.client_id, sid = %stream_id, attempts, "client send buffer stayed full: closing stream");
+        increment_counter!("ownserver_server.client.stream_congested");
+        Err(SendFailure::Congested(stream_id))
+    }
+}
+
+/// Refers to one client in the store, returned by `Store::add_client`.
+/// The handle does not keep the store alive.
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    client_id: ClientId,
+    endpoints: Endpoints,
+    store: Weak<Store>,
+}
+
+impl ClientHandle {
+    pub(crate) fn new(client: &Client) -> Self {
+        Self {
+            client_id: client.client_id,
+            endpoints: client.endpoints.clone(),
+            store: Arc::downgrade(&client.store),
+        }
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Remote port of the first endpoint, `None` when the client has no endpoints.
+    pub fn remote_port(&self) -> Option<u16> {
+        self.endpoints.first().map(|e| e.remote_port)
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// Close the tunnel with `CloseReason::Normal`. Does nothing once the store is dropped.
+    pub async fn disconnect(&self) {
+        if let Some(store) = self.store.upgrade() {
+            store.close_client(self.client_id, CloseReason::Normal).await;
+        }
+    }
+}
+
+fn log_close(client_id: ClientId, reason: &CloseReason) {
+    match reason {
+        CloseReason::Normal => tracing::info!(cid = %client_id, %reason, "client closed the connection"),
+        CloseReason::Abnormal => tracing::warn!(cid = %client_id, %reason, "client connection dropped abnormally"),
+        _ => tracing::warn!(cid = %client_id, %reason, "client closed the connection with code"),
+    }
+}
+
+#[cfg(test)]
+mod client_backpressure_test {
+    use super::*;
+    use std::convert::Infallible;
+    use tokio::time::Instant;
+    use crate::test_support::{client, client_over, client_with, next_packet};
+
+    fn stalled_client(store: Arc<Store>, send_timeout: Duration) -> Client {
+        // accepts a single message and never becomes ready again
+        let sink = futures::sink::unfold((), |_, _: Message| futures::future::pending::<Result<(), Infallible>>());
+        let options = ClientOptions { send_buffer: 1, send_timeout, ..Default::default() };
+        client_over(store, Vec::new(), sink, options)
+    }
+
+    #[tokio::test]
+    async fn drop_client_when_send_buffer_stays_full() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let client = stalled_client(store.clone(), Duration::from_millis(200));
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        // one message is stuck in the sink, one waits in the buffer
+        store.send_to_client(client_id, ControlPacketV2::Ping).await?;
+        tokio::task::yield_now().await;
+        store.send_to_client(client_id, ControlPacketV2::Ping).await?;
+
+        let start = Instant::now();
+        let result = store.send_to_client(client_id, ControlPacketV2::Ping).await;
+        assert!(result.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_serving_other_clients_while_one_is_stalled() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let stalled = stalled_client(store.clone(), Duration::from_secs(2));
+        let stalled_id = stalled.client_id;
+        store.add_client(stalled).await;
+        let (other, mut sent) = client(store.clone());
+        let other_id = other.client_id;
+        store.add_client(other).await;
+
+        store.send_to_client(stalled_id, ControlPacketV2::Ping).await?;
+        tokio::task::yield_now().await;
+        store.send_to_client(stalled_id, ControlPacketV2::Ping).await?;
+        // waits for room in the stalled client's buffer
+        let waiting = tokio::spawn({
+            let store = store.clone();
+            async move { store.send_to_client(stalled_id, ControlPacketV2::Ping).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        store.send_to_client(other_id, ControlPacketV2::Ping).await?;
+        assert!(sent.next().await.is_some());
+        store.client_statuses().await;
+        assert!(start.elapsed() < Duration::from_millis(500), "took {:?}", start.elapsed());
+        assert!(!waiting.is_finished());
+        waiting.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disconnect_client_when_quota_is_exceeded() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let options = ClientOptions {
+            quota: Some(ByteQuota::new(10, Duration::from_secs(60))),
+            ..Default::default()
+        };
+        let (client, mut sent) = client_with(store.clone(), Vec::new(), options);
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let stream_id = ownserver_lib::StreamId::new();
+        store.send_to_client(client_id, ControlPacketV2::Data(stream_id, vec![0; 8])).await?;
+        let result = store.send_to_client(client_id, ControlPacketV2::Data(stream_id, vec![0; 8])).await;
+        assert!(result.is_err());
+
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Data(stream_id, vec![0; 8]));
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Disconnect(CloseReason::QuotaExceeded));
+        assert!(tokio::time::timeout(Duration::from_secs(2), sent.next()).await?.is_none());
+
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 0);
+        Ok(())
+    }
+
+    // the first message blocks the sink for longer than one attempt but shorter than all of them
+    fn momentarily_blocked_client(store: Arc<Store>, data_send_retries: u32) -> (Client, futures::channel::mpsc::UnboundedReceiver<Message>) {
+        let (tx, sent) = futures::channel::mpsc::unbounded::<Message>();
+        let sink = futures::sink::unfold((tx, true), |(tx, first), message: Message| async move {
+            if first {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+            }
+            tx.unbounded_send(message).expect("receiver dropped");
+            Ok::<_, Infallible>((tx, false))
+        });
+        let options = ClientOptions { send_buffer: 1, send_timeout: Duration::from_millis(100), data_send_retries, ..Default::default() };
+        (client_over(store, Vec::new(), sink, options), sent)
+    }
+
+    #[tokio::test]
+    async fn retry_data_while_client_is_momentarily_blocked() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let (client, mut sent) = momentarily_blocked_client(store.clone(), 3);
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let stream_id = StreamId::new();
+        for i in 0..4u8 {
+            store.send_to_client(client_id, ControlPacketV2::Data(stream_id, vec![i; 16])).await?;
+            tokio::task::yield_now().await;
+        }
+
+        for i in 0..4u8 {
+            assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Data(stream_id, vec![i; 16]));
+        }
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_stream_of_momentarily_blocked_client_without_retries() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let (client, _sent) = momentarily_blocked_client(store.clone(), 0);
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let stream_id = StreamId::new();
+        let mut results = Vec::new();
+        for i in 0..4u8 {
+            results.push(store.send_to_client(client_id, ControlPacketV2::Data(stream_id, vec![i; 16])).await);
+            tokio::task::yield_now().await;
+        }
+        assert!(results.iter().any(|result| matches!(result, Err(ClientStreamError::Congested(sid)) if *sid == stream_id)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_client_when_data_retries_are_exhausted() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let client = stalled_client(store.clone(), Duration::from_millis(200));
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let stream_id = StreamId::new();
+        store.send_to_client(client_id, ControlPacketV2::Data(stream_id, vec![0; 8])).await?;
+        tokio::task::yield_now().await;
+        store.send_to_client(client_id, ControlPacketV2::Data(stream_id, vec![0; 8])).await?;
+
+        let result = store.send_to_client(client_id, ControlPacketV2::Data(stream_id, vec![0; 8])).await;
+        assert!(matches!(result, Err(ClientStreamError::Congested(sid)) if sid == stream_id));
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod client_heartbeat_test {
+    use super::*;
+    use crate::test_support::{client_with, client_with_incoming, encode, next_packet};
+
+    #[tokio::test]
+    async fn answer_heartbeat_and_measure_rtt() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let options = ClientOptions { capabilities: vec![Capability::Heartbeat], ..Default::default() };
+        let (client, incoming, mut sent) = client_with_incoming(store.clone(), Vec::new(), options);
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        incoming.unbounded_send(encode(ControlPacketV2::Heartbeat(42)))?;
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::HeartbeatAck(42));
+
+        store.ping_clients().await;
+        let nonce = match next_packet(&mut sent).await? {
+            ControlPacketV2::Heartbeat(nonce) => nonce,
+            packet => panic!("unexpected packet {:?}", packet),
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let rtt = store.ack_heartbeat(client_id, nonce).await.expect("heartbeat is not outstanding");
+        assert!(rtt >= Duration::from_millis(20));
+        assert_eq!(store.ack_heartbeat(client_id, nonce).await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disable_client_that_misses_heartbeats() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let options = ClientOptions { capabilities: vec![Capability::Heartbeat], ..Default::default() };
+        let (client, _sent) = client_with(store.clone(), Vec::new(), options);
+        store.add_client(client).await;
+
+        for _ in 0..ownserver_lib::MAX_MISSED_HEARTBEATS + 1 {
+            store.ping_clients().await;
+        }
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod client_session_duration_test {
+    use super::*;
+    use crate::test_support::{client_with, next_packet};
+
+    #[tokio::test]
+    async fn disconnect_when_session_expires() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let options = ClientOptions { max_session_duration: Some(Duration::from_millis(300)), ..Default::default() };
+        let (client, mut sent) = client_with(store.clone(), Vec::new(), options);
+        let client_id = client.client_id;
+        let connected_at = client.connected_at();
+        store.add_client(client).await;
+
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Disconnect(CloseReason::SessionExpired));
+        let elapsed = connected_at.elapsed();
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_millis(1000));
+
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 0);
+        assert!(store.send_to_client(client_id, ControlPacketV2::Ping).await.is_err());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod client_handle_test {
+    use super::*;
+    use ownserver_lib::{Endpoint, EndpointId, Protocol};
+    use crate::test_support::{client_with, next_packet};
+
+    #[tokio::test]
+    async fn disconnect_client_by_handle() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let endpoints = vec![Endpoint {
+            id: EndpointId::new(),
+            protocol: Protocol::TCP,
+            local_port: 25565,
+            remote_port: 10000,
+        }];
+        let (client, mut sent) = client_with(store.clone(), endpoints, Default::default());
+        let client_id = client.client_id;
+        let handle = store.add_client(client).await;
+        assert_eq!(handle.client_id(), client_id);
+        assert_eq!(handle.remote_port(), Some(10000));
+
+        handle.disconnect().await;
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Disconnect(CloseReason::Normal));
+
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod client_packet_filter_test {
+    use super::*;
+    use crate::test_support::{client_with_incoming, encode, next_packet};
+
+    #[tokio::test]
+    async fn drop_disallowed_packets_then_disconnect() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let options = ClientOptions {
+            packet_filter: Some(PacketFilter::new([PacketKind::Data, PacketKind::HeartbeatAck], 2)),
+            ..Default::default()
+        };
+        let (client, incoming, mut sent) = client_with_incoming(store.clone(), Vec::new(), options);
+        store.add_client(client).await;
+
+        // a heartbeat would be answered if it were allowed
+        incoming.unbounded_send(encode(ControlPacketV2::Heartbeat(1)))?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sent.try_next().is_err(), "disallowed packet was handled");
+
+        incoming.unbounded_send(encode(ControlPacketV2::Heartbeat(2)))?;
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Disconnect(CloseReason::ProtocolViolation));
+
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod client_who_am_i_test {
+    use super::*;
+    use rand::thread_rng;
+    use crate::test_support::{client_with_incoming, encode, get_endpoint_claims_single, next_packet};
+
+    #[tokio::test]
+    async fn answer_who_am_i_with_assignment() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::new(10000..10010));
+        let endpoints = store.allocate_endpoints_for(&mut thread_rng(), None, None, get_endpoint_claims_single()).await?;
+        let options = ClientOptions {
+            max_session_duration: Some(Duration::from_secs(3600)),
+            host: "foo.bar.local".to_string(),
+            ..Default::default()
+        };
+        let (client, incoming, mut sent) = client_with_incoming(store.clone(), endpoints.clone(), options);
+        let client_id = client.client_id;
+        store.add_client(client).await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        incoming.unbounded_send(encode(ControlPacketV2::WhoAmI))?;
+        match next_packet(&mut sent).await? {
+            ControlPacketV2::WhoAmIResp { client_id: cid, host, endpoints: assigned, expires_at } => {
+                assert_eq!(cid, client_id);
+                assert_eq!(host, "foo.bar.local");
+                assert_eq!(assigned, endpoints);
+                assert!((10000..10010).contains(&assigned[0].remote_port));
+                let expires_at = expires_at.expect("session has a maximum duration");
+                assert!(expires_at >= now + 3599 && expires_at <= now + 3601);
+            }
+            packet => panic!("expected who am i response, got {:?}", packet),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod client_decode_error_test {
+    use super::*;
+    use std::convert::Infallible;
+    use crate::test_support::{client_with_incoming, encode, next_packet};
+
+    fn garbage() -> Result<Message, Infallible> {
+        // 0xc1 is never used by msgpack
+        Ok(Message::binary(b"\xc1garbage".to_vec()))
+    }
+
+    #[tokio::test]
+    async fn drop_garbage_then_disconnect_after_consecutive_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let options = ClientOptions { capabilities: vec![Capability::Heartbeat], max_decode_errors: 3, ..Default::default() };
+        let (client, incoming, mut sent) = client_with_incoming(store.clone(), Vec::new(), options);
+        store.add_client(client).await;
+
+        // a packet that decodes is still handled and resets the count
+        incoming.unbounded_send(garbage())?;
+        incoming.unbounded_send(garbage())?;
+        incoming.unbounded_send(encode(ControlPacketV2::Heartbeat(1)))?;
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::HeartbeatAck(1));
+
+        incoming.unbounded_send(garbage())?;
+        incoming.unbounded_send(garbage())?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sent.try_next().is_err(), "client was disconnected before the threshold");
+        assert_eq!(store.len_clients().await, 1);
+
+        incoming.unbounded_send(garbage())?;
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Disconnect(CloseReason::ProtocolViolation));
+
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use flate2::{write::{GzEncoder, ZlibEncoder}, Compression};
+use metrics::{counter, gauge};
+use serde::Serialize;
+use warp::{http::{header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, VARY}, StatusCode}, hyper::Body, reply::Response, Filter, Rejection, Reply};
+
+/// Bodies shorter than this are sent as they are, compressing them saves next to nothing.
+pub const MIN_COMPRESS_LEN: usize = 1024;
+
+// totals over every coding since the server started, for the ratio
+static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+static BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+
+/// How well response compression did so far, reported by `/admin/status`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CompressionStats {
+    /// body bytes before compression
+    pub bytes_in: u64,
+    /// body bytes sent after compression
+    pub bytes_out: u64,
+    /// `bytes_out / bytes_in`, lower is better. `None` until something was compressed.
+    pub ratio: Option<f64>,
+}
+
+/// Totals of every response compressed so far.
+pub fn stats() -> CompressionStats {
+    let bytes_in = BYTES_IN.load(Ordering::Relaxed);
+    let bytes_out = BYTES_OUT.load(Ordering::Relaxed);
+    CompressionStats { bytes_in, bytes_out, ratio: ratio(bytes_in, bytes_out) }
+}
+
+fn ratio(bytes_in: u64, bytes_out: u64) -> Option<f64> {
+    if bytes_in == 0 {
+        return None;
+    }
+    Some(bytes_out as f64 / bytes_in as f64)
+}
+
+fn record(coding: Coding, bytes_in: usize, bytes_out: usize) {
+    counter!("ownserver_server.compression.bytes_in", bytes_in as u64, "coding" => coding.as_str());
+    counter!("ownserver_server.compression.bytes_out", bytes_out as u64, "coding" => coding.as_str());
+    let total_in = BYTES_IN.fetch_add(bytes_in as u64, Ordering::Relaxed) + bytes_in as u64;
+    let total_out = BYTES_OUT.fetch_add(bytes_out as u64, Ordering::Relaxed) + bytes_out as u64;
+    if let Some(ratio) = ratio(total_in, total_out) {
+        gauge!("ownserver_server.compression.ratio", ratio);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    /// zlib stream as http means by `deflate`
+    Deflate,
+}
+
+impl Coding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    /// Coding to answer a request with `accept_encoding`, gzip before deflate. `None` when neither is accepted.
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        // coding names with whether they were refused by `q=0`
+        let codings: Vec<(&str, bool)> = accept_encoding
+            .split(',')
+            .filter_map(|coding| {
+                let mut params = coding.split(';');
+                let name = params.next()?.trim();
+                let refused = params.any(|param| match param.trim().strip_prefix("q=") {
+                    Some(q) => q.trim().parse::<f32>().map(|q| q == 0.0).unwrap_or(false),
+                    None => false,
+                });
+                Some((name, refused))
+            })
+            .collect();
+        [Coding::Gzip, Coding::Deflate].into_iter().find(|coding| {
+            match codings.iter().find(|(name, _)| name.eq_ignore_ascii_case(coding.as_str())) {
+                Some(&(_, refused)) => !refused,
+                None => codings.iter().any(|&(name, refused)| name == "*" && !refused),
+            }
+        })
+    }
+
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Coding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Coding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compress replies of `filter` when the request sends `Accept-Encoding` with gzip or deflate
+/// and the body is at least `MIN_COMPRESS_LEN` bytes.
+pub fn compressed<F, T>(filter: F) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply + Send,
+{
+    warp::header::optional::<String>("accept-encoding")
+        .and(filter)
+        .and_then(|accept_encoding: Option<String>, reply: T| async move {
+            let coding = accept_encoding.as_deref().and_then(Coding::negotiate);
+            Ok::<_, Rejection>(compress(reply.into_response(), coding).await)
+        })
+}
+
+async fn compress(response: Response, coding: Option<Coding>) -> Response {
+    let coding = match coding {
+        Some(coding) if !response.headers().contains_key(CONTENT_ENCODING) => coding,
+        _ => return response,
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let data = match warp::hyper::body::to_bytes(body).await {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::warn!("failed to read response body to compress {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    if data.len() < MIN_COMPRESS_LEN {
+        return Response::from_parts(parts, Body::from(data));
+    }
+
+    match coding.encode(&data) {
+        Ok(encoded) => {
+            record(coding, data.len(), encoded.len());
+            parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(coding.as_str()));
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(encoded))
+        }
+        Err(e) => {
+            tracing::warn!("failed to compress response {:?}", e);
+            Response::from_parts(parts, Body::from(data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod compression_test {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use metrics_util::debugging::{DebugValue, Snapshotter};
+    use rand::{rngs::SmallRng, RngCore, SeedableRng};
+    use crate::test_support::install_debugging_recorder;
+
+    fn body(len: usize) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        warp::path("status").map(move || "a".repeat(len))
+    }
+
+    fn counter_value(name: &str, coding: &str) -> u64 {
+        let snapshot = match Snapshotter::current_thread_snapshot() {
+            Some(snapshot) => snapshot,
+            None => return 0,
+        };
+        snapshot.into_vec().into_iter()
+            .filter(|(key, ..)| key.key().name() == name)
+            .filter(|(key, ..)| key.key().labels().any(|label| label.key() == "coding" && label.value() == coding))
+            .map(|(.., value)| match value {
+                DebugValue::Counter(n) => n,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Bytes in and out `compressed` counted for `coding` while replying `body`.
+    async fn count_compression(body: Vec<u8>, coding: &str) -> (u64, u64) {
+        let filter = compressed(warp::path("status").map(move || body.clone()));
+        let before = (counter_value("ownserver_server.compression.bytes_in", coding), counter_value("ownserver_server.compression.bytes_out", coding));
+        let response = warp::test::request().path("/status").header("accept-encoding", coding).reply(&filter).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], coding);
+        (
+            counter_value("ownserver_server.compression.bytes_in", coding) - before.0,
+            counter_value("ownserver_server.compression.bytes_out", coding) - before.1,
+        )
+    }
+
+    #[tokio::test]
+    async fn compress_large_body_only_when_accepted() -> Result<(), Box<dyn std::error::Error>> {
+        let filter = compressed(body(4096));
+
+        let response = warp::test::request().path("/status").header("accept-encoding", "gzip, deflate").reply(&filter).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert!(response.body().len() < 4096);
+        let mut decoded = String::new();
+        GzDecoder::new(&response.body()[..]).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, "a".repeat(4096));
+
+        let response = warp::test::request().path("/status").header("accept-encoding", "deflate").reply(&filter).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], "deflate");
+        let mut decoded = String::new();
+        ZlibDecoder::new(&response.body()[..]).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, "a".repeat(4096));
+
+        let response = warp::test::request().path("/status").reply(&filter).await;
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(response.body().len(), 4096);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_small_body_uncompressed() {
+        let filter = compressed(body(100));
+        let response = warp::test::request().path("/status").header("accept-encoding", "gzip").reply(&filter).await;
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(response.body().len(), 100);
+    }
+
+    #[tokio::test]
+    async fn count_bytes_in_and_out() {
+        // the recorder keeps metrics per thread, the current thread runtime runs every task here
+        install_debugging_recorder();
+
+        let (bytes_in, bytes_out) = count_compression(b"a".repeat(4096), "gzip").await;
+        assert_eq!(bytes_in, 4096);
+        assert!(bytes_out < 4096 / 10, "{} bytes out of text", bytes_out);
+
+        let mut noise = vec![0; 4096];
+        SmallRng::seed_from_u64(335).fill_bytes(&mut noise);
+        let (bytes_in, bytes_out) = count_compression(noise, "deflate").await;
+        assert_eq!(bytes_in, 4096);
+        assert!(bytes_out >= 4096, "{} bytes out of noise", bytes_out);
+
+        let stats = stats();
+        assert!(stats.bytes_in >= 8192);
+        assert!(stats.ratio.is_some());
+    }
+
+    #[test]
+    fn ratio_of_nothing_compressed() {
+        assert_eq!(ratio(0, 0), None);
+        assert_eq!(ratio(4096, 1024), Some(0.25));
+    }
+
+    #[test]
+    fn negotiate_coding() {
+        assert_eq!(Coding::negotiate("deflate, gzip;q=0.5"), Some(Coding::Gzip));
+        assert_eq!(Coding::negotiate("gzip;q=0, deflate"), Some(Coding::Deflate));
+        assert_eq!(Coding::negotiate("*"), Some(Coding::Gzip));
+        assert_eq!(Coding::negotiate("gzip;q=0, *"), Some(Coding::Deflate));
+        assert_eq!(Coding::negotiate("br, identity"), None);
+    }
+}
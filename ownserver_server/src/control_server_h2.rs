@@ -0,0 +1,144 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::channel::mpsc::{unbounded, SendError, UnboundedReceiver, UnboundedSender};
+use futures::sink::SinkMapErr;
+use futures::{SinkExt, StreamExt};
+use h2::{server::SendResponse, RecvStream};
+use http::{Method, Request, Response, StatusCode};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+use tracing::Instrument;
+use warp::ws::Message;
+
+use crate::control_server_v2::handle_new_transport;
+use crate::{accept::accept_with_backoff, Config, Store};
+
+/// Accept control channels carried over HTTP/2 CONNECT streams.
+/// Control packets are framed with a length prefix and handled the same as websocket messages.
+#[tracing::instrument(skip(config, store))]
+pub async fn spawn<A: Into<SocketAddr> + std::fmt::Debug>(
+    config: Arc<Config>,
+    store: Arc<Store>,
+    addr: A,
+) {
+    let listener = match TcpListener::bind(addr.into()).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("failed to bind h2 control port: {:?}", e);
+            return;
+        }
+    };
+
+    loop {
+        let (socket, client_addr) = accept_with_backoff(&listener, |e| tracing::error!("failed to accept h2 connection: {:?}", e)).await;
+
+        let config = config.clone();
+        let store = store.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = serve_connection(config, store, socket, client_addr).await {
+                    tracing::info!(?client_addr, "h2 connection closed: {:?}", e);
+                }
+            }
+            .instrument(tracing::info_span!("handle_h2")),
+        );
+    }
+}
+
+async fn serve_connection(
+    config: Arc<Config>,
+    store: Arc<Store>,
+    socket: TcpStream,
+    client_addr: SocketAddr,
+) -> Result<(), h2::Error> {
+    let mut connection = h2::server::handshake(socket).await?;
+
+    // accept() also drives the connection, so keep polling it while tunnels are open
+    while let Some(request) = connection.accept().await {
+        let (request, respond) = request?;
+        if let Some((sink, stream)) = open_tunnel(request, respond)? {
+            tokio::spawn(handle_new_transport(config.clone(), store.clone(), client_addr, None, sink, stream));
+        }
+    }
+    Ok(())
+}
+
+type TunnelSink = SinkMapErr<UnboundedSender<Message>, fn(SendError) -> io::Error>;
+type TunnelStream = UnboundedReceiver<Result<Message, io::Error>>;
+
+fn open_tunnel(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+) -> Result<Option<(TunnelSink, TunnelStream)>, h2::Error> {
+    if request.method() != Method::CONNECT {
+        tracing::debug!(method = %request.method(), "reject non CONNECT request");
+        let response = Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(())
+            .expect("failed to build response");
+        respond.send_response(response, true)?;
+        return Ok(None);
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .body(())
+        .expect("failed to build response");
+    let mut send = respond.send_response(response, false)?;
+    let mut recv = request.into_body();
+
+    let (sink_tx, mut sink_rx) = unbounded::<Message>();
+    tokio::spawn(async move {
+        let mut codec = LengthDelimitedCodec::new();
+        while let Some(message) = sink_rx.next().await {
+            let mut frame = BytesMut::new();
+            if let Err(e) = codec.encode(Bytes::from(message.into_bytes()), &mut frame) {
+                tracing::warn!(error = ?e, "failed to frame message");
+                break;
+            }
+            if let Err(e) = send.send_data(frame.freeze(), false) {
+                tracing::debug!(error = ?e, "failed to write to h2 stream");
+                break;
+            }
+        }
+        let _ = send.send_data(Bytes::new(), true);
+    });
+
+    let (stream_tx, stream_rx) = unbounded::<Result<Message, io::Error>>();
+    tokio::spawn(async move {
+        let mut codec = LengthDelimitedCodec::new();
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = recv.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = stream_tx.unbounded_send(Err(io::Error::new(io::ErrorKind::Other, e)));
+                    return;
+                }
+            };
+            let _ = recv.flow_control().release_capacity(chunk.len());
+            buf.extend_from_slice(&chunk);
+
+            loop {
+                match codec.decode(&mut buf) {
+                    Ok(Some(frame)) => {
+                        if stream_tx.unbounded_send(Ok(Message::binary(frame.to_vec()))).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = stream_tx.unbounded_send(Err(e));
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    let closed: fn(SendError) -> io::Error = |_| io::Error::from(io::ErrorKind::BrokenPipe);
+    Ok(Some((sink_tx.sink_map_err(closed), stream_rx)))
+}
@@ -1,91 +1,402 @@
 use futures::{
     Sink, SinkExt, Stream, StreamExt,
 };
-use ownserver_lib::{ClientHelloV2, EndpointClaim, ServerHelloV2, EndpointClaims, ControlPacketV2, Protocol};
-pub use ownserver_lib::{ClientId, StreamId, CLIENT_HELLO_VERSION};
-use ownserver_auth::decode_jwt;
+use ownserver_lib::{capability_names, negotiate_capabilities, Capability, ClientHelloV2, CloseReason, ServerHelloV2, EndpointClaims, EndpointId, Endpoints, Protocol, SUPPORTED_CAPABILITIES};
+pub use ownserver_lib::{ClientId, StreamId, CLIENT_HELLO_VERSION, MIN_CLIENT_HELLO_VERSION};
 use metrics::increment_counter;
-use std::{convert::Infallible, time::Duration};
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::{collections::HashMap, convert::Infallible, time::{Duration, SystemTime, UNIX_EPOCH}};
 use std::net::SocketAddr;
+use tokio::net::TcpListener;
 use tokio::time::sleep;
 use tokio::task::JoinSet;
 use tracing::Instrument;
 use warp::{
+    path::FullPath,
     ws::{Message, WebSocket, Ws},
-    Error as WarpError, Filter,
+    Filter,
 };
 
 use rand::{rngs::StdRng, SeedableRng};
 use std::sync::Arc;
-use once_cell::sync::OnceCell;
+use serde::Deserialize;
 use thiserror::Error;
 
-use crate::{Store, Client};
-use crate::remote;
+use crate::{Store, Client, access::AccessError, admin::{AdminDenied, AdminScope}, audit::AuditEvent, cleanup::{run_periodic_cleanup, CleanupSchedule}, client::{ClientOptions, DEFAULT_DATA_SEND_RETRIES}, compression::compressed, listener::{ControlListeners, ListenerError, ListenerStatus, PeerAddr}, packet_filter::PacketFilter, port_allocator::PortAllocatorError, quota::ByteQuota, store::MAX_RESERVATION_TTL, tls::{ClientIdentity, TlsPeer}, verifier::{HmacVerifier, TokenClaims, TokenVerifier, VerifyError}};
+use crate::remote::{self, BoundRemote, RemoteBound, SocketOptions, SocketTimeouts, TcpOptions};
 use crate::Config;
 
 #[tracing::instrument(skip(config, store))]
 pub fn spawn<A: Into<SocketAddr> + std::fmt::Debug>(
-    config: &'static OnceCell<Config>,
+    config: Arc<Config>,
     store: Arc<Store>,
+    listeners: Arc<ControlListeners>,
     addr: A,
 ) -> JoinSet<()> {
-    let periodic_cleanup_interval = config.get().expect("failed to read config").periodic_cleanup_interval;
-    let periodic_ping_interval = config.get().expect("failed to read config").periodic_ping_interval;
+    let periodic_cleanup_interval = config.periodic_cleanup_interval;
+    let periodic_ping_interval = config.periodic_ping_interval;
 
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Health Check #2 triggered");
         "ok"
     });
 
-    let store_ = store.clone();
-    let client_conn = warp::path("tunnel").and(client_addr()).and(warp::ws()).map(
-        move |client_addr: SocketAddr, ws: Ws| {
-            let store_ = store_.clone();
-            ws.on_upgrade(move |w| {
+    let client_conn = control_channel(config, store.clone());
+
+    let routes = client_conn
+        .or(health_check)
+        .or(compressed(admin_status(store.clone())))
+        .or(compressed(admin_clients(store.clone())))
+        .or(admin_drain_port(store.clone()))
+        .or(admin_pause_client(store.clone()))
+        .or(admin_reserve_port(store.clone()))
+        .or(compressed(admin_ports(store.clone())))
+        .or(admin_release_port(store.clone()))
+        .or(admin_close_peer(store.clone()))
+        .or(admin_reload_access(store.clone()))
+        .or(compressed(admin_listeners(store.clone(), listeners.clone())))
+        .or(admin_start_listener(store.clone(), listeners.clone()))
+        .or(admin_drain_listener(store.clone(), listeners.clone()));
+
+    let mut set = JoinSet::new();
+    listeners.set_service(warp::service(routes), store.tls_config().cloned());
+    let addr = addr.into();
+    set.spawn(async move {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => panic!("failed to bind control port {}: {:?}", addr, e),
+        };
+        match listeners.listen(listener) {
+            Ok(serve) => serve.await,
+            Err(e) => tracing::error!("failed to serve control port {}: {}", addr, e),
+        }
+    });
+
+    set.spawn(run_periodic_cleanup(store.clone(), CleanupSchedule::new(Duration::from_secs(periodic_cleanup_interval))));
+
+    set.spawn(async move {
+        loop {
+            sleep(Duration::from_secs(periodic_ping_interval)).await;
+            store.ping_clients().await;
+            tracing::debug!("pinged clients");
+        }
+    });
+    
+    set
+}
+
+/// WebSocket control channel at `config.control_path`, `/tunnel` by default.
+pub fn control_channel(config: Arc<Config>, store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let control_path = config.control_path.clone();
+    exact_path(control_path).and(client_addr()).and(client_identity()).and(warp::ws()).map(
+        move |client_addr: SocketAddr, identity: Option<ClientIdentity>, ws: Ws| {
+            let config = config.clone();
+            let store_ = store.clone();
+            let max_size = store.max_message_size();
+            ws.max_message_size(max_size).max_frame_size(max_size).on_upgrade(move |w| {
                 async move {
                     handle_new_connection(
                         config,
                         store_,
                         client_addr,
+                        identity,
                         w
                     ).await
                 }
                 .instrument(tracing::info_span!("handle_websocket"))
             })
         },
-    );
+    )
+}
 
-    let routes = client_conn.or(health_check);
+// matches `path` as a whole, which may span several segments
+fn exact_path(path: String) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::path::full()
+        .and_then(move |full: FullPath| {
+            let matched = full.as_str() == path;
+            async move {
+                if matched {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
 
-    let mut set = JoinSet::new();
-    // TODO tls https://docs.rs/warp/0.3.1/warp/struct.Server.html#method.tls
-    set.spawn(warp::serve(routes).run(addr.into()));
+/// Rejects with `AdminDenied` unless the bearer token allows `scope`, see `Store::with_admin_tokens`.
+fn admin_auth(store: Arc<Store>, scope: AdminScope) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let authorized = store.admin_tokens().authorize(authorization.as_deref(), scope);
+            async move { authorized.map_err(warp::reject::custom) }
+        })
+        .untuple_one()
+}
 
-    let store_ = store.clone();
-    set.spawn(async move {
-        loop {
-            sleep(Duration::from_secs(periodic_cleanup_interval)).await;
-            store_.cleanup().await;
-        }
-    });
+// 401 or 403 with the reason, other rejections are left to the routes after this one
+async fn admin_denied(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    match rejection.find::<AdminDenied>() {
+        Some(denied) => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": denied.to_string() })),
+            denied.status(),
+        )),
+        None => Err(rejection),
+    }
+}
 
-    set.spawn(async move {
-        loop {
-            sleep(Duration::from_secs(periodic_ping_interval)).await;
-            store.broadcast_to_clients(ControlPacketV2::Ping).await;
-            tracing::debug!("broadcasted ping");
+/// `GET /admin/status` reports whether the server is draining and how many clients and streams remain.
+pub fn admin_status(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "status"))
+        .and(admin_auth(store.clone(), AdminScope::Read))
+        .and_then(move || {
+            let store = store.clone();
+            async move { Ok::<_, warp::Rejection>(warp::reply::json(&store.status().await)) }
+        })
+        .recover(admin_denied)
+}
+
+/// `GET /admin/clients` lists the connected clients with their token subject, labels and remote ports.
+pub fn admin_clients(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "clients"))
+        .and(admin_auth(store.clone(), AdminScope::Read))
+        .and_then(move || {
+            let store = store.clone();
+            async move { Ok::<_, warp::Rejection>(warp::reply::json(&store.client_statuses().await)) }
+        })
+        .recover(admin_denied)
+}
+
+/// `POST /admin/clients/{client_id}/pause` refuses new remote connections of a client until
+/// `POST /admin/clients/{client_id}/resume`, see `Store::pause_client`. 404 when the client is not connected.
+pub fn admin_pause_client(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let pause = warp::path!("admin" / "clients" / ClientId / "pause").map(|client_id| (client_id, true));
+    let resume = warp::path!("admin" / "clients" / ClientId / "resume").map(|client_id| (client_id, false));
+    warp::post()
+        .and(pause.or(resume).unify())
+        .and(admin_auth(store.clone(), AdminScope::Write))
+        .and_then(move |(client_id, paused): (ClientId, bool)| {
+            let store = store.clone();
+            async move {
+                let found = if paused { store.pause_client(client_id).await } else { store.resume_client(client_id).await };
+                if !found {
+                    return Err(warp::reject::not_found());
+                }
+                Ok(warp::reply::json(&serde_json::json!({ "client_id": client_id, "paused": paused })))
+            }
+        })
+        .recover(admin_denied)
+}
+
+/// `POST /admin/ports/{port}/drain` stops new remote connections on one port and reports the streams left on it.
+/// 404 when no endpoint listens on the port.
+pub fn admin_drain_port(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("admin" / "ports" / u16 / "drain"))
+        .and(admin_auth(store.clone(), AdminScope::Write))
+        .and_then(move |port: u16| {
+            let store = store.clone();
+            async move {
+                match store.drain_port(port).await {
+                    Some(status) => Ok(warp::reply::json(&status)),
+                    None => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .recover(admin_denied)
+}
+
+/// `GET /admin/ports` lists the allocated remote ports with the client using each, `orphaned` when none does.
+pub fn admin_ports(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "ports"))
+        .and(admin_auth(store.clone(), AdminScope::Read))
+        .and_then(move || {
+            let store = store.clone();
+            async move { Ok::<_, warp::Rejection>(warp::reply::json(&store.port_statuses().await)) }
+        })
+        .recover(admin_denied)
+}
+
+/// `POST /admin/ports/{port}/release` returns an orphaned port to its pool, see `Store::release_port`.
+/// 409 when a client uses the port, 404 when it is not allocated.
+pub fn admin_release_port(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("admin" / "ports" / u16 / "release"))
+        .and(admin_auth(store.clone(), AdminScope::Write))
+        .and_then(move |port: u16| {
+            let store = store.clone();
+            async move {
+                let (body, status) = match store.release_port(port).await {
+                    Ok(()) => (serde_json::json!({ "port": port, "released": true }), warp::http::StatusCode::OK),
+                    Err(e @ PortAllocatorError::PortInUse(_)) => (serde_json::json!({ "error": e.to_string() }), warp::http::StatusCode::CONFLICT),
+                    Err(e) => (serde_json::json!({ "error": e.to_string() }), warp::http::StatusCode::NOT_FOUND),
+                };
+                Ok::<_, warp::Rejection>(warp::reply::with_status(warp::reply::json(&body), status))
+            }
+        })
+        .recover(admin_denied)
+}
+
+#[derive(Debug, Deserialize)]
+struct ReserveQuery {
+    ttl_secs: u64,
+}
+
+/// `POST /admin/ports/{port}/reserve?ttl_secs=60` holds a free remote port for the client whose hello presents
+/// the returned token, see `Store::reserve_port`. 409 when the port is not free, 400 when `ttl_secs` is over a day.
+pub fn admin_reserve_port(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("admin" / "ports" / u16 / "reserve"))
+        .and(admin_auth(store.clone(), AdminScope::Write))
+        .and(warp::query::<ReserveQuery>())
+        .and_then(move |port: u16, query: ReserveQuery| {
+            let store = store.clone();
+            async move {
+                let ttl = Duration::from_secs(query.ttl_secs);
+                if ttl > MAX_RESERVATION_TTL {
+                    let body = serde_json::json!({ "error": format!("ttl_secs must be at most {}", MAX_RESERVATION_TTL.as_secs()) });
+                    return Ok::<_, warp::Rejection>(warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::BAD_REQUEST));
+                }
+                let reply = match store.reserve_port(port, ttl).await {
+                    Ok(token) => warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({ "token": token, "port": port, "ttl_secs": query.ttl_secs })),
+                        warp::http::StatusCode::OK,
+                    ),
+                    Err(e) => warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+                        warp::http::StatusCode::CONFLICT,
+                    ),
+                };
+                Ok::<_, warp::Rejection>(reply)
+            }
+        })
+        .recover(admin_denied)
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerQuery {
+    addr: SocketAddr,
+}
+
+/// `POST /admin/peers/close?addr=203.0.113.1:40000` closes the stream of one remote peer and reports its stream id.
+/// 404 when no stream has the peer.
+pub fn admin_close_peer(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("admin" / "peers" / "close"))
+        .and(admin_auth(store.clone(), AdminScope::Write))
+        .and(warp::query::<PeerQuery>())
+        .and_then(move |query: PeerQuery| {
+            let store = store.clone();
+            async move {
+                match store.close_stream_by_addr(&query.addr).await {
+                    Some(stream_id) => Ok(warp::reply::json(&serde_json::json!({ "addr": query.addr, "stream_id": stream_id }))),
+                    None => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .recover(admin_denied)
+}
+
+/// `POST /admin/access/reload` re-reads the access list and reports how many streams of now denied peers were closed.
+/// 404 without an access list, 422 when the list can't be read and the previous one stays in effect.
+pub fn admin_reload_access(store: Arc<Store>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("admin" / "access" / "reload"))
+        .and(admin_auth(store.clone(), AdminScope::Write))
+        .and_then(move || {
+            let store = store.clone();
+            async move {
+                match store.reload_access_control().await {
+                    Ok(closed) => Ok(warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({ "closed_streams": closed })),
+                        warp::http::StatusCode::OK,
+                    )),
+                    Err(AccessError::NotConfigured) => Err(warp::reject::not_found()),
+                    Err(e) => {
+                        tracing::warn!("failed to reload access list: {}", e);
+                        Ok(warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+                            warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+                        ))
+                    }
+                }
+            }
+        })
+        .recover(admin_denied)
+}
+
+fn listener_reply(result: Result<ListenerStatus, ListenerError>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match result {
+        Ok(status) => warp::reply::with_status(warp::reply::json(&status), warp::http::StatusCode::OK),
+        Err(e) => {
+            let status = match e {
+                ListenerError::UnknownPort(_) => warp::http::StatusCode::NOT_FOUND,
+                ListenerError::NotStarted => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                _ => warp::http::StatusCode::CONFLICT,
+            };
+            warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": e.to_string() })), status)
         }
-    });
-    
-    set
+    }
+}
+
+/// `GET /admin/listeners` lists the control ports, whether each still accepts clients and its open connections.
+pub fn admin_listeners(store: Arc<Store>, listeners: Arc<ControlListeners>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "listeners"))
+        .and(admin_auth(store, AdminScope::Read))
+        .map(move || warp::reply::json(&listeners.statuses()))
+        .recover(admin_denied)
+}
+
+/// `POST /admin/listeners/{port}` starts accepting clients on another control port, e.g. for a rolling upgrade.
+/// 409 when the port is already accepting or can't be bound.
+pub fn admin_start_listener(store: Arc<Store>, listeners: Arc<ControlListeners>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("admin" / "listeners" / u16))
+        .and(admin_auth(store, AdminScope::Write))
+        .and_then(move |port: u16| {
+            let listeners = listeners.clone();
+            async move { Ok::<_, warp::Rejection>(listener_reply(listeners.start(port).await)) }
+        })
+        .recover(admin_denied)
+}
+
+/// `POST /admin/listeners/{port}/drain` stops accepting clients on a control port. Connected clients stay
+/// until they reconnect to another port. 409 for the last accepting port, 404 when the port is not served.
+pub fn admin_drain_listener(store: Arc<Store>, listeners: Arc<ControlListeners>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("admin" / "listeners" / u16 / "drain"))
+        .and(admin_auth(store, AdminScope::Write))
+        .map(move |port: u16| listener_reply(listeners.drain(port)))
+        .recover(admin_denied)
+}
+
+/// Prometheus metrics on any path, as the exporter's own listener serves them.
+pub fn prometheus_metrics(handle: PrometheusHandle) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get().map(move || handle.render())
 }
 
 // fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
 fn client_addr() -> impl Filter<Extract = (SocketAddr,), Error = Infallible> + Copy {
     warp::any()
         .and(warp::addr::remote())
-        .map(|remote: Option<SocketAddr>| remote.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))))
+        .and(warp::ext::optional::<TlsPeer>())
+        .and(warp::ext::optional::<PeerAddr>())
+        .map(|remote: Option<SocketAddr>, peer: Option<TlsPeer>, plain: Option<PeerAddr>| {
+            // connections served by `tls::serve` and `listener::serve` carry their address in an extension
+            peer.map(|peer| peer.addr)
+                .or(plain.map(|PeerAddr(addr)| addr))
+                .or(remote)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)))
+        })
+}
+
+fn client_identity() -> impl Filter<Extract = (Option<ClientIdentity>,), Error = Infallible> + Copy {
+    warp::ext::optional::<TlsPeer>().map(|peer: Option<TlsPeer>| peer.and_then(|peer| peer.identity))
 }
 
 #[derive(Error, Debug, PartialEq)]
@@ -101,23 +412,74 @@ pub enum VerifyClientHandshakeError {
 
     #[error("Client sends unsupported client handshake version.")]
     VersionMismatch,
+
+    #[error("Client token has expired.")]
+    ExpiredToken,
+
+    #[error("Client token could not be verified.")]
+    VerifierUnavailable,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidatedClientHello {
+    pub endpoint_claims: EndpointClaims,
+    /// port pool requested by the token, see `Store::allocate_endpoints_in_pool`
+    pub tier: Option<String>,
+    /// `sub` claim of the token, recorded in the audit log
+    pub subject: Option<String>,
+    /// capabilities both the client and the server support
+    pub capabilities: Vec<Capability>,
+    /// remote ports the token allows, any port of the pool when `None`
+    pub allowed_ports: Option<Vec<u16>>,
+    /// `labels` claim of the token, recorded in the audit log
+    pub labels: HashMap<String, String>,
+    /// token of a port reserved with `Store::reserve_port`
+    pub reservation: Option<String>,
+    /// handshake version the client speaks, see `ServerHelloV2::for_client_version`
+    pub version: u16,
+}
+
+impl ValidatedClientHello {
+    /// A trusted client certificate names the client, whatever its token says.
+    pub fn with_identity(mut self, identity: &ClientIdentity) -> Self {
+        self.subject = Some(identity.subject.clone());
+        if let Some(ref tier) = identity.tier {
+            self.tier = Some(tier.clone());
+        }
+        self
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+enum ReadClientHelloError {
+    #[error("Client did not send hello.")]
+    Missing,
+
+    #[error("Client hello of {0} bytes is too large.")]
+    TooLarge(usize),
 }
 
 #[tracing::instrument(skip(websocket))]
-async fn read_client_hello(
-    websocket: &mut (impl Unpin + Stream<Item = Result<Message, WarpError>>)
-) -> Option<Vec<u8>> {
+async fn read_client_hello<E>(
+    websocket: &mut (impl Unpin + Stream<Item = Result<Message, E>>),
+    max_size: usize,
+) -> Result<Vec<u8>, ReadClientHelloError> {
     let client_hello_data = match websocket.next().await {
+        // dropped before it is parsed
+        Some(Ok(msg)) if msg.as_bytes().len() > max_size => {
+            tracing::warn!(size = msg.as_bytes().len(), max_size, "client hello is too large");
+            return Err(ReadClientHelloError::TooLarge(msg.as_bytes().len()));
+        }
         Some(Ok(msg)) if (msg.is_binary() || msg.is_text()) && !msg.as_bytes().is_empty() => {
             msg.into_bytes()
         }
         _ => {
             tracing::warn!("client did not send hello");
-            return None
+            return Err(ReadClientHelloError::Missing)
         }
     };
 
-    Some(client_hello_data)
+    Ok(client_hello_data)
 }
 
 #[tracing::instrument(skip(websocket))]
@@ -133,13 +495,21 @@ where
     Ok(())
 }
 
+/// Validate with the `HmacVerifier` of `config`.
 #[tracing::instrument(skip(config))]
 async fn validate_client_hello(
-    config: &'static OnceCell<Config>,
+    config: &Config,
     client_hello_data: Vec<u8>,
-) -> Result<Vec<EndpointClaim>, VerifyClientHandshakeError> {
-    let Config { ref token_secret, ref host, .. } = config.get().expect("failed to read config");
+) -> Result<ValidatedClientHello, VerifyClientHandshakeError> {
+    let Config { token_secret, host, .. } = config;
+    validate_client_hello_with(&HmacVerifier::new(token_secret.clone(), host.clone()), client_hello_data).await
+}
 
+#[tracing::instrument(skip(verifier))]
+async fn validate_client_hello_with(
+    verifier: &dyn TokenVerifier,
+    client_hello_data: Vec<u8>,
+) -> Result<ValidatedClientHello, VerifyClientHandshakeError> {
     let client_hello: ClientHelloV2 = match serde_json::from_slice(&client_hello_data) {
         Ok(client_hello) => client_hello,
         _ => {
@@ -149,52 +519,149 @@ async fn validate_client_hello(
     };
     tracing::debug!("got client handshake {:?}", client_hello);
 
-    if client_hello.version != CLIENT_HELLO_VERSION {
-        tracing::debug!("client sernt client hello version {} but server accept version {}", client_hello.version, CLIENT_HELLO_VERSION);
+    if !(MIN_CLIENT_HELLO_VERSION..=CLIENT_HELLO_VERSION).contains(&client_hello.version) {
+        tracing::debug!("client sernt client hello version {} but server accept version {}-{}", client_hello.version, MIN_CLIENT_HELLO_VERSION, CLIENT_HELLO_VERSION);
         return Err(VerifyClientHandshakeError::VersionMismatch);
     }
 
-    let claim = decode_jwt(token_secret, &client_hello.token);
-    match claim.map(|c| &c.host == host) {
-        Ok(true) => {
+    let claims = match verifier.verify(&client_hello.token).await {
+        Ok(claims) => {
             tracing::info!("successfully validate client jwt");
+            claims
         },
-        Ok(false) => {
+        Err(VerifyError::IllegalHost) => {
             tracing::info!("client jwt was valid but different host");
             return Err(VerifyClientHandshakeError::IllegalHost);
         },
-        Err(e) => {
-            tracing::info!("failed to parse client jwt: {:?}", e);
+        Err(VerifyError::Invalid) => {
             return Err(VerifyClientHandshakeError::InvalidJWT);
         }
+        Err(VerifyError::Expired) => {
+            tracing::info!("client jwt expired");
+            return Err(VerifyClientHandshakeError::ExpiredToken);
+        }
+        Err(VerifyError::Unavailable(e)) => {
+            tracing::warn!("failed to verify client token: {}", e);
+            return Err(VerifyClientHandshakeError::VerifierUnavailable);
+        }
     };
 
-    Ok(client_hello.endpoint_claims)
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    if claims.is_expired(now) {
+        tracing::info!("client token expired at {:?}", claims.expires_at);
+        return Err(VerifyClientHandshakeError::ExpiredToken);
+    }
+
+    let TokenClaims { subject, tier, allowed_ports, labels, .. } = claims;
+    Ok(ValidatedClientHello {
+        tier,
+        subject,
+        labels,
+        endpoint_claims: client_hello.endpoint_claims,
+        capabilities: negotiate_capabilities(&client_hello.capabilities, SUPPORTED_CAPABILITIES),
+        allowed_ports,
+        reservation: client_hello.reservation,
+        version: client_hello.version,
+    })
 }
 
 
 async fn process_client_claims(
-    config: &'static OnceCell<Config>,
+    config: &Config,
     store: Arc<Store>,
-    client_hello: Result<EndpointClaims, VerifyClientHandshakeError>,
+    client_hello: Result<ValidatedClientHello, VerifyClientHandshakeError>,
 ) -> ServerHelloV2 {
-    let Config { ref host, .. } = config.get().expect("failed to read config");
+    let Config { host, public_host, on_duplicate, max_clients, .. } = config;
     let mut rng = StdRng::from_entropy();
     match client_hello {
-        Ok(claims) => {
-            match store.allocate_endpoints(&mut rng, claims).await {
+        Ok(ValidatedClientHello { endpoint_claims, tier, subject, capabilities, allowed_ports, reservation, .. }) => {
+            if store.is_draining() {
+                tracing::info!("server is draining, reject new client");
+                increment_counter!("ownserver_server.control_server.process_client_claims.draining");
+
+                return ServerHelloV2::ServiceTemporaryUnavailable;
+            }
+
+            if let Some(max_clients) = *max_clients {
+                if store.len_clients().await >= max_clients {
+                    tracing::warn!("too many clients are connected, reject new client");
+                    increment_counter!("ownserver_server.control_server.process_client_claims.too_many_clients");
+
+                    return ServerHelloV2::Rejected {
+                        reason: CloseReason::TooManyClients,
+                        retry_after: store.retry_after(),
+                    };
+                }
+            }
+
+            let client_id = ClientId::new();
+            if let Some(ref subject) = subject {
+                if let Err(existing) = store.claim_subject(subject, client_id, *on_duplicate).await {
+                    tracing::warn!(cid = %existing, "token subject is already connected, reject new client");
+                    increment_counter!("ownserver_server.control_server.process_client_claims.already_connected");
+
+                    return ServerHelloV2::Rejected {
+                        reason: CloseReason::AlreadyConnected,
+                        retry_after: None,
+                    };
+                }
+                // release the ports of the previous client so that its reservation hands them out again
+                if store.has_held_streams(subject) {
+                    store.cleanup_subject(subject).await;
+                }
+            }
+
+            let allocated = match reservation {
+                Some(ref token) => store.allocate_reserved_endpoints(&mut rng, token, allowed_ports.as_deref(), endpoint_claims).await,
+                None => store.allocate_endpoints_within(&mut rng, tier.as_deref(), subject.as_deref(), allowed_ports.as_deref(), endpoint_claims).await,
+            };
+            match allocated {
                 Ok(endpoints) => {
                     let server_hello = ServerHelloV2::Success {
-                        client_id: ClientId::new(),
+                        client_id,
                         host: host.to_string(),
                         endpoints,
+                        version: CLIENT_HELLO_VERSION,
+                        capabilities: capability_names(&capabilities),
+                        public_host: public_host.clone(),
                     };
 
                     increment_counter!("ownserver_server.control_server.process_client_claims.success");
                     server_hello
                 },
+                Err(PortAllocatorError::Exhausted) => {
+                    tracing::warn!("no remote port is available, reject new client");
+                    store.release_subject(client_id).await;
+                    increment_counter!("ownserver_server.store.port_exhausted");
+
+                    ServerHelloV2::Rejected {
+                        reason: CloseReason::NoPortsAvailable,
+                        retry_after: store.retry_after(),
+                    }
+                }
+                Err(PortAllocatorError::ReservationInvalid) => {
+                    tracing::warn!("port reservation is unknown or has expired, reject new client");
+                    store.release_subject(client_id).await;
+                    increment_counter!("ownserver_server.control_server.process_client_claims.reservation_invalid");
+
+                    ServerHelloV2::Rejected {
+                        reason: CloseReason::ReservationInvalid,
+                        retry_after: None,
+                    }
+                }
+                Err(PortAllocatorError::PortUnavailable(port)) => {
+                    tracing::warn!(port, "requested remote port is not available, reject new client");
+                    store.release_subject(client_id).await;
+                    increment_counter!("ownserver_server.store.port_unavailable");
+
+                    ServerHelloV2::Rejected {
+                        reason: CloseReason::NoPortsAvailable,
+                        retry_after: store.retry_after(),
+                    }
+                }
                 Err(_) => {
                     tracing::error!("failed to allocate port");
+                    store.release_subject(client_id).await;
                     increment_counter!("ownserver_server.control_server.process_client_claims.service_temporary_unavailable");
 
                     ServerHelloV2::ServiceTemporaryUnavailable
@@ -213,6 +680,18 @@ async fn process_client_claims(
 
             ServerHelloV2::BadRequest
         },
+        Err(VerifyClientHandshakeError::ExpiredToken) => {
+            tracing::warn!("client token has expired");
+            increment_counter!("ownserver_server.control_server.process_client_claims.expired_token");
+
+            ServerHelloV2::BadRequest
+        },
+        Err(VerifyClientHandshakeError::VerifierUnavailable) => {
+            tracing::warn!("failed to verify client token");
+            increment_counter!("ownserver_server.control_server.process_client_claims.verifier_unavailable");
+
+            ServerHelloV2::ServiceTemporaryUnavailable
+        },
         Err(VerifyClientHandshakeError::IllegalHost) => {
             tracing::warn!("client try to connect to non-designated host");
             increment_counter!("ownserver_server.control_server.process_client_claims.illegal_host");
@@ -223,43 +702,119 @@ async fn process_client_claims(
             tracing::warn!("client sent not supported client handshake version");
             increment_counter!("ownserver_server.control_server.process_client_claims.version_mismatch");
 
-            ServerHelloV2::VersionMismatch
+            ServerHelloV2::Rejected {
+                reason: CloseReason::VersionUnsupported {
+                    min: MIN_CLIENT_HELLO_VERSION,
+                    max: CLIENT_HELLO_VERSION,
+                },
+                retry_after: None,
+            }
         }
     }
 }
 
-
-
-
 #[tracing::instrument(skip(config, store, websocket))]
 async fn handle_new_connection(
-    config: &'static OnceCell<Config>,
+    config: Arc<Config>,
     store: Arc<Store>,
     client_ip: SocketAddr,
-    mut websocket: WebSocket,
+    identity: Option<ClientIdentity>,
+    websocket: WebSocket,
 ) {
-    increment_counter!("ownserver_server.control_server.handle_new_connection");
+    let (sink, stream) = websocket.split();
+    handle_new_transport(config, store, client_ip, identity, sink, stream).await
+}
 
+/// Run the handshake and register the client over an already established carrier.
+/// `identity` comes from the client certificate when the server requires one.
+#[tracing::instrument(skip(config, store, sink, stream))]
+pub(crate) async fn handle_new_transport<Si, St, E>(
+    config: Arc<Config>,
+    store: Arc<Store>,
+    client_ip: SocketAddr,
+    identity: Option<ClientIdentity>,
+    mut sink: Si,
+    mut stream: St,
+) where
+    Si: Sink<Message> + Unpin + Send + 'static,
+    Si::Error: std::fmt::Debug,
+    St: Stream<Item = Result<Message, E>> + Unpin + Send + 'static,
+    E: Send + 'static,
+{
+    increment_counter!("ownserver_server.control_server.handle_new_connection");
 
     // 1. read client hello
-    let client_hello_data = match read_client_hello(&mut websocket).await {
-        Some(data) => data,
-        None => {
+    let client_hello_data = match read_client_hello(&mut stream, store.max_handshake_size()).await {
+        Ok(data) => data,
+        Err(ReadClientHelloError::TooLarge(_)) => {
+            increment_counter!("ownserver_server.control_server.handle_new_connection.client_hello_too_large");
+            let _ = sink.send(Message::close_with(1009u16, "client hello is too large")).await;
+            return
+        }
+        Err(ReadClientHelloError::Missing) => {
             increment_counter!("ownserver_server.control_server.handle_new_connection.read_client_hello_error");
             return
         }
     };
 
     // 2. parse and validate client hello
-    let client_hello = validate_client_hello(config, client_hello_data).await;
+    let client_hello = match store.token_verifier() {
+        Some(verifier) => validate_client_hello_with(verifier.as_ref(), client_hello_data).await,
+        None => validate_client_hello(&config, client_hello_data).await,
+    };
+    let client_hello = match identity {
+        Some(ref identity) => client_hello.map(|hello| {
+            tracing::info!(subject = %identity.subject, tier = ?identity.tier, "client authenticated with certificate");
+            hello.with_identity(identity)
+        }),
+        None => client_hello,
+    };
+
+    let token_subject = client_hello.as_ref().ok().and_then(|hello| hello.subject.clone());
+    let capabilities = client_hello.as_ref().map(|hello| hello.capabilities.clone()).unwrap_or_default();
+    let labels = client_hello.as_ref().map(|hello| hello.labels.clone()).unwrap_or_default();
+    let allowed_ports = client_hello.as_ref().ok().and_then(|hello| hello.allowed_ports.clone());
+    let client_version = client_hello.as_ref().map(|hello| hello.version).unwrap_or(CLIENT_HELLO_VERSION);
 
     // 3. convert client hello to server hello
     // allocate ports based on client claims
-    let server_hello = process_client_claims(config, store.clone(), client_hello).await;
+    let mut server_hello = process_client_claims(&config, store.clone(), client_hello).await;
+    // kept until the client is registered or turned away, so that its ports are not force released meanwhile
+    let _handshake = match server_hello {
+        ServerHelloV2::Success { client_id, ref endpoints, .. } => Some(store.track_handshake(client_id, endpoints)),
+        _ => None,
+    };
+    let Config { host, public_host, client_send_buffer, client_send_timeout, read_timeout, write_timeout, client_quota_bytes, client_quota_window, nodelay, tcp_keepalive, enable_ipv6, max_session_duration, allowed_packets, max_packet_violations, sniff_http, remote_banner, max_decode_errors, remote_backlog, .. } = &*config;
+
+    // 4. listen on the remote ports so that they accept players as soon as the client announces them
+    let mut bound = Vec::new();
+    if let ServerHelloV2::Success { client_id, ref mut endpoints, .. } = server_hello {
+        match bind_endpoints(store.clone(), client_id, endpoints, allowed_ports.as_deref(), *enable_ipv6, *remote_backlog).await {
+            Ok(sockets) => bound = sockets,
+            Err(e) => {
+                tracing::error!(cid = %client_id, "failed to bind remote ports {:?}", e);
+                increment_counter!("ownserver_server.control_server.handle_new_connection.bind_error");
+                for endpoint in endpoints.iter() {
+                    if let Err(e) = store.release_endpoint(endpoint.id).await {
+                        tracing::warn!(eid = %endpoint.id, "failed to release endpoint {:?}", e);
+                    }
+                }
+                store.release_subject(client_id).await;
+                server_hello = ServerHelloV2::Rejected {
+                    reason: CloseReason::PortBindFailed,
+                    retry_after: store.retry_after(),
+                };
+            }
+        }
+    }
 
-    // 4. respond with server hello
-    if let Err(e) = send_server_hello(&mut websocket, &server_hello).await {
+    // 5. respond with server hello
+    let server_hello = server_hello.for_client_version(client_version);
+    if let Err(e) = send_server_hello(&mut sink, &server_hello).await {
         tracing::error!("failed to send server hello: {:?}", e);
+        if let ServerHelloV2::Success { client_id, .. } = server_hello {
+            store.release_subject(client_id).await;
+        }
         increment_counter!("ownserver_server.control_server.handle_new_connection.send_server_hello_error");
         return;
     }
@@ -271,52 +826,126 @@ async fn handle_new_connection(
         }
     };
 
-    // 5. spawn remote listener
-    let client = Client::new(store.clone(), client_id, endpoints.clone(), websocket);
+    // 6. register the client and serve its remote ports
+    let options = ClientOptions {
+        send_buffer: *client_send_buffer,
+        send_timeout: Duration::from_secs(*client_send_timeout),
+        quota: client_quota_bytes.map(|limit| ByteQuota::new(limit, Duration::from_secs(*client_quota_window))),
+        capabilities,
+        max_session_duration: max_session_duration.map(Duration::from_secs),
+        packet_filter: allowed_packets.as_ref().map(|kinds| PacketFilter::new(kinds.iter().copied(), *max_packet_violations)),
+        subject: token_subject.clone(),
+        peer_addr: Some(client_ip),
+        labels: labels.clone(),
+        host: public_host.clone().unwrap_or_else(|| host.clone()),
+        max_decode_errors: *max_decode_errors,
+        data_send_retries: DEFAULT_DATA_SEND_RETRIES,
+    };
+    let client = Client::with_transport(store.clone(), client_id, endpoints.clone(), sink, stream, options);
     let ct = client.cancellation_token();
     store.add_client(client).await;
     tracing::info!(cid=%client_id, "register client to store");
+    store.audit(AuditEvent::Connect { client_id, token_subject, peer_addr: client_ip, labels });
+
+    for endpoint in endpoints.iter() {
+        store.audit(AuditEvent::PortAllocated {
+            client_id,
+            endpoint_id: endpoint.id,
+            protocol: endpoint.protocol,
+            remote_port: endpoint.remote_port,
+        });
+    }
 
-    for endpoint in endpoints {
-        match endpoint.protocol {
-            Protocol::TCP => {
-                if let Err(e) = remote::tcp::spawn_remote(store.clone(), client_id, endpoint.id, ct.clone()).await {
-                    tracing::error!(cid = %client_id, eid = %endpoint.id, "failed to spawn remote listener {:?}", e);
-                }
-            }
-            Protocol::UDP => {
-                if let Err(e) = remote::udp::spawn_remote(store.clone(), client_id, endpoint.id, ct.clone()).await {
-                    tracing::error!(cid = %client_id, eid = %endpoint.id, "failed to spawn remote listener {:?}", e);
+    let timeouts = SocketTimeouts {
+        connect: None,
+        read: read_timeout.map(Duration::from_secs),
+        write: write_timeout.map(Duration::from_secs),
+    };
+    let socket_options = SocketOptions {
+        tcp: TcpOptions { nodelay: *nodelay, keepalive: tcp_keepalive.map(Duration::from_secs) },
+        sniff_http: *sniff_http,
+        banner: remote_banner.as_deref(),
+    };
+    for (endpoint_id, sockets) in bound {
+        match sockets {
+            BoundRemote::Tcp(listeners) => remote::tcp::serve_remote(store.clone(), listeners, client_id, endpoint_id, timeouts, socket_options, ct.clone()),
+            BoundRemote::Udp(sockets) => remote::udp::serve_remote(store.clone(), sockets, client_id, endpoint_id, ct.clone()),
+        }
+    }
+}
+
+/// Remote ports tried for each port of a client, the first included, while other processes hold them.
+const BIND_ATTEMPTS: usize = 5;
+
+// bind every endpoint or none: sockets bound so far are closed when one fails.
+// a remote port held by another process is swapped for a free one, in `endpoints` as well
+async fn bind_endpoints(store: Arc<Store>, client_id: ClientId, endpoints: &mut Endpoints, allowed: Option<&[u16]>, ipv6: bool, backlog: u32) -> std::io::Result<Vec<(EndpointId, BoundRemote)>> {
+    let mut ports: Vec<u16> = endpoints.iter().map(|e| e.remote_port).collect();
+    ports.sort_unstable();
+    ports.dedup();
+
+    let mut rng = StdRng::from_entropy();
+    let mut bound = Vec::with_capacity(endpoints.len());
+    for mut port in ports {
+        let mut attempt = 1;
+        loop {
+            let e = match bind_port(store.clone(), client_id, endpoints, port, ipv6, backlog).await {
+                Ok(sockets) => {
+                    bound.extend(sockets);
+                    break;
                 }
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempt < BIND_ATTEMPTS => e,
+                Err(e) => return Err(e),
+            };
+
+            let eids: Vec<EndpointId> = endpoints.iter().filter(|endpoint| endpoint.remote_port == port).map(|endpoint| endpoint.id).collect();
+            let moved = store.move_endpoints(&mut rng, &eids, port, allowed).await.map_err(|_| e)?;
+            tracing::warn!(cid = %client_id, "remote port {} is used by another process, try {} instead", port, moved);
+            increment_counter!("ownserver_server.control_server.bind_endpoints.port_in_use");
+            for endpoint in endpoints.iter_mut().filter(|endpoint| endpoint.remote_port == port) {
+                endpoint.remote_port = moved;
             }
+            port = moved;
+            attempt += 1;
         }
+    }
+    Ok(bound)
+}
 
+// bind the endpoints on the remote `port`, e.g. tcp and udp of the same local port
+async fn bind_port(store: Arc<Store>, client_id: ClientId, endpoints: &Endpoints, port: u16, ipv6: bool, backlog: u32) -> std::io::Result<Vec<(EndpointId, BoundRemote)>> {
+    let mut bound = Vec::new();
+    for endpoint in endpoints.iter().filter(|e| e.remote_port == port) {
+        let sockets = match endpoint.protocol {
+            Protocol::TCP => BoundRemote::Tcp(remote::tcp::bind_remote(store.clone(), client_id, endpoint.id, ipv6, backlog).await?),
+            Protocol::UDP => BoundRemote::Udp(remote::udp::bind_remote(store.clone(), client_id, endpoint.id, ipv6).await?),
+        };
+        bound.push((endpoint.id, sockets));
+    }
+    for (endpoint_id, sockets) in bound.iter() {
+        for addr in sockets.local_addrs() {
+            store.notify_bound(RemoteBound { client_id, endpoint_id: *endpoint_id, addr });
+        }
     }
+    Ok(bound)
 }
 
 #[cfg(test)]
 mod verify_client_handshake_test {
     use super::*;
+    use crate::test_support::{admin_get, client_hello_with_token, get_endpoint_claims_single, with_admin_token};
     use ownserver_auth::make_jwt;
     use chrono::Duration;
-    use ownserver_lib::Protocol;
-
-    static CONFIG: OnceCell<Config> = OnceCell::new();
-    static EMPTY_CONFIG: OnceCell<Config> = OnceCell::new();
-
-    fn get_config() -> &'static OnceCell<Config> {
-        CONFIG.get_or_init(||
-            Config {
-                control_port: 5000,
-                token_secret: "supersecret".to_string(),
-                host: "foohost.test.local".to_string(),
-                remote_port_start: 10010,
-                remote_port_end: 10011,
-                periodic_cleanup_interval: 15,
-                periodic_ping_interval: 15,
-            }
-        );
-        &CONFIG
+    use ownserver_lib::{EndpointClaim, Protocol};
+
+    fn get_config() -> Arc<Config> {
+        let config = Config::builder()
+            .token_secret("supersecret")
+            .host("foohost.test.local")
+            .port_range(10010..10011)
+            .build()
+            .expect("invalid config");
+        Arc::new(config)
     }
 
     #[tokio::test]
@@ -331,21 +960,46 @@ mod verify_client_handshake_test {
                 local_port: 25565,
                 remote_port: 0,
             }],
+            capabilities: Vec::new(),
+            reservation: None,
         })
         .unwrap_or_default();
         let client_hello_data = Message::binary(hello).into_bytes();
 
-        let hello = validate_client_hello(config, client_hello_data).await;
+        let hello = validate_client_hello(&config, client_hello_data).await;
         assert!(hello.is_ok());
         Ok(())
     }
 
+    #[tokio::test]
+    async fn negotiate_supported_capabilities() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+
+        let hello = serde_json::to_vec(&ClientHelloV2 {
+            version: CLIENT_HELLO_VERSION,
+            token: make_jwt("supersecret", Duration::minutes(10), "foohost.test.local".to_string())?,
+            endpoint_claims: vec![EndpointClaim {
+                protocol: Protocol::TCP,
+                local_port: 25565,
+                remote_port: 0,
+            }],
+            capabilities: vec!["compression".to_string(), "teleport".to_string(), "flow-control".to_string()],
+            reservation: None,
+        })
+        .unwrap_or_default();
+        let client_hello_data = Message::binary(hello).into_bytes();
+
+        let hello = validate_client_hello(&config, client_hello_data).await?;
+        assert_eq!(hello.capabilities, vec![Capability::FlowControl]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn reject_invalid_text_hello() -> Result<(), Box<dyn std::error::Error>> {
         let config = get_config();
 
         let client_hello_data = Message::text("foobarbaz".to_string()).into_bytes();
-        let handshake= validate_client_hello(config, client_hello_data).await;
+        let handshake= validate_client_hello(&config, client_hello_data).await;
         let handshake_error = handshake.err().unwrap();
         assert_eq!(handshake_error, VerifyClientHandshakeError::InvalidClientHello);
         Ok(())
@@ -358,7 +1012,7 @@ mod verify_client_handshake_test {
         let hello = serde_json::to_vec(&"malformed".to_string()).unwrap_or_default();
         let client_hello_data = Message::binary(hello).into_bytes();
 
-        let handshake= validate_client_hello(config, client_hello_data).await;
+        let handshake= validate_client_hello(&config, client_hello_data).await;
         let handshake_error = handshake.err().unwrap();
         assert_eq!(handshake_error, VerifyClientHandshakeError::InvalidClientHello);
         Ok(())
@@ -376,11 +1030,13 @@ mod verify_client_handshake_test {
                 local_port: 25565,
                 remote_port: 0,
             }],
+            capabilities: Vec::new(),
+            reservation: None,
         })
         .unwrap_or_default();
         let client_hello_data = Message::binary(hello).into_bytes();
 
-        let handshake= validate_client_hello(config, client_hello_data).await;
+        let handshake= validate_client_hello(&config, client_hello_data).await;
         let handshake_error = handshake.err().unwrap();
         assert_eq!(handshake_error, VerifyClientHandshakeError::InvalidJWT);
         Ok(())
@@ -398,52 +1054,851 @@ mod verify_client_handshake_test {
                 local_port: 25565,
                 remote_port: 0,
             }],
+            capabilities: Vec::new(),
+            reservation: None,
         })
         .unwrap_or_default();
         let client_hello_data = Message::binary(hello).into_bytes();
 
-        let handshake= validate_client_hello(config, client_hello_data).await;
+        let handshake= validate_client_hello(&config, client_hello_data).await;
         let handshake_error = handshake.err().unwrap();
         assert_eq!(handshake_error, VerifyClientHandshakeError::IllegalHost);
         Ok(())
     }
 
     #[tokio::test]
-    #[should_panic]
-    async fn panic_when_config_not_initialized() {
+    async fn reject_when_version_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+
         let hello = serde_json::to_vec(&ClientHelloV2 {
             version: CLIENT_HELLO_VERSION,
-            token: make_jwt("supersecret", Duration::minutes(10), "foohost.test.local".to_string()).unwrap(),
+            token: make_jwt("supersecret", Duration::minutes(10), "foohost.test.local".to_string())?,
             endpoint_claims: vec![EndpointClaim {
                 protocol: Protocol::TCP,
                 local_port: 25565,
                 remote_port: 0,
             }],
+            capabilities: Vec::new(),
+            reservation: None,
         })
         .unwrap_or_default();
         let client_hello_data = Message::binary(hello).into_bytes();
 
-        let _ = validate_client_hello(&EMPTY_CONFIG, client_hello_data).await;
+        let hello = validate_client_hello(&config, client_hello_data).await;
+        assert!(hello.is_ok());
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn reject_when_version_mismatch() -> Result<(), Box<dyn std::error::Error>> {
-        let config = get_config();
-
+    fn client_hello_with_version(version: u16) -> Vec<u8> {
         let hello = serde_json::to_vec(&ClientHelloV2 {
-            version: CLIENT_HELLO_VERSION,
-            token: make_jwt("supersecret", Duration::minutes(10), "foohost.test.local".to_string())?,
+            version,
+            token: make_jwt("supersecret", Duration::minutes(10), "foohost.test.local".to_string()).unwrap(),
             endpoint_claims: vec![EndpointClaim {
                 protocol: Protocol::TCP,
                 local_port: 25565,
                 remote_port: 0,
             }],
+            capabilities: Vec::new(),
+            reservation: None,
         })
         .unwrap_or_default();
-        let client_hello_data = Message::binary(hello).into_bytes();
+        Message::binary(hello).into_bytes()
+    }
 
-        let hello = validate_client_hello(config, client_hello_data).await;
-        assert!(hello.is_ok());
+    #[tokio::test]
+    async fn accept_supported_version() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+
+        for version in MIN_CLIENT_HELLO_VERSION..=CLIENT_HELLO_VERSION {
+            let hello = validate_client_hello(&config, client_hello_with_version(version)).await;
+            assert!(hello.is_ok());
+        }
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn reject_too_old_version() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+
+        let handshake = validate_client_hello(&config, client_hello_with_version(MIN_CLIENT_HELLO_VERSION - 1)).await;
+        assert_eq!(handshake.err().unwrap(), VerifyClientHandshakeError::VersionMismatch);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_too_new_version() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+
+        let handshake = validate_client_hello(&config, client_hello_with_version(CLIENT_HELLO_VERSION + 1)).await;
+        assert_eq!(handshake.err().unwrap(), VerifyClientHandshakeError::VersionMismatch);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn respond_supported_range_when_version_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+        let store = Arc::new(Store::new(10010..10011));
+
+        let server_hello = process_client_claims(&config, store, Err(VerifyClientHandshakeError::VersionMismatch)).await;
+        match server_hello {
+            ServerHelloV2::Rejected { reason, retry_after } => {
+                assert_eq!(retry_after, None);
+                assert_eq!(reason, CloseReason::VersionUnsupported { min: MIN_CLIENT_HELLO_VERSION, max: CLIENT_HELLO_VERSION });
+            }
+            other => panic!("unexpected server hello {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_when_ports_are_exhausted() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+        let store = Arc::new(Store::new(10010..10011).with_retry_after(std::time::Duration::from_secs(30)));
+        let claims = || vec![EndpointClaim {
+            protocol: Protocol::TCP,
+            local_port: 25565,
+            remote_port: 0,
+        }];
+        let client_hello = || Ok(ValidatedClientHello {
+            tier: None,
+            subject: None,
+            endpoint_claims: claims(),
+            capabilities: Vec::new(),
+            allowed_ports: None,
+            labels: HashMap::new(),
+            reservation: None,
+            version: CLIENT_HELLO_VERSION,
+        });
+
+        let server_hello = process_client_claims(&config, store.clone(), client_hello()).await;
+        assert!(matches!(server_hello, ServerHelloV2::Success { .. }));
+
+        let server_hello = process_client_claims(&config, store, client_hello()).await;
+        match server_hello {
+            ServerHelloV2::Rejected { reason, retry_after } => {
+                assert_eq!(reason, CloseReason::NoPortsAvailable);
+                assert_eq!(retry_after, Some(std::time::Duration::from_secs(30)));
+            }
+            other => panic!("unexpected server hello {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct StubVerifier(TokenClaims);
+
+    #[async_trait::async_trait]
+    impl TokenVerifier for StubVerifier {
+        async fn verify(&self, token: &str) -> Result<TokenClaims, VerifyError> {
+            match token {
+                "valid" => Ok(self.0.clone()),
+                _ => Err(VerifyError::Invalid),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn allocate_from_tier_and_ports_of_stub_verifier() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+        let pools = std::collections::HashMap::from([("paid".to_string(), 10100..10110)]);
+        let store = Arc::new(Store::with_port_pools(10010..10011, pools));
+        let verifier = StubVerifier(TokenClaims {
+            subject: Some("alice".to_string()),
+            tier: Some("paid".to_string()),
+            expires_at: Some(u64::MAX),
+            allowed_ports: Some(vec![10107]),
+            ..Default::default()
+        });
+
+        let hello = validate_client_hello_with(&verifier, client_hello_with_token("valid", get_endpoint_claims_single())).await?;
+        assert_eq!(hello.subject, Some("alice".to_string()));
+        match process_client_claims(&config, store, Ok(hello)).await {
+            ServerHelloV2::Success { endpoints, .. } => assert_eq!(endpoints[0].remote_port, 10107),
+            other => panic!("unexpected server hello {:?}", other),
+        }
+
+        let hello = validate_client_hello_with(&verifier, client_hello_with_token("forged", get_endpoint_claims_single())).await;
+        assert_eq!(hello, Err(VerifyClientHandshakeError::InvalidJWT));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_expired_claims_of_stub_verifier() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+        let store = Arc::new(Store::new(10010..10011));
+        let verifier = StubVerifier(TokenClaims { expires_at: Some(1), ..Default::default() });
+
+        let hello = validate_client_hello_with(&verifier, client_hello_with_token("valid", get_endpoint_claims_single())).await;
+        assert_eq!(hello, Err(VerifyClientHandshakeError::ExpiredToken));
+        assert!(matches!(process_client_claims(&config, store, hello).await, ServerHelloV2::BadRequest));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_client_without_token_in_no_auth_mode() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+        let store = Arc::new(Store::new(10030..10031).with_token_verifier(Arc::new(crate::verifier::NoAuthVerifier)));
+
+        let (sink, mut sent) = futures::channel::mpsc::unbounded::<Message>();
+        let hello = Message::binary(client_hello_with_token("", get_endpoint_claims_single()));
+        let stream = futures::stream::iter(vec![Ok::<_, Infallible>(hello)]).chain(futures::stream::pending());
+        tokio::spawn(handle_new_transport(config, store.clone(), "127.0.0.1:40000".parse()?, None, sink, stream));
+
+        let message = tokio::time::timeout(std::time::Duration::from_secs(2), sent.next()).await?.expect("no server hello");
+        match serde_json::from_slice::<ServerHelloV2>(message.as_bytes())? {
+            ServerHelloV2::Success { endpoints, .. } => assert_eq!(endpoints[0].remote_port, 10030),
+            other => panic!("unexpected server hello {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_server_hello_after_remote_port_listens() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+        let (bind_tx, mut bind_rx) = tokio::sync::mpsc::unbounded_channel();
+        let store = Arc::new(Store::new(10020..10021).with_bind_events(bind_tx));
+
+        let hello = client_hello_with_version(CLIENT_HELLO_VERSION);
+        let (sink, mut sent) = futures::channel::mpsc::unbounded::<Message>();
+        let stream = futures::stream::iter(vec![Ok::<_, Infallible>(Message::binary(hello))]).chain(futures::stream::pending());
+        tokio::spawn(handle_new_transport(config, store.clone(), "127.0.0.1:40000".parse()?, None, sink, stream));
+
+        let message = tokio::time::timeout(std::time::Duration::from_secs(2), sent.next()).await?.expect("no server hello");
+        let remote_port = match serde_json::from_slice::<ServerHelloV2>(message.as_bytes())? {
+            ServerHelloV2::Success { endpoints, .. } => endpoints[0].remote_port,
+            other => panic!("unexpected server hello {:?}", other),
+        };
+
+        // the port was reported before the hello and connections are accepted at once
+        let bound = bind_rx.try_recv()?;
+        assert_eq!(bound.addr.port(), remote_port);
+        tokio::net::TcpStream::connect(("127.0.0.1", remote_port)).await?;
+        Ok(())
+    }
+
+    async fn server_hello_for_tcp_claim(store: Arc<Store>) -> Result<ServerHelloV2, Box<dyn std::error::Error>> {
+        let hello = client_hello_with_version(CLIENT_HELLO_VERSION);
+        let (sink, mut sent) = futures::channel::mpsc::unbounded::<Message>();
+        let stream = futures::stream::iter(vec![Ok::<_, Infallible>(Message::binary(hello))]).chain(futures::stream::pending());
+        tokio::spawn(handle_new_transport(get_config(), store, "127.0.0.1:40000".parse()?, None, sink, stream));
+
+        let message = tokio::time::timeout(std::time::Duration::from_secs(2), sent.next()).await?.expect("no server hello");
+        Ok(serde_json::from_slice::<ServerHelloV2>(message.as_bytes())?)
+    }
+
+    #[tokio::test]
+    async fn skip_remote_port_held_by_another_process() -> Result<(), Box<dyn std::error::Error>> {
+        let _other_process = std::net::TcpListener::bind("0.0.0.0:10086")?;
+        let store = Arc::new(Store::new(10086..10088).with_deterministic_ports());
+
+        match server_hello_for_tcp_claim(store.clone()).await? {
+            ServerHelloV2::Success { endpoints, .. } => {
+                assert_eq!(endpoints[0].remote_port, 10087);
+                assert_eq!(store.get_remote_port_by_endpoint_id(endpoints[0].id), Some(10087));
+            }
+            other => panic!("unexpected server hello {:?}", other),
+        }
+        tokio::net::TcpStream::connect(("127.0.0.1", 10087)).await?;
+        // the held port went back to the pool
+        assert_eq!(store.allocate_port(&mut rand::thread_rng()).await?, 10086);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_client_when_every_remote_port_is_held() -> Result<(), Box<dyn std::error::Error>> {
+        let _other_process = std::net::TcpListener::bind("0.0.0.0:10088")?;
+        let store = Arc::new(Store::new(10088..10089));
+
+        match server_hello_for_tcp_claim(store.clone()).await? {
+            ServerHelloV2::Rejected { reason, .. } => assert_eq!(reason, CloseReason::PortBindFailed),
+            other => panic!("unexpected server hello {:?}", other),
+        }
+        assert_eq!(store.allocate_port(&mut rand::thread_rng()).await?, 10088);
+        Ok(())
+    }
+
+    async fn next_event(events: &mut tokio::sync::broadcast::Receiver<AuditEvent>) -> Result<AuditEvent, Box<dyn std::error::Error>> {
+        Ok(tokio::time::timeout(std::time::Duration::from_secs(2), events.recv()).await??)
+    }
+
+    #[tokio::test]
+    async fn broadcast_events_in_order() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+        let store = Arc::new(Store::new(10068..10069).with_events(16));
+        let mut events = store.subscribe().expect("events are enabled");
+
+        let hello = client_hello_with_version(CLIENT_HELLO_VERSION);
+        let (sink, mut sent) = futures::channel::mpsc::unbounded::<Message>();
+        let (incoming, stream) = futures::channel::mpsc::unbounded::<Result<Message, Infallible>>();
+        incoming.unbounded_send(Ok(Message::binary(hello)))?;
+        let client_addr: std::net::SocketAddr = "127.0.0.1:40000".parse()?;
+        tokio::spawn(handle_new_transport(config, store.clone(), client_addr, None, sink, stream));
+        tokio::time::timeout(std::time::Duration::from_secs(2), sent.next()).await?.expect("no server hello");
+
+        let (client_id, token_subject) = match next_event(&mut events).await? {
+            AuditEvent::Connect { client_id, token_subject, peer_addr, .. } => {
+                assert_eq!(peer_addr, client_addr);
+                (client_id, token_subject)
+            }
+            event => panic!("expected connect, got {:?}", event),
+        };
+        assert!(matches!(
+            next_event(&mut events).await?,
+            AuditEvent::PortAllocated { client_id: cid, protocol: Protocol::TCP, remote_port: 10068, .. } if cid == client_id
+        ));
+
+        let peer = tokio::net::TcpStream::connect("127.0.0.1:10068").await?;
+        let peer_addr = peer.local_addr()?;
+        let stream_id = match next_event(&mut events).await? {
+            AuditEvent::StreamOpen { client_id: cid, stream_id, token_subject: ref subject, peer_addr: addr } if cid == client_id && *subject == token_subject && addr == peer_addr => stream_id,
+            event => panic!("expected stream open, got {:?}", event),
+        };
+        drop(peer);
+        assert_eq!(next_event(&mut events).await?, AuditEvent::StreamClose { client_id, stream_id, token_subject: token_subject.clone(), peer_addr: Some(peer_addr) });
+
+        // the client goes away
+        drop(incoming);
+        assert_eq!(next_event(&mut events).await?, AuditEvent::Disconnect { client_id, token_subject, peer_addr: Some(client_addr) });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_token_labels_of_clients() -> Result<(), Box<dyn std::error::Error>> {
+        let config = get_config();
+        let labels = HashMap::from([("account_id".to_string(), "1234".to_string()), ("plan".to_string(), "pro".to_string())]);
+        let verifier = StubVerifier(TokenClaims {
+            subject: Some("alice".to_string()),
+            labels: labels.clone(),
+            ..Default::default()
+        });
+        let store = Arc::new(with_admin_token(Store::new(10070..10071).with_token_verifier(Arc::new(verifier)).with_events(16)));
+        let mut events = store.subscribe().expect("events are enabled");
+
+        let (sink, mut sent) = futures::channel::mpsc::unbounded::<Message>();
+        let stream = futures::stream::iter(vec![Ok::<_, Infallible>(Message::binary(client_hello_with_token("valid", get_endpoint_claims_single())))]).chain(futures::stream::pending());
+        tokio::spawn(handle_new_transport(config, store.clone(), "127.0.0.1:40000".parse()?, None, sink, stream));
+        tokio::time::timeout(std::time::Duration::from_secs(2), sent.next()).await?.expect("no server hello");
+
+        match next_event(&mut events).await? {
+            AuditEvent::Connect { labels: event_labels, .. } => assert_eq!(event_labels, labels),
+            event => panic!("expected connect, got {:?}", event),
+        }
+
+        let response = admin_get("/admin/clients").reply(&admin_clients(store.clone())).await;
+        let clients: serde_json::Value = serde_json::from_slice(response.body())?;
+        assert_eq!(clients[0]["subject"], "alice");
+        assert_eq!(clients[0]["labels"], serde_json::json!({ "account_id": "1234", "plan": "pro" }));
+        assert_eq!(clients[0]["remote_ports"], serde_json::json!([10070]));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod admin_status_test {
+    use super::*;
+    use crate::test_support::{admin_get, client, with_admin_token};
+
+    async fn get_status(store: &Arc<Store>) -> Result<serde_json::Value, serde_json::Error> {
+        let response = admin_get("/admin/status").reply(&admin_status(store.clone())).await;
+        serde_json::from_slice(response.body())
+    }
+
+    #[tokio::test]
+    async fn report_drain_progress() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(with_admin_token(Store::default()));
+        let (first, _first_rx) = client(store.clone());
+        let (second, _second_rx) = client(store.clone());
+        let first_id = first.client_id;
+        store.add_client(first).await;
+        store.add_client(second).await;
+
+        let status = get_status(&store).await?;
+        assert_eq!(status["state"], "running");
+        assert_eq!(status["clients"], 2);
+        assert!(status["drain_elapsed_secs"].is_null());
+        assert!(status["compression"]["bytes_in"].is_u64());
+
+        store.start_drain();
+        let status = get_status(&store).await?;
+        assert_eq!(status["state"], "draining");
+        assert_eq!(status["clients"], 2);
+        assert!(status["drain_elapsed_secs"].as_f64().is_some());
+
+        store.disable_client(first_id).await;
+        store.cleanup().await;
+        let status = get_status(&store).await?;
+        assert_eq!(status["state"], "draining");
+        assert_eq!(status["clients"], 1);
+        assert_eq!(status["streams"], 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod admin_drain_port_test {
+    use super::*;
+    use crate::test_support::{admin_post, next_init, next_packet, serve_tcp_client, with_admin_token};
+    use ownserver_lib::{ControlPacketV2, EndpointClaim};
+    use rand::thread_rng;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn refuse_new_connections_to_drained_port_only() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(with_admin_token(Store::new(10040..10050)));
+        let claims = vec![
+            EndpointClaim { protocol: Protocol::TCP, local_port: 3000, remote_port: 0 },
+            EndpointClaim { protocol: Protocol::TCP, local_port: 3001, remote_port: 0 },
+        ];
+        let endpoints = store.allocate_endpoints_for(&mut thread_rng(), None, None, claims).await?;
+        let (drained, other) = ((endpoints[0].id, endpoints[0].remote_port), (endpoints[1].id, endpoints[1].remote_port));
+        let ct = CancellationToken::new();
+        let (_, mut sent) = serve_tcp_client(&store, endpoints, &ct).await?;
+
+        let mut open = TcpStream::connect(("127.0.0.1", drained.1)).await?;
+        let (stream_id, eid) = next_init(&mut sent).await?;
+        assert_eq!(eid, drained.0);
+
+        let response = admin_post(&format!("/admin/ports/{}/drain", drained.1))
+            .reply(&admin_drain_port(store.clone()))
+            .await;
+        assert_eq!(response.status(), 200);
+        let status: serde_json::Value = serde_json::from_slice(response.body())?;
+        assert_eq!(status["port"], drained.1);
+        assert_eq!(status["streams"], 1);
+
+        // the listener stays open, connections to it are closed right away
+        let mut refused = TcpStream::connect(("127.0.0.1", drained.1)).await?;
+        let mut buf = [0; 1];
+        let n = tokio::time::timeout(Duration::from_secs(2), refused.read(&mut buf)).await?.unwrap_or(0);
+        assert_eq!(n, 0);
+
+        open.write_all(b"still open").await?;
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Data(stream_id, b"still open".to_vec()));
+
+        let _other_peer = TcpStream::connect(("127.0.0.1", other.1)).await?;
+        assert_eq!(next_init(&mut sent).await?.1, other.0);
+
+        let response = admin_post("/admin/ports/10050/drain")
+            .reply(&admin_drain_port(store.clone()))
+            .await;
+        assert_eq!(response.status(), 404);
+        ct.cancel();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod admin_pause_client_test {
+    use super::*;
+    use crate::test_support::{admin_post, next_init, next_packet, serve_tcp_client, with_admin_token};
+    use ownserver_lib::{ControlPacketV2, EndpointClaim};
+    use rand::thread_rng;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+    use tokio_util::sync::CancellationToken;
+
+    async fn post(store: &Arc<Store>, path: String) -> u16 {
+        let response = admin_post(&path).reply(&admin_pause_client(store.clone())).await;
+        response.status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn refuse_new_connections_while_paused() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(with_admin_token(Store::new(10090..10091)));
+        let claims = vec![EndpointClaim { protocol: Protocol::TCP, local_port: 3000, remote_port: 0 }];
+        let endpoints = store.allocate_endpoints(&mut thread_rng(), claims).await?;
+        let (endpoint_id, port) = (endpoints[0].id, endpoints[0].remote_port);
+        let ct = CancellationToken::new();
+        let (client_id, mut sent) = serve_tcp_client(&store, endpoints, &ct).await?;
+
+        let mut open = TcpStream::connect(("127.0.0.1", port)).await?;
+        let (stream_id, _) = next_init(&mut sent).await?;
+
+        assert_eq!(post(&store, format!("/admin/clients/{}/pause", client_id)).await, 200);
+        assert!(store.client_statuses().await[0].paused);
+        let mut refused = TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut buf = [0; 1];
+        let n = tokio::time::timeout(Duration::from_secs(2), refused.read(&mut buf)).await?.unwrap_or(0);
+        assert_eq!(n, 0);
+
+        // the open stream goes on while paused
+        open.write_all(b"still open").await?;
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Data(stream_id, b"still open".to_vec()));
+
+        assert_eq!(post(&store, format!("/admin/clients/{}/resume", client_id)).await, 200);
+        let _accepted = TcpStream::connect(("127.0.0.1", port)).await?;
+        assert!(matches!(next_packet(&mut sent).await?, ControlPacketV2::Init(_, eid) if eid == endpoint_id));
+
+        assert_eq!(post(&store, format!("/admin/clients/{}/pause", ClientId::new())).await, 404);
+        ct.cancel();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod admin_ports_test {
+    use super::*;
+    use crate::test_support::{admin_get, admin_post, client_with, with_admin_token};
+    use ownserver_lib::{ClientId, EndpointClaim};
+    use rand::thread_rng;
+
+    fn claims(local_port: u16) -> EndpointClaims {
+        vec![EndpointClaim { protocol: Protocol::TCP, local_port, remote_port: 0 }]
+    }
+
+    async fn release(store: &Arc<Store>, port: u16) -> u16 {
+        let response = admin_post(&format!("/admin/ports/{}/release", port))
+            .reply(&admin_release_port(store.clone()))
+            .await;
+        response.status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn list_and_release_orphaned_port() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(with_admin_token(Store::new(1000..1010).with_deterministic_ports()));
+        let endpoints = store.allocate_endpoints(&mut thread_rng(), claims(25565)).await?;
+        let (client, _rx) = client_with(store.clone(), endpoints, Default::default());
+        let client_id = client.client_id;
+        store.add_client(client).await;
+        // allocated by a client that crashed before registering
+        store.allocate_endpoints(&mut thread_rng(), claims(25566)).await?;
+
+        let response = admin_get("/admin/ports").reply(&admin_ports(store.clone())).await;
+        let ports: serde_json::Value = serde_json::from_slice(response.body())?;
+        assert_eq!(ports, serde_json::json!([
+            { "port": 1000, "pool": "default", "client_id": client_id, "state": "in_use" },
+            { "port": 1001, "pool": "default", "client_id": null, "state": "orphaned" },
+        ]));
+
+        assert_eq!(release(&store, 1000).await, 409);
+        assert_eq!(release(&store, 1001).await, 200);
+        assert_eq!(release(&store, 1001).await, 404);
+        assert_eq!(store.port_statuses().await.len(), 1);
+        assert_eq!(store.allocate_port(&mut thread_rng()).await?, 1001);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_ports_of_clients_in_handshake() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(with_admin_token(Store::new(1010..1020).with_deterministic_ports()));
+        let endpoints = store.allocate_endpoints(&mut thread_rng(), claims(25565)).await?;
+        let client_id = ClientId::new();
+        let handshake = store.track_handshake(client_id, &endpoints);
+
+        let response = admin_get("/admin/ports").reply(&admin_ports(store.clone())).await;
+        let ports: serde_json::Value = serde_json::from_slice(response.body())?;
+        assert_eq!(ports, serde_json::json!([{ "port": 1010, "pool": "default", "client_id": client_id, "state": "connecting" }]));
+        assert_eq!(release(&store, 1010).await, 409);
+
+        // the client never registered
+        drop(handshake);
+        assert_eq!(release(&store, 1010).await, 200);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod admin_close_peer_test {
+    use super::*;
+    use crate::test_support::{add_udp_stream, admin_post, client, next_packet, with_admin_token};
+    use ownserver_lib::ControlPacketV2;
+
+    #[tokio::test]
+    async fn remove_udp_peer_and_end_its_stream() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(with_admin_token(Store::default()));
+        let (client, mut sent) = client(store.clone());
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let peer_addr: SocketAddr = "127.0.0.1:40000".parse()?;
+        let stream_id = add_udp_stream(&store, client_id, peer_addr).await?;
+        assert_eq!(store.find_stream_id_by_addr(&peer_addr).await, Some(stream_id));
+
+        let response = admin_post("/admin/peers/close?addr=127.0.0.1:40000")
+            .reply(&admin_close_peer(store.clone()))
+            .await;
+        assert_eq!(response.status(), 200);
+        let closed: serde_json::Value = serde_json::from_slice(response.body())?;
+        assert_eq!(closed["stream_id"], serde_json::to_value(stream_id)?);
+
+        assert_eq!(store.find_stream_id_by_addr(&peer_addr).await, None);
+        assert_eq!(store.len_streams().await, 0);
+        assert!(store.get_stream_ids().await.is_empty());
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::End(stream_id));
+
+        // the peer is gone already
+        let response = admin_post("/admin/peers/close?addr=127.0.0.1:40000")
+            .reply(&admin_close_peer(store.clone()))
+            .await;
+        assert_eq!(response.status(), 404);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod admin_auth_test {
+    use super::*;
+    use crate::admin::AdminTokens;
+    use crate::test_support::{add_udp_stream, client};
+
+    async fn list(store: &Arc<Store>, token: &str) -> u16 {
+        let response = warp::test::request()
+            .path("/admin/clients")
+            .header("authorization", format!("Bearer {}", token))
+            .reply(&admin_clients(store.clone()))
+            .await;
+        response.status().as_u16()
+    }
+
+    async fn close(store: &Arc<Store>, token: Option<&str>) -> u16 {
+        let mut request = warp::test::request().method("POST").path("/admin/peers/close?addr=127.0.0.1:40000");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        request.reply(&admin_close_peer(store.clone())).await.status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn readonly_token_lists_but_never_closes() -> Result<(), Box<dyn std::error::Error>> {
+        let tokens = AdminTokens::new(Some("full".to_string()), Some("readonly".to_string()));
+        let store = Arc::new(Store::default().with_admin_tokens(tokens));
+        let (client, _sent) = client(store.clone());
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        add_udp_stream(&store, client_id, "127.0.0.1:40000".parse()?).await?;
+
+        assert_eq!(list(&store, "readonly").await, 200);
+        assert_eq!(list(&store, "wrong").await, 401);
+        assert_eq!(close(&store, Some("readonly")).await, 403);
+        assert_eq!(close(&store, None).await, 401);
+        assert_eq!(store.len_streams().await, 1);
+
+        assert_eq!(list(&store, "full").await, 200);
+        assert_eq!(close(&store, Some("full")).await, 200);
+        assert_eq!(store.len_streams().await, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_everything_without_admin_tokens() {
+        let store: Arc<Store> = Default::default();
+        assert_eq!(list(&store, "anything").await, 401);
+        assert_eq!(close(&store, None).await, 403);
+    }
+}
+
+#[cfg(test)]
+mod control_path_test {
+    use super::*;
+    use crate::{test_support::{client_hello_with_token, get_endpoint_claims_single}, verifier::NoAuthVerifier};
+
+    #[tokio::test]
+    async fn handshake_on_configured_path_only() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .token_secret("supersecret")
+            .host("foohost.test.local")
+            .control_path("/ownserver/tunnel")
+            .port_range(10032..10033)
+            .build()?;
+        let store = Arc::new(Store::new(10032..10033).with_token_verifier(Arc::new(NoAuthVerifier)));
+        let filter = control_channel(Arc::new(config), store.clone());
+
+        assert!(warp::test::ws().path("/tunnel").handshake(filter.clone()).await.is_err());
+
+        let mut client = warp::test::ws().path("/ownserver/tunnel").handshake(filter).await?;
+        client.send(Message::binary(client_hello_with_token("", get_endpoint_claims_single()))).await;
+        let message = tokio::time::timeout(Duration::from_secs(2), client.recv()).await??;
+        match serde_json::from_slice::<ServerHelloV2>(message.as_bytes())? {
+            ServerHelloV2::Success { endpoints, .. } => assert_eq!(endpoints[0].remote_port, 10032),
+            other => panic!("unexpected server hello {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod port_reservation_test {
+    use super::*;
+    use ownserver_lib::EndpointClaim;
+    use crate::store::ReservationToken;
+    use crate::test_support::{admin_post, with_admin_token};
+
+    fn config() -> Arc<Config> {
+        let config = Config::builder()
+            .token_secret("supersecret")
+            .host("foohost.test.local")
+            .port_range(10000..20000)
+            .build()
+            .expect("valid config");
+        Arc::new(config)
+    }
+
+    fn hello(reservation: Option<&ReservationToken>) -> Result<ValidatedClientHello, VerifyClientHandshakeError> {
+        Ok(ValidatedClientHello {
+            tier: None,
+            subject: None,
+            endpoint_claims: vec![EndpointClaim { protocol: Protocol::TCP, local_port: 25565, remote_port: 0 }],
+            capabilities: Vec::new(),
+            allowed_ports: None,
+            labels: HashMap::new(),
+            reservation: reservation.map(|token| token.as_str().to_string()),
+            version: CLIENT_HELLO_VERSION,
+        })
+    }
+
+    fn remote_port(server_hello: &ServerHelloV2) -> Option<u16> {
+        match server_hello {
+            ServerHelloV2::Success { endpoints, .. } => Some(endpoints[0].remote_port),
+            _ => None,
+        }
+    }
+
+    fn rejected_for(server_hello: &ServerHelloV2) -> Option<&CloseReason> {
+        match server_hello {
+            ServerHelloV2::Rejected { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn assign_reserved_port_to_its_token_only() -> Result<(), Box<dyn std::error::Error>> {
+        let config = config();
+        let store = Arc::new(Store::new(10080..10082));
+        let token = store.reserve_port(10081, Duration::from_secs(60)).await?;
+        assert_eq!(store.reserve_port(10081, Duration::from_secs(60)).await, Err(PortAllocatorError::PortUnavailable(10081)));
+
+        let other = process_client_claims(&config, store.clone(), hello(None)).await;
+        assert_eq!(remote_port(&other), Some(10080));
+        let full = process_client_claims(&config, store.clone(), hello(None)).await;
+        assert_eq!(rejected_for(&full), Some(&CloseReason::NoPortsAvailable));
+
+        let reserved = process_client_claims(&config, store.clone(), hello(Some(&token))).await;
+        assert_eq!(remote_port(&reserved), Some(10081));
+        // used up by the first client
+        let again = process_client_claims(&config, store.clone(), hello(Some(&token))).await;
+        assert_eq!(rejected_for(&again), Some(&CloseReason::ReservationInvalid));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_reservation_the_client_cannot_get() -> Result<(), Box<dyn std::error::Error>> {
+        let config = config();
+        let store = Arc::new(Store::new(10111..10113));
+        let token = store.reserve_port(10112, Duration::from_secs(60)).await?;
+        let mut limited = hello(Some(&token))?;
+        limited.allowed_ports = Some(vec![10111]);
+
+        let refused = process_client_claims(&config, store.clone(), Ok(limited)).await;
+        assert_eq!(rejected_for(&refused), Some(&CloseReason::ReservationInvalid));
+        // neither port is handed out and the reservation is kept
+        let reserved = process_client_claims(&config, store.clone(), hello(Some(&token))).await;
+        assert_eq!(remote_port(&reserved), Some(10112));
+        let other = process_client_claims(&config, store.clone(), hello(None)).await;
+        assert_eq!(remote_port(&other), Some(10111));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_reservation_ttl_over_a_day() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(with_admin_token(Store::new(10113..10114)));
+        let response = admin_post(&format!("/admin/ports/10113/reserve?ttl_secs={}", u64::MAX))
+            .reply(&admin_reserve_port(store.clone()))
+            .await;
+        assert_eq!(response.status(), 400);
+
+        let response = admin_post("/admin/ports/10113/reserve?ttl_secs=60")
+            .reply(&admin_reserve_port(store.clone()))
+            .await;
+        assert_eq!(response.status(), 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn return_unclaimed_port_after_ttl() -> Result<(), Box<dyn std::error::Error>> {
+        let config = config();
+        let store = Arc::new(Store::new(10084..10085));
+        let token = store.reserve_port(10084, Duration::from_millis(50)).await?;
+        let full = process_client_claims(&config, store.clone(), hello(None)).await;
+        assert_eq!(rejected_for(&full), Some(&CloseReason::NoPortsAvailable));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.expire_port_reservations().await, 1);
+        let late = process_client_claims(&config, store.clone(), hello(Some(&token))).await;
+        assert_eq!(rejected_for(&late), Some(&CloseReason::ReservationInvalid));
+        let other = process_client_claims(&config, store.clone(), hello(None)).await;
+        assert_eq!(remote_port(&other), Some(10084));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod max_handshake_size_test {
+    use super::*;
+    use crate::{test_support::{client_hello_with_token, get_endpoint_claims_single}, verifier::NoAuthVerifier};
+
+    #[tokio::test]
+    async fn close_without_parsing_oversized_hello() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .token_secret("supersecret")
+            .host("foohost.test.local")
+            .port_range(10078..10079)
+            .build()?;
+        let store = Arc::new(Store::new(10078..10079).with_token_verifier(Arc::new(NoAuthVerifier)).with_max_handshake_size(128));
+        let filter = control_channel(Arc::new(config), store.clone());
+
+        let mut client = warp::test::ws().path("/tunnel").handshake(filter).await?;
+        client.send(Message::binary(client_hello_with_token(&"x".repeat(256), get_endpoint_claims_single()))).await;
+        let message = tokio::time::timeout(Duration::from_secs(2), client.recv()).await??;
+        assert_eq!(message.close_frame(), Some((1009, "client hello is too large")));
+
+        assert_eq!(store.len_clients().await, 0);
+        assert_eq!(store.allocate_port(&mut rand::thread_rng()).await, Ok(10078));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_oversized_messages_in_websocket_layer() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .token_secret("supersecret")
+            .host("foohost.test.local")
+            .port_range(10079..10080)
+            .build()?;
+        let store = Arc::new(Store::new(10079..10080).with_token_verifier(Arc::new(NoAuthVerifier)).with_max_handshake_size(128));
+        let filter = control_channel(Arc::new(config), store.clone());
+
+        let mut client = warp::test::ws().path("/tunnel").handshake(filter).await?;
+        client.send(Message::binary(vec![0; store.max_message_size() + 1])).await;
+        // the websocket layer fails the read as too long before `read_client_hello` sees it,
+        // so the connection is dropped without a close frame or a server hello
+        let received = tokio::time::timeout(Duration::from_secs(2), client.recv()).await?;
+        assert!(received.is_err(), "unexpected {:?}", received);
+        assert_eq!(store.len_clients().await, 0);
+        assert_eq!(store.allocate_port(&mut rand::thread_rng()).await, Ok(10079));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_hello_just_under_max_handshake_size() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .token_secret("supersecret")
+            .host("foohost.test.local")
+            .port_range(10094..10095)
+            .build()?;
+        let store = Arc::new(Store::new(10094..10095).with_token_verifier(Arc::new(NoAuthVerifier)).with_max_handshake_size(256));
+        let filter = control_channel(Arc::new(config), store.clone());
+
+        let padding = store.max_handshake_size() - 1 - client_hello_with_token("", get_endpoint_claims_single()).len();
+        let hello = client_hello_with_token(&"x".repeat(padding), get_endpoint_claims_single());
+        assert_eq!(hello.len(), store.max_handshake_size() - 1);
+
+        let mut client = warp::test::ws().path("/tunnel").handshake(filter).await?;
+        client.send(Message::binary(hello)).await;
+        let message = tokio::time::timeout(Duration::from_secs(2), client.recv()).await??;
+        match serde_json::from_slice::<ServerHelloV2>(message.as_bytes())? {
+            ServerHelloV2::Success { endpoints, .. } => assert_eq!(endpoints[0].remote_port, 10094),
+            other => panic!("unexpected server hello {:?}", other),
+        }
+        Ok(())
+    }
+}
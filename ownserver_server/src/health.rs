@@ -0,0 +1,102 @@
+use std::{sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::Duration};
+
+/// Round trip times up to this cost no health points.
+pub const GOOD_RTT: Duration = Duration::from_millis(100);
+/// Round trip times from this on cost all of `RTT_POINTS`.
+pub const BAD_RTT: Duration = Duration::from_millis(1000);
+const RTT_POINTS: f64 = 40.0;
+/// Taken in full when every send fails.
+const SEND_FAILURE_POINTS: f64 = 40.0;
+/// Taken per reconnect within `RECONNECT_WINDOW`, up to `MAX_RECONNECT_POINTS`.
+const RECONNECT_POINTS: f64 = 10.0;
+const MAX_RECONNECT_POINTS: f64 = 20.0;
+/// Reconnects of a token subject older than this no longer lower its health.
+pub const RECONNECT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Tunnel quality from 0 to 100, 100 being a healthy tunnel:
+/// `100 - rtt_points - 40 * send_failure_rate - 10 * reconnects (at most 20)`
+/// where `rtt_points` grows linearly from 0 at `GOOD_RTT` to 40 at `BAD_RTT`.
+/// A client that has not answered a heartbeat yet loses no rtt points.
+pub fn health_score(rtt: Option<Duration>, send_failure_rate: f64, reconnects: u32) -> u8 {
+    let rtt_points = match rtt {
+        Some(rtt) => {
+            let over = rtt.saturating_sub(GOOD_RTT).as_secs_f64() / (BAD_RTT - GOOD_RTT).as_secs_f64();
+            over.min(1.0) * RTT_POINTS
+        }
+        None => 0.0,
+    };
+    let failure_points = send_failure_rate.clamp(0.0, 1.0) * SEND_FAILURE_POINTS;
+    let reconnect_points = (reconnects as f64 * RECONNECT_POINTS).min(MAX_RECONNECT_POINTS);
+    (100.0 - rtt_points - failure_points - reconnect_points).round().clamp(0.0, 100.0) as u8
+}
+
+/// Send results and heartbeat round trip of a client, scored by `health_score`.
+/// Shared with the senders of the client, so it is updated without the store's client lock.
+#[derive(Debug, Default)]
+pub struct ClientHealth {
+    sends: AtomicU64,
+    failed_sends: AtomicU64,
+    // latest heartbeat round trip
+    rtt: Mutex<Option<Duration>>,
+}
+
+impl ClientHealth {
+    pub fn record_success(&self) {
+        self.sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        self.sends.fetch_add(1, Ordering::Relaxed);
+        self.failed_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rtt(&self, rtt: Duration) {
+        *self.rtt.lock().unwrap() = Some(rtt);
+    }
+
+    /// Share of sends to the client that failed since it connected.
+    pub fn send_failure_rate(&self) -> f64 {
+        match self.sends.load(Ordering::Relaxed) {
+            0 => 0.0,
+            sends => self.failed_sends.load(Ordering::Relaxed) as f64 / sends as f64,
+        }
+    }
+
+    /// See `health_score`.
+    pub fn health(&self, reconnects: u32) -> u8 {
+        health_score(*self.rtt.lock().unwrap(), self.send_failure_rate(), reconnects)
+    }
+}
+
+#[cfg(test)]
+mod client_health_test {
+    use super::*;
+
+    #[test]
+    fn score_tunnel_quality() {
+        assert_eq!(health_score(None, 0.0, 0), 100);
+        assert_eq!(health_score(Some(GOOD_RTT), 0.0, 0), 100);
+        assert_eq!(health_score(Some(Duration::from_millis(550)), 0.0, 0), 80);
+        assert_eq!(health_score(Some(Duration::from_secs(5)), 0.0, 0), 60);
+        assert_eq!(health_score(None, 0.5, 0), 80);
+        assert_eq!(health_score(None, 0.0, 1), 90);
+        assert_eq!(health_score(None, 0.0, 5), 80);
+        assert_eq!(health_score(Some(Duration::from_secs(5)), 1.0, 5), 0);
+    }
+
+    #[test]
+    fn degrade_with_failed_sends_and_slow_heartbeats() {
+        let health = ClientHealth::default();
+        health.record_success();
+        health.record_rtt(Duration::from_millis(20));
+        assert_eq!(health.health(0), 100);
+
+        health.record_failure();
+        assert_eq!(health.send_failure_rate(), 0.5);
+        assert_eq!(health.health(0), 80);
+
+        health.record_rtt(Duration::from_millis(550));
+        assert_eq!(health.health(0), 60);
+        assert_eq!(health.health(1), 50);
+    }
+}
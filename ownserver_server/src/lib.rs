@@ -1,25 +1,342 @@
+use std::ops::Range;
+
 use futures::channel::mpsc::SendError;
 use ownserver_lib::{ClientId, StreamId};
 use thiserror::Error;
 
+pub mod accept;
+pub mod access;
+pub mod admin;
+pub mod audit;
+pub mod cleanup;
 pub mod client;
-pub use client::Client;
+pub use client::{Client, ClientHandle};
+pub mod compression;
 pub mod control_server_v2;
+pub mod control_server_h2;
 pub mod remote;
+pub mod state;
 pub mod proxy_server;
+pub mod health;
+pub mod listener;
+pub mod logging;
+pub mod metrics_push;
+pub mod mirror;
+pub mod packet_filter;
+pub mod peer_index;
 pub mod port_allocator;
+pub mod verifier;
+pub mod quota;
+pub mod rate_limit;
 pub mod store;
+pub mod telemetry;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod tls;
 pub use store::Store;
 
+/// Built by `Config::builder()`, fields are added without a major version.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct Config {
     pub control_port: u16,
+    /// accept control channels over HTTP/2 CONNECT on this port as well
+    pub h2_control_port: Option<u16>,
     pub token_secret: String,
     pub host: String,
     pub remote_port_start: u16,
     pub remote_port_end: u16,
+    /// base seconds between cleanups, adapted by `cleanup::CleanupSchedule`
     pub periodic_cleanup_interval: u64,
     pub periodic_ping_interval: u64,
+    /// messages queued per client before `send_to_client` waits
+    pub client_send_buffer: usize,
+    /// seconds a full client queue is tolerated before the client is dropped
+    pub client_send_timeout: u64,
+    /// seconds a remote tcp peer may stay silent before its stream is closed
+    pub read_timeout: Option<u64>,
+    /// seconds a write to a remote tcp peer may take before its stream is closed
+    pub write_timeout: Option<u64>,
+    /// bytes a client may forward in either direction per quota window, unlimited when `None`
+    pub client_quota_bytes: Option<u64>,
+    /// seconds of the rolling window `client_quota_bytes` applies to
+    pub client_quota_window: u64,
+    /// what to do when a token subject connects twice
+    pub on_duplicate: store::DuplicatePolicy,
+    /// clients connected at once before new ones are turned away, unlimited when `None`
+    pub max_clients: Option<usize>,
+    /// set TCP_NODELAY on remote tcp sockets
+    pub nodelay: bool,
+    /// seconds a remote tcp socket may idle before keepalive probes are sent, no keepalive when `None`
+    pub tcp_keepalive: Option<u64>,
+    /// host advertised to clients for players to connect to, `host` when `None`
+    pub public_host: Option<String>,
+    /// also accept players on IPv6 at every remote port
+    pub enable_ipv6: bool,
+    /// seconds after connecting a client is disconnected regardless of activity, unlimited when `None`
+    pub max_session_duration: Option<u64>,
+    /// packet types accepted from clients, every type when `None`
+    pub allowed_packets: Option<Vec<packet_filter::PacketKind>>,
+    /// disallowed packets tolerated from a client before it is disconnected
+    pub max_packet_violations: u32,
+    /// peek the first bytes of remote tcp connections to tell http from raw tcp
+    pub sniff_http: bool,
+    /// written to every new remote tcp connection before it is relayed, nothing when `None`
+    pub remote_banner: Option<Vec<u8>>,
+    /// undecodable packets in a row tolerated from a client before it is disconnected
+    pub max_decode_errors: u32,
+    /// pending connections each remote tcp listener queues
+    pub remote_backlog: u32,
+    /// path of the WebSocket control endpoint, e.g. `/tunnel`
+    pub control_path: String,
+}
+
+/// Config taken by `proxy_server::run_with_config`.
+pub type ServerConfig = Config;
+
+impl Config {
+    /// Defaults match the command line defaults of `ownserver-server`.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    control_port: u16,
+    h2_control_port: Option<u16>,
+    token_secret: Option<String>,
+    host: Option<String>,
+    port_range: Option<Range<u16>>,
+    periodic_cleanup_interval: u64,
+    periodic_ping_interval: u64,
+    client_send_buffer: usize,
+    client_send_timeout: u64,
+    read_timeout: Option<u64>,
+    write_timeout: Option<u64>,
+    client_quota_bytes: Option<u64>,
+    client_quota_window: u64,
+    on_duplicate: store::DuplicatePolicy,
+    max_clients: Option<usize>,
+    nodelay: bool,
+    tcp_keepalive: Option<u64>,
+    public_host: Option<String>,
+    enable_ipv6: bool,
+    max_session_duration: Option<u64>,
+    allowed_packets: Option<Vec<packet_filter::PacketKind>>,
+    max_packet_violations: u32,
+    sniff_http: bool,
+    remote_banner: Option<Vec<u8>>,
+    max_decode_errors: u32,
+    remote_backlog: u32,
+    control_path: String,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self {
+            control_port: 5000,
+            h2_control_port: None,
+            token_secret: None,
+            host: None,
+            port_range: None,
+            periodic_cleanup_interval: 15,
+            periodic_ping_interval: 15,
+            client_send_buffer: 256,
+            client_send_timeout: 10,
+            read_timeout: None,
+            write_timeout: None,
+            client_quota_bytes: None,
+            client_quota_window: 3600,
+            on_duplicate: store::DuplicatePolicy::default(),
+            max_clients: None,
+            nodelay: true,
+            tcp_keepalive: None,
+            public_host: None,
+            enable_ipv6: false,
+            max_session_duration: None,
+            allowed_packets: None,
+            max_packet_violations: packet_filter::DEFAULT_MAX_PACKET_VIOLATIONS,
+            sniff_http: false,
+            remote_banner: None,
+            max_decode_errors: client::DEFAULT_MAX_DECODE_ERRORS,
+            remote_backlog: remote::DEFAULT_BACKLOG,
+            control_path: ownserver_lib::DEFAULT_CONTROL_PATH.to_string(),
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn control_port(mut self, port: u16) -> Self {
+        self.control_port = port;
+        self
+    }
+
+    pub fn h2_control_port(mut self, port: impl Into<Option<u16>>) -> Self {
+        self.h2_control_port = port.into();
+        self
+    }
+
+    pub fn token_secret(mut self, token_secret: impl Into<String>) -> Self {
+        self.token_secret = Some(token_secret.into());
+        self
+    }
+
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Remote ports of the default pool, required.
+    pub fn port_range(mut self, range: Range<u16>) -> Self {
+        self.port_range = Some(range);
+        self
+    }
+
+    pub fn periodic_cleanup_interval(mut self, seconds: u64) -> Self {
+        self.periodic_cleanup_interval = seconds;
+        self
+    }
+
+    pub fn periodic_ping_interval(mut self, seconds: u64) -> Self {
+        self.periodic_ping_interval = seconds;
+        self
+    }
+
+    pub fn client_send_buffer(mut self, messages: usize) -> Self {
+        self.client_send_buffer = messages;
+        self
+    }
+
+    pub fn client_send_timeout(mut self, seconds: u64) -> Self {
+        self.client_send_timeout = seconds;
+        self
+    }
+
+    pub fn read_timeout(mut self, seconds: impl Into<Option<u64>>) -> Self {
+        self.read_timeout = seconds.into();
+        self
+    }
+
+    pub fn write_timeout(mut self, seconds: impl Into<Option<u64>>) -> Self {
+        self.write_timeout = seconds.into();
+        self
+    }
+
+    /// Allow `bytes` per client within every `window_seconds`.
+    pub fn client_quota(mut self, bytes: u64, window_seconds: u64) -> Self {
+        self.client_quota_bytes = Some(bytes);
+        self.client_quota_window = window_seconds;
+        self
+    }
+
+    pub fn on_duplicate(mut self, policy: store::DuplicatePolicy) -> Self {
+        self.on_duplicate = policy;
+        self
+    }
+
+    pub fn max_clients(mut self, max_clients: impl Into<Option<usize>>) -> Self {
+        self.max_clients = max_clients.into();
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, seconds: impl Into<Option<u64>>) -> Self {
+        self.tcp_keepalive = seconds.into();
+        self
+    }
+
+    pub fn public_host(mut self, public_host: impl Into<String>) -> Self {
+        self.public_host = Some(public_host.into());
+        self
+    }
+
+    pub fn enable_ipv6(mut self, enable_ipv6: bool) -> Self {
+        self.enable_ipv6 = enable_ipv6;
+        self
+    }
+
+    pub fn max_session_duration(mut self, seconds: impl Into<Option<u64>>) -> Self {
+        self.max_session_duration = seconds.into();
+        self
+    }
+
+    pub fn allowed_packets(mut self, kinds: impl IntoIterator<Item = packet_filter::PacketKind>) -> Self {
+        self.allowed_packets = Some(kinds.into_iter().collect());
+        self
+    }
+
+    pub fn max_packet_violations(mut self, violations: u32) -> Self {
+        self.max_packet_violations = violations;
+        self
+    }
+
+    pub fn sniff_http(mut self, sniff_http: bool) -> Self {
+        self.sniff_http = sniff_http;
+        self
+    }
+
+    /// An empty banner is the same as none.
+    pub fn remote_banner(mut self, banner: Vec<u8>) -> Self {
+        self.remote_banner = Some(banner).filter(|banner| !banner.is_empty());
+        self
+    }
+
+    pub fn max_decode_errors(mut self, errors: u32) -> Self {
+        self.max_decode_errors = errors;
+        self
+    }
+
+    pub fn remote_backlog(mut self, backlog: u32) -> Self {
+        self.remote_backlog = backlog;
+        self
+    }
+
+    pub fn control_path(mut self, path: impl Into<String>) -> Self {
+        self.control_path = path.into();
+        self
+    }
+
+    /// Fails when `token_secret`, `host` or `port_range` is not set, they have no sensible default,
+    /// or when `control_path` does not start with `/`.
+    pub fn build(self) -> Result<Config, ProxyServerError> {
+        let control_path = ownserver_lib::parse_control_path(&self.control_path).map_err(ProxyServerError::InvalidConfig)?;
+        let port_range = self.port_range.ok_or(ProxyServerError::MissingConfig("port_range"))?;
+        Ok(Config {
+            control_port: self.control_port,
+            h2_control_port: self.h2_control_port,
+            token_secret: self.token_secret.ok_or(ProxyServerError::MissingConfig("token_secret"))?,
+            host: self.host.ok_or(ProxyServerError::MissingConfig("host"))?,
+            remote_port_start: port_range.start,
+            remote_port_end: port_range.end,
+            periodic_cleanup_interval: self.periodic_cleanup_interval,
+            periodic_ping_interval: self.periodic_ping_interval,
+            client_send_buffer: self.client_send_buffer,
+            client_send_timeout: self.client_send_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            client_quota_bytes: self.client_quota_bytes,
+            client_quota_window: self.client_quota_window,
+            on_duplicate: self.on_duplicate,
+            max_clients: self.max_clients,
+            nodelay: self.nodelay,
+            tcp_keepalive: self.tcp_keepalive,
+            public_host: self.public_host,
+            enable_ipv6: self.enable_ipv6,
+            max_session_duration: self.max_session_duration,
+            allowed_packets: self.allowed_packets,
+            max_packet_violations: self.max_packet_violations,
+            sniff_http: self.sniff_http,
+            remote_banner: self.remote_banner,
+            max_decode_errors: self.max_decode_errors,
+            remote_backlog: self.remote_backlog,
+            control_path,
+        })
+    }
 }
 
 
@@ -27,6 +344,10 @@ pub struct Config {
 pub enum ProxyServerError {
     #[error("Failed to load config because it is not initialized.")]
     ConfigNotInitialized,
+    #[error("Config {0} is required.")]
+    MissingConfig(&'static str),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
 }
 
 #[derive(Error, Debug, PartialEq)]
@@ -47,7 +368,80 @@ pub enum ClientStreamError {
     ClientNotAvailable(ClientId),
     #[error("Stream {0} is not registered to Store or no longer available.")]
     StreamNotAvailable(StreamId),
+    #[error("Stream {0} has been closed.")]
+    StreamDisabled(StreamId),
     #[error("Remote stream has closed.")]
     RemoteEnd,
+    #[error("Client queue stayed full, stream {0} could not forward its data.")]
+    Congested(StreamId),
 }
 
+#[cfg(test)]
+mod config_builder_test {
+    use super::*;
+    use crate::store::DuplicatePolicy;
+
+    #[test]
+    fn build_with_defaults() -> Result<(), ProxyServerError> {
+        let config = ServerConfig::builder()
+            .token_secret("supersecret")
+            .host("localhost")
+            .port_range(20000..30000)
+            .build()?;
+
+        assert_eq!(config.control_port, 5000);
+        assert_eq!(config.h2_control_port, None);
+        assert_eq!((config.remote_port_start, config.remote_port_end), (20000, 30000));
+        assert_eq!(config.periodic_cleanup_interval, 15);
+        assert_eq!(config.client_send_buffer, 256);
+        assert_eq!(config.client_quota_bytes, None);
+        assert_eq!(config.on_duplicate, DuplicatePolicy::Reject);
+        assert_eq!(config.max_clients, None);
+        assert!(config.nodelay);
+        assert_eq!(config.tcp_keepalive, None);
+        assert_eq!(config.control_path, "/tunnel");
+        Ok(())
+    }
+
+    #[test]
+    fn build_with_overrides() -> Result<(), ProxyServerError> {
+        let config = ServerConfig::builder()
+            .token_secret("supersecret")
+            .host("example.com")
+            .control_port(6000)
+            .h2_control_port(6001)
+            .port_range(4500..4600)
+            .client_quota(1024, 60)
+            .on_duplicate(DuplicatePolicy::Replace)
+            .max_clients(8)
+            .build()?;
+
+        assert_eq!(config.host, "example.com");
+        assert_eq!(config.control_port, 6000);
+        assert_eq!(config.h2_control_port, Some(6001));
+        assert_eq!((config.remote_port_start, config.remote_port_end), (4500, 4600));
+        assert_eq!((config.client_quota_bytes, config.client_quota_window), (Some(1024), 60));
+        assert_eq!(config.on_duplicate, DuplicatePolicy::Replace);
+        assert_eq!(config.max_clients, Some(8));
+        Ok(())
+    }
+
+    #[test]
+    fn require_token_secret_host_and_port_range() {
+        let err = ServerConfig::builder().host("localhost").port_range(20000..30000).build().unwrap_err();
+        assert_eq!(err, ProxyServerError::MissingConfig("token_secret"));
+
+        let err = ServerConfig::builder().token_secret("supersecret").port_range(20000..30000).build().unwrap_err();
+        assert_eq!(err, ProxyServerError::MissingConfig("host"));
+
+        let err = ServerConfig::builder().token_secret("supersecret").host("localhost").build().unwrap_err();
+        assert_eq!(err, ProxyServerError::MissingConfig("port_range"));
+    }
+
+    #[test]
+    fn require_control_path_to_start_with_slash() {
+        let builder = ServerConfig::builder().token_secret("supersecret").host("localhost").port_range(20000..30000);
+        assert!(matches!(builder.clone().control_path("tunnel").build(), Err(ProxyServerError::InvalidConfig(_))));
+        assert_eq!(builder.control_path("/ownserver/tunnel").build().map(|config| config.control_path), Ok("/ownserver/tunnel".to_string()));
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use hyper::{server::conn::Http, service::{service_fn, Service}, Body, Request, Response};
+use once_cell::sync::OnceCell;
+use rustls::ServerConfig;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
+
+use crate::{accept::accept_with_backoff, tls};
+
+#[derive(Error, Debug)]
+pub enum ListenerError {
+    #[error("The control server has not started yet.")]
+    NotStarted,
+
+    #[error("Control port {0} is already accepting clients.")]
+    AlreadyListening(u16),
+
+    #[error("Control port {0} is not served.")]
+    UnknownPort(u16),
+
+    #[error("Control port {0} is the last one accepting clients.")]
+    LastListener(u16),
+
+    #[error("Failed to bind control port {0}: {1}")]
+    Bind(u16, io::Error),
+}
+
+/// Inserted into every plain request served by `serve`, `tls::TlsPeer` takes its place over TLS.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListenerStatus {
+    pub port: u16,
+    /// `accepting`, `draining` or `drained`
+    pub state: &'static str,
+    /// connections accepted on the port that are still open, websockets included
+    pub connections: usize,
+}
+
+type ServeFn = Box<dyn Fn(TcpListener, watch::Receiver<bool>, Arc<AtomicUsize>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+struct Listener {
+    drain: watch::Sender<bool>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Listener {
+    fn status(&self, port: u16) -> ListenerStatus {
+        let connections = self.connections.load(Ordering::Relaxed);
+        let state = match (*self.drain.borrow(), connections) {
+            (false, _) => "accepting",
+            (true, 0) => "drained",
+            (true, _) => "draining",
+        };
+        ListenerStatus { port, state, connections }
+    }
+
+    fn accepting(&self) -> bool {
+        !*self.drain.borrow()
+    }
+}
+
+/// Control ports of `proxy_server::run`. Another port can be started and an old one drained at runtime,
+/// so clients move over as they reconnect, e.g. behind a load balancer during a rolling upgrade.
+/// A drained port stops accepting, its clients stay connected until they leave.
+#[derive(Default)]
+pub struct ControlListeners {
+    listeners: Mutex<BTreeMap<u16, Listener>>,
+    serve: OnceCell<ServeFn>,
+}
+
+impl std::fmt::Debug for ControlListeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ControlListeners").field("ports", &self.statuses()).finish()
+    }
+}
+
+impl ControlListeners {
+    /// Serve every listener with `service`, over TLS when `tls_config` is set. Later calls are ignored.
+    pub fn set_service<S>(&self, service: S, tls_config: Option<Arc<ServerConfig>>)
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + Sync + 'static,
+        S::Future: Send + 'static,
+    {
+        let acceptor = tls_config.map(TlsAcceptor::from);
+        let _ = self.serve.set(Box::new(move |listener, drain, connections| {
+            Box::pin(serve(service.clone(), listener, acceptor.clone(), drain, connections))
+        }));
+    }
+
+    /// Register `listener` and return the future serving it until the port is drained.
+    pub fn listen(&self, listener: TcpListener) -> Result<BoxFuture<'static, ()>, ListenerError> {
+        let serve = self.serve.get().ok_or(ListenerError::NotStarted)?;
+        let port = listener.local_addr().map(|addr| addr.port()).unwrap_or_default();
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.get(&port).map_or(false, Listener::accepting) {
+            return Err(ListenerError::AlreadyListening(port));
+        }
+
+        let (drain, drained) = watch::channel(false);
+        let connections = Arc::new(AtomicUsize::new(0));
+        let future = serve(listener, drained, connections.clone());
+        listeners.insert(port, Listener { drain, connections });
+        Ok(future)
+    }
+
+    /// Bind `0.0.0.0:port` and accept clients on it as well.
+    pub async fn start(&self, port: u16) -> Result<ListenerStatus, ListenerError> {
+        if self.listeners.lock().unwrap().get(&port).map_or(false, Listener::accepting) {
+            return Err(ListenerError::AlreadyListening(port));
+        }
+        let listener = TcpListener::bind(("0.0.0.0", port)).await.map_err(|e| ListenerError::Bind(port, e))?;
+        tokio::spawn(self.listen(listener)?);
+        tracing::info!(port, "started control port");
+        self.status(port).ok_or(ListenerError::UnknownPort(port))
+    }
+
+    /// Stop accepting on `port`. Refused for the last accepting port, clients would have nowhere to reconnect.
+    pub fn drain(&self, port: u16) -> Result<ListenerStatus, ListenerError> {
+        let listeners = self.listeners.lock().unwrap();
+        let listener = listeners.get(&port).ok_or(ListenerError::UnknownPort(port))?;
+        if listener.accepting() && listeners.values().filter(|l| l.accepting()).count() == 1 {
+            return Err(ListenerError::LastListener(port));
+        }
+        listener.drain.send_replace(true);
+        tracing::info!(port, "draining control port");
+        Ok(listener.status(port))
+    }
+
+    /// Stop accepting on every port, e.g. at shutdown.
+    pub fn drain_all(&self) {
+        for listener in self.listeners.lock().unwrap().values() {
+            listener.drain.send_replace(true);
+        }
+    }
+
+    pub fn status(&self, port: u16) -> Option<ListenerStatus> {
+        self.listeners.lock().unwrap().get(&port).map(|l| l.status(port))
+    }
+
+    pub fn statuses(&self) -> Vec<ListenerStatus> {
+        self.listeners.lock().unwrap().iter().map(|(port, l)| l.status(*port)).collect()
+    }
+}
+
+/// Accept connections on `listener` until `drain` turns true. Accepted connections are served to the end.
+pub async fn serve<S>(
+    service: S,
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    mut drain: watch::Receiver<bool>,
+    connections: Arc<AtomicUsize>,
+) where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    loop {
+        let (socket, peer_addr) = tokio::select! {
+            accepted = accept_with_backoff(&listener, |e| tracing::error!("failed to accept control connection: {:?}", e)) => accepted,
+            // only ever set to true, or dropped once the port is started again
+            _ = drain.changed() => break,
+        };
+
+        let socket = Counted::new(socket, connections.clone());
+        match &acceptor {
+            Some(acceptor) => {
+                tokio::spawn(
+                    tls::serve_connection(acceptor.clone(), socket, peer_addr, service.clone())
+                        .instrument(tracing::info_span!("handle_tls")),
+                );
+            }
+            None => {
+                let mut service = service.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |mut request: Request<Body>| {
+                        request.extensions_mut().insert(PeerAddr(peer_addr));
+                        service.call(request)
+                    });
+                    if let Err(e) = Http::new().serve_connection(socket, service).with_upgrades().await {
+                        tracing::debug!(%peer_addr, "control connection closed: {:?}", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+// counts itself in `connections` until dropped, which for a websocket is when it closes
+struct Counted<T> {
+    inner: T,
+    connections: Arc<AtomicUsize>,
+}
+
+impl<T> Counted<T> {
+    fn new(inner: T, connections: Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::Relaxed);
+        Self { inner, connections }
+    }
+}
+
+impl<T> Drop for Counted<T> {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,123 @@
+use ownserver_lib::{ClientId, StreamId};
+use tracing::{Span, Subscriber};
+use tracing_subscriber::{fmt::{self, MakeWriter}, registry::LookupSpan, Layer};
+
+pub use ownserver_lib::LogFormat;
+
+/// Formatter layer writing events in `format` to `writer`.
+pub fn fmt_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Pretty => fmt::layer().with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+    }
+}
+
+/// Span every log line of a client shares, see `Store::client_span` for the one of a connected client.
+pub fn client_span(client_id: ClientId) -> Span {
+    tracing::info_span!("client", cid = %client_id)
+}
+
+/// Span of a stream, nested in `client_span`.
+pub fn stream_span(client_span: &Span, stream_id: StreamId) -> Span {
+    tracing::info_span!(parent: client_span, "stream", sid = %stream_id)
+}
+
+#[cfg(test)]
+mod logging_test {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use ownserver_lib::{ClientId, StreamId};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn write_json_lines() -> Result<(), Box<dyn std::error::Error>> {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(LogFormat::Json, buffer.clone()));
+
+        let client_id = ClientId::new();
+        let stream_id = StreamId::new();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(cid = %client_id, sid = %stream_id, "forward message to remote stream");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+        let line = output.lines().next().expect("no log line");
+        let value: serde_json::Value = serde_json::from_str(line)?;
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["target"], module_path!());
+        assert!(value["timestamp"].is_string());
+        assert_eq!(value["fields"]["message"], "forward message to remote stream");
+        assert_eq!(value["fields"]["cid"], client_id.to_string());
+        assert_eq!(value["fields"]["sid"], stream_id.to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nest_stream_span_in_client_span() -> Result<(), Box<dyn std::error::Error>> {
+        use futures::StreamExt;
+        use ownserver_lib::EndpointId;
+        use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
+        use crate::{remote::{stream::RemoteStream, tcp::RemoteTcp, SocketTimeouts}, test_support::add_client, Store};
+
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(LogFormat::Json, buffer.clone()));
+        // the test runtime is single threaded, so spawned tasks log to this subscriber as well
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let store: Arc<Store> = Default::default();
+        let (client_id, mut sent) = add_client(&store).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, peer_addr) = listener.accept().await?;
+        let remote = RemoteTcp::new(store.clone(), socket, client_id, EndpointId::new(), SocketTimeouts::default(), false, false);
+        let stream_id = remote.stream_id;
+        store.add_remote(RemoteStream::RemoteTcp(remote), peer_addr).await;
+
+        peer.write_all(b"hello").await?;
+        sent.next().await.expect("client got no data");
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+        let line = output
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .find(|line| line["fields"]["message"] == "sent data packet to client")
+            .expect("no data event");
+        let spans = line["spans"].as_array().expect("event has no spans");
+        assert_eq!(spans[0]["name"], "client");
+        assert_eq!(spans[0]["cid"], client_id.to_string());
+        assert_eq!(spans[1]["name"], "stream");
+        assert_eq!(spans[1]["sid"], stream_id.to_string());
+        assert_eq!(spans[2]["name"], "remote_tcp_read_loop");
+        Ok(())
+    }
+}
@@ -1,65 +1,422 @@
-use ownserver_server::Store;
+use ownserver_server::{access::AccessControl, admin::AdminTokens, audit::AuditLog, compression::compressed, control_server_v2::prometheus_metrics, logging::{fmt_layer, LogFormat}, metrics_push::{self, PushTarget, StatsdRecorder}, mirror::TrafficMirror, packet_filter::PacketKind, remote::{parse_backlog, Banner}, rate_limit::ConnectionRateLimiter, store::DuplicatePolicy, tls, verifier::{JwtVerifier, NoAuthVerifier, TokenVerifier}, Store};
 pub use ownserver_server::{
-    port_allocator::PortAllocator,
+    port_allocator::{load_port_pools, PortAllocator},
     proxy_server::run,
     Config,
 };
-use metrics::{describe_counter, describe_gauge};
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_util::layers::FanoutBuilder;
 use tracing_subscriber::prelude::*;
-use std::sync::Arc;
+use std::{collections::HashMap, ffi::OsString, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use once_cell::sync::OnceCell;
+use ownserver_lib::parse_control_path;
 use structopt::StructOpt;
 
 static CONFIG: OnceCell<Config> = OnceCell::new();
 
+/// Every option can also be set by the `OWNSERVER_` environment variable of its name, e.g. `OWNSERVER_CONTROL_PORT`.
+/// The command line wins over the environment, which wins over the default.
 #[derive(StructOpt, Debug)]
 #[structopt(name = "ownserver-server")]
 struct Opt {
-    #[structopt(long, default_value = "5000")]
+    #[structopt(long, env = "OWNSERVER_CONTROL_PORT", default_value = "5000")]
     control_port: u16,
 
-    #[structopt(long, env = "MT_TOKEN_SECRET")]
-    token_secret: String,
+    /// also accept control channels over HTTP/2 CONNECT on this port. clients with `--transport h2` dial 5001
+    /// unless given another `--control-port`
+    #[structopt(long, env = "OWNSERVER_H2_CONTROL_PORT")]
+    h2_control_port: Option<u16>,
 
-    #[structopt(short, long)]
+    /// secret HS256 client tokens are signed with. not needed with --jwks-url, --jwt-public-key or --no-auth
+    #[structopt(long, env = "OWNSERVER_TOKEN_SECRET", hide_env_values = true, required_unless_one = &["jwks-url", "jwt-public-key", "no-auth"])]
+    token_secret: Option<String>,
+
+    /// verify RS256/ES256 client tokens with the key of their `kid` in the JWKS at this url
+    #[structopt(long, env = "OWNSERVER_JWKS_URL", conflicts_with = "jwt-public-key")]
+    jwks_url: Option<String>,
+
+    /// verify RS256/ES256 client tokens with this pem encoded public key
+    #[structopt(long, env = "OWNSERVER_JWT_PUBLIC_KEY", parse(from_os_str))]
+    jwt_public_key: Option<PathBuf>,
+
+    /// reject tokens of --jwks-url or --jwt-public-key whose `aud` claim doesn't contain this
+    #[structopt(long, env = "OWNSERVER_JWT_AUDIENCE")]
+    jwt_audience: Option<String>,
+
+    /// INSECURE: accept every client without a token, for local development only.
+    /// needs --i-understand-this-is-insecure and, unlike other flags, can't be set from the environment
+    #[structopt(long, requires = "i-understand-this-is-insecure", conflicts_with_all = &["jwks-url", "jwt-public-key"])]
+    no_auth: bool,
+
+    /// acknowledge that --no-auth lets anyone open remote ports on this server
+    #[structopt(long = "i-understand-this-is-insecure")]
+    i_understand_this_is_insecure: bool,
+
+    #[structopt(short, long, env = "OWNSERVER_HOST")]
     host: String,
 
-    #[structopt(long)]
+    /// host players connect to, advertised to clients. defaults to --host
+    #[structopt(long, env = "OWNSERVER_PUBLIC_HOST")]
+    public_host: Option<String>,
+
+    #[structopt(long, env = "OWNSERVER_REMOTE_PORT_START")]
     remote_port_start: u16,
 
-    #[structopt(long)]
+    #[structopt(long, env = "OWNSERVER_REMOTE_PORT_END")]
     remote_port_end: u16,
 
-    #[structopt(long, default_value = "15")]
+    /// seconds between cleanups of closed clients and streams. shortened while many are closed and
+    /// lengthened while idle
+    #[structopt(long = "cleanup-interval", alias = "periodic-cleanup-interval", env = "OWNSERVER_CLEANUP_INTERVAL", default_value = "15")]
     periodic_cleanup_interval: u64,
 
-    #[structopt(long, default_value = "15")]
+    #[structopt(long, env = "OWNSERVER_PERIODIC_PING_INTERVAL", default_value = "15")]
     periodic_ping_interval: u64,
+
+    /// messages queued per client before forwarding waits for the client to catch up
+    #[structopt(long, env = "OWNSERVER_CLIENT_SEND_BUFFER", default_value = "256")]
+    client_send_buffer: usize,
+
+    /// seconds the client queue may stay full before the client is dropped
+    #[structopt(long, env = "OWNSERVER_CLIENT_SEND_TIMEOUT", default_value = "10")]
+    client_send_timeout: u64,
+
+    /// seconds a remote tcp peer may stay silent before its stream is closed
+    #[structopt(long, env = "OWNSERVER_READ_TIMEOUT")]
+    read_timeout: Option<u64>,
+
+    /// seconds a write to a remote tcp peer may take before its stream is closed
+    #[structopt(long, env = "OWNSERVER_WRITE_TIMEOUT")]
+    write_timeout: Option<u64>,
+
+    /// bytes a client may forward in either direction per quota window before it is disconnected
+    #[structopt(long, env = "OWNSERVER_CLIENT_QUOTA_BYTES")]
+    client_quota_bytes: Option<u64>,
+
+    /// seconds of the rolling window --client-quota-bytes applies to
+    #[structopt(long, env = "OWNSERVER_CLIENT_QUOTA_WINDOW", default_value = "3600")]
+    client_quota_window: u64,
+
+    /// `reject` a client whose token subject is already connected, or `replace` the connected one
+    #[structopt(long, env = "OWNSERVER_ON_DUPLICATE", default_value = "reject")]
+    on_duplicate: DuplicatePolicy,
+
+    /// clients connected at once before new ones are turned away
+    #[structopt(long, env = "OWNSERVER_MAX_CLIENTS")]
+    max_clients: Option<usize>,
+
+    /// keep Nagle's algorithm on remote tcp sockets
+    #[structopt(long)]
+    no_nodelay: bool,
+
+    /// seconds a remote tcp socket may idle before keepalive probes are sent
+    #[structopt(long, env = "OWNSERVER_TCP_KEEPALIVE")]
+    tcp_keepalive: Option<u64>,
+
+    /// also accept players on IPv6 at every remote port
+    #[structopt(long)]
+    enable_ipv6: bool,
+
+    /// seconds after connecting a client is disconnected, unlimited when unset
+    #[structopt(long, env = "OWNSERVER_MAX_SESSION_DURATION")]
+    max_session_duration: Option<u64>,
+
+    /// comma separated packet types accepted from clients, e.g. `data,refused,init_ack`. every type when unset
+    #[structopt(long, env = "OWNSERVER_ALLOWED_PACKETS", use_delimiter = true)]
+    allowed_packets: Option<Vec<PacketKind>>,
+
+    /// disallowed packets tolerated from a client before it is disconnected
+    #[structopt(long, env = "OWNSERVER_MAX_PACKET_VIOLATIONS", default_value = "10")]
+    max_packet_violations: u32,
+
+    /// peek the first bytes of remote tcp connections to tell http requests from raw tcp on the same port.
+    /// the Host header of http requests is told to the client, which routes them with `--http-route`. the request is forwarded unchanged
+    #[structopt(long)]
+    sniff_http: bool,
+
+    /// bytes written to every new remote tcp connection before it is relayed.
+    /// text, `base64:<data>` for binary banners or `file:<path>`. nothing is written when empty
+    #[structopt(long, env = "OWNSERVER_REMOTE_BANNER")]
+    remote_banner: Option<Banner>,
+
+    /// undecodable packets in a row tolerated from a client before it is disconnected
+    #[structopt(long, env = "OWNSERVER_MAX_DECODE_ERRORS", default_value = "10")]
+    max_decode_errors: u32,
+
+    /// pending connections each remote tcp port queues during bursts of new players, 1-65535.
+    /// the kernel caps it further by net.core.somaxconn
+    #[structopt(long, env = "OWNSERVER_REMOTE_BACKLOG", default_value = "1024", parse(try_from_str = parse_backlog))]
+    remote_backlog: u32,
+
+    /// path of the WebSocket control endpoint, e.g. `/ownserver/tunnel` behind a reverse proxy shared with other services
+    #[structopt(long, env = "OWNSERVER_CONTROL_PATH", default_value = "/tunnel", parse(try_from_str = parse_control_path))]
+    control_path: String,
+
+    /// pem file of the certificate the control port is served with over TLS
+    #[structopt(long, env = "OWNSERVER_TLS_CERT", parse(from_os_str), requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// pem file of the private key of --tls-cert
+    #[structopt(long, env = "OWNSERVER_TLS_KEY", parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// require clients to present a certificate signed by a CA in this pem file. needs --tls-cert.
+    /// the certificate's common name replaces the token subject, its organizational unit the token tier
+    #[structopt(long, env = "OWNSERVER_CLIENT_CA", parse(from_os_str), requires = "tls-cert")]
+    client_ca: Option<PathBuf>,
+
+    /// json file of named port pools selected by the token's `tier` claim.
+    /// ports between --remote-port-start and --remote-port-end are the default pool.
+    #[structopt(long, env = "OWNSERVER_PORT_POOLS", parse(from_os_str))]
+    port_pools: Option<PathBuf>,
+
+    /// write connect/disconnect/stream/port events as newline-delimited json to this file, `-` for stdout
+    #[structopt(long, env = "OWNSERVER_AUDIT_LOG", parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+
+    /// copy every byte forwarded from remote peers to clients to the tcp sink at this address, e.g. an analyzer.
+    /// copies are dropped rather than slowing down forwarding
+    #[structopt(long, env = "OWNSERVER_MIRROR_ADDR")]
+    mirror_addr: Option<SocketAddr>,
+
+    /// comma separated remote ports never allocated, e.g. `8080,9000` used by other services.
+    /// each must lie between --remote-port-start and --remote-port-end or in a port pool
+    #[structopt(long, env = "OWNSERVER_RESERVED_PORTS", use_delimiter = true)]
+    reserved_ports: Option<Vec<u16>>,
+
+    /// json file to keep each token subject's remote ports across restarts
+    #[structopt(long, env = "OWNSERVER_STATE_FILE", parse(from_os_str))]
+    state_file: Option<PathBuf>,
+
+    /// seconds a token subject keeps its remote ports in --state-file after its last client left, 30 days by default
+    #[structopt(long, env = "OWNSERVER_STATE_TTL")]
+    state_ttl: Option<u64>,
+
+    /// new remote connections per second accepted from each source ip, unlimited when unset
+    #[structopt(long, env = "OWNSERVER_REMOTE_CONNECTION_RATE")]
+    remote_connection_rate: Option<f64>,
+
+    /// new remote connections a source ip may open at once under --remote-connection-rate
+    #[structopt(long, env = "OWNSERVER_REMOTE_CONNECTION_BURST", default_value = "10")]
+    remote_connection_burst: u32,
+
+    /// json file of remote peer networks to allow and deny e.g.) `{"allow": ["10.0.0.0/8"], "deny": ["10.0.0.13"]}`.
+    /// re-read on SIGHUP or `POST /admin/access/reload`, applying to new connections
+    #[structopt(long, env = "OWNSERVER_ACCESS_LIST", parse(from_os_str))]
+    access_list: Option<PathBuf>,
+
+    /// bearer token of every admin endpoint. without it the admin endpoints that change the server are refused,
+    /// and the ones that only report need --admin-token-readonly
+    #[structopt(long, env = "OWNSERVER_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// bearer token of the admin endpoints that only report e.g.) `/admin/status`, for monitoring dashboards.
+    /// requests that change the server are answered with 403
+    #[structopt(long, env = "OWNSERVER_ADMIN_TOKEN_READONLY", hide_env_values = true)]
+    admin_token_readonly: Option<String>,
+
+    /// close the streams of peers a reloaded --access-list denies, instead of only refusing their new connections
+    #[structopt(long)]
+    reload_drops_denied: bool,
+
+    /// remote peers remembered at once, the least recently used one is disconnected beyond this
+    #[structopt(long, env = "OWNSERVER_MAX_REMOTE_PEERS", default_value = "65536")]
+    max_remote_peers: usize,
+
+    /// largest udp payload forwarded in either direction, larger datagrams are dropped.
+    /// datagrams of 90% of it and more are counted in `ownserver_server.remote.udp.near_limit`
+    #[structopt(long, env = "OWNSERVER_MAX_UDP_PAYLOAD", default_value = "65507")]
+    max_udp_payload: usize,
+
+    /// largest ClientHello in bytes, connections sending a larger first message are closed
+    #[structopt(long, env = "OWNSERVER_MAX_HANDSHAKE_SIZE", default_value = "16384")]
+    max_handshake_size: usize,
+
+    /// messages per second forwarded from each remote stream, e.g. tcp reads or udp datagrams of a peer.
+    /// tcp reads over the limit are delayed and coalesced, udp datagrams over it are dropped. unlimited when unset
+    #[structopt(long, env = "OWNSERVER_MAX_MSG_RATE")]
+    max_msg_rate: Option<f64>,
+
+    /// seconds tcp streams of a disconnected client are held for its token subject to reconnect.
+    /// needs --state-file so that the reconnecting client gets the same remote ports
+    #[structopt(long, env = "OWNSERVER_RECONNECT_WINDOW")]
+    reconnect_window: Option<u64>,
+
+    /// seconds clients turned away for a full server are told to wait before connecting again.
+    /// they back off on their own when unset
+    #[structopt(long, env = "OWNSERVER_RETRY_AFTER")]
+    retry_after: Option<u64>,
+
+    /// allocate the lowest free remote port instead of a random one, for reproducible tests
+    #[structopt(long)]
+    deterministic_ports: bool,
+
+    /// grant only the remote port each client endpoint asks for e.g.) `25565/tcp/25565`, without random fallback.
+    /// for single-tenant setups
+    #[structopt(long)]
+    fixed_ports: bool,
+
+    /// also push metrics to a StatsD daemon e.g.) `statsd://127.0.0.1:8125`. the Prometheus endpoint keeps serving them
+    #[structopt(long, env = "OWNSERVER_METRICS_PUSH_URL")]
+    metrics_push_url: Option<PushTarget>,
+
+    /// seconds between metrics pushes to --metrics-push-url
+    #[structopt(long, env = "OWNSERVER_METRICS_PUSH_INTERVAL", default_value = "10")]
+    metrics_push_interval: u64,
+
+    /// comma separated token labels that label client metrics e.g.) `plan`.
+    /// name only labels of a few values, each value is a metric series of its own
+    #[structopt(long, env = "OWNSERVER_METRIC_LABELS", use_delimiter = true)]
+    metric_labels: Option<Vec<String>>,
+
+    /// `pretty` or `json`
+    #[structopt(long, env = "OWNSERVER_LOG_FORMAT", default_value = "pretty")]
+    log_format: LogFormat,
+}
+
+// the token secret was read from this variable before every option had an `OWNSERVER_` one
+const LEGACY_TOKEN_SECRET_ENV: &str = "MT_TOKEN_SECRET";
+
+impl Opt {
+    fn from_args_and_env() -> Self {
+        match Self::from_iter_and_env(std::env::args_os()) {
+            Ok(opt) => opt,
+            Err(e) => e.exit(),
+        }
+    }
+
+    fn from_iter_and_env<I>(args: I) -> Result<Self, structopt::clap::Error>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+    {
+        if std::env::var_os("OWNSERVER_TOKEN_SECRET").is_none() {
+            if let Some(secret) = std::env::var_os(LEGACY_TOKEN_SECRET_ENV) {
+                std::env::set_var("OWNSERVER_TOKEN_SECRET", secret);
+            }
+        }
+        let mut opt = Self::from_iter_safe(args)?;
+
+        // structopt can't read flags from the environment, a flag is set by `1`, `true`, `yes` or `on`
+        opt.no_nodelay |= env_flag("OWNSERVER_NO_NODELAY");
+        opt.enable_ipv6 |= env_flag("OWNSERVER_ENABLE_IPV6");
+        opt.sniff_http |= env_flag("OWNSERVER_SNIFF_HTTP");
+        opt.deterministic_ports |= env_flag("OWNSERVER_DETERMINISTIC_PORTS");
+        opt.fixed_ports |= env_flag("OWNSERVER_FIXED_PORTS");
+        opt.reload_drops_denied |= env_flag("OWNSERVER_RELOAD_DROPS_DENIED");
+        Ok(opt)
+    }
+
+    /// Verifier of --no-auth, --jwks-url or --jwt-public-key, client tokens are checked against --token-secret without them.
+    fn token_verifier(&self) -> Option<Arc<dyn TokenVerifier>> {
+        if self.no_auth && self.i_understand_this_is_insecure {
+            return Some(Arc::new(NoAuthVerifier));
+        }
+        let verifier = match (&self.jwks_url, &self.jwt_public_key) {
+            (Some(url), _) => JwtVerifier::with_jwks_url(url.clone()),
+            (None, Some(path)) => {
+                let pem = std::fs::read(path).expect("failed to read jwt public key");
+                JwtVerifier::with_public_key(&pem).expect("failed to parse jwt public key")
+            }
+            (None, None) => return None,
+        };
+        match self.jwt_audience {
+            Some(ref audience) => Some(Arc::new(verifier.with_audience(audience.clone()))),
+            None => Some(Arc::new(verifier)),
+        }
+    }
+
+    /// Config of --tls-cert, --tls-key and --client-ca, the control port is served without TLS when `None`.
+    fn tls_config(&self) -> Option<Arc<rustls::ServerConfig>> {
+        let (cert, key) = self.tls_cert.as_ref().zip(self.tls_key.as_ref())?;
+        Some(tls::server_config(cert, key, self.client_ca.as_deref()).expect("failed to load tls certificates"))
+    }
+
+    fn access_control(&self) -> Option<AccessControl> {
+        let path = self.access_list.as_ref()?;
+        let access = AccessControl::load(path).expect("failed to load --access-list");
+        Some(access.dropping_denied(self.reload_drops_denied))
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    match std::env::var(name) {
+        Ok(value) => matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"),
+        Err(_) => false,
+    }
 }
 
 impl From<Opt> for Config {
     fn from(opt: Opt) -> Config {
         let Opt {
             control_port,
+            h2_control_port,
             token_secret,
             host,
             remote_port_start,
             remote_port_end,
             periodic_cleanup_interval,
             periodic_ping_interval,
+            client_send_buffer,
+            client_send_timeout,
+            read_timeout,
+            write_timeout,
+            client_quota_bytes,
+            client_quota_window,
+            on_duplicate,
+            max_clients,
+            no_nodelay,
+            tcp_keepalive,
+            public_host,
+            enable_ipv6,
+            max_session_duration,
+            allowed_packets,
+            max_packet_violations,
+            sniff_http,
+            remote_banner,
+            max_decode_errors,
+            remote_backlog,
+            control_path,
             ..
         } = opt;
 
-        Config {
-            control_port,
-            token_secret,
-            host,
-            remote_port_start,
-            remote_port_end,
-            periodic_cleanup_interval,
-            periodic_ping_interval,
+        let mut builder = Config::builder()
+            .control_port(control_port)
+            .h2_control_port(h2_control_port)
+            .token_secret(token_secret.unwrap_or_default())
+            .host(host)
+            .port_range(remote_port_start..remote_port_end)
+            .periodic_cleanup_interval(periodic_cleanup_interval)
+            .periodic_ping_interval(periodic_ping_interval)
+            .client_send_buffer(client_send_buffer)
+            .client_send_timeout(client_send_timeout)
+            .read_timeout(read_timeout)
+            .write_timeout(write_timeout)
+            .on_duplicate(on_duplicate)
+            .max_clients(max_clients)
+            .nodelay(!no_nodelay)
+            .tcp_keepalive(tcp_keepalive)
+            .enable_ipv6(enable_ipv6)
+            .max_session_duration(max_session_duration)
+            .max_packet_violations(max_packet_violations)
+            .sniff_http(sniff_http)
+            .max_decode_errors(max_decode_errors)
+            .remote_backlog(remote_backlog)
+            .control_path(control_path);
+        if let Some(bytes) = client_quota_bytes {
+            builder = builder.client_quota(bytes, client_quota_window);
+        }
+        if let Some(public_host) = public_host {
+            builder = builder.public_host(public_host);
+        }
+        if let Some(kinds) = allowed_packets {
+            builder = builder.allowed_packets(kinds);
         }
+        if let Some(banner) = remote_banner {
+            builder = builder.remote_banner(banner.0);
+        }
+        // every required option has a value and --control-path is already parsed
+        builder.build().expect("invalid config")
     }
 }
 
@@ -68,51 +425,258 @@ async fn main() {
     // for tokio-console
     // console_subscriber::init();
 
-    let opt = Opt::from_args();
+    let opt = Opt::from_args_and_env();
+    let port_pools = match opt.port_pools {
+        Some(ref path) => load_port_pools(path).expect("failed to load port pools"),
+        None => HashMap::new(),
+    };
+    let audit_log = opt.audit_log.clone();
+    let state_file = opt.state_file.clone();
+    let state_ttl = opt.state_ttl;
+    let mirror_addr = opt.mirror_addr;
+    let reserved_ports = opt.reserved_ports.clone();
+    let log_format = opt.log_format;
+    let deterministic_ports = opt.deterministic_ports;
+    let fixed_ports = opt.fixed_ports;
+    let max_remote_peers = opt.max_remote_peers;
+    let max_udp_payload = opt.max_udp_payload;
+    let max_handshake_size = opt.max_handshake_size;
+    let max_msg_rate = opt.max_msg_rate;
+    let reconnect_window = opt.reconnect_window;
+    let retry_after = opt.retry_after;
+    let metric_labels = opt.metric_labels.clone();
+    let metrics_push = opt.metrics_push_url.clone().map(|target| (target, Duration::from_secs(opt.metrics_push_interval)));
+    let rate_limiter = opt.remote_connection_rate.map(|rate| ConnectionRateLimiter::new(rate, opt.remote_connection_burst));
+    let token_verifier = opt.token_verifier();
+    let tls_config = opt.tls_config();
+    let access_control = opt.access_control();
+    let admin_tokens = AdminTokens::new(opt.admin_token.clone(), opt.admin_token_readonly.clone());
+    let no_auth = opt.no_auth;
     let config = Config::from(opt);
     CONFIG.set(config).expect("failed to initialize config");
 
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new("INFO"))
-        .with(tracing_subscriber::fmt::layer())
+        .with(fmt_layer(log_format, std::io::stdout))
         .try_init()
         .expect("Failed to register tracer with registry");
 
-    let builder = PrometheusBuilder::new();
-    builder.install().expect("failed to install recorder/exporter");
-    describe_gauge!("ownserver_server.store.clients", "[gauge] The number of Clients at this time.");
-    describe_gauge!("ownserver_server.store.streams", "[gauge] The number of RemoteStreams at this time.");
-    describe_counter!("ownserver_server.control_server.handle_new_connection", "[counter] The number of successfully accepted websocket connections so far.");
-    describe_counter!("ownserver_server.control_server.try_client_handshake.success", "[counter] The number of succesfully handshake requests so far.");
-    describe_counter!("ownserver_server.control_server.try_client_handshake.service_temporary_unavailable", "[counter] The number of handshake error ServiceTemporaryUnavailable so far.");
-    describe_counter!("ownserver_server.control_server.try_client_handshake.invalid_client_hello", "[counter] The number of handshake error InvalidClientHello so far.");
-    describe_counter!("ownserver_server.control_server.try_client_handshake.invalid_jwt", "[counter] The number of handshake error InvalidJWT so far.");
-    describe_counter!("ownserver_server.control_server.try_client_handshake.illegal_host", "[counter] The number of handshake error IllegalHost so far.");
-    describe_counter!("ownserver_server.control_server.try_client_handshake.version_mismatch", "[counter] The number of handshake error VersionMismatch so far.");
-    describe_counter!("ownserver_server.control_server.try_client_handshake.other", "[counter] The number of handshake error Other so far.");
-    describe_counter!("ownserver_server.remote.tcp.swawn_remote", "[counter] How many times tcp::spawn_remote called.");
-    describe_counter!("ownserver_server.remote.udp.swawn_remote", "[counter] How many times udp::spawn_remote called.");
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let metrics_handle = recorder.handle();
+    let installed = match metrics_push {
+        Some((target, interval)) => {
+            let statsd = StatsdRecorder::default();
+            metrics_push::spawn(statsd.clone(), target, interval);
+            metrics::set_boxed_recorder(Box::new(FanoutBuilder::default().add_recorder(recorder).add_recorder(statsd).build()))
+        }
+        None => metrics::set_boxed_recorder(Box::new(recorder)),
+    };
+    installed.expect("failed to install recorder");
     tracing::info!("Prometheus endpoint: localhost:9000");
 
     tracing::debug!("{:?}", CONFIG.get().expect("failed to read config"));
     let Config {remote_port_start, remote_port_end  , ..}  = CONFIG.get().expect("failed to read config");
 
-    let store = Arc::new(Store::new(*remote_port_start..*remote_port_end));
+    let mut store = Store::with_port_pools(*remote_port_start..*remote_port_end, port_pools)
+        .with_addrs_map_capacity(max_remote_peers)
+        .with_max_udp_payload(max_udp_payload)
+        .with_max_handshake_size(max_handshake_size);
+    if let Some(ref ports) = reserved_ports {
+        store = store.with_excluded_ports(ports).expect("invalid --reserved-ports");
+    }
+    if let Some(ref path) = audit_log {
+        let audit_log = AuditLog::open(path).await.expect("failed to open audit log");
+        store = store.with_audit_log(audit_log);
+    }
+    if let Some(rate_limiter) = rate_limiter {
+        store = store.with_rate_limiter(rate_limiter);
+    }
+    if let Some(rate) = max_msg_rate {
+        store = store.with_max_msg_rate(rate);
+    }
+    if deterministic_ports {
+        store = store.with_deterministic_ports();
+    }
+    if fixed_ports {
+        store = store.with_fixed_ports();
+    }
+    if let Some(path) = state_file {
+        store = store.with_state_file(path);
+    }
+    if let Some(secs) = state_ttl {
+        store = store.with_subject_reservation_ttl(Duration::from_secs(secs));
+    }
+    if let Some(window) = reconnect_window {
+        store = store.with_reconnect_window(Duration::from_secs(window));
+    }
+    if let Some(secs) = retry_after {
+        store = store.with_retry_after(Duration::from_secs(secs));
+    }
+    if let Some(addr) = mirror_addr {
+        store = store.with_mirror(TrafficMirror::connect(addr));
+    }
+    if let Some(verifier) = token_verifier {
+        store = store.with_token_verifier(verifier);
+    }
+    if let Some(tls_config) = tls_config {
+        store = store.with_tls_config(tls_config);
+    }
+    if let Some(access_control) = access_control {
+        store = store.with_access_control(access_control);
+    }
+    store = store.with_admin_tokens(admin_tokens);
+    if let Some(keys) = metric_labels {
+        store = store.with_metric_labels(keys).expect("invalid --metric-labels");
+    }
+    if no_auth {
+        tracing::warn!("--no-auth is set: every client is accepted without a token. never expose this server publicly");
+    }
+    let store = Arc::new(store);
 
-    let mut set = run(
+    let mut handle = run(
         &CONFIG,
-        store,
+        store.clone(),
     ).await;
-    
-    
-    while let Some(res) = set.join_next().await {
-        match res {
-            Err(join_error) => {
-                tracing::error!("join error {:?} for proxy_server", join_error);
-            }
-            Ok(_) => {
-                tracing::info!("proxy_server successfully terminated");
+    handle.spawn(run_metrics_upkeep(metrics_handle.clone()));
+    handle.spawn(warp::serve(compressed(prometheus_metrics(metrics_handle))).run(([0, 0, 0, 0], 9000)));
+    #[cfg(unix)]
+    handle.spawn(reload_access_on_sighup(store.clone()));
+
+    let summary = handle.wait_until(drain_on_ctrl_c(store)).await;
+    tracing::info!(%summary, "proxy_server terminated");
+}
+
+// the recorder is installed without its exporter, which would otherwise drain histograms on this interval
+const METRICS_UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn run_metrics_upkeep(handle: PrometheusHandle) {
+    let mut ticker = tokio::time::interval(METRICS_UPKEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        handle.run_upkeep();
+    }
+}
+
+#[cfg(unix)]
+async fn reload_access_on_sighup(store: Arc<Store>) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::warn!("failed to listen for SIGHUP, reload the access list with POST /admin/access/reload: {:?}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match store.reload_access_control().await {
+            Ok(closed) => tracing::info!(closed, "reloaded access list on SIGHUP"),
+            Err(e) => tracing::warn!("failed to reload access list on SIGHUP: {}", e),
+        }
+    }
+}
+
+// the first ctrl-c stops accepting clients and returns once connected ones have left, a second one returns at once
+async fn drain_on_ctrl_c(store: Arc<Store>) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        tracing::warn!("failed to listen for ctrl-c, graceful shutdown is disabled");
+        return std::future::pending().await;
+    }
+    store.start_drain();
+
+    let drained = async {
+        loop {
+            store.cleanup().await;
+            let status = store.status().await;
+            if status.clients == 0 {
+                break;
             }
+            tracing::info!(clients = status.clients, streams = status.streams, "waiting for clients to leave");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    };
+    tokio::select! {
+        _ = drained => tracing::info!("all clients left, shutting down"),
+        _ = tokio::signal::ctrl_c() => tracing::warn!("received ctrl-c again, shutting down"),
+    }
+}
+
+#[cfg(test)]
+mod opt_env_test {
+    use super::*;
+    use serial_test::serial;
+
+    const ARGS: [&str; 5] = ["ownserver-server", "--host", "foo.local", "--remote-port-end", "30000"];
+
+    fn clear_env() {
+        for name in ["OWNSERVER_TOKEN_SECRET", LEGACY_TOKEN_SECRET_ENV, "OWNSERVER_JWKS_URL", "OWNSERVER_REMOTE_PORT_START", "OWNSERVER_REMOTE_PORT_END", "OWNSERVER_CONTROL_PORT", "OWNSERVER_ENABLE_IPV6", "OWNSERVER_ALLOWED_PACKETS"] {
+            std::env::remove_var(name);
         }
     }
+
+    #[test]
+    #[serial]
+    fn read_options_from_env() -> Result<(), structopt::clap::Error> {
+        clear_env();
+        std::env::set_var("OWNSERVER_TOKEN_SECRET", "supersecret");
+        std::env::set_var("OWNSERVER_REMOTE_PORT_START", "20000");
+        std::env::set_var("OWNSERVER_REMOTE_PORT_END", "25000");
+        std::env::set_var("OWNSERVER_CONTROL_PORT", "6000");
+        std::env::set_var("OWNSERVER_ENABLE_IPV6", "true");
+        std::env::set_var("OWNSERVER_ALLOWED_PACKETS", "data,init_ack");
+
+        let config = Config::from(Opt::from_iter_and_env(ARGS)?);
+        clear_env();
+
+        assert_eq!(config.token_secret, "supersecret");
+        assert_eq!(config.remote_port_start, 20000);
+        // the command line wins over the environment
+        assert_eq!(config.remote_port_end, 30000);
+        assert_eq!(config.control_port, 6000);
+        assert!(config.enable_ipv6);
+        assert_eq!(config.allowed_packets, Some(vec![PacketKind::Data, PacketKind::InitAck]));
+        // the default applies without either
+        assert_eq!(config.periodic_ping_interval, 15);
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn read_token_secret_from_legacy_env() -> Result<(), structopt::clap::Error> {
+        clear_env();
+        std::env::set_var(LEGACY_TOKEN_SECRET_ENV, "oldsecret");
+        std::env::set_var("OWNSERVER_REMOTE_PORT_START", "20000");
+
+        let config = Config::from(Opt::from_iter_and_env(ARGS)?);
+        clear_env();
+
+        assert_eq!(config.token_secret, "oldsecret");
+        assert!(!config.enable_ipv6);
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn take_jwks_url_instead_of_token_secret() -> Result<(), structopt::clap::Error> {
+        clear_env();
+        assert!(Opt::from_iter_and_env(ARGS.into_iter().chain(["--remote-port-start", "20000"])).is_err());
+
+        std::env::set_var("OWNSERVER_JWKS_URL", "https://auth.example.com/.well-known/jwks.json");
+        let opt = Opt::from_iter_and_env(ARGS.into_iter().chain(["--remote-port-start", "20000", "--jwt-audience", "ownserver"]))?;
+        clear_env();
+
+        assert!(opt.token_verifier().is_some());
+        assert_eq!(Config::from(opt).token_secret, "");
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn require_acknowledgement_for_no_auth() -> Result<(), structopt::clap::Error> {
+        clear_env();
+        let args = ["ownserver-server", "--host", "localhost", "--remote-port-start", "20000", "--remote-port-end", "30000", "--no-auth"];
+        assert!(Opt::from_iter_and_env(args).is_err());
+
+        let opt = Opt::from_iter_and_env(args.into_iter().chain(["--i-understand-this-is-insecure"]))?;
+        assert!(opt.token_verifier().is_some());
+        Ok(())
+    }
 }
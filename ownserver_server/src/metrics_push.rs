@@ -0,0 +1,298 @@
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+use std::time::Duration;
+
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Recorder, SharedString, Unit};
+use metrics_util::{registry::{AtomicStorage, Registry}, AtomicBucket};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use url::Url;
+
+/// Datagrams stay below this many bytes so they are not fragmented on a 1500 byte MTU.
+pub const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Where `--metrics-push-url` sends metrics, e.g. `statsd://127.0.0.1:8125`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushTarget {
+    /// `host:port` of the StatsD daemon
+    pub addr: String,
+}
+
+impl FromStr for PushTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("invalid metrics push url `{}`: {}", s, e))?;
+        match url.scheme() {
+            "statsd" => {}
+            "otlp" | "http" | "https" => return Err(format!("pushing to `{}` is not supported, only `statsd://host:port` is", s)),
+            scheme => return Err(format!("unknown metrics push scheme `{}`, expected `statsd`", scheme)),
+        }
+        match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => Ok(PushTarget { addr: format!("{}:{}", host, port) }),
+            _ => Err(format!("metrics push url `{}` needs a host and a port", s)),
+        }
+    }
+}
+
+/// Keeps what was recorded since the last push, installed next to the Prometheus recorder with
+/// `metrics_util::layers::FanoutBuilder` so that both see every metric.
+#[derive(Clone)]
+pub struct StatsdRecorder {
+    registry: Arc<Registry<Key, AtomicStorage>>,
+}
+
+impl Default for StatsdRecorder {
+    fn default() -> Self {
+        Self { registry: Arc::new(Registry::atomic()) }
+    }
+}
+
+impl std::fmt::Debug for StatsdRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatsdRecorder").finish_non_exhaustive()
+    }
+}
+
+impl Recorder for StatsdRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key) -> Counter {
+        self.registry.get_or_create_counter(key, |counter| Counter::from_arc(counter.clone()))
+    }
+
+    fn register_gauge(&self, key: &Key) -> Gauge {
+        self.registry.get_or_create_gauge(key, |gauge| Gauge::from_arc(gauge.clone()))
+    }
+
+    fn register_histogram(&self, key: &Key) -> Histogram {
+        self.registry.get_or_create_histogram(key, |histogram| Histogram::from_arc(histogram.clone()))
+    }
+}
+
+// what to put back when the line could not be sent
+enum Unsent {
+    Nothing,
+    Increase(Arc<AtomicU64>, u64),
+    Sample(Arc<AtomicBucket<f64>>, f64),
+}
+
+struct Line {
+    text: String,
+    unsent: Unsent,
+}
+
+impl StatsdRecorder {
+    // one StatsD line per counter increase, gauge and histogram sample since the last collect,
+    // labels become DogStatsD tags e.g.) `name:3|c|#plan:pro`
+    fn collect(&self) -> Vec<Line> {
+        let mut lines = Vec::new();
+        self.registry.visit_counters(|key, counter| {
+            let increase = counter.swap(0, Ordering::AcqRel);
+            if increase > 0 {
+                lines.push(Line { text: statsd_line(key, increase as f64, "c"), unsent: Unsent::Increase(counter.clone(), increase) });
+            }
+        });
+        self.registry.visit_gauges(|key, gauge| {
+            let value = f64::from_bits(gauge.load(Ordering::Acquire));
+            lines.push(Line { text: statsd_line(key, value, "g"), unsent: Unsent::Nothing });
+        });
+        self.registry.visit_histograms(|key, histogram| {
+            histogram.clear_with(|samples| {
+                for sample in samples {
+                    lines.push(Line { text: statsd_line(key, *sample, "h"), unsent: Unsent::Sample(histogram.clone(), *sample) });
+                }
+            });
+        });
+        lines.retain(|line| !line.text.is_empty());
+        lines
+    }
+}
+
+// put counter increases and samples of lines that were not sent back, so that the next push carries them
+fn restore(lines: &[Line]) {
+    for line in lines {
+        match &line.unsent {
+            Unsent::Nothing => {}
+            Unsent::Increase(counter, increase) => {
+                counter.fetch_add(*increase, Ordering::AcqRel);
+            }
+            Unsent::Sample(histogram, sample) => histogram.push(*sample),
+        }
+    }
+}
+
+// empty for a value StatsD cannot carry
+fn statsd_line(key: &Key, value: f64, kind: &str) -> String {
+    if !value.is_finite() {
+        return String::new();
+    }
+    let mut line = format!("{}:{}|{}", key.name(), value, kind);
+    let tags: Vec<String> = key.labels().map(|label| format!("{}:{}", label.key(), sanitize_tag(label.value()))).collect();
+    if !tags.is_empty() {
+        line.push_str("|#");
+        line.push_str(&tags.join(","));
+    }
+    line
+}
+
+/// Push what `recorder` recorded to `target` every `interval`. Counters go out as their increase since
+/// the last push, gauges as gauges and every histogram sample as a DogStatsD histogram value.
+pub fn spawn(recorder: StatsdRecorder, target: PushTarget, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let pusher = match StatsdPusher::connect(&target.addr).await {
+            Ok(pusher) => pusher,
+            Err(e) => {
+                tracing::error!(addr = %target.addr, "failed to set up metrics push: {:?}", e);
+                return;
+            }
+        };
+        tracing::info!(addr = %target.addr, "pushing metrics every {:?}", interval);
+
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = pusher.push(&recorder).await {
+                tracing::warn!(addr = %target.addr, "failed to push metrics, sending them with the next push: {:?}", e);
+            }
+        }
+    })
+}
+
+/// Sends recorded metrics to a StatsD daemon, several lines per datagram.
+#[derive(Debug)]
+pub struct StatsdPusher {
+    socket: UdpSocket,
+}
+
+impl StatsdPusher {
+    pub async fn connect(addr: &str) -> io::Result<Self> {
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", addr)))?;
+        let local: SocketAddr = if addr.is_ipv6() { "[::]:0".parse().unwrap() } else { "0.0.0.0:0".parse().unwrap() };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(Self { socket })
+    }
+
+    /// Send what `recorder` recorded since the last push and return the number of datagrams.
+    /// What could not be sent stays in `recorder` for the next push.
+    pub async fn push(&self, recorder: &StatsdRecorder) -> io::Result<usize> {
+        let lines = recorder.collect();
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        let datagrams = chunk_datagrams(&texts, MAX_DATAGRAM_SIZE);
+        for (i, range) in datagrams.iter().enumerate() {
+            if let Err(e) = self.socket.send(texts[range.clone()].join("\n").as_bytes()).await {
+                restore(&lines[range.start..]);
+                tracing::debug!(sent = i, "failed to send datagram");
+                return Err(e);
+            }
+        }
+        Ok(datagrams.len())
+    }
+}
+
+// `,`, `|` and `:` separate tags and fields, `#` starts the tags
+fn sanitize_tag(value: &str) -> String {
+    value.chars().map(|c| if matches!(c, ',' | '|' | ':' | '#' | '\n') { '_' } else { c }).collect()
+}
+
+// group lines joined with `\n` into datagrams of at most `max` bytes, a longer line goes alone
+fn chunk_datagrams(lines: &[&str], max: usize) -> Vec<Range<usize>> {
+    let mut datagrams = Vec::new();
+    let mut start = 0;
+    let mut len = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if i > start && len + 1 + line.len() > max {
+            datagrams.push(start..i);
+            start = i;
+            len = 0;
+        }
+        len += if i > start { 1 + line.len() } else { line.len() };
+    }
+    if start < lines.len() {
+        datagrams.push(start..lines.len());
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod metrics_push_test {
+    use super::*;
+    use metrics::Label;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use metrics_util::layers::FanoutBuilder;
+
+    fn texts(lines: &[Line]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    #[test]
+    fn parse_push_url() {
+        assert_eq!("statsd://127.0.0.1:8125".parse(), Ok(PushTarget { addr: "127.0.0.1:8125".to_string() }));
+        assert!("statsd://127.0.0.1".parse::<PushTarget>().is_err());
+        assert!("otlp://collector:4317".parse::<PushTarget>().is_err());
+    }
+
+    #[test]
+    fn send_counter_increases_gauges_and_samples() {
+        let recorder = StatsdRecorder::default();
+        let requests = recorder.register_counter(&Key::from_parts("requests", vec![Label::new("plan", "pro")]));
+        requests.increment(3);
+        recorder.register_gauge(&Key::from_name("clients")).set(2.0);
+        recorder.register_histogram(&Key::from_name("rtt_ms")).record(1.5);
+        assert_eq!(texts(&recorder.collect()), vec!["requests:3|c|#plan:pro", "clients:2|g", "rtt_ms:1.5|h"]);
+
+        requests.increment(2);
+        assert_eq!(texts(&recorder.collect()), vec!["requests:2|c|#plan:pro", "clients:2|g"]);
+    }
+
+    #[test]
+    fn keep_what_was_not_sent() {
+        let recorder = StatsdRecorder::default();
+        recorder.register_counter(&Key::from_name("requests")).increment(3);
+        recorder.register_histogram(&Key::from_name("rtt_ms")).record(1.5);
+        restore(&recorder.collect());
+
+        recorder.register_counter(&Key::from_name("requests")).increment(1);
+        assert_eq!(texts(&recorder.collect()), vec!["requests:4|c", "rtt_ms:1.5|h"]);
+    }
+
+    #[test]
+    fn split_lines_into_datagrams() {
+        let lines = ["a:1|c", "b:2|c", "c:3|c"];
+        assert_eq!(chunk_datagrams(&lines, 11), vec![0..2, 2..3]);
+        assert_eq!(chunk_datagrams(&lines, 3), vec![0..1, 1..2, 2..3]);
+    }
+
+    #[tokio::test]
+    async fn push_metrics_to_statsd_receiver() -> Result<(), Box<dyn std::error::Error>> {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await?;
+        let prometheus = PrometheusBuilder::new().build_recorder();
+        let handle = prometheus.handle();
+        let statsd = StatsdRecorder::default();
+        let recorder = FanoutBuilder::default().add_recorder(prometheus).add_recorder(statsd.clone()).build();
+        recorder.register_counter(&Key::from_parts("ownserver_server.test.pushed", vec![Label::new("plan", "pro")])).increment(3);
+        recorder.register_gauge(&Key::from_name("ownserver_server.test.clients")).set(2.0);
+        assert!(handle.render().contains("ownserver_server_test_pushed{plan=\"pro\"} 3"));
+
+        let target = PushTarget { addr: receiver.local_addr()?.to_string() };
+        let pusher = spawn(statsd, target, Duration::from_millis(50));
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let n = tokio::time::timeout(Duration::from_secs(5), receiver.recv(&mut buf)).await??;
+        let datagram = String::from_utf8_lossy(&buf[..n]).to_string();
+        assert!(datagram.contains("ownserver_server.test.pushed:3|c|#plan:pro"), "{}", datagram);
+        assert!(datagram.contains("ownserver_server.test.clients:2|g"), "{}", datagram);
+
+        pusher.abort();
+        Ok(())
+    }
+}
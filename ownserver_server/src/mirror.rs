@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use metrics::increment_counter;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+const MIRROR_BUFFER: usize = 1024;
+const MIRROR_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Copies remote→client bytes to a tcp sink such as an analyzer, from a background task.
+/// Mirroring never waits on the sink; copies are dropped when the buffer is full or the sink is down.
+#[derive(Debug)]
+pub struct TrafficMirror {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl TrafficMirror {
+    /// Connect to `addr` in the background, reconnecting whenever the sink goes away.
+    pub fn connect(addr: SocketAddr) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MIRROR_BUFFER);
+
+        tokio::spawn(async move {
+            loop {
+                let mut sink = match TcpStream::connect(addr).await {
+                    Ok(sink) => {
+                        tracing::info!(%addr, "connected to traffic mirror");
+                        sink
+                    }
+                    Err(e) => {
+                        tracing::warn!(%addr, error = ?e, "failed to connect to traffic mirror");
+                        tokio::time::sleep(MIRROR_RECONNECT_DELAY).await;
+                        // copies queued while the sink was down are stale
+                        while rx.try_recv().is_ok() {
+                            increment_counter!("ownserver_server.mirror.dropped");
+                        }
+                        if rx.is_closed() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                loop {
+                    let data = match rx.recv().await {
+                        Some(data) => data,
+                        None => return,
+                    };
+                    if let Err(e) = sink.write_all(&data).await {
+                        tracing::warn!(%addr, error = ?e, "failed to write to traffic mirror");
+                        increment_counter!("ownserver_server.mirror.dropped");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    pub fn record(&self, data: Vec<u8>) {
+        match self.tx.try_send(data) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                increment_counter!("ownserver_server.mirror.dropped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod mirror_test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn copy_recorded_bytes_to_sink() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mirror = TrafficMirror::connect(listener.local_addr()?);
+        let (mut sink, _) = listener.accept().await?;
+
+        mirror.record(b"hello ".to_vec());
+        mirror.record(b"world".to_vec());
+
+        let mut received = [0; 11];
+        tokio::time::timeout(Duration::from_secs(2), sink.read_exact(&mut received)).await??;
+        assert_eq!(&received, b"hello world");
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use ownserver_lib::ControlPacketV2;
+
+/// Disallowed packets tolerated from a client before it is disconnected.
+pub const DEFAULT_MAX_PACKET_VIOLATIONS: u32 = 10;
+
+/// `ControlPacketV2` variant without its payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PacketKind {
+    Init,
+    Data,
+    Refused,
+    End,
+    Ping,
+    WindowUpdate,
+    Disconnect,
+    InitAck,
+    Heartbeat,
+    HeartbeatAck,
+    WhoAmI,
+    WhoAmIResp,
+    ShutdownWrite,
+    InitHttp,
+}
+
+impl PacketKind {
+    pub const ALL: [PacketKind; 14] = [
+        PacketKind::Init,
+        PacketKind::Data,
+        PacketKind::Refused,
+        PacketKind::End,
+        PacketKind::Ping,
+        PacketKind::WindowUpdate,
+        PacketKind::Disconnect,
+        PacketKind::InitAck,
+        PacketKind::Heartbeat,
+        PacketKind::HeartbeatAck,
+        PacketKind::WhoAmI,
+        PacketKind::WhoAmIResp,
+        PacketKind::ShutdownWrite,
+        PacketKind::InitHttp,
+    ];
+
+    pub fn of(packet: &ControlPacketV2) -> Self {
+        match packet {
+            ControlPacketV2::Init(..) => PacketKind::Init,
+            ControlPacketV2::Data(..) => PacketKind::Data,
+            ControlPacketV2::Refused(..) => PacketKind::Refused,
+            ControlPacketV2::End(..) => PacketKind::End,
+            ControlPacketV2::Ping => PacketKind::Ping,
+            ControlPacketV2::WindowUpdate(..) => PacketKind::WindowUpdate,
+            ControlPacketV2::Disconnect(..) => PacketKind::Disconnect,
+            ControlPacketV2::InitAck(..) => PacketKind::InitAck,
+            ControlPacketV2::Heartbeat(..) => PacketKind::Heartbeat,
+            ControlPacketV2::HeartbeatAck(..) => PacketKind::HeartbeatAck,
+            ControlPacketV2::WhoAmI => PacketKind::WhoAmI,
+            ControlPacketV2::WhoAmIResp { .. } => PacketKind::WhoAmIResp,
+            ControlPacketV2::ShutdownWrite(..) => PacketKind::ShutdownWrite,
+            ControlPacketV2::InitHttp(..) => PacketKind::InitHttp,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PacketKind::Init => "init",
+            PacketKind::Data => "data",
+            PacketKind::Refused => "refused",
+            PacketKind::End => "end",
+            PacketKind::Ping => "ping",
+            PacketKind::WindowUpdate => "window_update",
+            PacketKind::Disconnect => "disconnect",
+            PacketKind::InitAck => "init_ack",
+            PacketKind::Heartbeat => "heartbeat",
+            PacketKind::HeartbeatAck => "heartbeat_ack",
+            PacketKind::WhoAmI => "who_am_i",
+            PacketKind::WhoAmIResp => "who_am_i_resp",
+            PacketKind::ShutdownWrite => "shutdown_write",
+            PacketKind::InitHttp => "init_http",
+        }
+    }
+}
+
+impl fmt::Display for PacketKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PacketKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PacketKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| format!("unknown packet type `{}`", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterVerdict {
+    Accept,
+    Drop,
+    /// the client sent too many disallowed packets
+    Disconnect,
+}
+
+/// Packets a client may send, checked for every packet it sends.
+#[derive(Debug, Clone)]
+pub struct PacketFilter {
+    allowed: HashSet<PacketKind>,
+    max_violations: u32,
+    violations: u32,
+}
+
+impl PacketFilter {
+    pub fn new(allowed: impl IntoIterator<Item = PacketKind>, max_violations: u32) -> Self {
+        Self {
+            allowed: allowed.into_iter().collect(),
+            max_violations,
+            violations: 0,
+        }
+    }
+
+    pub fn check(&mut self, kind: PacketKind) -> FilterVerdict {
+        if self.allowed.contains(&kind) {
+            return FilterVerdict::Accept;
+        }
+        self.violations += 1;
+        if self.violations >= self.max_violations {
+            FilterVerdict::Disconnect
+        } else {
+            FilterVerdict::Drop
+        }
+    }
+
+    pub fn violations(&self) -> u32 {
+        self.violations
+    }
+}
+
+#[cfg(test)]
+mod packet_filter_test {
+    use super::*;
+
+    #[test]
+    fn parse_packet_kinds() {
+        for kind in PacketKind::ALL {
+            assert_eq!(kind.as_str().parse::<PacketKind>(), Ok(kind));
+        }
+        assert!("admin".parse::<PacketKind>().is_err());
+    }
+
+    #[test]
+    fn disconnect_after_repeated_violations() {
+        let mut filter = PacketFilter::new([PacketKind::Data], 2);
+
+        assert_eq!(filter.check(PacketKind::Data), FilterVerdict::Accept);
+        assert_eq!(filter.check(PacketKind::Ping), FilterVerdict::Drop);
+        assert_eq!(filter.check(PacketKind::Data), FilterVerdict::Accept);
+        assert_eq!(filter.check(PacketKind::Ping), FilterVerdict::Disconnect);
+        assert_eq!(filter.violations(), 2);
+    }
+}
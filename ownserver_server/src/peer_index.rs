@@ -0,0 +1,138 @@
+use std::{collections::{BTreeSet, HashMap}, net::SocketAddr, sync::Mutex};
+
+use ownserver_lib::StreamId;
+
+/// Stream of each remote peer, ordered by when the peer was last seen so that the least recently used
+/// one is found without scanning every peer.
+#[derive(Debug, Default)]
+pub struct PeerIndex {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // with the sequence number of the last time each peer was seen
+    peers: HashMap<SocketAddr, (StreamId, u64)>,
+    by_last_seen: BTreeSet<(u64, SocketAddr)>,
+    next_seq: u64,
+}
+
+impl Inner {
+    fn remove(&mut self, addr: &SocketAddr) -> Option<StreamId> {
+        let (stream_id, seen) = self.peers.remove(addr)?;
+        self.by_last_seen.remove(&(seen, *addr));
+        Some(stream_id)
+    }
+
+    fn insert(&mut self, addr: SocketAddr, stream_id: StreamId) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.peers.insert(addr, (stream_id, seq));
+        self.by_last_seen.insert((seq, addr));
+    }
+}
+
+impl PeerIndex {
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.inner.lock().unwrap().peers.contains_key(addr)
+    }
+
+    /// Remember `addr` as seen now, replacing the stream it had.
+    pub fn insert(&self, addr: SocketAddr, stream_id: StreamId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&addr);
+        inner.insert(addr, stream_id);
+    }
+
+    /// Mark `addr` as seen now and return its stream.
+    pub fn touch(&self, addr: &SocketAddr) -> Option<StreamId> {
+        let mut inner = self.inner.lock().unwrap();
+        let stream_id = inner.remove(addr)?;
+        inner.insert(*addr, stream_id);
+        Some(stream_id)
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) -> Option<StreamId> {
+        self.inner.lock().unwrap().remove(addr)
+    }
+
+    /// Forget the least recently seen peer and return it.
+    pub fn pop_oldest(&self) -> Option<(SocketAddr, StreamId)> {
+        let mut inner = self.inner.lock().unwrap();
+        let (_, addr) = *inner.by_last_seen.iter().next()?;
+        inner.remove(&addr).map(|stream_id| (addr, stream_id))
+    }
+
+    /// Keep only the peers whose stream passes `keep`.
+    pub fn retain(&self, mut keep: impl FnMut(&StreamId) -> bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { peers, by_last_seen, .. } = &mut *inner;
+        peers.retain(|addr, (stream_id, seen)| {
+            let kept = keep(stream_id);
+            if !kept {
+                by_last_seen.remove(&(*seen, *addr));
+            }
+            kept
+        });
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.inner.lock().unwrap().peers.keys().copied().collect()
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.peers.clear();
+        inner.by_last_seen.clear();
+    }
+}
+
+#[cfg(test)]
+mod peer_index_test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn pop_least_recently_seen_peer() {
+        let index = PeerIndex::default();
+        let (first, second, third) = (StreamId::new(), StreamId::new(), StreamId::new());
+        index.insert(addr(1), first);
+        index.insert(addr(2), second);
+        index.insert(addr(3), third);
+        assert_eq!(index.touch(&addr(1)), Some(first));
+
+        assert_eq!(index.pop_oldest(), Some((addr(2), second)));
+        assert_eq!(index.pop_oldest(), Some((addr(3), third)));
+        assert_eq!(index.pop_oldest(), Some((addr(1), first)));
+        assert_eq!(index.pop_oldest(), None);
+    }
+
+    #[test]
+    fn keep_order_in_step_with_peers() {
+        let index = PeerIndex::default();
+        let (kept, dropped) = (StreamId::new(), StreamId::new());
+        index.insert(addr(1), dropped);
+        index.insert(addr(2), kept);
+        index.insert(addr(3), dropped);
+        index.retain(|sid| *sid == kept);
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.remove(&addr(1)), None);
+
+        // a peer inserted again is not listed twice
+        index.insert(addr(2), dropped);
+        assert_eq!(index.pop_oldest(), Some((addr(2), dropped)));
+        assert!(index.is_empty());
+        assert_eq!(index.touch(&addr(2)), None);
+    }
+}
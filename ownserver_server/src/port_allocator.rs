@@ -5,29 +5,56 @@ use ownserver_lib::EndpointId;
 use ownserver_lib::Endpoints;
 use rand::prelude::*;
 use rand::Rng;
+use serde::Deserialize;
 use std::collections::HashMap;
+use std::collections::BTreeSet;
 use std::collections::HashSet;
-use std::iter::ExactSizeIterator;
+use std::io;
 use std::ops::Range;
+use std::path::Path;
 
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
 pub enum PortAllocatorError {
-    #[error("Port allocation failed because there is no available port.")]
+    #[error("Port allocation failed because the endpoint claims are invalid.")]
     AllocationFailed,
 
+    #[error("Port allocation failed because there is no available port.")]
+    Exhausted,
+
     #[error("Try to release port that is out of range.")]
     PortOutOfRange,
 
     #[error("Try to release port that is already exist in available port table.")]
     PortAlreadyReleased,
+
+    #[error("Excluded port {0} is out of the port range.")]
+    ExcludedPortOutOfRange(u16),
+
+    #[error("Requested port {0} is not available.")]
+    PortUnavailable(u16),
+
+    #[error("The port reservation is unknown or has expired.")]
+    ReservationInvalid,
+
+    #[error("Port {0} is in use by a client.")]
+    PortInUse(u16),
 }
 
 #[derive(Debug)]
 pub struct PortAllocator {
-    available_ports: HashSet<u16>,
+    // ordered so that a seeded rng picks the same ports every run
+    available_ports: BTreeSet<u16>,
+    // held for a returning client, see `allocate_ports_preferring`
+    reserved: HashSet<u16>,
+    // used by other services, never handed out
+    excluded: HashSet<u16>,
     range: Range<u16>,
+    // hand out the lowest free port instead of a random one
+    sequential: bool,
+    // only hand out the remote port each claim asks for
+    fixed: bool,
 }
 
 impl Default for PortAllocator {
@@ -38,23 +65,83 @@ impl Default for PortAllocator {
 
 impl PortAllocator {
     pub fn new(range: Range<u16>) -> Self {
-        let mut set = HashSet::with_capacity(range.len());
-        for p in range.clone() {
-            set.insert(p);
-        }
-
         PortAllocator {
-            available_ports: set,
+            available_ports: range.clone().collect(),
+            reserved: HashSet::new(),
+            excluded: HashSet::new(),
             range,
+            sequential: false,
+            fixed: false,
+        }
+    }
+
+    /// Same as `new` but never allocates the ports in `excluded`. Fails when one is out of `range`.
+    pub fn new_excluding(range: Range<u16>, excluded: &[u16]) -> Result<Self, PortAllocatorError> {
+        let mut alloc = Self::new(range);
+        for port in excluded {
+            alloc.exclude_port(*port)?;
+        }
+        Ok(alloc)
+    }
+
+    /// Never allocate `port`, not even to a client it was reserved for.
+    pub fn exclude_port(&mut self, port: u16) -> Result<(), PortAllocatorError> {
+        if !self.range.contains(&port) {
+            return Err(PortAllocatorError::ExcludedPortOutOfRange(port));
+        }
+        self.available_ports.remove(&port);
+        self.reserved.remove(&port);
+        self.excluded.insert(port);
+        Ok(())
+    }
+
+    /// Always allocate the lowest free port, for reproducible tests.
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Grant exactly the remote port of each claim or fail with `PortUnavailable`, never a random one.
+    /// Claims must then ask for a port instead of 0.
+    pub fn set_fixed(&mut self, fixed: bool) {
+        self.fixed = fixed;
+    }
+
+    /// The ports of the range this allocator can ever hand out.
+    pub fn total_ports(&self) -> usize {
+        self.range.len() - self.excluded.len()
+    }
+
+    /// The ports handed out at this time, reserved ones are not counted.
+    pub fn allocated_ports(&self) -> usize {
+        self.total_ports() - self.available_ports.len() - self.reserved.len()
+    }
+
+    /// Whether `port` is handed out at this time, see `allocated_ports`.
+    pub fn is_allocated(&self, port: u16) -> bool {
+        self.range.contains(&port) && !self.available_ports.contains(&port) && !self.reserved.contains(&port) && !self.excluded.contains(&port)
+    }
+
+    /// The ports handed out at this time in ascending order.
+    pub fn allocated(&self) -> impl Iterator<Item = u16> + '_ {
+        self.range.clone().filter(|port| self.is_allocated(*port))
+    }
+
+    // any available port when `allowed` is `None`
+    fn pick_port(&self, rng: &mut impl Rng, allowed: Option<&[u16]>) -> Option<u16> {
+        match (allowed, self.sequential) {
+            (None, true) => self.available_ports.first().copied(),
+            (None, false) => self.available_ports.iter().choose(rng).copied(),
+            (Some(allowed), true) => self.available_ports.iter().find(|p| allowed.contains(p)).copied(),
+            (Some(allowed), false) => self.available_ports.iter().filter(|p| allowed.contains(p)).choose(rng).copied(),
         }
     }
 
     pub fn allocate_port(&mut self, rng: &mut impl Rng) -> Result<u16, PortAllocatorError> {
-        if let Some(n) = self.available_ports.iter().choose(rng).copied() {
+        if let Some(n) = self.pick_port(rng, None) {
             self.available_ports.remove(&n);
             Ok(n)
         } else {
-            Err(PortAllocatorError::AllocationFailed)
+            Err(PortAllocatorError::Exhausted)
         }
     }
 
@@ -68,41 +155,103 @@ impl PortAllocator {
 
     fn validate_endpoint_claims(&self, aggregated_claims: &HashMap<u16, EndpointClaims>) -> Result<(), PortAllocatorError> {
         if aggregated_claims.keys().len() > self.available_ports.len() {
-            return Err(PortAllocatorError::AllocationFailed);
+            return Err(PortAllocatorError::Exhausted);
         }
 
         let mut local_ports = HashSet::with_capacity(aggregated_claims.len());
-        for EndpointClaim { local_port, protocol, remote_port, .. } in aggregated_claims.values().flatten() {
-            // check local port is unique
-            if !local_ports.insert((*local_port, *protocol)) {
-                return Err(PortAllocatorError::AllocationFailed);
-            }
-            // check remote port is always 0
-            if *remote_port != 0 {
-                return Err(PortAllocatorError::AllocationFailed);
+        for claims in aggregated_claims.values() {
+            for EndpointClaim { local_port, protocol, remote_port, .. } in claims {
+                // check local port is unique
+                if !local_ports.insert((*local_port, *protocol)) {
+                    return Err(PortAllocatorError::AllocationFailed);
+                }
+                // check remote port is always 0, or requested and shared by the claims of a local port
+                let valid = if self.fixed {
+                    *remote_port != 0 && *remote_port == claims[0].remote_port
+                } else {
+                    *remote_port == 0
+                };
+                if !valid {
+                    return Err(PortAllocatorError::AllocationFailed);
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Keep an available `port` out of random allocation. Returns `false` when the port is not available.
+    pub fn reserve_port(&mut self, port: u16) -> bool {
+        if self.available_ports.remove(&port) {
+            self.reserved.insert(port);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Make a port of `reserve_port` available again. Returns `false` when the port was not reserved.
+    pub fn unreserve_port(&mut self, port: u16) -> bool {
+        if self.reserved.remove(&port) {
+            self.available_ports.insert(port);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn allocate_ports(&mut self, rng: &mut impl Rng, client_claims: EndpointClaims) -> Result<Endpoints, PortAllocatorError> {
+        self.allocate_ports_preferring(rng, client_claims, &[])
+    }
+
+    /// Same as `allocate_ports` but hands out the reserved ports in `preferred` first.
+    /// Reserved ports that are not used become available again.
+    pub fn allocate_ports_preferring(&mut self, rng: &mut impl Rng, client_claims: EndpointClaims, preferred: &[u16]) -> Result<Endpoints, PortAllocatorError> {
+        self.allocate_ports_within(rng, client_claims, preferred, None)
+    }
+
+    /// Same as `allocate_ports_preferring` but only hands out ports in `allowed` when it is set.
+    pub fn allocate_ports_within(&mut self, rng: &mut impl Rng, client_claims: EndpointClaims, preferred: &[u16], allowed: Option<&[u16]>) -> Result<Endpoints, PortAllocatorError> {
+        let preferred: Vec<u16> = preferred.iter().copied().filter(|p| self.reserved.remove(p)).collect();
+        self.available_ports.extend(preferred.iter().copied());
+
+        let result = self.allocate_ports_from(rng, client_claims, &preferred, allowed);
+        if result.is_err() {
+            for p in preferred {
+                self.reserve_port(p);
+            }
+        }
+        result
+    }
+
+    fn allocate_ports_from(&mut self, rng: &mut impl Rng, client_claims: EndpointClaims, preferred: &[u16], allowed: Option<&[u16]>) -> Result<Endpoints, PortAllocatorError> {
         let aggregated_claims = self.aggregate_claims_by_local_port(client_claims);
         self.validate_endpoint_claims(&aggregated_claims)?;
 
         let num_ports = aggregated_claims.keys().len();
         let mut ports = Vec::with_capacity(num_ports);
-        for _ in 0..num_ports {
-            if let Some(n) = self.available_ports.iter().choose(rng).copied() {
-                self.available_ports.remove(&n);
-                ports.push(n);
+        let grantable = |alloc: &Self, p: &u16| alloc.available_ports.contains(p) && allowed.map_or(true, |allowed| allowed.contains(p));
+        for (i, claims) in aggregated_claims.values().enumerate() {
+            let port = if self.fixed {
+                let requested = claims[0].remote_port;
+                Some(requested).filter(|p| grantable(self, p)).ok_or(PortAllocatorError::PortUnavailable(requested))
             } else {
-                // should never happen
-                // return temporary allocated ports back to available_ports
-                for p in ports {
-                    self.available_ports.insert(p);
+                let preferred = preferred.get(i).copied().filter(|p| grantable(self, p));
+                // should never fail
+                preferred.or_else(|| self.pick_port(rng, allowed)).ok_or(PortAllocatorError::Exhausted)
+            };
+            match port {
+                Ok(n) => {
+                    self.available_ports.remove(&n);
+                    ports.push(n);
+                }
+                Err(e) => {
+                    // return temporary allocated ports back to available_ports
+                    for p in ports {
+                        self.available_ports.insert(p);
+                    }
+                    return Err(e);
                 }
-                return Err(PortAllocatorError::AllocationFailed);
             }
         }
 
@@ -120,6 +269,21 @@ impl PortAllocator {
         Ok(endpoints)
     }
 
+    /// Hand out another port in place of the allocated `port`, then release `port`.
+    /// Fails with `PortUnavailable` when only requested ports are granted, see `set_fixed`.
+    pub fn move_port(&mut self, rng: &mut impl Rng, port: u16, allowed: Option<&[u16]>) -> Result<u16, PortAllocatorError> {
+        if self.fixed {
+            return Err(PortAllocatorError::PortUnavailable(port));
+        }
+        let moved = self.pick_port(rng, allowed).ok_or(PortAllocatorError::Exhausted)?;
+        self.available_ports.remove(&moved);
+        if let Err(e) = self.release_port(port) {
+            self.available_ports.insert(moved);
+            return Err(e);
+        }
+        Ok(moved)
+    }
+
     pub fn release_port(&mut self, port: u16) -> Result<(), PortAllocatorError> {
         if !self.range.contains(&port) {
             return Err(PortAllocatorError::PortOutOfRange);
@@ -127,6 +291,9 @@ impl PortAllocator {
         if self.available_ports.contains(&port) {
             return Err(PortAllocatorError::PortAlreadyReleased);
         }
+        if self.excluded.contains(&port) {
+            return Ok(());
+        }
 
         self.available_ports.insert(port);
 
@@ -134,6 +301,21 @@ impl PortAllocator {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PortPoolRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Load named port pools from a json file e.g.) `{"free": {"start": 20000, "end": 25000}, "paid": {"start": 25000, "end": 30000}}`
+pub fn load_port_pools(path: &Path) -> io::Result<HashMap<String, Range<u16>>> {
+    let data = std::fs::read(path)?;
+    let pools: HashMap<String, PortPoolRange> = serde_json::from_slice(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(pools.into_iter().map(|(name, PortPoolRange { start, end })| (name, start..end)).collect())
+}
+
 #[cfg(test)]
 mod allocate_port_tests {
     use super::*;
@@ -168,7 +350,66 @@ mod allocate_port_tests {
         assert_eq!(alloc.available_ports.len(), 0);
 
         let port = alloc.allocate_port(&mut rng);
-        assert_eq!(port.err().unwrap(), PortAllocatorError::AllocationFailed);
+        assert_eq!(port.err().unwrap(), PortAllocatorError::Exhausted);
+    }
+
+    #[test]
+    fn allocate_lowest_port_when_sequential() {
+        let mut rng = thread_rng();
+        let mut alloc = PortAllocator::new(1000..1010);
+        alloc.set_sequential(true);
+
+        let ports: Vec<u16> = (0..3).map(|_| alloc.allocate_port(&mut rng).unwrap()).collect();
+        assert_eq!(ports, vec![1000, 1001, 1002]);
+
+        alloc.release_port(1001).unwrap();
+        assert_eq!(alloc.allocate_port(&mut rng).unwrap(), 1001);
+        assert_eq!(alloc.allocate_port(&mut rng).unwrap(), 1003);
+    }
+}
+
+#[cfg(test)]
+mod count_ports_test {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn count_allocated_ports_of_range() {
+        let mut rng = thread_rng();
+        let mut alloc = PortAllocator::new_excluding(1000..1010, &[1009]).unwrap();
+        assert_eq!(alloc.total_ports(), 9);
+        assert_eq!(alloc.allocated_ports(), 0);
+
+        let first = alloc.allocate_port(&mut rng).unwrap();
+        alloc.allocate_port(&mut rng).unwrap();
+        assert!(alloc.reserve_port(alloc.available_ports.first().copied().unwrap()));
+        assert_eq!(alloc.allocated_ports(), 2);
+
+        alloc.release_port(first).unwrap();
+        assert_eq!(alloc.allocated_ports(), 1);
+        assert_eq!(alloc.total_ports(), 9);
+    }
+}
+
+#[cfg(test)]
+mod endpoint_id_test {
+    use super::*;
+    use ownserver_lib::ids::{set_id_generator, SequentialIds};
+    use ownserver_lib::Protocol;
+    use rand::thread_rng;
+
+    #[test]
+    fn number_endpoints_in_claim_order() {
+        let _ids = set_id_generator(SequentialIds::default());
+        let mut alloc = PortAllocator::new(1000..1010);
+        let claims = vec![
+            EndpointClaim { protocol: Protocol::TCP, local_port: 25565, remote_port: 0 },
+            EndpointClaim { protocol: Protocol::UDP, local_port: 25565, remote_port: 0 },
+        ];
+        let endpoints = alloc.allocate_ports(&mut thread_rng(), claims).unwrap();
+        let ids: Vec<EndpointId> = endpoints.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![EndpointId::from_u128(1), EndpointId::from_u128(2)]);
+        assert_eq!(endpoints[0].protocol, Protocol::TCP);
     }
 }
 
@@ -205,6 +446,83 @@ mod release_port_tests {
     }
 }
 
+#[cfg(test)]
+mod reserve_port_tests {
+    use super::*;
+    use ownserver_lib::Protocol;
+    use rand::thread_rng;
+
+    fn claims() -> EndpointClaims {
+        vec![EndpointClaim { protocol: Protocol::TCP, local_port: 25565, remote_port: 0 }]
+    }
+
+    #[test]
+    fn hand_out_reserved_port_only_when_preferred() {
+        let mut rng = thread_rng();
+        let mut alloc = PortAllocator::new(1000..1002);
+        assert!(alloc.reserve_port(1000));
+
+        let endpoints = alloc.allocate_ports(&mut rng, claims()).unwrap();
+        assert_eq!(endpoints[0].remote_port, 1001);
+        assert_eq!(alloc.allocate_ports(&mut rng, claims()).err().unwrap(), PortAllocatorError::Exhausted);
+
+        let endpoints = alloc.allocate_ports_preferring(&mut rng, claims(), &[1000]).unwrap();
+        assert_eq!(endpoints[0].remote_port, 1000);
+    }
+
+    #[test]
+    fn hand_out_allowed_ports_only() {
+        let mut rng = thread_rng();
+        let mut alloc = PortAllocator::new(1000..1010);
+
+        let endpoints = alloc.allocate_ports_within(&mut rng, claims(), &[], Some(&[1005, 2000])).unwrap();
+        assert_eq!(endpoints[0].remote_port, 1005);
+        assert_eq!(alloc.allocate_ports_within(&mut rng, claims(), &[], Some(&[1005, 2000])).err().unwrap(), PortAllocatorError::Exhausted);
+        assert!(alloc.allocate_ports_within(&mut rng, claims(), &[], None).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod exclude_port_tests {
+    use super::*;
+    use ownserver_lib::Protocol;
+    use rand::thread_rng;
+
+    #[test]
+    fn never_hand_out_excluded_ports() {
+        let mut rng = thread_rng();
+        let excluded = [1003, 1007];
+        for _ in 0..100 {
+            let mut alloc = PortAllocator::new_excluding(1000..1010, &excluded).unwrap();
+            let mut ports = Vec::new();
+            while let Ok(port) = alloc.allocate_port(&mut rng) {
+                ports.push(port);
+            }
+            assert_eq!(ports.len(), 8);
+            assert!(ports.iter().all(|port| !excluded.contains(port)));
+
+            // nor once given back
+            assert_eq!(alloc.release_port(1003), Ok(()));
+            assert_eq!(alloc.allocate_port(&mut rng), Err(PortAllocatorError::Exhausted));
+        }
+    }
+
+    #[test]
+    fn never_hand_out_excluded_ports_for_claims() {
+        let mut rng = thread_rng();
+        let mut alloc = PortAllocator::new_excluding(1000..1002, &[1000]).unwrap();
+        alloc.set_sequential(true);
+        let claims = vec![EndpointClaim { protocol: Protocol::TCP, local_port: 25565, remote_port: 0 }];
+
+        assert_eq!(alloc.allocate_ports(&mut rng, claims.clone()).unwrap()[0].remote_port, 1001);
+        assert_eq!(alloc.allocate_ports_preferring(&mut rng, claims, &[1000]).err().unwrap(), PortAllocatorError::Exhausted);
+    }
+
+    #[test]
+    fn reject_excluded_port_out_of_range() {
+        assert_eq!(PortAllocator::new_excluding(1000..1010, &[1010]).err().unwrap(), PortAllocatorError::ExcludedPortOutOfRange(1010));
+    }
+}
 
 #[cfg(test)]
 mod aggregate_claims_by_local_port {
@@ -309,7 +627,7 @@ mod validate_endpoint_claims_tests {
         let aggregated_claims = alloc.aggregate_claims_by_local_port(claims);
 
         let result = alloc.validate_endpoint_claims(&aggregated_claims);
-        assert_eq!(result.err().unwrap(), PortAllocatorError::AllocationFailed);
+        assert_eq!(result.err().unwrap(), PortAllocatorError::Exhausted);
     }
 
     #[test]
@@ -417,7 +735,7 @@ mod allocate_ports_test {
         ];
 
         let endpoints = alloc.allocate_ports(&mut rng, claims);
-        assert_eq!(endpoints.err().unwrap(), PortAllocatorError::AllocationFailed);
+        assert_eq!(endpoints.err().unwrap(), PortAllocatorError::Exhausted);
     }
 
     #[test]
@@ -432,6 +750,58 @@ mod allocate_ports_test {
         ];
 
         let endpoints = alloc.allocate_ports(&mut rng, claims);
-        assert_eq!(endpoints.err().unwrap(), PortAllocatorError::AllocationFailed);
+        assert_eq!(endpoints.err().unwrap(), PortAllocatorError::Exhausted);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod fixed_ports_test {
+    use super::*;
+    use ownserver_lib::Protocol;
+    use rand::thread_rng;
+
+    #[test]
+    fn grant_requested_port_or_reject_without_fallback() -> Result<(), PortAllocatorError> {
+        let mut rng = thread_rng();
+        let mut alloc = PortAllocator::new(1000..1010);
+        alloc.set_fixed(true);
+
+        let claims = vec![
+            EndpointClaim { protocol: Protocol::TCP, local_port: 25565, remote_port: 1005 },
+            EndpointClaim { protocol: Protocol::UDP, local_port: 25565, remote_port: 1005 },
+        ];
+        let endpoints = alloc.allocate_ports(&mut rng, claims)?;
+        assert_eq!(endpoints.len(), 2);
+        assert!(endpoints.iter().all(|e| e.remote_port == 1005));
+
+        // taken, even though other ports are free
+        let taken = vec![EndpointClaim { protocol: Protocol::TCP, local_port: 25566, remote_port: 1005 }];
+        assert_eq!(alloc.allocate_ports(&mut rng, taken).err(), Some(PortAllocatorError::PortUnavailable(1005)));
+        assert_eq!(alloc.available_ports.len(), 9);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_claims_without_requested_port() {
+        let mut rng = thread_rng();
+        let mut alloc = PortAllocator::new(1000..1010);
+        alloc.set_fixed(true);
+
+        let claims = vec![EndpointClaim { protocol: Protocol::TCP, local_port: 25565, remote_port: 0 }];
+        assert_eq!(alloc.allocate_ports(&mut rng, claims).err(), Some(PortAllocatorError::AllocationFailed));
+    }
+
+    #[test]
+    fn return_every_port_when_one_is_unavailable() {
+        let mut rng = thread_rng();
+        let mut alloc = PortAllocator::new(1000..1010);
+        alloc.set_fixed(true);
+
+        let claims = vec![
+            EndpointClaim { protocol: Protocol::TCP, local_port: 25565, remote_port: 1001 },
+            EndpointClaim { protocol: Protocol::TCP, local_port: 25566, remote_port: 2000 },
+        ];
+        assert_eq!(alloc.allocate_ports(&mut rng, claims).err(), Some(PortAllocatorError::PortUnavailable(2000)));
+        assert_eq!(alloc.available_ports.len(), 10);
+    }
+}
@@ -1,24 +1,258 @@
 pub use ownserver_lib::{ClientId, StreamId};
-use std::sync::Arc;
-use tokio::task::JoinSet;
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+use tokio::{sync::broadcast, task::{JoinError, JoinSet}, time::Instant};
 use once_cell::sync::OnceCell;
 
-use crate::{control_server_v2, Store};
-use crate::Config;
+use crate::{audit::ServerEvent, control_server_h2, control_server_v2, listener::ControlListeners, telemetry, Store};
+use crate::{Config, ServerConfig};
 
-#[tracing::instrument(skip(config, store))]
+/// Same as `run_with_config` with a config kept in a static, as the `ownserver-server` binary does.
 pub async fn run(
     config: &'static OnceCell<Config>,
     store: Arc<Store>,
-) -> JoinSet<()> {
+) -> RunHandle {
+    let config = config.get().expect("failed to read config").clone();
+    run_with_config(Arc::new(config), store).await
+}
+
+/// Client tokens are checked by the verifier set with `Store::with_token_verifier`, e.g. a `JwtVerifier`,
+/// jwts signed with `config.token_secret` otherwise.
+#[tracing::instrument(skip(config, store))]
+pub async fn run_with_config(config: Arc<ServerConfig>, store: Arc<Store>) -> RunHandle {
+    let started_at = Instant::now();
     tracing::info!("starting server!");
+    telemetry::init_metrics();
 
-    let control_port = config.get().expect("failed to read config").control_port;
+    let control_port = config.control_port;
+    let h2_control_port = config.h2_control_port;
 
-    let set = control_server_v2::spawn(
-        config,
-        store,
+    let listeners = Arc::new(ControlListeners::default());
+    let mut set = control_server_v2::spawn(
+        config.clone(),
+        store.clone(),
+        listeners.clone(),
         ([0, 0, 0, 0], control_port));
     tracing::info!("started tunnelto server on 0.0.0.0:{}", control_port);
-    set
+
+    if let Some(h2_control_port) = h2_control_port {
+        set.spawn(control_server_h2::spawn(
+            config.clone(),
+            store.clone(),
+            ([0, 0, 0, 0], h2_control_port)));
+        tracing::info!("started h2 tunnel server on 0.0.0.0:{}", h2_control_port);
+    }
+    RunHandle { set, store, listeners, started_at }
+}
+
+/// Why `RunHandle::wait_until` returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    /// the shutdown future completed
+    Shutdown,
+    /// every server task returned by itself
+    Finished,
+    /// a server task panicked or was cancelled, the first one is reported
+    TaskFailed(String),
+}
+
+/// End of session report returned by `RunHandle::wait_until`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub streams: u64,
+    pub bytes_to_clients: u64,
+    pub bytes_from_clients: u64,
+    pub uptime: Duration,
+    pub exit_reason: ExitReason,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "streams={} bytes_to_clients={} bytes_from_clients={} uptime={}s exit_reason={:?}",
+            self.streams, self.bytes_to_clients, self.bytes_from_clients, self.uptime.as_secs(), self.exit_reason,
+        )
+    }
+}
+
+/// Server tasks started by `run`. More tasks may be added with `spawn`, e.g. a metrics endpoint.
+#[derive(Debug)]
+pub struct RunHandle {
+    set: JoinSet<()>,
+    store: Arc<Store>,
+    listeners: Arc<ControlListeners>,
+    started_at: Instant,
+}
+
+impl RunHandle {
+    pub fn spawn<F>(&mut self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.set.spawn(task);
+    }
+
+    /// Connect, disconnect and stream events of the store, see `Store::with_events`.
+    pub fn events(&self) -> Option<broadcast::Receiver<ServerEvent>> {
+        self.store.subscribe()
+    }
+
+    /// Control ports being served, more can be started and old ones drained, see `ControlListeners`.
+    pub fn listeners(&self) -> &Arc<ControlListeners> {
+        &self.listeners
+    }
+
+    pub async fn join_next(&mut self) -> Option<Result<(), JoinError>> {
+        self.set.join_next().await
+    }
+
+    /// Wait until every task returns or `shutdown` completes, whichever is first, and report the session.
+    /// Tasks still running at shutdown are aborted.
+    pub async fn wait_until(mut self, shutdown: impl Future<Output = ()>) -> RunSummary {
+        tokio::pin!(shutdown);
+        let mut failure = None;
+        let exit_reason = loop {
+            tokio::select! {
+                res = self.set.join_next() => match res {
+                    Some(Err(join_error)) => {
+                        tracing::error!("join error {:?} for proxy_server", join_error);
+                        failure.get_or_insert_with(|| join_error.to_string());
+                    }
+                    Some(Ok(())) => tracing::info!("proxy_server task successfully terminated"),
+                    None => break ExitReason::Finished,
+                },
+                _ = &mut shutdown => break ExitReason::Shutdown,
+            }
+        };
+        self.listeners.drain_all();
+        self.set.shutdown().await;
+
+        let totals = self.store.traffic_totals();
+        RunSummary {
+            streams: totals.streams,
+            bytes_to_clients: totals.bytes_to_clients,
+            bytes_from_clients: totals.bytes_from_clients,
+            uptime: self.started_at.elapsed(),
+            exit_reason: failure.map(ExitReason::TaskFailed).unwrap_or(exit_reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod run_test {
+    use super::*;
+
+    #[tokio::test]
+    #[should_panic]
+    async fn panic_when_config_not_initialized() {
+        static EMPTY_CONFIG: OnceCell<Config> = OnceCell::new();
+        run(&EMPTY_CONFIG, Default::default()).await;
+    }
+}
+
+#[cfg(test)]
+mod run_summary_test {
+    use super::*;
+    use ownserver_lib::ControlPacketV2;
+    use tokio::net::UdpSocket;
+    use crate::{remote::stream::StreamMessage, test_support::{add_client, add_udp_stream}};
+
+    #[tokio::test]
+    async fn report_forwarded_traffic_after_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+        let config = ServerConfig::builder()
+            .token_secret("supersecret")
+            .host("localhost")
+            .control_port(10062)
+            .port_range(10000..20000)
+            .build()?;
+        let store: Arc<Store> = Default::default();
+        let handle = run_with_config(Arc::new(config), store.clone()).await;
+
+        let (client_id, _sent) = add_client(&store).await;
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let stream_id = add_udp_stream(&store, client_id, peer.local_addr()?).await?;
+
+        store.send_to_client(client_id, ControlPacketV2::Data(stream_id, vec![0; 100])).await?;
+        store.send_to_remote(stream_id, StreamMessage::Data(vec![0; 40])).await?;
+
+        let summary = handle.wait_until(async {}).await;
+        assert_eq!(summary.streams, 1);
+        assert_eq!(summary.bytes_to_clients, 100);
+        assert_eq!(summary.bytes_from_clients, 40);
+        assert_eq!(summary.exit_reason, ExitReason::Shutdown);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod listener_migration_test {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use ownserver_lib::{ClientHelloV2, EndpointClaim, Protocol, ServerHelloV2, CLIENT_HELLO_VERSION};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message;
+    use crate::{listener::ListenerError, verifier::NoAuthVerifier};
+
+    async fn health_check(port: u16) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream.write_all(b"GET /health_check HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    async fn wait_for_state(listeners: &ControlListeners, port: u16, state: &str) -> Option<crate::listener::ListenerStatus> {
+        for _ in 0..50 {
+            match listeners.status(port) {
+                Some(status) if status.state == state => return Some(status),
+                _ => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+        listeners.status(port)
+    }
+
+    #[tokio::test]
+    async fn keep_clients_of_drained_control_port() -> Result<(), Box<dyn std::error::Error>> {
+        let config = ServerConfig::builder()
+            .token_secret("supersecret")
+            .host("localhost")
+            .control_port(10072)
+            .port_range(10076..10077)
+            .build()?;
+        let store = Arc::new(Store::new(config.remote_port_start..config.remote_port_end).with_token_verifier(Arc::new(NoAuthVerifier)));
+        let handle = run_with_config(Arc::new(config), store.clone()).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (mut ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:10072/tunnel").await?;
+        let hello = serde_json::to_vec(&ClientHelloV2 {
+            version: CLIENT_HELLO_VERSION,
+            token: String::new(),
+            endpoint_claims: vec![EndpointClaim { protocol: Protocol::TCP, local_port: 25565, remote_port: 0 }],
+            capabilities: Vec::new(),
+            reservation: None,
+        })?;
+        ws.send(Message::binary(hello)).await?;
+        let message = tokio::time::timeout(Duration::from_secs(2), ws.next()).await?.ok_or("closed before hello")??;
+        assert!(matches!(serde_json::from_slice::<ServerHelloV2>(&message.into_data())?, ServerHelloV2::Success { .. }));
+
+        let listeners = handle.listeners().clone();
+        assert!(matches!(listeners.drain(10072), Err(ListenerError::LastListener(10072))));
+        listeners.start(10074).await?;
+        let draining = listeners.drain(10072)?;
+        assert_eq!(draining.state, "draining");
+        assert_eq!(draining.connections, 1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(("127.0.0.1", 10072)).await.is_err());
+        assert!(health_check(10074).await?.starts_with("HTTP/1.1 200"));
+        assert_eq!(store.len_clients().await, 1);
+
+        drop(ws);
+        let drained = wait_for_state(&listeners, 10072, "drained").await;
+        assert_eq!(drained.map(|status| status.connections), Some(0));
+        assert_eq!(listeners.status(10074).map(|status| status.state), Some("accepting"));
+        Ok(())
+    }
 }
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Number of buckets the window is split into. Old traffic expires one bucket at a time.
+const BUCKETS: u32 = 60;
+
+/// Rolling byte counter that allows at most `limit` bytes within any `window`.
+#[derive(Debug, Clone)]
+pub struct ByteQuota {
+    limit: u64,
+    window: Duration,
+    bucket: Duration,
+    buckets: VecDeque<(Instant, u64)>,
+    total: u64,
+}
+
+impl ByteQuota {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            bucket: (window / BUCKETS).max(Duration::from_millis(1)),
+            buckets: VecDeque::new(),
+            total: 0,
+        }
+    }
+
+    /// Count `n` bytes. Returns `false` once the window holds more than `limit` bytes.
+    pub fn add(&mut self, n: u64) -> bool {
+        self.add_at(Instant::now(), n)
+    }
+
+    fn add_at(&mut self, now: Instant, n: u64) -> bool {
+        while let Some(&(start, bytes)) = self.buckets.front() {
+            if now.duration_since(start) < self.window {
+                break;
+            }
+            self.total -= bytes;
+            self.buckets.pop_front();
+        }
+
+        match self.buckets.back_mut() {
+            Some((start, bytes)) if now.duration_since(*start) < self.bucket => *bytes += n,
+            _ => self.buckets.push_back((now, n)),
+        }
+        self.total += n;
+
+        self.total <= self.limit
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod byte_quota_test {
+    use super::*;
+
+    #[test]
+    fn exceed_quota_within_window() {
+        let mut quota = ByteQuota::new(100, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(quota.add_at(now, 60));
+        assert!(quota.add_at(now + Duration::from_secs(10), 40));
+        assert!(!quota.add_at(now + Duration::from_secs(20), 1));
+        assert_eq!(quota.total(), 101);
+    }
+
+    #[test]
+    fn expire_old_traffic() {
+        let mut quota = ByteQuota::new(100, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(quota.add_at(now, 100));
+        assert!(quota.add_at(now + Duration::from_secs(61), 100));
+        assert_eq!(quota.total(), 100);
+    }
+}
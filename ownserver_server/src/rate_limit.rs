@@ -0,0 +1,217 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+/// Buckets of IPs that opened nothing for this long are forgotten once they are full again.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest `MessageRateLimiter::delay` holds a message back.
+const MAX_MESSAGE_DELAY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token bucket per source IP, limiting how fast new remote connections are opened.
+#[derive(Debug)]
+pub struct ConnectionRateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, TokenBucket>,
+}
+
+impl ConnectionRateLimiter {
+    /// Allow `rate` connections per second on average and `burst` at once from each IP.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a token for a new connection from `ip`. `false` when the connection should be dropped.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut bucket = self.buckets.entry(ip).or_insert(TokenBucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        bucket.tokens = self.refill(&bucket, now);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    /// Drop buckets of idle IPs. A dropped bucket is indistinguishable from a fresh one.
+    pub fn prune(&self) {
+        self.prune_at(Instant::now())
+    }
+
+    fn prune_at(&self, now: Instant) {
+        self.buckets.retain(|_, bucket| {
+            now.saturating_duration_since(bucket.updated_at) < IDLE_TIMEOUT || self.refill(bucket, now) < self.burst
+        });
+    }
+}
+
+/// Token bucket of a single stream, limiting how many messages per second it forwards.
+/// A second worth of messages may be sent at once.
+#[derive(Debug, Clone)]
+pub struct MessageRateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: TokenBucket,
+}
+
+impl MessageRateLimiter {
+    pub fn new(rate: f64) -> Self {
+        Self::new_at(rate, Instant::now())
+    }
+
+    fn new_at(rate: f64, now: Instant) -> Self {
+        let burst = rate.ceil().max(1.0);
+        Self {
+            rate,
+            burst,
+            bucket: TokenBucket { tokens: burst, updated_at: now },
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.bucket.updated_at).as_secs_f64();
+        self.bucket.tokens = (self.bucket.tokens + elapsed * self.rate).min(self.burst);
+        self.bucket.updated_at = now;
+    }
+
+    /// Take a token for the next message. `false` when the message should be dropped.
+    pub fn check(&mut self) -> bool {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.bucket.tokens >= 1.0 {
+            self.bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take a token for the next message, how long to wait before it may be sent.
+    pub fn delay(&mut self) -> Duration {
+        self.delay_at(Instant::now())
+    }
+
+    fn delay_at(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        // the token of a delayed message is borrowed from the time it waits
+        self.bucket.tokens -= 1.0;
+        if self.bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            // tiny rates would wait longer than a `Duration` holds
+            Duration::try_from_secs_f64(-self.bucket.tokens / self.rate).unwrap_or(MAX_MESSAGE_DELAY).min(MAX_MESSAGE_DELAY)
+        }
+    }
+
+    /// `true` once the bucket is full again, when it is no different from a new one.
+    pub fn is_idle(&mut self) -> bool {
+        self.refill(Instant::now());
+        self.bucket.tokens >= self.burst
+    }
+}
+
+#[cfg(test)]
+mod connection_rate_limiter_test {
+    use super::*;
+
+    #[test]
+    fn reject_connections_over_the_limit() {
+        let limiter = ConnectionRateLimiter::new(2.0, 3);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        let accepted = (0..10).filter(|_| limiter.check_at(ip, now)).count();
+        assert_eq!(accepted, 3);
+        assert!(limiter.check_at(other, now));
+
+        // 2 tokens per second
+        assert!(limiter.check_at(ip, now + Duration::from_millis(500)));
+        assert!(!limiter.check_at(ip, now + Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn forget_idle_ips() {
+        let limiter = ConnectionRateLimiter::new(1.0, 3);
+        let idle: IpAddr = "192.0.2.1".parse().unwrap();
+        let active: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(idle, now));
+        assert!(limiter.check_at(active, now + IDLE_TIMEOUT));
+        limiter.prune_at(now + IDLE_TIMEOUT);
+
+        assert!(!limiter.buckets.contains_key(&idle));
+        assert!(limiter.buckets.contains_key(&active));
+    }
+}
+
+#[cfg(test)]
+mod message_rate_limiter_test {
+    use super::*;
+
+    #[test]
+    fn drop_messages_over_the_limit() {
+        let now = Instant::now();
+        let mut limiter = MessageRateLimiter::new_at(10.0, now);
+
+        let accepted = (0..100).filter(|_| limiter.check_at(now)).count();
+        assert_eq!(accepted, 10);
+        assert!(limiter.check_at(now + Duration::from_millis(100)));
+        assert!(!limiter.check_at(now + Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn delay_messages_over_the_limit() {
+        let now = Instant::now();
+        let mut limiter = MessageRateLimiter::new_at(10.0, now);
+
+        let about = |delay: Duration, millis: f64| (delay.as_secs_f64() * 1000.0 - millis).abs() < 0.001;
+
+        for _ in 0..10 {
+            assert_eq!(limiter.delay_at(now), Duration::ZERO);
+        }
+        assert!(about(limiter.delay_at(now), 100.0));
+        // the message after the delayed one waits for a token of its own
+        assert!(about(limiter.delay_at(now + Duration::from_millis(100)), 100.0));
+    }
+
+    #[test]
+    fn cap_delay_of_tiny_rates() {
+        let now = Instant::now();
+        let mut limiter = MessageRateLimiter::new_at(1e-300, now);
+
+        assert_eq!(limiter.delay_at(now), Duration::ZERO);
+        assert_eq!(limiter.delay_at(now), MAX_MESSAGE_DELAY);
+    }
+}
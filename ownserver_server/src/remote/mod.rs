@@ -1,3 +1,159 @@
 pub mod udp;
 pub mod tcp;
-pub mod stream;
\ No newline at end of file
+pub mod sniff;
+pub mod stream;
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use ownserver_lib::{ClientId, EndpointId};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+pub use ownserver_lib::socket::{with_timeout, SocketTimeouts, TcpOptions};
+
+/// Pending connections each remote tcp listener queues, the same as `TcpListener::bind`.
+pub const DEFAULT_BACKLOG: u32 = 1024;
+/// The kernel caps the backlog further by `net.core.somaxconn`.
+pub const MAX_BACKLOG: u32 = 65535;
+
+/// Parse a listen backlog between 1 and `MAX_BACKLOG`.
+pub fn parse_backlog(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(backlog) if (1..=MAX_BACKLOG).contains(&backlog) => Ok(backlog),
+        Ok(backlog) => Err(format!("backlog {} is out of range 1-{}", backlog, MAX_BACKLOG)),
+        Err(e) => Err(format!("invalid backlog `{}`: {}", s, e)),
+    }
+}
+
+/// Sockets of an endpoint, bound before the client is told its remote port.
+#[derive(Debug)]
+pub enum BoundRemote {
+    Tcp(Vec<TcpListener>),
+    Udp(Vec<UdpSocket>),
+}
+
+impl BoundRemote {
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        match self {
+            BoundRemote::Tcp(listeners) => listeners.iter().filter_map(|l| l.local_addr().ok()).collect(),
+            BoundRemote::Udp(sockets) => sockets.iter().filter_map(|s| s.local_addr().ok()).collect(),
+        }
+    }
+}
+
+/// Sent to `Store::with_bind_events` once a remote port is listening.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteBound {
+    pub client_id: ClientId,
+    pub endpoint_id: EndpointId,
+    pub addr: SocketAddr,
+}
+
+/// Bytes written to every new remote tcp connection before it is relayed.
+/// Given as text, `base64:<data>` for binary banners or `file:<path>` to read them from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Banner(pub Vec<u8>);
+
+impl FromStr for Banner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(data) = s.strip_prefix("base64:") {
+            STANDARD.decode(data.trim()).map(Banner).map_err(|e| format!("invalid base64 banner: {}", e))
+        } else if let Some(path) = s.strip_prefix("file:") {
+            std::fs::read(path).map(Banner).map_err(|e| format!("failed to read banner file `{}`: {}", path, e))
+        } else {
+            Ok(Banner(s.as_bytes().to_vec()))
+        }
+    }
+}
+
+/// Options set on every accepted remote tcp socket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub tcp: TcpOptions,
+    /// peek the first bytes to tell http requests from raw tcp before a stream is opened
+    pub sniff_http: bool,
+    /// written to the remote connection before anything is relayed
+    pub banner: Option<&'static [u8]>,
+}
+
+// IPv6 only, so that it coexists with the IPv4 listener on the same port
+fn ipv6_socket(port: u16, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, ty, Some(protocol))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket)
+}
+
+fn listen(socket: Socket, backlog: u32) -> io::Result<TcpListener> {
+    socket.listen(backlog.min(MAX_BACKLOG) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Listen on `0.0.0.0:port` with room for `backlog` pending connections.
+pub fn bind_tcp(port: u16, backlog: u32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    listen(socket, backlog)
+}
+
+/// Listen on `[::]:port` next to the IPv4 listener of a remote tcp port.
+pub fn bind_ipv6_tcp(port: u16, backlog: u32) -> io::Result<TcpListener> {
+    listen(ipv6_socket(port, Type::STREAM, Protocol::TCP)?, backlog)
+}
+
+/// Bind `[::]:port` next to the IPv4 socket of a remote udp port.
+pub fn bind_ipv6_udp(port: u16) -> io::Result<UdpSocket> {
+    let socket = ipv6_socket(port, Type::DGRAM, Protocol::UDP)?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod socket_options_test {
+    use super::*;
+    use socket2::SockRef;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn listen_with_backlog() -> io::Result<()> {
+        let listener = bind_tcp(0, 16)?;
+        // the backlog itself can't be read back, so check that the socket listens and accepts
+        assert!(SockRef::from(&listener).is_listener()?);
+        let port = listener.local_addr()?.port();
+        let _client = TcpStream::connect(("127.0.0.1", port)).await?;
+        listener.accept().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn reject_backlog_out_of_range() {
+        assert_eq!(parse_backlog("4096"), Ok(4096));
+        assert_eq!(parse_backlog("65535"), Ok(MAX_BACKLOG));
+        assert!(parse_backlog("0").is_err());
+        assert!(parse_backlog("65536").is_err());
+        assert!(parse_backlog("-1").is_err());
+    }
+
+    #[test]
+    fn parse_text_base64_and_file_banners() -> io::Result<()> {
+        assert_eq!("welcome\n".parse::<Banner>(), Ok(Banner(b"welcome\n".to_vec())));
+        assert_eq!("base64:AP8K".parse::<Banner>(), Ok(Banner(vec![0x00, 0xff, 0x0a])));
+        assert!("base64:not base64!".parse::<Banner>().is_err());
+
+        let path = std::env::temp_dir().join(format!("ownserver-banner-{}", std::process::id()));
+        std::fs::write(&path, [0xfe, 0x01])?;
+        assert_eq!(format!("file:{}", path.display()).parse::<Banner>(), Ok(Banner(vec![0xfe, 0x01])));
+        std::fs::remove_file(&path)?;
+        assert!("file:/nonexistent/banner".parse::<Banner>().is_err());
+        Ok(())
+    }
+}
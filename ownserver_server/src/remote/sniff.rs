@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// How long to wait for the first bytes of a remote connection before it is taken as raw tcp,
+/// for protocols where the server speaks first.
+pub const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+const SNIFF_LEN: usize = 2048;
+// retry delay while only part of a request line has arrived, peek returns at once with the same bytes
+const SNIFF_RETRY: Duration = Duration::from_millis(10);
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE ",
+];
+
+/// What a remote connection speaks, told from its first bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sniffed {
+    /// `host` is the Host header when it arrived with the first bytes
+    Http { host: Option<String> },
+    Raw,
+}
+
+impl Sniffed {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Sniffed::Http { .. } => "http",
+            Sniffed::Raw => "raw",
+        }
+    }
+}
+
+/// Peek the first bytes of `socket`. Nothing is consumed, the stream forwards every byte afterwards.
+pub async fn sniff(socket: &TcpStream, timeout: Duration) -> Sniffed {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; SNIFF_LEN];
+    loop {
+        let n = match tokio::time::timeout_at(deadline, socket.peek(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => n,
+            _ => return Sniffed::Raw,
+        };
+        if let Some(sniffed) = classify(&buf[..n]) {
+            return sniffed;
+        }
+        if Instant::now() + SNIFF_RETRY >= deadline {
+            return Sniffed::Raw;
+        }
+        tokio::time::sleep(SNIFF_RETRY).await;
+    }
+}
+
+// `None` while the bytes could still become an http request line
+fn classify(data: &[u8]) -> Option<Sniffed> {
+    if HTTP_METHODS.iter().any(|method| data.starts_with(method)) {
+        return Some(Sniffed::Http { host: find_host(data) });
+    }
+    if HTTP_METHODS.iter().any(|method| method.starts_with(data)) {
+        return None;
+    }
+    Some(Sniffed::Raw)
+}
+
+fn find_host(data: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines: Vec<&str> = text.split("\r\n").collect();
+    // the last line is cut off unless the peeked bytes end with a line break
+    lines.pop();
+    lines
+        .into_iter()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod sniff_test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn keep_peeked_bytes_of_http_and_raw_connections() -> std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let request = b"GET /status HTTP/1.1\r\nHost: play.example.com\r\n\r\n".to_vec();
+        let blob: Vec<u8> = (0..=255).collect();
+
+        for (sent, expected) in [
+            (request.clone(), Sniffed::Http { host: Some("play.example.com".to_string()) }),
+            (blob.clone(), Sniffed::Raw),
+        ] {
+            let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+            let (mut socket, _) = listener.accept().await?;
+            peer.write_all(&sent).await?;
+            peer.shutdown().await?;
+
+            assert_eq!(sniff(&socket, SNIFF_TIMEOUT).await, expected);
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await?;
+            assert_eq!(received, sent);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn treat_silent_connection_as_raw() -> std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let _peer = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, _) = listener.accept().await?;
+        assert_eq!(sniff(&socket, Duration::from_millis(100)).await, Sniffed::Raw);
+        Ok(())
+    }
+
+    #[test]
+    fn classify_first_bytes() {
+        assert_eq!(
+            classify(b"GET / HTTP/1.1\r\nhost: play.example.com\r\n\r\n"),
+            Some(Sniffed::Http { host: Some("play.example.com".to_string()) })
+        );
+        assert_eq!(classify(b"POST /api HTTP/1.1\r\n"), Some(Sniffed::Http { host: None }));
+        assert_eq!(classify(b"GET / HTTP/1.1\r\nHost: play.exa"), Some(Sniffed::Http { host: None }));
+        assert_eq!(classify(b"GE"), None);
+        assert_eq!(classify(b"\x10\x00\xf5\x05"), Some(Sniffed::Raw));
+    }
+}
@@ -1,9 +1,49 @@
-use ownserver_lib::{StreamId, ClientId, ControlPacketV2};
+use std::time::Duration;
+
+use metrics::increment_counter;
+use ownserver_lib::{StreamId, ClientId, ControlPacketV2, EndpointId};
 use crate::ClientStreamError;
 
 use super::{tcp::RemoteTcp, udp::RemoteUdp};
 
 
+/// Why a stream was torn down, counted by `ownserver_server.stream.closed{cause}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseCause {
+    /// the remote peer closed its connection
+    PeerClosed,
+    /// the local service closed its connection, told by the client
+    LocalClosed,
+    /// a read or write timed out, or the least recently seen peer was evicted
+    IdleTimeout,
+    /// the client went over its traffic quota
+    QuotaExceeded,
+    /// the client went away and the stream was not held for it
+    ClientDisconnected,
+    /// closed through the admin API, an access list reload or a server shutdown
+    AdminClosed,
+    Error,
+}
+
+impl CloseCause {
+    pub fn label(&self) -> &'static str {
+        match self {
+            CloseCause::PeerClosed => "peer_closed",
+            CloseCause::LocalClosed => "local_closed",
+            CloseCause::IdleTimeout => "idle_timeout",
+            CloseCause::QuotaExceeded => "quota_exceeded",
+            CloseCause::ClientDisconnected => "client_disconnected",
+            CloseCause::AdminClosed => "admin_closed",
+            CloseCause::Error => "error",
+        }
+    }
+
+    // only the first cause of a stream is counted
+    pub(crate) fn record(&self) {
+        increment_counter!("ownserver_server.stream.closed", "cause" => self.label());
+    }
+}
+
 #[derive(Debug)]
 pub enum RemoteStream {
     RemoteTcp(RemoteTcp),
@@ -34,13 +74,46 @@ impl RemoteStream {
     }
 
 
-    pub fn disable(&mut self) {
+    /// Only tcp streams are flow controlled, udp datagrams are forwarded best-effort.
+    pub fn add_window(&self, n: u32) {
+        if let RemoteStream::RemoteTcp(tcp) = self {
+            tcp.add_window(n);
+        }
+    }
+
+    /// Keep a tcp stream open for `window` while its client reconnects. Returns `false` for udp,
+    /// datagrams are not worth holding.
+    pub fn hold(&self, window: Duration) -> bool {
+        match self {
+            RemoteStream::RemoteTcp(tcp) => {
+                tcp.hold(window);
+                true
+            }
+            RemoteStream::RemoteUdp(_) => false,
+        }
+    }
+
+    pub fn rebind(&mut self, client_id: ClientId, endpoint_id: EndpointId) {
+        if let RemoteStream::RemoteTcp(tcp) = self {
+            tcp.rebind(client_id, endpoint_id);
+        }
+    }
+
+    /// Token subject reported by the audit events of the stream.
+    pub fn set_token_subject(&mut self, subject: Option<String>) {
+        match self {
+            RemoteStream::RemoteTcp(tcp) => tcp.token_subject = subject,
+            RemoteStream::RemoteUdp(udp) => udp.token_subject = subject,
+        }
+    }
+
+    pub fn disable(&mut self, cause: CloseCause) {
         match self {
             RemoteStream::RemoteTcp(tcp) => {
-                tcp.disable();
+                tcp.disable(cause);
             }
             RemoteStream::RemoteUdp(udp) => {
-                udp.disable();
+                udp.disable(cause);
             }
         }
     }
@@ -67,6 +140,17 @@ impl RemoteStream {
         }
     }
 
+    pub fn endpoint_id(&self) -> EndpointId {
+        match self {
+            RemoteStream::RemoteTcp(tcp) => {
+                tcp.endpoint_id
+            }
+            RemoteStream::RemoteUdp(udp) => {
+                udp.endpoint_id
+            }
+        }
+    }
+
     pub fn client_id(&self) -> ClientId {
         match self {
             RemoteStream::RemoteTcp(tcp) => {
@@ -84,4 +168,6 @@ pub enum StreamMessage {
     Data(Vec<u8>),
     TunnelRefused,
     NoClientTunnel,
+    /// the local service is done sending, shut down the write half of the remote socket
+    ShutdownWrite,
 }
\ No newline at end of file
@@ -1,41 +1,127 @@
 use metrics::increment_counter;
-use ownserver_lib::{EndpointId, ControlPacketV2};
+use ownserver_lib::{Capability, EndpointId, ControlPacketV2, INITIAL_STREAM_WINDOW};
 use std::io::{self, ErrorKind};
-use std::sync::Arc;
-use tokio::{net::{TcpListener, TcpStream}, io::{WriteHalf, AsyncReadExt, AsyncWriteExt}};
+use std::net::SocketAddr;
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+use std::time::Duration;
+use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}, sync::{Semaphore, watch, mpsc::{unbounded_channel, UnboundedSender}}};
 use tracing::Instrument;
 use tokio_util::sync::CancellationToken;
 
-use crate::{ClientStreamError, Store, remote::stream::RemoteStream};
+use crate::{accept::{accept_with_backoff, is_fd_exhausted, ACCEPT_BACKOFF}, audit::AuditEvent, logging::stream_span, rate_limit::MessageRateLimiter, ClientStreamError, Store, remote::stream::{CloseCause, RemoteStream}};
 pub use ownserver_lib::{ClientId, StreamId};
 
+use super::sniff::{sniff, Sniffed, SNIFF_TIMEOUT};
 use super::stream::StreamMessage;
+use super::{bind_ipv6_tcp, bind_tcp, with_timeout, DEFAULT_BACKLOG, SocketOptions, SocketTimeouts};
 
 #[tracing::instrument(skip(store, cancellation_token))]
 pub async fn spawn_remote(
     store: Arc<Store>,
     client_id: ClientId,
     endpoint_id: EndpointId,
+    timeouts: SocketTimeouts,
+    options: SocketOptions,
+    ipv6: bool,
     cancellation_token: CancellationToken,
 ) -> io::Result<()> {
+    let listeners = bind_remote(store.clone(), client_id, endpoint_id, ipv6, DEFAULT_BACKLOG).await?;
+    serve_remote(store, listeners, client_id, endpoint_id, timeouts, options, cancellation_token);
+    Ok(())
+}
+
+/// Listen on the remote port of `endpoint_id`. Up to `backlog` connections wait until `serve_remote`.
+pub async fn bind_remote(store: Arc<Store>, client_id: ClientId, endpoint_id: EndpointId, ipv6: bool, backlog: u32) -> io::Result<Vec<TcpListener>> {
     // create our accept any server
-    let listen_addr = store.get_remote_addr_by_endpoint_id(endpoint_id).ok_or(io::Error::from(ErrorKind::Other))?;
-    let listener = TcpListener::bind(listen_addr.clone()).await?;
-    tracing::info!(cid = %client_id, eid = %endpoint_id, "remote process listening on {:?}", listen_addr);
+    let port = store.get_remote_port_by_endpoint_id(endpoint_id).ok_or(io::Error::from(ErrorKind::Other))?;
+    let listener = bind_tcp(port, backlog)?;
+    tracing::info!(cid = %client_id, eid = %endpoint_id, backlog, "remote process listening on 0.0.0.0:{}", port);
+    let mut listeners = vec![listener];
+
+    if ipv6 {
+        match bind_ipv6_tcp(port, backlog) {
+            Ok(listener) => {
+                tracing::info!(cid = %client_id, eid = %endpoint_id, "remote process listening on [::]:{}", port);
+                listeners.push(listener);
+            }
+            Err(e) => tracing::warn!(cid = %client_id, eid = %endpoint_id, "failed to listen on [::]:{}, accept IPv4 only: {:?}", port, e),
+        }
+    }
+    Ok(listeners)
+}
+
+/// Accept connections on `listeners` until `cancellation_token` is cancelled.
+pub fn serve_remote(
+    store: Arc<Store>,
+    listeners: Vec<TcpListener>,
+    client_id: ClientId,
+    endpoint_id: EndpointId,
+    timeouts: SocketTimeouts,
+    options: SocketOptions,
+    cancellation_token: CancellationToken,
+) {
+    for listener in listeners {
+        spawn_accept_loop(store.clone(), listener, client_id, endpoint_id, timeouts, options, cancellation_token.clone());
+    }
+    increment_counter!("ownserver_server.remote.tcp.swawn_remote");
+}
 
-    let ct = cancellation_token.clone();
+/// Log and count a failed `accept()` by kind.
+fn record_accept_error(client_id: ClientId, endpoint_id: EndpointId, e: &io::Error) {
+    let fd_exhausted = is_fd_exhausted(e);
+    let kind = if fd_exhausted { "too_many_open_files".to_string() } else { snake_case(&format!("{:?}", e.kind())) };
+    increment_counter!("ownserver_server.remote.accept_errors", "kind" => kind.clone());
 
+    if fd_exhausted {
+        tracing::warn!(cid = %client_id, eid = %endpoint_id, kind = %kind, "failed to accept remote connection, retry in {:?}: {:?}", ACCEPT_BACKOFF, e);
+    } else {
+        tracing::warn!(cid = %client_id, eid = %endpoint_id, kind = %kind, "failed to accept remote connection: {:?}", e);
+    }
+}
+
+// `ConnectionAborted` into `connection_aborted`
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+fn spawn_accept_loop(
+    store: Arc<Store>,
+    listener: TcpListener,
+    client_id: ClientId,
+    endpoint_id: EndpointId,
+    timeouts: SocketTimeouts,
+    options: SocketOptions,
+    ct: CancellationToken,
+) {
+    let port = listener.local_addr().map(|addr| addr.port()).unwrap_or_default();
     tokio::spawn(async move {
         loop {
             let socket = tokio::select! {
-                socket = listener.accept() => {
-                    match socket {
-                        Ok((socket, _)) => socket,
-                        _ => {
-                            tracing::debug!(cid = %client_id, eid = %endpoint_id, "failed to accept socket");
-                            continue;
-                        }
+                (socket, peer_addr) = accept_with_backoff(&listener, |e| record_accept_error(client_id, endpoint_id, e)) => {
+                    if store.is_port_draining(port) {
+                        tracing::debug!(cid = %client_id, eid = %endpoint_id, "refuse connection from {} to draining port {}", peer_addr, port);
+                        continue;
+                    }
+                    if store.is_client_paused(client_id) {
+                        tracing::debug!(cid = %client_id, eid = %endpoint_id, "refuse connection from {} to paused client", peer_addr);
+                        continue;
+                    }
+                    if !store.permits_peer(peer_addr.ip()) {
+                        tracing::debug!(cid = %client_id, eid = %endpoint_id, "refuse connection from denied {}", peer_addr);
+                        continue;
                     }
+                    if !store.allow_remote_connection(peer_addr.ip()) {
+                        tracing::debug!(cid = %client_id, eid = %endpoint_id, "drop connection from {} by rate limit", peer_addr);
+                        continue;
+                    }
+                    socket
                 },
                 _ = ct.cancelled() => {
                     tracing::info!(cid = %client_id, eid = %endpoint_id, "tcp listener is cancelled.");
@@ -47,23 +133,22 @@ pub async fn spawn_remote(
 
             tokio::spawn(
                 async move {
-                    accept_connection(store_, socket, client_id, endpoint_id).await;
+                    accept_connection(store_, socket, client_id, endpoint_id, timeouts, options).await;
                 }
                 .instrument(tracing::info_span!("remote_connect")),
             );
         }
     }.instrument(tracing::info_span!("spawn_accept_connection")));
-
-    increment_counter!("ownserver_server.remote.tcp.swawn_remote");
-    Ok(())
 }
 
 #[tracing::instrument(skip(store, socket))]
 pub async fn accept_connection(
     store: Arc<Store>,
-    socket: TcpStream,
+    mut socket: TcpStream,
     client_id: ClientId,
     endpoint_id: EndpointId,
+    timeouts: SocketTimeouts,
+    options: SocketOptions,
 ) {
     tracing::info!(cid = %client_id, "new remote connection");
 
@@ -76,10 +161,35 @@ pub async fn accept_connection(
         }
     };
     tracing::info!(cid = %client_id, "remote ip is {}", peer_addr);
+    if let Err(e) = options.tcp.apply(&socket) {
+        tracing::warn!(cid = %client_id, "failed to set socket options: {:?}", e);
+    }
+    if let Some(banner) = options.banner {
+        if let Err(e) = with_timeout(timeouts.write, socket.write_all(banner)).await {
+            tracing::info!(cid = %client_id, "failed to write banner to remote {:?}", e);
+            increment_counter!("ownserver_server.remote.tcp.banner_error");
+            return;
+        }
+    }
+    // sniffing only peeks, the request with its Host header is forwarded byte for byte
+    let mut http_host = None;
+    if options.sniff_http {
+        let sniffed = sniff(&socket, SNIFF_TIMEOUT).await;
+        tracing::info!(cid = %client_id, protocol = sniffed.protocol(), ?sniffed, "sniffed remote connection");
+        increment_counter!("ownserver_server.remote.tcp.sniffed", "protocol" => sniffed.protocol());
+        if let Sniffed::Http { host } = sniffed {
+            http_host = host;
+        }
+    }
+    if http_host.is_some() && !store.client_supports(client_id, Capability::HttpHost).await {
+        http_host = None;
+    }
 
 
-    let remote = RemoteTcp::new(store.clone(), socket, client_id, endpoint_id);
-    if remote.send_init_to_client().await.is_ok() {
+    let flow_control = store.client_supports(client_id, Capability::FlowControl).await;
+    let half_close = store.client_supports(client_id, Capability::HalfClose).await;
+    let remote = RemoteTcp::new(store.clone(), socket, client_id, endpoint_id, timeouts, flow_control, half_close);
+    if remote.send_init_to_client(http_host).await.is_ok() {
         tracing::info!(cid = %client_id, sid = %remote.stream_id, "add new remote stream");
         store.add_remote(RemoteStream::RemoteTcp(remote), peer_addr).await;
     }
@@ -92,32 +202,75 @@ pub struct RemoteTcp {
     pub stream_id: StreamId,
     pub client_id: ClientId,
     pub endpoint_id: EndpointId,
-    socket_tx: WriteHalf<TcpStream>,
+    socket_tx: UnboundedSender<StreamMessage>,
+    // client and endpoint the stream is forwarded to, changed when the stream is resumed by another client
+    binding: watch::Sender<(ClientId, EndpointId)>,
+    window: Arc<Semaphore>,
     ct: CancellationToken,
     store: Arc<Store>,
+    peer_addr: Option<SocketAddr>,
+    // of the client the stream was opened for, see `RemoteStream::set_token_subject`
+    token_subject: Option<String>,
     disabled: bool,
 }
 
 impl RemoteTcp {
-    pub fn new(store: Arc<Store>, socket: TcpStream, client_id: ClientId, endpoint_id: EndpointId) -> Self {
-        let (mut stream, sink) = tokio::io::split(socket);
+    /// A remote peer that sends nothing for `timeouts.read` or does not accept data for `timeouts.write` is disconnected.
+    /// Without `flow_control` the stream neither waits for nor sends `WindowUpdate`, for clients that don't know it.
+    /// With `half_close` an EOF from either side closes only that direction, the stream is closed once both are.
+    pub fn new(store: Arc<Store>, socket: TcpStream, client_id: ClientId, endpoint_id: EndpointId, timeouts: SocketTimeouts, flow_control: bool, half_close: bool) -> Self {
+        let peer_addr = socket.peer_addr().ok();
+        let (mut stream, mut sink) = tokio::io::split(socket);
         let stream_id = StreamId::new();
         let ct: CancellationToken = CancellationToken::new();
+        let window = Arc::new(Semaphore::new(INITIAL_STREAM_WINDOW as usize));
+        let (binding, _) = watch::channel((client_id, endpoint_id));
+        let span = stream_span(&store.client_span(client_id), stream_id);
+        // directions still open, the one closing the last disables the stream
+        let open_halves = Arc::new(AtomicUsize::new(2));
 
         let mut buf = [0; 4096];
         let ct_ = ct.clone();
         let store_ = store.clone();
+        let window_ = window.clone();
+        let open_halves_ = open_halves.clone();
+        let mut binding_rx = binding.subscribe();
+        let mut msg_limiter = store.max_msg_rate().map(MessageRateLimiter::new);
         tokio::spawn(async move {
-            loop {
+            let cause = 'read: loop {
+                let client_id = binding_rx.borrow().0;
+
+                // reads over the message rate wait instead of being dropped, what arrives meanwhile goes in one Data packet
+                if let Some(ref mut limiter) = msg_limiter {
+                    let delay = limiter.delay();
+                    if !delay.is_zero() {
+                        increment_counter!("ownserver_server.remote.tcp.throttled");
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => {}
+                            _ = ct_.cancelled() => {
+                                tracing::info!(cid = %client_id, id=%stream_id, "read loop was cancelled while throttled");
+                                return;
+                            }
+                        }
+                    }
+                }
+
                 let n = tokio::select! {
-                    read = stream.read(&mut buf) => {
+                    read = with_timeout(timeouts.read, stream.read(&mut buf)) => {
                         match read {
                             Ok(n) => n,
+                            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                                tracing::info!(cid = %client_id, sid = %stream_id, "remote read timed out, closing stream");
+                                increment_counter!("ownserver_server.remote.tcp.read_timeout");
+
+                                let _ = store_.send_to_client(client_id, ControlPacketV2::End(stream_id)).await;
+                                break CloseCause::IdleTimeout
+                            }
                             Err(e) => {
                                 tracing::warn!(cid = %client_id, sid = %stream_id, "failed to read from tcp socket: {:?}", e);
         
                                 // error: clean up this remote stream
-                                break
+                                break CloseCause::Error
                             }
                         }
                     }
@@ -132,6 +285,18 @@ impl RemoteTcp {
                 if n == 0 {
                     tracing::debug!(cid = %client_id, sid = %stream_id, "remote client streams end");
 
+                    if half_close {
+                        // the remote peer may still read what the local service sends
+                        if let Err(e) = store_.send_to_client(client_id, ControlPacketV2::ShutdownWrite(stream_id)).await {
+                            tracing::warn!(cid = %client_id, sid = %stream_id, "failed to send shutdown write: {:?}", e);
+                            break CloseCause::Error
+                        }
+                        if open_halves_.fetch_sub(1, Ordering::AcqRel) > 1 {
+                            tracing::debug!(cid = %client_id, sid = %stream_id, "remote half-closed the stream");
+                            return;
+                        }
+                        break CloseCause::PeerClosed
+                    }
 
                     let _ = store_ 
                         .send_to_client(client_id, ControlPacketV2::End(stream_id))
@@ -140,30 +305,149 @@ impl RemoteTcp {
                             tracing::warn!(cid = %client_id, sid = %stream_id, "failed to send end signal: {:?}", e);
                         });
                     // safely close this remote stream
-                    break
+                    break CloseCause::PeerClosed
                 }
 
-                let data = &buf[..n];
-                let packet = ControlPacketV2::Data(stream_id, data.to_vec());
+                // an active peer is not the least recently used one to evict
+                if let Some(ref addr) = peer_addr {
+                    store_.touch_addr(addr);
+                }
 
-                match store_.send_to_client(client_id, packet).await {
-                    Ok(_) => {
-                        tracing::debug!(cid = %client_id, sid = %stream_id, "sent data packet to client");
-                    },
-                    Err(e) => {
-                        tracing::warn!(cid = %client_id, sid = %stream_id, "failed to forward tcp packets to client. {:?}", e);
-                        // error: client is unavailable or error
-                        // error: clean up this remote stream
-                        break
+                // wait until the client has room for this stream, other streams keep flowing
+                if flow_control {
+                    let permit = tokio::select! {
+                        permit = window_.acquire_many(n as u32) => permit,
+                        _ = ct_.cancelled() => {
+                            tracing::info!(cid = %client_id, id=%stream_id, "read loop was cancelled while waiting for window");
+                            return;
+                        }
+                    };
+                    match permit {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => break CloseCause::Error,
                     }
                 }
-            }
+
+                // while the client reconnects the data read so far is kept and replayed to the resuming client
+                loop {
+                    let client_id = binding_rx.borrow_and_update().0;
+                    let packet = ControlPacketV2::Data(stream_id, buf[..n].to_vec());
+                    match store_.send_to_client(client_id, packet).await {
+                        Ok(_) => {
+                            tracing::debug!(cid = %client_id, sid = %stream_id, "sent data packet to client");
+                            break
+                        },
+                        Err(ClientStreamError::Congested(_)) => {
+                            tracing::warn!(cid = %client_id, sid = %stream_id, "client is congested, close the stream");
+                            break 'read CloseCause::Error
+                        }
+                        Err(e) => {
+                            if !store_.waits_for_reconnect(client_id).await {
+                                tracing::warn!(cid = %client_id, sid = %stream_id, "failed to forward tcp packets to client. {:?}", e);
+                                break 'read CloseCause::ClientDisconnected
+                            }
+                            tracing::info!(cid = %client_id, sid = %stream_id, "client is unavailable, wait for it to reconnect. {:?}", e);
+                            tokio::select! {
+                                changed = binding_rx.changed() => {
+                                    if changed.is_err() {
+                                        break 'read CloseCause::ClientDisconnected
+                                    }
+                                }
+                                _ = ct_.cancelled() => {
+                                    tracing::info!(cid = %client_id, id=%stream_id, "read loop was cancelled while waiting for reconnect");
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+            };
 
             tracing::info!(cid = %client_id, sid = %stream_id, "exit from read loop");
-            store_.disable_remote(stream_id).await;
-        }.instrument(tracing::info_span!("remote_tcp_read_loop")));
+            store_.disable_remote(stream_id, cause).await;
+        }.instrument(tracing::info_span!(parent: &span, "remote_tcp_read_loop")));
+
+        // Write to the remote socket in a dedicated task so that a slow remote peer
+        // never blocks the client read loop shared by every stream
+        let (socket_tx, mut socket_rx) = unbounded_channel::<StreamMessage>();
+        let ct_ = ct.clone();
+        let store_ = store.clone();
+        let binding_rx = binding.subscribe();
+        tokio::spawn(async move {
+            let cause = loop {
+                let message = tokio::select! {
+                    biased;
+                    message = socket_rx.recv() => message,
+                    _ = ct_.cancelled() => {
+                        tracing::info!(cid = %client_id, id=%stream_id, "write loop was cancelled");
+                        return;
+                    }
+                };
+
+                let data = match message {
+                    Some(StreamMessage::Data(data)) => data,
+                    Some(StreamMessage::TunnelRefused) => {
+                        let _ = sink.shutdown().await.map_err(|_e| {
+                            tracing::error!(sid = %stream_id, "error shutting down remote tcp stream");
+                        });
+                        return;
+                    }
+                    Some(StreamMessage::ShutdownWrite) => {
+                        if let Err(e) = sink.shutdown().await {
+                            tracing::warn!(sid = %stream_id, "failed to shut down write half of remote tcp stream {:?}", e);
+                            break CloseCause::Error
+                        }
+                        if open_halves.fetch_sub(1, Ordering::AcqRel) > 1 {
+                            tracing::debug!(sid = %stream_id, "local service half-closed the stream");
+                            return;
+                        }
+                        break CloseCause::LocalClosed
+                    }
+                    Some(StreamMessage::NoClientTunnel) => {
+                        unimplemented!();
+                    }
+                    None => return,
+                };
+                let client_id = binding_rx.borrow().0;
+
+                // write_all retries partial writes, so data reaches the peer in order or not at all
+                match with_timeout(timeouts.write, sink.write_all(&data)).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {
+                        tracing::info!(cid = %client_id, sid = %stream_id, "remote write timed out, closing stream");
+                        increment_counter!("ownserver_server.remote.tcp.write_timeout");
+
+                        let _ = store_.send_to_client(client_id, ControlPacketV2::End(stream_id)).await;
+                        break CloseCause::IdleTimeout
+                    }
+                    Err(e) => {
+                        tracing::warn!(cid = %client_id, sid = %stream_id, "could not write data to remote socket {:?}", e);
+                        increment_counter!("ownserver_server.remote.tcp.write_error");
 
-        Self { stream_id, client_id, endpoint_id, socket_tx: sink, store, ct, disabled: false }
+                        // the remote peer is gone, let the client close its local connection too
+                        let _ = store_.send_to_client(client_id, ControlPacketV2::End(stream_id)).await;
+                        break CloseCause::PeerClosed
+                    }
+                }
+
+                if !flow_control {
+                    continue
+                }
+                let packet = ControlPacketV2::WindowUpdate(stream_id, data.len() as u32);
+                if let Err(e) = store_.send_to_client(client_id, packet).await {
+                    tracing::warn!(cid = %client_id, sid = %stream_id, "failed to send window update. {:?}", e);
+                    // the resuming client starts with a full window
+                    if !store_.waits_for_reconnect(client_id).await {
+                        break CloseCause::ClientDisconnected
+                    }
+                }
+            };
+
+            tracing::info!(cid = %client_id, sid = %stream_id, "exit from write loop");
+            store_.disable_remote(stream_id, cause).await;
+        }.instrument(tracing::info_span!(parent: &span, "remote_tcp_write_loop")));
+
+        Self { stream_id, client_id, endpoint_id, socket_tx, binding, window, store, ct, peer_addr, token_subject: None, disabled: false }
     }
 
     pub async fn send_to_remote(&mut self, stream_id: StreamId, message: StreamMessage) -> Result<(), ClientStreamError> {
@@ -171,42 +455,74 @@ impl RemoteTcp {
             return Err(ClientStreamError::StreamNotAvailable(stream_id));
         }
 
-        let data = match message {
-            StreamMessage::Data(data) => data,
-            StreamMessage::TunnelRefused => {
-                // TODO
-                tracing::info!(sid = %self.stream_id, "tunnel refused");
+        if message == StreamMessage::TunnelRefused {
+            // TODO
+            tracing::info!(sid = %self.stream_id, "tunnel refused");
 
-                let _ = self.socket_tx.shutdown().await.map_err(|_e| {
-                    tracing::error!(sid = %self.stream_id, "error shutting down remote tcp stream");
-                });
-
-                self.disable();
-                return Err(ClientStreamError::RemoteError(format!("stream_id: {}, TunnelRefused", self.stream_id)))
-            }
-            StreamMessage::NoClientTunnel => {
-                unimplemented!();
-            }
-        };
+            // write loop shuts down the socket before it observes the cancellation
+            let _ = self.socket_tx.send(message);
+            self.disable(CloseCause::LocalClosed);
+            return Err(ClientStreamError::RemoteError(format!("stream_id: {}, TunnelRefused", self.stream_id)))
+        }
 
-        if let Err(e) = self.socket_tx.write_all(&data).await {
-            tracing::warn!(sid = %self.stream_id, "could not write data to remote socket {:?}", e);
+        if let Err(e) = self.socket_tx.send(message) {
+            tracing::warn!(sid = %self.stream_id, "could not queue data to remote socket {:?}", e);
 
-            self.disable();
+            self.disable(CloseCause::Error);
             return Err(ClientStreamError::RemoteError(format!("stream_id: {}, {:?}", self.stream_id, e)))
         }
         Ok(())
     }
 
+    /// Keep the stream open for `window` after its client went away. It is disabled unless `rebind` is called in time.
+    pub fn hold(&self, window: Duration) {
+        let mut binding_rx = self.binding.subscribe();
+        let ct = self.ct.clone();
+        let store = self.store.clone();
+        let stream_id = self.stream_id;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = binding_rx.changed() => {}
+                _ = ct.cancelled() => {}
+                _ = tokio::time::sleep(window) => {
+                    tracing::info!(sid = %stream_id, "client did not reconnect in time, closing held stream");
+                    store.disable_remote(stream_id, CloseCause::ClientDisconnected).await;
+                }
+            }
+        });
+    }
+
+    /// Forward the stream to `client_id` from now on. Data read while no client was connected is replayed to it.
+    pub fn rebind(&mut self, client_id: ClientId, endpoint_id: EndpointId) {
+        tracing::info!(cid = %client_id, sid = %self.stream_id, old_cid = %self.client_id, "resume stream");
+        self.client_id = client_id;
+        self.endpoint_id = endpoint_id;
+        // window updates of the previous client are lost
+        let room = (INITIAL_STREAM_WINDOW as usize).saturating_sub(self.window.available_permits());
+        self.window.add_permits(room);
+        self.binding.send_replace((client_id, endpoint_id));
+    }
+
+    /// Give back `n` bytes of window acknowledged by the client.
+    pub fn add_window(&self, n: u32) {
+        // never grow beyond the initial window even if the client sends bogus updates
+        let room = (INITIAL_STREAM_WINDOW as usize).saturating_sub(self.window.available_permits());
+        self.window.add_permits((n as usize).min(room));
+    }
+
     pub async fn send_to_client(&self, packet: ControlPacketV2) -> Result<(), ClientStreamError> {
         let client_id = self.client_id;
         self.store.send_to_client(client_id, packet).await?;
         Ok(())
     }
 
-    pub async fn send_init_to_client(&self) -> Result<(), ClientStreamError> {
+    /// `http_host` is told to clients that negotiated `Capability::HttpHost`.
+    pub async fn send_init_to_client(&self, http_host: Option<String>) -> Result<(), ClientStreamError> {
         let client_id = self.client_id;
-        let packet = ControlPacketV2::Init(self.stream_id, self.endpoint_id);
+        let packet = match http_host {
+            Some(host) => ControlPacketV2::InitHttp(self.stream_id, self.endpoint_id, host),
+            None => ControlPacketV2::Init(self.stream_id, self.endpoint_id),
+        };
         self.store.send_to_client(client_id, packet).await?;
         Ok(())
     }
@@ -214,10 +530,293 @@ impl RemoteTcp {
     pub fn disabled(&self) -> bool {
         self.disabled
     }
-    pub fn disable(&mut self) {
-        tracing::info!(sid = %self.stream_id, "tcp stream was disabled");
+    pub fn disable(&mut self, cause: CloseCause) {
+        if !self.disabled {
+            self.store.audit(AuditEvent::StreamClose {
+                client_id: self.client_id,
+                stream_id: self.stream_id,
+                token_subject: self.token_subject.clone(),
+                peer_addr: self.peer_addr,
+            });
+            cause.record();
+        }
+        tracing::info!(sid = %self.stream_id, cause = cause.label(), "tcp stream was disabled");
         self.ct.cancel();
         self.disabled = true;
     }
 }
 
+
+#[cfg(test)]
+mod remote_tcp_write_test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn deliver_all_bytes_to_slow_peer_in_order() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, _) = listener.accept().await?;
+
+        let mut remote = RemoteTcp::new(store, socket, ClientId::new(), EndpointId::new(), SocketTimeouts::default(), false, false);
+        let expected: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        for chunk in expected.chunks(16 * 1024) {
+            remote.send_to_remote(remote.stream_id, StreamMessage::Data(chunk.to_vec())).await?;
+        }
+
+        // read in small pieces so that the socket buffer fills and writes are partial
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while received.len() < expected.len() {
+            let n = tokio::time::timeout(Duration::from_secs(5), peer.read(&mut buf)).await??;
+            assert_ne!(n, 0);
+            received.extend_from_slice(&buf[..n]);
+            tokio::time::sleep(Duration::from_micros(100)).await;
+        }
+        assert_eq!(received, expected);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod remote_tcp_banner_test {
+    use super::*;
+    use crate::test_support::{add_tcp_client, encode, next_init, TcpClient};
+
+    #[tokio::test]
+    async fn write_banner_before_forwarded_data() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::new(10000..10010));
+        let TcpClient { client_id, endpoint_id, incoming, mut sent } = add_tcp_client(&store, Default::default()).await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, _) = listener.accept().await?;
+        let options = SocketOptions { banner: Some(b"\x00welcome\n"), ..Default::default() };
+        accept_connection(store.clone(), socket, client_id, endpoint_id, SocketTimeouts::default(), options).await;
+
+        let (stream_id, eid) = next_init(&mut sent).await?;
+        assert_eq!(eid, endpoint_id);
+        incoming.unbounded_send(encode(ControlPacketV2::Data(stream_id, b"forwarded".to_vec())))?;
+
+        let expected = b"\x00welcome\nforwarded".to_vec();
+        let mut received = vec![0; expected.len()];
+        tokio::time::timeout(Duration::from_secs(2), peer.read_exact(&mut received)).await??;
+        assert_eq!(received, expected);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod remote_tcp_half_close_test {
+    use super::*;
+    use crate::client::ClientOptions;
+    use crate::test_support::{add_tcp_client, encode, next_init, next_packet, TcpClient};
+
+    #[tokio::test]
+    async fn propagate_shutdown_write_in_both_directions() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::new(10000..10010));
+        let options = ClientOptions { capabilities: vec![Capability::HalfClose], ..Default::default() };
+        let TcpClient { client_id, endpoint_id, incoming, mut sent } = add_tcp_client(&store, options).await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, _) = listener.accept().await?;
+        accept_connection(store.clone(), socket, client_id, endpoint_id, SocketTimeouts::default(), SocketOptions::default()).await;
+        let (stream_id, _) = next_init(&mut sent).await?;
+
+        // the remote peer sends its request and half-closes, the stream keeps open for the response
+        peer.write_all(b"request").await?;
+        peer.shutdown().await?;
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Data(stream_id, b"request".to_vec()));
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::ShutdownWrite(stream_id));
+
+        incoming.unbounded_send(encode(ControlPacketV2::Data(stream_id, b"response".to_vec())))?;
+        incoming.unbounded_send(encode(ControlPacketV2::ShutdownWrite(stream_id)))?;
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), peer.read_to_end(&mut received)).await??;
+        assert_eq!(received, b"response");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod remote_tcp_msg_rate_test {
+    use super::*;
+    use crate::test_support::{add_tcp_client, next_packet, TcpClient};
+
+    #[tokio::test]
+    async fn coalesce_tiny_packets_over_the_message_rate() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::new(10000..10010).with_max_msg_rate(10.0));
+        let TcpClient { client_id, endpoint_id, incoming: _incoming, mut sent } = add_tcp_client(&store, Default::default()).await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+        peer.set_nodelay(true)?;
+        let (socket, _) = listener.accept().await?;
+        accept_connection(store.clone(), socket, client_id, endpoint_id, SocketTimeouts::default(), SocketOptions::default()).await;
+
+        // a byte every 2ms, 500 packets per second
+        tokio::spawn(async move {
+            for _ in 0..100 {
+                let _ = peer.write_all(b"x").await;
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            // keep the connection open until the test is done
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let (mut forwarded, mut data_packets) = (0, 0);
+        while forwarded < 100 {
+            match next_packet(&mut sent).await? {
+                ControlPacketV2::Init(..) => {}
+                ControlPacketV2::Data(_, data) => {
+                    forwarded += data.len();
+                    data_packets += 1;
+                }
+                packet => panic!("expected data, got {:?}", packet),
+            }
+        }
+        assert_eq!(forwarded, 100);
+        // a burst of 10 reads, then one read per 100ms while the peer writes for about 200ms
+        assert!(data_packets <= 15, "{} data packets forwarded", data_packets);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod remote_tcp_http_host_test {
+    use super::*;
+    use crate::client::ClientOptions;
+    use crate::test_support::{add_tcp_client, next_packet, TcpClient};
+
+    // odd casing and spacing that a rewriting proxy would normalize
+    const REQUEST: &[u8] = b"GET /index.html HTTP/1.1\r\nhOsT:  Play.Example.com:8080 \r\nUser-Agent: test\r\n\r\n";
+
+    // the store and the peer are returned to keep the stream open
+    async fn open_http_stream(capabilities: Vec<Capability>) -> Result<(Arc<Store>, TcpStream, TcpClient), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::new(10000..10010));
+        let options = ClientOptions { capabilities, ..Default::default() };
+        let client = add_tcp_client(&store, options).await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, _) = listener.accept().await?;
+        peer.write_all(REQUEST).await?;
+        let options = SocketOptions { sniff_http: true, ..Default::default() };
+        accept_connection(store.clone(), socket, client.client_id, client.endpoint_id, SocketTimeouts::default(), options).await;
+        Ok((store, peer, client))
+    }
+
+    #[tokio::test]
+    async fn tell_host_and_forward_request_unchanged() -> Result<(), Box<dyn std::error::Error>> {
+        let (_store, _peer, TcpClient { endpoint_id, incoming: _incoming, mut sent, .. }) = open_http_stream(vec![Capability::HttpHost]).await?;
+
+        let stream_id = match next_packet(&mut sent).await? {
+            ControlPacketV2::InitHttp(stream_id, eid, host) if eid == endpoint_id => {
+                assert_eq!(host, "Play.Example.com:8080");
+                stream_id
+            }
+            packet => panic!("expected init with host, got {:?}", packet),
+        };
+
+        let mut forwarded = Vec::new();
+        while forwarded.len() < REQUEST.len() {
+            match next_packet(&mut sent).await? {
+                ControlPacketV2::Data(sid, data) if sid == stream_id => forwarded.extend(data),
+                packet => panic!("expected data, got {:?}", packet),
+            }
+        }
+        assert_eq!(forwarded, REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_plain_init_to_clients_without_http_host() -> Result<(), Box<dyn std::error::Error>> {
+        let (_store, _peer, TcpClient { endpoint_id, incoming: _incoming, mut sent, .. }) = open_http_stream(Vec::new()).await?;
+
+        assert!(matches!(next_packet(&mut sent).await?, ControlPacketV2::Init(_, eid) if eid == endpoint_id));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod remote_tcp_access_reload_test {
+    use super::*;
+    use futures::StreamExt;
+    use rand::thread_rng;
+    use crate::access::AccessControl;
+    use crate::test_support::{get_endpoint_claims_single, next_init, next_packet, serve_tcp_client};
+
+    #[tokio::test]
+    async fn refuse_newly_denied_peers_after_reload() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("ownserver-access-reload-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"allow": ["127.0.0.0/8"]}"#)?;
+        let access = AccessControl::load(&path)?.dropping_denied(true);
+        let store = Arc::new(Store::new(10066..10067).with_access_control(access));
+
+        let endpoints = store.allocate_endpoints_for(&mut thread_rng(), None, None, get_endpoint_claims_single()).await?;
+        let endpoint_id = endpoints[0].id;
+        let ct = CancellationToken::new();
+        let (_, mut sent) = serve_tcp_client(&store, endpoints, &ct).await?;
+
+        let _allowed = TcpStream::connect("127.0.0.1:10066").await?;
+        let (stream_id, eid) = next_init(&mut sent).await?;
+        assert_eq!(eid, endpoint_id);
+
+        std::fs::write(&path, r#"{"allow": ["127.0.0.0/8"], "deny": ["127.0.0.1"]}"#)?;
+        assert_eq!(store.reload_access_control().await?, 1);
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::End(stream_id));
+
+        let mut denied = TcpStream::connect("127.0.0.1:10066").await?;
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), denied.read(&mut buf)).await?;
+        assert!(matches!(read, Ok(0) | Err(_)), "denied peer was served");
+        assert!(tokio::time::timeout(Duration::from_millis(200), sent.next()).await.is_err(), "client was told about a denied peer");
+
+        ct.cancel();
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod remote_tcp_accept_error_test {
+    use super::*;
+    use metrics_util::debugging::{DebugValue, Snapshotter};
+    use crate::test_support::install_debugging_recorder;
+
+    fn accept_errors(kind: &str) -> u64 {
+        let snapshot = match Snapshotter::current_thread_snapshot() {
+            Some(snapshot) => snapshot,
+            None => return 0,
+        };
+        snapshot.into_vec().into_iter()
+            .filter(|(key, ..)| key.key().name() == "ownserver_server.remote.accept_errors")
+            .filter(|(key, ..)| key.key().labels().any(|label| label.key() == "kind" && label.value() == kind))
+            .map(|(.., value)| match value {
+                DebugValue::Counter(n) => n,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn count_running_out_of_file_descriptors() {
+        install_debugging_recorder();
+        let (client_id, endpoint_id) = (ClientId::new(), EndpointId::new());
+
+        record_accept_error(client_id, endpoint_id, &io::Error::from_raw_os_error(libc::EMFILE));
+        record_accept_error(client_id, endpoint_id, &io::Error::from_raw_os_error(libc::ENFILE));
+        assert_eq!(accept_errors("too_many_open_files"), 2);
+    }
+
+    #[test]
+    fn count_other_errors_by_kind() {
+        install_debugging_recorder();
+        record_accept_error(ClientId::new(), EndpointId::new(), &io::Error::from(ErrorKind::ConnectionAborted));
+        assert_eq!(accept_errors("connection_aborted"), 1);
+        assert_eq!(accept_errors("too_many_open_files"), 0);
+    }
+}
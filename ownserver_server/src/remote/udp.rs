@@ -1,4 +1,4 @@
-use std::{io::{self, ErrorKind}, net::SocketAddr};
+use std::{collections::HashMap, io::{self, ErrorKind}, net::SocketAddr, sync::atomic::{AtomicU64, Ordering}};
 use metrics::increment_counter;
 use ownserver_lib::{ControlPacketV2, EndpointId};
 use tokio::net::UdpSocket;
@@ -6,37 +6,81 @@ use tracing::Instrument;
 use tokio_util::sync::CancellationToken;
 use std::sync::Arc;
 
-use crate::{Store, remote::stream::RemoteStream, ClientStreamError};
+use crate::{audit::AuditEvent, rate_limit::MessageRateLimiter, Store, remote::stream::{CloseCause, RemoteStream}, ClientStreamError};
 pub use ownserver_lib::{ClientId, StreamId};
 
+use super::bind_ipv6_udp;
 use super::stream::StreamMessage;
 
+/// Datagrams of at least this share of --max-udp-payload, in percent, are counted as near the limit.
+pub const NEAR_LIMIT_PERCENT: usize = 90;
+
+static NEAR_LIMIT_DATAGRAMS: AtomicU64 = AtomicU64::new(0);
+
+// datagrams that still fit but are about to be dropped tell the operator to raise --max-udp-payload.
+// warned after 1, 2, 4, 8, ... of them so that a busy port does not flood the log
+fn check_near_limit(len: usize, max_payload: usize, from: &'static str) {
+    if len * 100 < max_payload * NEAR_LIMIT_PERCENT {
+        return;
+    }
+    increment_counter!("ownserver_server.remote.udp.near_limit", "from" => from);
+    let seen = NEAR_LIMIT_DATAGRAMS.fetch_add(1, Ordering::Relaxed) + 1;
+    if seen.is_power_of_two() {
+        tracing::warn!(len, max_payload, from, seen, "udp datagram is close to --max-udp-payload, raise it if datagrams get dropped");
+    }
+}
+
 #[tracing::instrument(skip(store, cancellation_token))]
 pub async fn spawn_remote(
     store: Arc<Store>,
     client_id: ClientId,
     endpoint_id: EndpointId,
+    ipv6: bool,
     cancellation_token: CancellationToken,
 ) -> io::Result<()> {
+    let sockets = bind_remote(store.clone(), client_id, endpoint_id, ipv6).await?;
+    serve_remote(store, sockets, client_id, endpoint_id, cancellation_token);
+    Ok(())
+}
+
+/// Bind the remote port of `endpoint_id`. Datagrams are queued by the socket until `serve_remote`.
+pub async fn bind_remote(store: Arc<Store>, client_id: ClientId, endpoint_id: EndpointId, ipv6: bool) -> io::Result<Vec<UdpSocket>> {
     let listen_addr = store.get_remote_addr_by_endpoint_id(endpoint_id).ok_or(io::Error::from(ErrorKind::Other))?;
     let socket = UdpSocket::bind(listen_addr.clone()).await?;
     tracing::info!(cid = %client_id, eid = %endpoint_id, "remote process listening on {:?}", listen_addr);
-    let socket = Arc::new(socket);
+    let mut sockets = vec![socket];
 
-    let ct = cancellation_token.clone();
+    if ipv6 {
+        let port = store.get_remote_port_by_endpoint_id(endpoint_id).ok_or(io::Error::from(ErrorKind::Other))?;
+        match bind_ipv6_udp(port) {
+            Ok(socket) => {
+                tracing::info!(cid = %client_id, eid = %endpoint_id, "remote process listening on [::]:{}", port);
+                sockets.push(socket);
+            }
+            Err(e) => tracing::warn!(cid = %client_id, eid = %endpoint_id, "failed to bind [::]:{}, accept IPv4 only: {:?}", port, e),
+        }
+    }
+    Ok(sockets)
+}
 
+/// Forward datagrams received on `sockets` until `cancellation_token` is cancelled.
+pub fn serve_remote(store: Arc<Store>, sockets: Vec<UdpSocket>, client_id: ClientId, endpoint_id: EndpointId, cancellation_token: CancellationToken) {
+    for socket in sockets {
+        spawn_process_udp_stream(store.clone(), Arc::new(socket), client_id, endpoint_id, cancellation_token.clone());
+    }
+    increment_counter!("ownserver_server.remote.udp.swawn_remote");
+}
+
+
+fn spawn_process_udp_stream(store: Arc<Store>, socket: Arc<UdpSocket>, client_id: ClientId, endpoint_id: EndpointId, ct: CancellationToken) {
     tokio::spawn(
         async move {
             process_udp_stream(ct, store, client_id, endpoint_id, socket).await;
         }
         .instrument(tracing::info_span!("process_udp_stream")),
     );
-
-    increment_counter!("ownserver_server.remote.udp.swawn_remote");
-    Ok(())
 }
 
-
 #[tracing::instrument(skip(ct, store, udp_socket))]
 async fn process_udp_stream(
     ct: CancellationToken,
@@ -46,7 +90,12 @@ async fn process_udp_stream(
     udp_socket: Arc<UdpSocket>,
 )
 {
-    let mut buf = [0; 4096];
+    // one byte more than allowed, so that an oversized datagram is told from one that fits exactly
+    let max_payload = store.max_udp_payload();
+    let mut buf = vec![0; max_payload + 1];
+    let port = udp_socket.local_addr().map(|addr| addr.port()).unwrap_or_default();
+    let max_msg_rate = store.max_msg_rate();
+    let mut msg_limiters: HashMap<StreamId, MessageRateLimiter> = HashMap::new();
     loop {
         let (n, peer_addr) = tokio::select! {
             read = udp_socket.recv_from(&mut buf) => {
@@ -65,10 +114,35 @@ async fn process_udp_stream(
             }
         };
 
+        if n > max_payload {
+            tracing::info!(cid = %client_id, "drop datagram from {} larger than {} bytes", peer_addr, max_payload);
+            increment_counter!("ownserver_server.remote.udp.oversized", "from" => "remote");
+            continue;
+        }
+        check_near_limit(n, max_payload, "remote");
+
         let stream_id = match store.find_stream_id_by_addr(&peer_addr).await {
             Some(stream_id) => stream_id,
             None => {
+                if store.is_port_draining(port) {
+                    tracing::debug!(cid = %client_id, "drop packet from {} to draining port {}", peer_addr, port);
+                    continue;
+                }
+                if store.is_client_paused(client_id) {
+                    tracing::debug!(cid = %client_id, "drop packet from {} to paused client", peer_addr);
+                    continue;
+                }
+                if !store.permits_peer(peer_addr.ip()) {
+                    tracing::debug!(cid = %client_id, "drop packet from denied {}", peer_addr);
+                    continue;
+                }
+                if !store.allow_remote_connection(peer_addr.ip()) {
+                    tracing::debug!(cid = %client_id, "drop packet from {} by rate limit", peer_addr);
+                    continue;
+                }
                 tracing::info!(cid = %client_id, "remote ip is {}", peer_addr);
+                // a full bucket is no different from a new one
+                msg_limiters.retain(|_, limiter| !limiter.is_idle());
                 let remote = RemoteUdp::new(store.clone(), udp_socket.clone(), peer_addr, client_id, endpoint_id);
                 let stream_id = remote.stream_id;
                 if remote.send_init_to_client().await.is_ok() {
@@ -82,6 +156,15 @@ async fn process_udp_stream(
             }
         };
 
+        if let Some(rate) = max_msg_rate {
+            let limiter = msg_limiters.entry(stream_id).or_insert_with(|| MessageRateLimiter::new(rate));
+            if !limiter.check() {
+                tracing::debug!(cid = %client_id, sid = %stream_id, "drop datagram from {} by message rate", peer_addr);
+                increment_counter!("ownserver_server.remote.udp.msg_ratelimited");
+                continue;
+            }
+        }
+
         // TODO
         // gauge!("ownserver_server.remotes.udp.streams", active_streams.len() as f64);
 
@@ -121,6 +204,8 @@ pub struct RemoteUdp {
     peer_addr: SocketAddr,
     ct: CancellationToken,
     store: Arc<Store>,
+    // of the client the stream was opened for, see `RemoteStream::set_token_subject`
+    token_subject: Option<String>,
     disabled: bool,
 }
 
@@ -129,7 +214,7 @@ impl RemoteUdp {
         let stream_id = StreamId::new();
         let ct = CancellationToken::new();
 
-        Self { stream_id, client_id, endpoint_id, socket, store, ct, peer_addr, disabled: false }
+        Self { stream_id, client_id, endpoint_id, socket, store, ct, peer_addr, token_subject: None, disabled: false }
     }
 
     pub async fn send_to_remote(&mut self, stream_id: StreamId, message: StreamMessage) -> Result<(), ClientStreamError> {
@@ -143,18 +228,29 @@ impl RemoteUdp {
                 // TODO
                 tracing::info!(sid = %self.stream_id, "tunnel refused");
 
-                self.disable();
+                self.disable(CloseCause::LocalClosed);
                 return Err(ClientStreamError::RemoteError(format!("stream_id: {}, TunnelRefused", self.stream_id)))
             }
             StreamMessage::NoClientTunnel => {
                 unimplemented!();
             }
+            // datagrams have no direction to close
+            StreamMessage::ShutdownWrite => return Ok(()),
         };
 
+        let max_payload = self.store.max_udp_payload();
+        if data.len() > max_payload {
+            // the stream stays open, only this frame is lost
+            tracing::info!(sid = %self.stream_id, "drop {} bytes frame from client larger than {} bytes", data.len(), max_payload);
+            increment_counter!("ownserver_server.remote.udp.oversized", "from" => "client");
+            return Ok(())
+        }
+        check_near_limit(data.len(), max_payload, "client");
+
         if let Err(e) = self.socket.send_to(&data, self.peer_addr).await {
             tracing::warn!(sid = %self.stream_id, "could not write data to remote socket {:?}", e);
 
-            self.disable();
+            self.disable(CloseCause::Error);
             return Err(ClientStreamError::RemoteError(format!("stream_id: {}, {:?}", self.stream_id, e)))
         }
         Ok(())
@@ -176,10 +272,168 @@ impl RemoteUdp {
     pub fn disabled(&self) -> bool {
         self.disabled
     }
-    pub fn disable(&mut self) {
-        tracing::info!(sid = %self.stream_id, "udp stream was disabled");
+    pub fn disable(&mut self, cause: CloseCause) {
+        if !self.disabled {
+            self.store.audit(AuditEvent::StreamClose {
+                client_id: self.client_id,
+                stream_id: self.stream_id,
+                token_subject: self.token_subject.clone(),
+                peer_addr: Some(self.peer_addr),
+            });
+            cause.record();
+        }
+        tracing::info!(sid = %self.stream_id, cause = cause.label(), "udp stream was disabled");
         self.ct.cancel();
         self.disabled = true;
     }
 }
 
+
+#[cfg(test)]
+mod remote_udp_payload_test {
+    use super::*;
+    use std::time::Duration;
+    use bytes::BytesMut;
+    use futures::StreamExt;
+    use metrics_util::debugging::{DebugValue, Snapshotter};
+    use ownserver_lib::ControlPacketV2Codec;
+    use tokio_util::codec::Decoder;
+    use warp::ws::Message;
+    use crate::test_support::{add_client, install_debugging_recorder};
+
+    fn decode(message: Message) -> Result<Option<ControlPacketV2>, Box<dyn std::error::Error>> {
+        let mut bytes = BytesMut::from(&message.into_bytes()[..]);
+        Ok(ControlPacketV2Codec::new().decode(&mut bytes)?)
+    }
+
+    fn oversized_count(from: &str) -> u64 {
+        counter_value("ownserver_server.remote.udp.oversized", from)
+    }
+
+    fn counter_value(name: &str, from: &str) -> u64 {
+        let snapshot = match Snapshotter::current_thread_snapshot() {
+            Some(snapshot) => snapshot,
+            None => return 0,
+        };
+        snapshot.into_vec().into_iter()
+            .filter(|(key, ..)| key.key().name() == name)
+            .filter(|(key, ..)| key.key().labels().any(|label| label.key() == "from" && label.value() == from))
+            .map(|(.., value)| match value {
+                DebugValue::Counter(n) => n,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn drop_and_count_oversized_datagrams() -> Result<(), Box<dyn std::error::Error>> {
+        // the recorder keeps metrics per thread, the current thread runtime runs every task here
+        install_debugging_recorder();
+        let store = Arc::new(Store::default().with_max_udp_payload(8));
+        let (client_id, mut sent) = add_client(&store).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let remote_addr = socket.local_addr()?;
+        let endpoint_id = EndpointId::new();
+        let ct = CancellationToken::new();
+        spawn_process_udp_stream(store.clone(), Arc::new(socket), client_id, endpoint_id, ct.clone());
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        peer.send_to(b"123456789", remote_addr).await?;
+        peer.send_to(b"12345678", remote_addr).await?;
+
+        // the oversized datagram opens no stream and is not forwarded
+        let message = tokio::time::timeout(Duration::from_secs(2), sent.next()).await?.expect("client got no message");
+        let stream_id = match decode(message)? {
+            Some(ControlPacketV2::Init(stream_id, eid)) if eid == endpoint_id => stream_id,
+            packet => panic!("expected init, got {:?}", packet),
+        };
+        let message = tokio::time::timeout(Duration::from_secs(2), sent.next()).await?.expect("client got no message");
+        assert_eq!(decode(message)?, Some(ControlPacketV2::Data(stream_id, b"12345678".to_vec())));
+        assert_eq!(oversized_count("remote"), 1);
+
+        // an oversized frame from the client is dropped but the stream stays open
+        store.send_to_remote(stream_id, StreamMessage::Data(b"123456789".to_vec())).await?;
+        store.send_to_remote(stream_id, StreamMessage::Data(b"ok".to_vec())).await?;
+        let mut buf = [0; 16];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(2), peer.recv_from(&mut buf)).await??;
+        assert_eq!(&buf[..n], b"ok");
+        assert_eq!(oversized_count("client"), 1);
+
+        ct.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_datagrams_near_the_limit() -> Result<(), Box<dyn std::error::Error>> {
+        install_debugging_recorder();
+        let store = Arc::new(Store::default().with_max_udp_payload(10));
+        let (client_id, mut sent) = add_client(&store).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let remote_addr = socket.local_addr()?;
+        let ct = CancellationToken::new();
+        spawn_process_udp_stream(store.clone(), Arc::new(socket), client_id, EndpointId::new(), ct.clone());
+
+        // 9 of 10 bytes is near the limit, 8 is not
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        peer.send_to(b"123456789", remote_addr).await?;
+        peer.send_to(b"12345678", remote_addr).await?;
+        let stream_id = match decode(tokio::time::timeout(Duration::from_secs(2), sent.next()).await?.expect("client got no message"))? {
+            Some(ControlPacketV2::Init(stream_id, _)) => stream_id,
+            packet => panic!("expected init, got {:?}", packet),
+        };
+        for _ in 0..2 {
+            tokio::time::timeout(Duration::from_secs(2), sent.next()).await?.expect("client got no message");
+        }
+        assert_eq!(counter_value("ownserver_server.remote.udp.near_limit", "remote"), 1);
+
+        store.send_to_remote(stream_id, StreamMessage::Data(b"1234567890".to_vec())).await?;
+        let mut buf = [0; 16];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(2), peer.recv_from(&mut buf)).await??;
+        assert_eq!(n, 10);
+        assert_eq!(counter_value("ownserver_server.remote.udp.near_limit", "client"), 1);
+        assert_eq!(oversized_count("client"), 0);
+
+        ct.cancel();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod remote_udp_msg_rate_test {
+    use super::*;
+    use std::time::Duration;
+    use bytes::BytesMut;
+    use futures::StreamExt;
+    use ownserver_lib::ControlPacketV2Codec;
+    use tokio_util::codec::Decoder;
+    use crate::test_support::add_client;
+
+    #[tokio::test]
+    async fn drop_datagrams_over_the_message_rate() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default().with_max_msg_rate(5.0));
+        let (client_id, mut sent) = add_client(&store).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let remote_addr = socket.local_addr()?;
+        let ct = CancellationToken::new();
+        spawn_process_udp_stream(store.clone(), Arc::new(socket), client_id, EndpointId::new(), ct.clone());
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        for _ in 0..50 {
+            peer.send_to(b"x", remote_addr).await?;
+        }
+
+        let mut data_packets = 0;
+        while let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(300), sent.next()).await {
+            let mut bytes = BytesMut::from(&message.into_bytes()[..]);
+            if let Some(ControlPacketV2::Data(..)) = ControlPacketV2Codec::new().decode(&mut bytes)? {
+                data_packets += 1;
+            }
+        }
+        assert_eq!(data_packets, 5);
+        ct.cancel();
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Changes within this period are written to disk at once.
+const SAVE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Remote ports last assigned to each token subject.
+pub type PortReservations = HashMap<String, Vec<u16>>;
+
+/// Reservations and the unix time in seconds each token subject last used them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    pub reservations: PortReservations,
+    // missing from files written before reservations expired
+    #[serde(default)]
+    pub last_used: HashMap<String, u64>,
+}
+
+/// Read the state saved by `StateFile`. A missing or corrupt file starts fresh.
+pub fn load_state(path: &Path) -> State {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return State::default(),
+        Err(e) => {
+            tracing::warn!(path = %path.display(), "failed to read state file, start fresh: {:?}", e);
+            return State::default();
+        }
+    };
+
+    match serde_json::from_slice::<State>(&data) {
+        Ok(state) => state,
+        Err(e) => {
+            tracing::warn!(path = %path.display(), "state file is corrupt, start fresh: {:?}", e);
+            State::default()
+        }
+    }
+}
+
+/// Read only the reservations of `load_state`.
+pub fn load_reservations(path: &Path) -> PortReservations {
+    load_state(path).reservations
+}
+
+/// Writes the latest state to a json file from a background task.
+#[derive(Debug)]
+pub struct StateFile {
+    tx: watch::Sender<State>,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        let (tx, mut rx) = watch::channel(State::default());
+
+        tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                tokio::time::sleep(SAVE_DEBOUNCE).await;
+                let state = rx.borrow_and_update().clone();
+                if let Err(e) = write_state(&path, &state).await {
+                    tracing::error!(path = %path.display(), "failed to write state file: {:?}", e);
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    pub fn save(&self, state: State) {
+        self.tx.send_replace(state);
+    }
+}
+
+// write to a temporary file first so that a crash never leaves a truncated state file
+async fn write_state(path: &Path, state: &State) -> io::Result<()> {
+    let data = serde_json::to_vec(state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod state_file_test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ownserver-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn start_fresh_when_file_is_missing_or_corrupt() -> io::Result<()> {
+        let path = temp_path("missing-state");
+        let _ = std::fs::remove_file(&path);
+        assert!(load_reservations(&path).is_empty());
+
+        std::fs::write(&path, b"{not json")?;
+        assert!(load_reservations(&path).is_empty());
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn load_file_written_before_reservations_expired() -> io::Result<()> {
+        let path = temp_path("old-state");
+        std::fs::write(&path, br#"{"reservations":{"alice":[1000]}}"#)?;
+        let state = load_state(&path);
+        assert_eq!(state.reservations, PortReservations::from([("alice".to_string(), vec![1000])]));
+        assert!(state.last_used.is_empty());
+        std::fs::remove_file(&path)
+    }
+
+    #[tokio::test]
+    async fn save_latest_reservations() -> Result<(), Box<dyn std::error::Error>> {
+        let path = temp_path("saved-state");
+        let _ = std::fs::remove_file(&path);
+        let state_file = StateFile::new(path.clone());
+
+        let state = |port| State {
+            reservations: PortReservations::from([("alice".to_string(), vec![port])]),
+            last_used: HashMap::from([("alice".to_string(), 1_700_000_000)]),
+        };
+        state_file.save(state(1000));
+        state_file.save(state(1001));
+        tokio::time::sleep(SAVE_DEBOUNCE * 3).await;
+
+        assert_eq!(load_state(&path), state(1001));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1,42 +1,614 @@
-use std::{net::SocketAddr, collections::HashMap, ops::Range};
+use std::{net::{IpAddr, SocketAddr}, collections::{HashMap, HashSet}, ops::Range, path::PathBuf, str::FromStr, sync::{atomic::{AtomicU64, Ordering}, Arc, Weak}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use dashmap::DashMap;
-use ownserver_lib::{StreamId, ClientId, EndpointClaims, Endpoints, ControlPacketV2, EndpointId, Endpoint};
-use metrics::gauge;
-use rand::Rng;
-use tokio::{sync::{RwLock, Mutex}, net::ToSocketAddrs};
+use dashmap::{DashMap, DashSet};
+use ownserver_lib::{Capability, StreamId, ClientId, CloseReason, EndpointClaims, Endpoints, ControlPacketV2, EndpointId, Endpoint};
+use metrics::{counter, gauge, histogram, increment_counter, Label};
+use rand::{distributions::Alphanumeric, rngs::StdRng, Rng};
+use serde::Serialize;
+use tokio::{sync::{RwLock, Mutex, broadcast, mpsc::UnboundedSender}, net::ToSocketAddrs};
 
-use crate::{remote::stream::{RemoteStream, StreamMessage}, Client, ClientStreamError, port_allocator::{PortAllocator, PortAllocatorError}};
+use crate::{access::{AccessControl, AccessError}, admin::AdminTokens, remote::{RemoteBound, stream::{CloseCause, RemoteStream, StreamMessage}}, Client, client::ClientHandle, ClientStreamError, ProxyServerError, peer_index::PeerIndex, port_allocator::{PortAllocator, PortAllocatorError}, audit::{AuditEvent, AuditLog, ServerEvent}, compression::{self, CompressionStats}, health, logging, mirror::TrafficMirror, rate_limit::ConnectionRateLimiter, state::{self, PortReservations, State, StateFile}, verifier::TokenVerifier};
 
 
-#[derive(Debug, Default)]
+pub const DEFAULT_PORT_POOL: &str = "default";
+/// Remote peers remembered at once before the least recently used one is evicted.
+pub const DEFAULT_ADDRS_MAP_CAPACITY: usize = 65536;
+/// Largest payload of a udp datagram over IPv4.
+pub const DEFAULT_MAX_UDP_PAYLOAD: usize = 65507;
+/// Largest `ClientHello` accepted unless configured otherwise.
+pub const DEFAULT_MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+/// Largest websocket message or frame a client may send after its `ClientHello`, well above a udp datagram.
+pub const MAX_CONTROL_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Longest a port is held by `Store::reserve_port`.
+pub const MAX_RESERVATION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a token subject keeps its ports of `Store::with_state_file` after its last client left.
+pub const DEFAULT_SUBJECT_RESERVATION_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// the last use of a subject with a connected client is written at most this often
+const RESERVATION_REFRESH: Duration = Duration::from_secs(60);
+
+/// What to do when a client connects with the token subject of a client that is still connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// keep the connected client and reject the new one
+    #[default]
+    Reject,
+    /// close the connected client in favor of the new one
+    Replace,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(DuplicatePolicy::Reject),
+            "replace" => Ok(DuplicatePolicy::Replace),
+            _ => Err(format!("unknown duplicate policy `{}`, expected `reject` or `replace`", s)),
+        }
+    }
+}
+
+/// Whether the server accepts new clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerState {
+    Running,
+    /// new clients are turned away while connected ones finish
+    Draining { since: Instant },
+}
+
+/// Returned by `/admin/status`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServerStatus {
+    /// `running` or `draining`
+    pub state: &'static str,
+    pub clients: usize,
+    pub streams: usize,
+    /// seconds since draining started
+    pub drain_elapsed_secs: Option<f64>,
+    /// response compression so far, see `compression::stats`
+    pub compression: CompressionStats,
+}
+
+/// What the store forwarded since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficTotals {
+    /// remote streams opened
+    pub streams: u64,
+    /// data bytes sent to clients
+    pub bytes_to_clients: u64,
+    /// data bytes clients sent to remote peers
+    pub bytes_from_clients: u64,
+}
+
+/// An entry of `/admin/clients`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientStatus {
+    pub client_id: ClientId,
+    pub subject: Option<String>,
+    /// labels of the token, see `TokenClaims::labels`
+    pub labels: HashMap<String, String>,
+    pub remote_ports: Vec<u16>,
+    /// refusing new remote connections, see `Store::pause_client`
+    pub paused: bool,
+    /// tunnel quality from 0 to 100, see `health::health_score`
+    pub health: u8,
+}
+
+/// An entry of `/admin/ports`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PortStatus {
+    pub port: u16,
+    pub pool: String,
+    /// connected, or connecting, client the port belongs to
+    pub client_id: Option<ClientId>,
+    /// `in_use`, `connecting` while its client is in the handshake, `held` for the next client of a token subject,
+    /// or `orphaned` when nothing uses it
+    pub state: &'static str,
+}
+
+/// Names a port held by `Store::reserve_port` until a client presents it in `ClientHelloV2::reservation`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(transparent)]
+pub struct ReservationToken(String);
+
+impl ReservationToken {
+    fn generate() -> Self {
+        let token = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+        Self(token)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ReservationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PortReservation {
+    pool: String,
+    port: u16,
+    expires_at: Instant,
+}
+
+/// Returned by `Store::track_handshake`.
+#[derive(Debug)]
+pub struct HandshakeGuard {
+    store: Weak<Store>,
+    client_id: ClientId,
+}
+
+impl Drop for HandshakeGuard {
+    fn drop(&mut self) {
+        if let Some(store) = self.store.upgrade() {
+            store.handshakes.remove(&self.client_id);
+        }
+    }
+}
+
+/// Returned by `/admin/ports/{port}/drain`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PortDrainStatus {
+    pub port: u16,
+    /// streams still open on the port
+    pub streams: usize,
+}
+
+#[derive(Debug)]
 pub struct Store {
     streams: RwLock<HashMap<StreamId, RemoteStream>>,
     clients: RwLock<HashMap<ClientId, Client>>,
-    addrs_map: DashMap<SocketAddr, StreamId>,
+    // streams of each client, kept in step with `streams`
+    client_streams: DashMap<ClientId, HashSet<StreamId>>,
+    // stream of each remote peer and when the peer was last seen
+    addrs_map: PeerIndex,
+    addrs_map_capacity: usize,
+    max_udp_payload: usize,
+    max_handshake_size: usize,
+    max_msg_rate: Option<f64>,
     endpoints_map: DashMap<EndpointId, Endpoint>,
-    alloc: Mutex<PortAllocator>,
+    endpoint_pools: DashMap<EndpointId, String>,
+    alloc: Mutex<HashMap<String, PortAllocator>>,
+    audit_log: Option<AuditLog>,
+    events: Option<broadcast::Sender<ServerEvent>>,
+    reservations: DashMap<String, Vec<u16>>,
+    // unix time in seconds each subject of `reservations` was last used
+    reservations_used: DashMap<String, u64>,
+    subject_reservation_ttl: Duration,
+    // ports reserved out of band, by token
+    port_reservations: DashMap<String, PortReservation>,
+    state_file: Option<StateFile>,
+    subjects: Mutex<HashMap<String, ClientId>>,
+    // streams not yet acknowledged by the client
+    opening: DashMap<StreamId, Instant>,
+    rate_limiter: Option<ConnectionRateLimiter>,
+    access_control: Option<AccessControl>,
+    admin_tokens: AdminTokens,
+    // token label keys that label client metrics
+    metric_labels: Vec<String>,
+    // used instead of the caller's rng when set, see `new_with_rng`
+    rng: Mutex<Option<StdRng>>,
+    state: std::sync::Mutex<ServerState>,
+    // remote ports accepting no new connections
+    draining_ports: DashSet<u16>,
+    reconnect_window: Option<Duration>,
+    // sent to clients turned away because the server is full
+    retry_after: Option<Duration>,
+    // tcp streams and their remote port kept for the next client of each token subject
+    held: std::sync::Mutex<HashMap<String, Vec<(StreamId, u16)>>>,
+    bind_events: Option<UnboundedSender<RemoteBound>>,
+    token_verifier: Option<Arc<dyn TokenVerifier>>,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    mirror: Option<TrafficMirror>,
+    streams_total: AtomicU64,
+    bytes_to_clients: AtomicU64,
+    bytes_from_clients: AtomicU64,
+    // connect times of each token subject within `health::RECONNECT_WINDOW`
+    connects: DashMap<String, Vec<Instant>>,
+    // label sets of the `ownserver_server.client.health` series, see `record_health`
+    health_labels: std::sync::Mutex<HashSet<Vec<Label>>>,
+    // refusing new remote connections, see `pause_client`
+    paused_clients: DashSet<ClientId>,
+    // endpoints of clients between port allocation and `add_client`, see `track_handshake`
+    handshakes: DashMap<ClientId, Vec<EndpointId>>,
+    // span of each client, the parent of its stream spans, see `client_span`
+    client_spans: DashMap<ClientId, tracing::Span>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::with_allocators(PortAllocator::default(), HashMap::new())
+    }
 }
 
 impl Store {
     pub fn new(range: Range<u16>) -> Self {
+        Self::with_port_pools(range, HashMap::new())
+    }
+
+    /// `range` backs the default pool, `pools` are additional named pools selected by the token's tier.
+    pub fn with_port_pools(range: Range<u16>, pools: HashMap<String, Range<u16>>) -> Self {
+        let pools = pools
+            .into_iter()
+            .map(|(name, range)| (name, PortAllocator::new(range)))
+            .collect();
+        Self::with_allocators(PortAllocator::new(range), pools)
+    }
+
+    fn with_allocators(default: PortAllocator, mut pools: HashMap<String, PortAllocator>) -> Self {
+        pools.insert(DEFAULT_PORT_POOL.to_string(), default);
+        record_port_gauges(&pools);
         Self {
             streams: Default::default(),
             clients: Default::default(),
+            client_streams: Default::default(),
             addrs_map: Default::default(),
+            addrs_map_capacity: DEFAULT_ADDRS_MAP_CAPACITY,
+            max_udp_payload: DEFAULT_MAX_UDP_PAYLOAD,
+            max_handshake_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_msg_rate: None,
             endpoints_map: Default::default(),
-            alloc: Mutex::new(PortAllocator::new(range)),
+            endpoint_pools: Default::default(),
+            alloc: Mutex::new(pools),
+            audit_log: None,
+            events: None,
+            reservations: Default::default(),
+            reservations_used: Default::default(),
+            subject_reservation_ttl: DEFAULT_SUBJECT_RESERVATION_TTL,
+            port_reservations: Default::default(),
+            state_file: None,
+            subjects: Default::default(),
+            opening: Default::default(),
+            rate_limiter: None,
+            access_control: None,
+            admin_tokens: AdminTokens::default(),
+            metric_labels: Vec::new(),
+            rng: Mutex::new(None),
+            state: std::sync::Mutex::new(ServerState::Running),
+            draining_ports: Default::default(),
+            reconnect_window: None,
+            retry_after: None,
+            held: Default::default(),
+            bind_events: None,
+            token_verifier: None,
+            tls_config: None,
+            mirror: None,
+            streams_total: AtomicU64::new(0),
+            bytes_to_clients: AtomicU64::new(0),
+            bytes_from_clients: AtomicU64::new(0),
+            connects: Default::default(),
+            health_labels: Default::default(),
+            paused_clients: Default::default(),
+            handshakes: Default::default(),
+            client_spans: Default::default(),
+        }
+    }
+
+    /// Allocate ports with `rng` regardless of the rng passed to `allocate_*`,
+    /// so that a seeded rng makes allocation reproducible.
+    pub fn new_with_rng(range: Range<u16>, rng: StdRng) -> Self {
+        let mut store = Self::new(range);
+        store.rng = Mutex::new(Some(rng));
+        store
+    }
+
+    /// Never allocate `ports`, e.g. ones used by services next to the server.
+    /// Fails when a port is outside the range of every pool.
+    pub fn with_excluded_ports(mut self, ports: &[u16]) -> Result<Self, PortAllocatorError> {
+        for port in ports {
+            let mut excluded = false;
+            for alloc in self.alloc.get_mut().values_mut() {
+                excluded |= alloc.exclude_port(*port).is_ok();
+            }
+            if !excluded {
+                return Err(PortAllocatorError::ExcludedPortOutOfRange(*port));
+            }
+        }
+        record_port_gauges(self.alloc.get_mut());
+        Ok(self)
+    }
+
+    /// Hand out the lowest free port of each pool instead of a random one.
+    pub fn with_deterministic_ports(mut self) -> Self {
+        for alloc in self.alloc.get_mut().values_mut() {
+            alloc.set_sequential(true);
+        }
+        self
+    }
+
+    /// Grant only the remote port each claim asks for, see `PortAllocator::set_fixed`.
+    pub fn with_fixed_ports(mut self) -> Self {
+        for alloc in self.alloc.get_mut().values_mut() {
+            alloc.set_fixed(true);
+        }
+        self
+    }
+
+    pub fn with_addrs_map_capacity(mut self, capacity: usize) -> Self {
+        self.addrs_map_capacity = capacity.max(1);
+        self
+    }
+
+    /// Datagrams larger than `max_payload` are dropped in either direction instead of being truncated.
+    pub fn with_max_udp_payload(mut self, max_payload: usize) -> Self {
+        self.max_udp_payload = max_payload.max(1);
+        self
+    }
+
+    pub fn max_udp_payload(&self) -> usize {
+        self.max_udp_payload
+    }
+
+    /// Close connections whose first message is larger than `max_size` bytes without parsing it.
+    pub fn with_max_handshake_size(mut self, max_size: usize) -> Self {
+        self.max_handshake_size = max_size.max(1);
+        self
+    }
+
+    pub fn max_handshake_size(&self) -> usize {
+        self.max_handshake_size
+    }
+
+    /// Limit of the websocket layer, so that it does not buffer far more than a `ClientHello` or a packet.
+    pub fn max_message_size(&self) -> usize {
+        self.max_handshake_size.max(MAX_CONTROL_MESSAGE_SIZE)
+    }
+
+    /// Forward at most `rate` messages per second from each remote stream, see `MessageRateLimiter`.
+    /// A rate of 0 or less is ignored.
+    pub fn with_max_msg_rate(mut self, rate: f64) -> Self {
+        self.max_msg_rate = Some(rate).filter(|rate| *rate > 0.0);
+        self
+    }
+
+    pub fn max_msg_rate(&self) -> Option<f64> {
+        self.max_msg_rate
+    }
+
+    /// Hold the tcp streams of a disconnected client for `window`. A client with the same token subject
+    /// connecting in time takes them over on the same remote ports.
+    pub fn with_reconnect_window(mut self, window: Duration) -> Self {
+        self.reconnect_window = Some(window);
+        self
+    }
+
+    pub fn holds_streams(&self) -> bool {
+        self.reconnect_window.is_some()
+    }
+
+    /// Tell clients rejected for `NoPortsAvailable` or `TooManyClients` to wait `retry_after` before
+    /// connecting again, in place of their own backoff.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    /// Report every remote port once it is listening, before the client is told about it.
+    pub fn with_bind_events(mut self, tx: UnboundedSender<RemoteBound>) -> Self {
+        self.bind_events = Some(tx);
+        self
+    }
+
+    pub fn notify_bound(&self, event: RemoteBound) {
+        if let Some(ref tx) = self.bind_events {
+            let _ = tx.send(event);
+        }
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: ConnectionRateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// `false` when `ip` opens remote connections faster than the rate limit allows.
+    pub fn allow_remote_connection(&self, ip: IpAddr) -> bool {
+        match self.rate_limiter {
+            Some(ref limiter) if !limiter.check(ip) => {
+                increment_counter!("ownserver_server.remote.ratelimited");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    pub fn with_access_control(mut self, access_control: AccessControl) -> Self {
+        self.access_control = Some(access_control);
+        self
+    }
+
+    /// Require bearer tokens on the admin endpoints, every request is allowed otherwise.
+    pub fn with_admin_tokens(mut self, tokens: AdminTokens) -> Self {
+        self.admin_tokens = tokens;
+        self
+    }
+
+    pub fn admin_tokens(&self) -> &AdminTokens {
+        &self.admin_tokens
+    }
+
+    /// Label the metrics of each client with these token labels e.g.) `plan`. Only name keys of a few
+    /// values, every distinct value is a series of its own. A client without the label gets an empty value.
+    /// Fails on `client_id`, which client metrics are already labeled with.
+    pub fn with_metric_labels(mut self, keys: Vec<String>) -> Result<Self, ProxyServerError> {
+        if keys.iter().any(|key| key == "client_id") {
+            return Err(ProxyServerError::InvalidConfig("metric label client_id is reserved".to_string()));
+        }
+        self.metric_labels = keys;
+        Ok(self)
+    }
+
+    pub fn metric_labels(&self, labels: &HashMap<String, String>) -> Vec<Label> {
+        self.metric_labels
+            .iter()
+            .map(|key| Label::new(key.clone(), labels.get(key).cloned().unwrap_or_default()))
+            .collect()
+    }
+
+    /// `false` when the access list denies `ip`, checked before the rate limit.
+    pub fn permits_peer(&self, ip: IpAddr) -> bool {
+        match self.access_control {
+            Some(ref access) if !access.permits(ip) => {
+                increment_counter!("ownserver_server.remote.denied");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Re-read the access list, applying to new remote connections at once.
+    /// Streams of now denied peers are closed when the access control drops them. Returns how many were.
+    pub async fn reload_access_control(&self) -> Result<usize, AccessError> {
+        let access = self.access_control.as_ref().ok_or(AccessError::NotConfigured)?;
+        let list = match access.reload() {
+            Ok(list) => list,
+            Err(e) => {
+                increment_counter!("ownserver_server.access.reload_failed");
+                return Err(e);
+            }
+        };
+        increment_counter!("ownserver_server.access.reloaded");
+        tracing::info!(allow = list.allow.len(), deny = list.deny.len(), "reloaded access list");
+        if !access.drops_denied() {
+            return Ok(0);
+        }
+
+        let denied: Vec<SocketAddr> = self.addrs_map.addrs().into_iter().filter(|addr| !list.permits(addr.ip())).collect();
+        let mut closed = 0;
+        for addr in denied {
+            if self.close_stream_by_addr(&addr).await.is_some() {
+                closed += 1;
+            }
+        }
+        Ok(closed)
+    }
+
+    /// Verify client tokens with `verifier` instead of the `HmacVerifier` of the config.
+    pub fn with_token_verifier(mut self, verifier: Arc<dyn TokenVerifier>) -> Self {
+        self.token_verifier = Some(verifier);
+        self
+    }
+
+    pub fn token_verifier(&self) -> Option<&Arc<dyn TokenVerifier>> {
+        self.token_verifier.as_ref()
+    }
+
+    /// Serve the control port over TLS, see `tls::server_config`.
+    pub fn with_tls_config(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
+    pub fn tls_config(&self) -> Option<&Arc<rustls::ServerConfig>> {
+        self.tls_config.as_ref()
+    }
+
+    /// Copy the data of every packet sent to clients to `mirror`.
+    pub fn with_mirror(mut self, mirror: TrafficMirror) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Give each token subject the same remote ports across restarts. Reservations are loaded from `path`
+    /// and written back on change. Reserved ports are never handed to other clients until the subject goes unused
+    /// for `with_subject_reservation_ttl`.
+    pub fn with_state_file(mut self, path: PathBuf) -> Self {
+        let State { reservations, last_used } = state::load_state(&path);
+        let alloc = self.alloc.get_mut();
+        for (subject, ports) in reservations.iter() {
+            for port in ports {
+                if !alloc.values_mut().any(|a| a.reserve_port(*port)) {
+                    tracing::warn!(subject = %subject, port = %port, "reserved port is not available");
+                }
+            }
+        }
+
+        let now = unix_now();
+        self.reservations_used = reservations.keys().map(|s| (s.clone(), last_used.get(s).copied().unwrap_or(now))).collect();
+        self.reservations = reservations.into_iter().collect();
+        self.state_file = Some(StateFile::new(path));
+        self.save_reservations();
+        self
+    }
+
+    /// Forget the ports of a token subject no client used for `ttl`, `DEFAULT_SUBJECT_RESERVATION_TTL` by default.
+    pub fn with_subject_reservation_ttl(mut self, ttl: Duration) -> Self {
+        self.subject_reservation_ttl = ttl;
+        self
+    }
+
+    fn save_reservations(&self) {
+        if let Some(ref state_file) = self.state_file {
+            let reservations: PortReservations = self.reservations.iter().map(|e| (e.key().clone(), e.value().clone())).collect();
+            let last_used = self.reservations_used.iter().map(|e| (e.key().clone(), *e.value())).collect();
+            state_file.save(State { reservations, last_used });
+        }
+    }
+
+    /// Broadcast server events to the receivers of `subscribe`. A receiver more than `capacity` events behind
+    /// skips the oldest ones with `RecvError::Lagged` instead of slowing down the server.
+    pub fn with_events(mut self, capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        self.events = Some(tx);
+        self
+    }
+
+    /// Receive every server event from now on. `None` unless the store was built `with_events`.
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<ServerEvent>> {
+        self.events.as_ref().map(|tx| tx.subscribe())
+    }
+
+    pub fn audit(&self, event: AuditEvent) {
+        if let Some(ref tx) = self.events {
+            // no receiver is not an error
+            let _ = tx.send(event.clone());
+        }
+        if let Some(ref audit_log) = self.audit_log {
+            audit_log.record(event);
         }
     }
 
+    #[tracing::instrument(level = "debug", skip(self, packet), fields(cid = %client_id))]
     pub async fn send_to_client(&self, client_id: ClientId, packet: ControlPacketV2) -> Result<(), ClientStreamError> {
-        match self.clients.write().await.get_mut(&client_id) {
-            Some(client) => {
-                client.send_to_client(packet).await
-            },
-            None => {
-                Err(ClientStreamError::ClientNotAvailable(client_id))
+        // the lock is only held to clone the sender, a client that does not keep up must not hold up the others
+        let sender = match self.clients.read().await.get(&client_id) {
+            Some(client) => client.sender(),
+            None => return Err(ClientStreamError::ClientNotAvailable(client_id)),
+        };
+        let mirrored = match (&self.mirror, &packet) {
+            (Some(_), ControlPacketV2::Data(_, data)) => Some(data.clone()),
+            _ => None,
+        };
+        let data_len = match &packet {
+            ControlPacketV2::Data(_, data) => data.len() as u64,
+            _ => 0,
+        };
+
+        match sender.send(packet).await {
+            Ok(()) => {
+                self.bytes_to_clients.fetch_add(data_len, Ordering::Relaxed);
+                if let (Some(mirror), Some(data)) = (&self.mirror, mirrored) {
+                    mirror.record(data);
+                }
+                Ok(())
+            }
+            Err(failure) if failure.ends_client() => match self.clients.write().await.get_mut(&client_id) {
+                Some(client) => Err(client.handle_send_failure(failure).await),
+                None => Err(failure.into_error()),
             },
+            Err(failure) => Err(failure.into_error()),
         }
     }
 
@@ -49,10 +621,109 @@ impl Store {
         }
     }
 
+    /// Send `Heartbeat` to clients that negotiated it and `Ping` to the others.
+    /// A client that left too many heartbeats unanswered is disabled instead.
+    pub async fn ping_clients(&self) {
+        let mut packets = Vec::new();
+        let mut dead = Vec::new();
+        for (client_id, client) in self.clients.write().await.iter_mut() {
+            if !client.supports(Capability::Heartbeat) {
+                packets.push((*client_id, ControlPacketV2::Ping));
+                continue;
+            }
+            let nonce = client.heartbeat_mut().ping();
+            if client.heartbeat_mut().is_dead() {
+                dead.push(*client_id);
+            } else {
+                packets.push((*client_id, ControlPacketV2::Heartbeat(nonce)));
+            }
+        }
+
+        for client_id in dead {
+            tracing::warn!(cid = %client_id, "client missed heartbeats, disconnect");
+            increment_counter!("ownserver_server.client.heartbeat_timeout");
+            self.disable_client(client_id).await;
+        }
+        // side by side, a stalled client must not delay the heartbeats of the others
+        futures::future::join_all(packets.into_iter().map(|(client_id, packet)| async move {
+            if let Err(e) = self.send_to_client(client_id, packet).await {
+                tracing::warn!(cid = %client_id, "failed to send packet {:?}", e);
+            }
+        })).await;
+    }
+
+    /// Match a `HeartbeatAck` and record the round trip time.
+    pub async fn ack_heartbeat(&self, client_id: ClientId, nonce: u64) -> Option<Duration> {
+        let mut clients = self.clients.write().await;
+        let client = clients.get_mut(&client_id)?;
+        let rtt = client.heartbeat_mut().pong(nonce)?;
+        client.health().record_rtt(rtt);
+        histogram!("ownserver_server.client.rtt_ms", rtt.as_secs_f64() * 1000.0);
+        tracing::trace!(cid = %client_id, nonce, "heartbeat rtt {:?}", rtt);
+        Some(rtt)
+    }
+
+    /// Reconnects of the token `subject` within `health::RECONNECT_WINDOW`, 0 without a subject.
+    pub fn reconnects(&self, subject: Option<&str>) -> u32 {
+        let now = Instant::now();
+        subject
+            .and_then(|subject| self.connects.get(subject))
+            .map(|connects| {
+                let recent = connects.iter().filter(|at| now.duration_since(**at) < health::RECONNECT_WINDOW).count();
+                recent.saturating_sub(1) as u32
+            })
+            .unwrap_or(0)
+    }
+
+    /// Health score of `client` from 0 to 100, see `health::health_score`.
+    pub fn client_health(&self, client: &Client) -> u8 {
+        client.health().health(self.reconnects(client.subject()))
+    }
+
+    // lowest health of the connected clients per set of `metric_labels`, so that the series stay as few as
+    // the label values. A set whose clients are all gone reads 100 again.
+    async fn record_health(&self) {
+        let mut lowest: HashMap<Vec<Label>, u8> = HashMap::new();
+        for client in self.clients.read().await.values().filter(|client| !client.disabled()) {
+            let health = self.client_health(client);
+            let entry = lowest.entry(self.metric_labels(client.labels())).or_insert(health);
+            *entry = (*entry).min(health);
+        }
+        let mut reported = self.health_labels.lock().unwrap();
+        for labels in reported.iter().filter(|labels| !lowest.contains_key(*labels)) {
+            gauge!("ownserver_server.client.health", 100.0, labels.clone());
+        }
+        reported.clear();
+        for (labels, health) in lowest {
+            gauge!("ownserver_server.client.health", health as f64, labels.clone());
+            reported.insert(labels);
+        }
+    }
+
+    fn record_connect(&self, subject: &str) {
+        self.connects.entry(subject.to_string()).or_default().push(Instant::now());
+    }
+
+    // older connects only count until `health::RECONNECT_WINDOW` has passed
+    fn prune_connects(&self) {
+        let now = Instant::now();
+        self.connects.retain(|_, connects| {
+            connects.retain(|at| now.duration_since(*at) < health::RECONNECT_WINDOW);
+            !connects.is_empty()
+        });
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, message), fields(sid = %stream_id))]
     pub async fn send_to_remote(&self, stream_id: StreamId, message: StreamMessage) -> Result<(), ClientStreamError> {
         match self.streams.write().await.get_mut(&stream_id) {
             Some(stream) => {
-                stream.send_to_remote(stream_id, message).await
+                let data_len = match &message {
+                    StreamMessage::Data(data) => data.len() as u64,
+                    _ => 0,
+                };
+                stream.send_to_remote(stream_id, message).await?;
+                self.bytes_from_clients.fetch_add(data_len, Ordering::Relaxed);
+                Ok(())
             },
             None => {
                 Err(ClientStreamError::StreamNotAvailable(stream_id))
@@ -60,116 +731,1732 @@ impl Store {
         }
     }
 
-    pub async fn disable_remote(&self, stream_id: StreamId) {
-        if let Some(stream) = self.streams.write().await.get_mut(&stream_id) {
-            stream.disable();
+    /// Write `data` to the remote peer of `stream_id` as if the client had sent it, e.g. from a test harness.
+    /// Fails with `StreamNotAvailable` for an unknown stream and `StreamDisabled` for a closed one.
+    pub async fn inject_to_remote(&self, stream_id: StreamId, data: &[u8]) -> Result<(), ClientStreamError> {
+        if self.streams.read().await.get(&stream_id).map_or(false, RemoteStream::disabled) {
+            return Err(ClientStreamError::StreamDisabled(stream_id));
         }
+        self.send_to_remote(stream_id, StreamMessage::Data(data.to_vec())).await
     }
 
-    pub async fn disable_remote_by_client(&self, client_id: ClientId) {
-        for (_, stream) in self.streams.write().await.iter_mut() {
-            if stream.client_id() == client_id {
-                stream.disable()
-            }
+    pub async fn update_window(&self, stream_id: StreamId, n: u32) {
+        if let Some(stream) = self.streams.read().await.get(&stream_id) {
+            stream.add_window(n);
         }
     }
-    pub async fn disable_client(&self, client_id: ClientId) {
-        if let Some(client) = self.clients.write().await.get_mut(&client_id) {
-            client.disable().await;
+
+    pub async fn disable_remote(&self, stream_id: StreamId, cause: CloseCause) {
+        if let Some(stream) = self.streams.write().await.get_mut(&stream_id) {
+            stream.disable(cause);
         }
     }
 
-    pub async fn add_client(&self, client: Client) {
-        let client_id = client.client_id;
-        self.clients.write().await.insert(client_id, client);
+    /// Close every stream of `client_id` and drop it right away instead of at the next `cleanup`,
+    /// so that remote sockets and stream ids are freed as soon as the client goes away.
+    pub async fn disable_remote_by_client(&self, client_id: ClientId, cause: CloseCause) {
+        let stream_ids = self.streams_for_client(client_id);
+        let mut streams = self.streams.write().await;
+        self.close_streams(&mut streams, client_id, stream_ids, cause);
+    }
 
-        let v = self.len_clients().await as f64;
-        gauge!("ownserver_server.store.clients", v);
+    // disable and drop `stream_ids`, counting the ones that were still open
+    fn close_streams(&self, streams: &mut HashMap<StreamId, RemoteStream>, client_id: ClientId, stream_ids: HashSet<StreamId>, cause: CloseCause) {
+        if stream_ids.is_empty() {
+            return
+        }
+        let mut closed = 0;
+        for stream_id in stream_ids.iter() {
+            if let Some(mut stream) = streams.remove(stream_id) {
+                if !stream.disabled() {
+                    closed += 1;
+                }
+                stream.disable(cause);
+                self.unindex_stream(stream.client_id(), *stream_id);
+            }
+            self.opening.remove(stream_id);
+        }
+        self.addrs_map.retain(|sid| !stream_ids.contains(sid));
+        tracing::debug!(cid = %client_id, "closed {} streams of the client", closed);
+        counter!("ownserver_server.store.streams_closed_with_client", closed);
     }
 
-    pub async fn add_remote(&self, remote: RemoteStream, peer_addr: SocketAddr) {
-        let stream_id = remote.stream_id();
-        self.streams.write().await.insert(stream_id, remote);
-        self.addrs_map.insert(peer_addr, stream_id);
+    /// Streams opened for `client_id` that were not cleaned up yet, including disabled ones.
+    pub fn streams_for_client(&self, client_id: ClientId) -> HashSet<StreamId> {
+        self.client_streams.get(&client_id).map(|ids| ids.value().clone()).unwrap_or_default()
+    }
 
-        let v = self.len_streams().await as f64;
-        gauge!("ownserver_server.store.streams", v);
+    fn index_stream(&self, client_id: ClientId, stream_id: StreamId) {
+        self.client_streams.entry(client_id).or_default().insert(stream_id);
     }
 
-    pub async fn cleanup(&self) {
-        tracing::debug!("Store::cleanup");
-        self.streams.write().await.retain(|_, v| !v.disabled());
+    fn unindex_stream(&self, client_id: ClientId, stream_id: StreamId) {
+        if let Some(mut ids) = self.client_streams.get_mut(&client_id) {
+            ids.remove(&stream_id);
+        }
+        self.client_streams.remove_if(&client_id, |_, ids| ids.is_empty());
+    }
 
-        let mut eids_to_remove = Vec::new();
-        for (_, client ) in self.clients.read().await.iter() {
-            if client.disabled() {
-                client.endpoints().iter().for_each(|e| {
-                    eids_to_remove.push(e.id)
-                });
+    /// Same as `disable_remote_by_client` but tcp streams are held for the next client of `subject`
+    /// when the store has a reconnect window.
+    pub async fn detach_remote_by_client(&self, client_id: ClientId, subject: Option<&str>, cause: CloseCause) {
+        let (window, subject) = match (self.reconnect_window, subject) {
+            (Some(window), Some(subject)) => (window, subject),
+            _ => return self.disable_remote_by_client(client_id, cause).await,
+        };
+
+        let mut held = Vec::new();
+        let mut to_close = HashSet::new();
+        let stream_ids = self.streams_for_client(client_id);
+        let mut streams = self.streams.write().await;
+        for stream_id in stream_ids {
+            let stream = match streams.get_mut(&stream_id) {
+                Some(stream) if !stream.disabled() => stream,
+                _ => {
+                    to_close.insert(stream_id);
+                    continue
+                }
+            };
+            match self.get_remote_port_by_endpoint_id(stream.endpoint_id()) {
+                Some(port) if stream.hold(window) => held.push((stream_id, port)),
+                _ => {
+                    to_close.insert(stream_id);
+                }
             }
         }
-        self.clients.write().await.retain(|_, v| !v.disabled());
-        for eid in eids_to_remove {
-            if let Err(e) = self.release_endpoint(eid).await {
-                tracing::warn!(eid = %eid, "failed to release endpoint {:?}", e);
-            }
+        self.close_streams(&mut streams, client_id, to_close, cause);
+        drop(streams);
+        if held.is_empty() {
+            return
         }
+        tracing::info!(cid = %client_id, subject = %subject, "hold {} streams for {:?}", held.len(), window);
+        counter!("ownserver_server.store.streams_held", held.len() as u64);
+        self.held.lock().unwrap().entry(subject.to_string()).or_default().extend(held);
+    }
 
-        let v = self.len_clients().await as f64;
-        gauge!("ownserver_server.store.clients", v);
-        let v = self.len_streams().await as f64;
-        gauge!("ownserver_server.store.streams", v);
+    pub fn has_held_streams(&self, subject: &str) -> bool {
+        self.held.lock().unwrap().contains_key(subject)
     }
 
-    pub async fn find_stream_id_by_addr(&self, addr: &SocketAddr) -> Option<StreamId> {
-        let stream_id = if let Some(e) = self.addrs_map.get(addr) {
-            e.value().to_owned()
-        } else {
-            return None
+    /// Whether streams of `client_id` should wait for its subject to reconnect rather than close:
+    /// streams are held and the client has gone away.
+    pub async fn waits_for_reconnect(&self, client_id: ClientId) -> bool {
+        self.holds_streams() && self.clients.read().await.get(&client_id).map_or(true, |client| client.disabled())
+    }
+
+    // rebind streams held for `subject` to the endpoints of `client_id` with the same remote port
+    async fn resume_streams(&self, subject: &str, client_id: ClientId, endpoints: &Endpoints) {
+        let held = match self.held.lock().unwrap().remove(subject) {
+            Some(held) => held,
+            None => return,
         };
-        
-        if let Some(stream) = self.streams.read().await.get(&stream_id) {
-            if !stream.disabled() {
-                return Some(stream.stream_id())
+
+        let mut resumable = Vec::new();
+        for (stream_id, stream) in self.streams.write().await.iter_mut() {
+            let port = match held.iter().find(|(sid, _)| sid == stream_id) {
+                Some(&(_, port)) => port,
+                None => continue,
+            };
+            match endpoints.iter().find(|e| e.remote_port == port) {
+                Some(endpoint) if !stream.disabled() => resumable.push((*stream_id, endpoint.id)),
+                _ => stream.disable(CloseCause::ClientDisconnected),
+            }
+        }
+
+        // the client learns about the stream before data read by the held stream is replayed
+        for &(stream_id, endpoint_id) in resumable.iter() {
+            self.opening.insert(stream_id, Instant::now());
+            if let Err(e) = self.send_to_client(client_id, ControlPacketV2::Init(stream_id, endpoint_id)).await {
+                tracing::warn!(cid = %client_id, sid = %stream_id, "failed to resume stream {:?}", e);
+            }
+        }
+
+        let mut streams = self.streams.write().await;
+        for (stream_id, endpoint_id) in resumable {
+            if let Some(stream) = streams.get_mut(&stream_id) {
+                self.unindex_stream(stream.client_id(), stream_id);
+                stream.rebind(client_id, endpoint_id);
+                self.index_stream(client_id, stream_id);
+                increment_counter!("ownserver_server.store.streams_resumed");
             }
         }
-        None
     }
 
-    pub async fn get_stream_ids(&self) -> Vec<StreamId> {
-        self.streams.read().await.iter().map(|(_, v)| v.stream_id()).collect()
+    /// Span every log line of `client_id` shares, created on first use and dropped with the client.
+    pub fn client_span(&self, client_id: ClientId) -> tracing::Span {
+        self.client_spans.entry(client_id).or_insert_with(|| logging::client_span(client_id)).clone()
     }
 
-    pub async fn len_streams(&self) -> usize {
-        self.streams.read().await.len()
+    pub async fn client_supports(&self, client_id: ClientId, capability: Capability) -> bool {
+        self.clients.read().await.get(&client_id).map(|c| c.supports(capability)).unwrap_or(false)
     }
 
-    pub async fn len_clients(&self) -> usize {
-        self.clients.read().await.len()
+    /// Refuse new remote connections of `client_id` e.g. for a short maintenance, without dropping the client.
+    /// Its open streams go on and it keeps its ports. `false` when no such client is connected.
+    pub async fn pause_client(&self, client_id: ClientId) -> bool {
+        if !self.clients.read().await.contains_key(&client_id) {
+            return false
+        }
+        self.paused_clients.insert(client_id);
+        tracing::info!(cid = %client_id, "paused client");
+        true
     }
 
+    /// Accept new remote connections of a client paused by `pause_client` again.
+    pub async fn resume_client(&self, client_id: ClientId) -> bool {
+        if !self.clients.read().await.contains_key(&client_id) {
+            return false
+        }
+        self.paused_clients.remove(&client_id);
+        tracing::info!(cid = %client_id, "resumed client");
+        true
+    }
 
-    pub async fn allocate_port(&self, rng: &mut impl Rng) -> Result<u16, PortAllocatorError> {
-        self.alloc.lock().await.allocate_port(rng)
+    /// Checked for every remote connection, so it does not wait for the client lock.
+    pub fn is_client_paused(&self, client_id: ClientId) -> bool {
+        self.paused_clients.contains(&client_id)
     }
 
-    pub async fn allocate_endpoints(&self, rng: &mut impl Rng, client_claims: EndpointClaims) -> Result<Endpoints, PortAllocatorError> {
-        let endpoints = self.alloc.lock().await.allocate_ports(rng, client_claims)?;
-        for endpoint in endpoints.clone().into_iter() {
-            self.endpoints_map.insert(endpoint.id, endpoint);
+    pub async fn close_client(&self, client_id: ClientId, reason: CloseReason) {
+        if let Some(client) = self.clients.write().await.get_mut(&client_id) {
+            client.close(reason).await;
         }
-        Ok(endpoints)
     }
 
-    pub async fn release_endpoint(&self, eid: EndpointId) -> Result<(), PortAllocatorError> {
-        let endpoint = self.endpoints_map.get(&eid).ok_or(PortAllocatorError::PortOutOfRange)?;
-        self.alloc.lock().await.release_port(endpoint.remote_port)
+    /// Index `client_id` under the token `subject` before it is added. When another client of the same
+    /// subject is still connected or handshaking, `Reject` fails with its id and `Replace` closes it.
+    pub async fn claim_subject(&self, subject: &str, client_id: ClientId, policy: DuplicatePolicy) -> Result<(), ClientId> {
+        let mut subjects = self.subjects.lock().await;
+        if let Some(&existing) = subjects.get(subject) {
+            let connected = match self.clients.read().await.get(&existing) {
+                Some(client) => !client.disabled(),
+                None => true,
+            };
+            if connected {
+                match policy {
+                    DuplicatePolicy::Reject => return Err(existing),
+                    DuplicatePolicy::Replace => {
+                        subjects.insert(subject.to_string(), client_id);
+                        drop(subjects);
+                        tracing::info!(cid = %existing, new_cid = %client_id, "replace client with the same token subject");
+                        self.close_client(existing, CloseReason::AlreadyConnected).await;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        subjects.insert(subject.to_string(), client_id);
+        Ok(())
     }
 
-    pub fn get_remote_addr_by_endpoint_id(&self, eid: EndpointId) -> Option<impl ToSocketAddrs + std::fmt::Debug + Clone> {
-        let endpoint = self.endpoints_map.get(&eid)?;
+    /// Forget the subject of a client that failed to complete the handshake.
+    pub async fn release_subject(&self, client_id: ClientId) {
+        self.subjects.lock().await.retain(|_, v| *v != client_id);
+    }
 
-        Some(format!("0.0.0.0:{}", endpoint.remote_port))
+    pub async fn disable_client(&self, client_id: ClientId) {
+        if let Some(client) = self.clients.write().await.get_mut(&client_id) {
+            client.disable().await;
+        }
     }
 
-}
\ No newline at end of file
+    pub async fn add_client(&self, client: Client) -> ClientHandle {
+        let client_id = client.client_id;
+        let handle = ClientHandle::new(&client);
+        let resume = client.subject().map(|subject| (subject.to_string(), client.endpoints().clone()));
+        counter!("ownserver_server.client.connected", 1, self.metric_labels(client.labels()));
+        if let Some(subject) = client.subject() {
+            self.record_connect(subject);
+        }
+        self.clients.write().await.insert(client_id, client);
+        if let Some((subject, endpoints)) = resume {
+            self.resume_streams(&subject, client_id, &endpoints).await;
+        }
+
+        let v = self.len_clients().await as f64;
+        gauge!("ownserver_server.store.clients", v);
+        handle
+    }
+
+    pub async fn add_remote(&self, mut remote: RemoteStream, peer_addr: SocketAddr) {
+        let stream_id = remote.stream_id();
+        let token_subject = self.clients.read().await.get(&remote.client_id()).and_then(|c| c.subject().map(str::to_string));
+        self.audit(AuditEvent::StreamOpen { client_id: remote.client_id(), stream_id, token_subject: token_subject.clone(), peer_addr });
+        remote.set_token_subject(token_subject);
+        self.opening.insert(stream_id, Instant::now());
+        self.streams_total.fetch_add(1, Ordering::Relaxed);
+        self.index_stream(remote.client_id(), stream_id);
+        self.streams.write().await.insert(stream_id, remote);
+        self.insert_addr(peer_addr, stream_id).await;
+
+        let v = self.len_streams().await as f64;
+        gauge!("ownserver_server.store.streams", v);
+    }
+
+    // when the map is full, forget peers of closed streams first, then evict the least recently used peer
+    async fn insert_addr(&self, peer_addr: SocketAddr, stream_id: StreamId) {
+        while self.addrs_map.len() >= self.addrs_map_capacity && !self.addrs_map.contains(&peer_addr) {
+            let (addr, evicted) = match self.addrs_map.pop_oldest() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            // peers of closed streams only wait for cleanup to forget them
+            let open = self.streams.read().await.get(&evicted).map(|s| !s.disabled()).unwrap_or(false);
+            if open {
+                tracing::warn!(sid = %evicted, "too many remote peers, evict least recently used peer {}", addr);
+                increment_counter!("ownserver_server.store.addrs_map_evicted");
+                self.close_remote(evicted, CloseCause::IdleTimeout).await;
+            }
+        }
+        self.addrs_map.insert(peer_addr, stream_id);
+        gauge!("ownserver_server.store.addrs_map_size", self.addrs_map.len() as f64);
+    }
+
+    // disable the stream and tell its client that it ended
+    async fn close_remote(&self, stream_id: StreamId, cause: CloseCause) {
+        let client_id = self.streams.write().await.get_mut(&stream_id).map(|stream| {
+            stream.disable(cause);
+            stream.client_id()
+        });
+        if let Some(client_id) = client_id {
+            if let Err(e) = self.send_to_client(client_id, ControlPacketV2::End(stream_id)).await {
+                tracing::debug!(cid = %client_id, sid = %stream_id, "failed to send end {:?}", e);
+            }
+        }
+    }
+
+    /// Evict the remote peer at `addr`, e.g. a misbehaving udp peer that never ends its stream.
+    /// Its stream is disabled and dropped and the client is told with `End`. `None` when no stream has the peer.
+    pub async fn close_stream_by_addr(&self, addr: &SocketAddr) -> Option<StreamId> {
+        let stream_id = self.addrs_map.remove(addr)?;
+        tracing::info!(sid = %stream_id, "close stream of remote peer {}", addr);
+        self.close_remote(stream_id, CloseCause::AdminClosed).await;
+
+        if let Some(stream) = self.streams.write().await.remove(&stream_id) {
+            self.unindex_stream(stream.client_id(), stream_id);
+        }
+        self.opening.remove(&stream_id);
+        gauge!("ownserver_server.store.addrs_map_size", self.addrs_map.len() as f64);
+        let v = self.len_streams().await as f64;
+        gauge!("ownserver_server.store.streams", v);
+        Some(stream_id)
+    }
+
+    /// Record how long the client took to acknowledge the stream since `add_remote`.
+    /// Returns `None` when the stream is unknown or already acknowledged.
+    pub fn ack_remote(&self, stream_id: StreamId) -> Option<Duration> {
+        let (_, opened_at) = self.opening.remove(&stream_id)?;
+        let latency = opened_at.elapsed();
+        histogram!("ownserver_server.remote.open_latency_ms", latency.as_secs_f64() * 1000.0);
+        Some(latency)
+    }
+
+    /// Drop disabled clients and streams. Returns how many were dropped.
+    pub async fn cleanup(&self) -> usize {
+        tracing::debug!("Store::cleanup");
+        let mut removed = 0;
+        {
+            let mut streams = self.streams.write().await;
+            streams.retain(|stream_id, v| {
+                if v.disabled() {
+                    self.unindex_stream(v.client_id(), *stream_id);
+                    removed += 1;
+                }
+                !v.disabled()
+            });
+            self.opening.retain(|sid, _| streams.contains_key(sid));
+            self.addrs_map.retain(|sid| streams.contains_key(sid));
+            self.held.lock().unwrap().retain(|_, held| {
+                held.retain(|(sid, _)| streams.contains_key(sid));
+                !held.is_empty()
+            });
+        }
+        gauge!("ownserver_server.store.addrs_map_size", self.addrs_map.len() as f64);
+        if let Some(ref limiter) = self.rate_limiter {
+            limiter.prune();
+        }
+        self.expire_port_reservations().await;
+        self.expire_subject_reservations().await;
+
+        removed += self.remove_clients(|client| client.disabled()).await;
+        self.prune_connects();
+        self.record_health().await;
+
+        let v = self.len_clients().await as f64;
+        gauge!("ownserver_server.store.clients", v);
+        let v = self.len_streams().await as f64;
+        gauge!("ownserver_server.store.streams", v);
+        removed
+    }
+
+    /// Drop the disconnected clients of `subject` and release their ports, leaving the rest of the
+    /// store to the periodic `cleanup`.
+    pub async fn cleanup_subject(&self, subject: &str) -> usize {
+        let removed = self.remove_clients(|client| client.disabled() && client.subject() == Some(subject)).await;
+        let v = self.len_clients().await as f64;
+        gauge!("ownserver_server.store.clients", v);
+        removed
+    }
+
+    // remove the clients picked by `remove` and return their endpoints to the pools
+    async fn remove_clients(&self, remove: impl Fn(&Client) -> bool) -> usize {
+        let mut eids_to_remove = Vec::new();
+        let mut cids_to_remove = HashSet::new();
+        self.clients.write().await.retain(|_, client| {
+            if !remove(client) {
+                return true;
+            }
+            eids_to_remove.extend(client.endpoints().iter().map(|e| e.id));
+            cids_to_remove.insert(client.client_id);
+            false
+        });
+        self.subjects.lock().await.retain(|_, v| !cids_to_remove.contains(v));
+        self.paused_clients.retain(|client_id| !cids_to_remove.contains(client_id));
+        self.client_spans.retain(|client_id, _| !cids_to_remove.contains(client_id));
+        for eid in eids_to_remove {
+            if let Err(e) = self.release_endpoint(eid).await {
+                tracing::warn!(eid = %eid, "failed to release endpoint {:?}", e);
+            }
+        }
+        cids_to_remove.len()
+    }
+
+    /// Tear down everything: tell clients their streams ended, disable clients and streams,
+    /// return every allocated port to its pool and clear the maps. The store can be reused afterwards.
+    pub async fn shutdown(&self) {
+        tracing::debug!("Store::shutdown");
+        let ends: Vec<(ClientId, StreamId)> = self.streams.read().await
+            .values()
+            .filter(|s| !s.disabled())
+            .map(|s| (s.client_id(), s.stream_id()))
+            .collect();
+        futures::future::join_all(ends.into_iter().map(|(client_id, stream_id)| async move {
+            if let Err(e) = self.send_to_client(client_id, ControlPacketV2::End(stream_id)).await {
+                tracing::debug!(cid = %client_id, sid = %stream_id, "failed to send end {:?}", e);
+            }
+        })).await;
+
+        // disabled out of the map, so that the lock is not held meanwhile
+        let mut clients: Vec<Client> = self.clients.write().await.drain().map(|(_, client)| client).collect();
+        for client in clients.iter_mut() {
+            client.disable().await;
+        }
+        for (_, stream) in self.streams.write().await.iter_mut() {
+            stream.disable(CloseCause::AdminClosed);
+        }
+
+        let eids: Vec<EndpointId> = self.endpoints_map.iter().map(|e| *e.key()).collect();
+        for eid in eids {
+            match self.release_endpoint(eid).await {
+                // released by an earlier cleanup or shared by another endpoint
+                Ok(()) | Err(PortAllocatorError::PortAlreadyReleased) => {}
+                Err(e) => tracing::warn!(eid = %eid, "failed to release endpoint {:?}", e),
+            }
+        }
+
+        self.streams.write().await.clear();
+        self.client_streams.clear();
+        self.clients.write().await.clear();
+        self.paused_clients.clear();
+        self.client_spans.clear();
+        self.addrs_map.clear();
+        self.endpoints_map.clear();
+        self.endpoint_pools.clear();
+        self.subjects.lock().await.clear();
+        self.opening.clear();
+        self.held.lock().unwrap().clear();
+
+        gauge!("ownserver_server.store.clients", 0.0);
+        gauge!("ownserver_server.store.streams", 0.0);
+    }
+
+    /// Mark the remote peer at `addr` as active, e.g. on tcp reads, so that it is not evicted as least recently used.
+    pub fn touch_addr(&self, addr: &SocketAddr) {
+        self.addrs_map.touch(addr);
+    }
+
+    pub async fn find_stream_id_by_addr(&self, addr: &SocketAddr) -> Option<StreamId> {
+        let stream_id = self.addrs_map.touch(addr)?;
+
+        if let Some(stream) = self.streams.read().await.get(&stream_id) {
+            if !stream.disabled() {
+                return Some(stream.stream_id())
+            }
+        }
+        None
+    }
+
+    pub async fn get_stream_ids(&self) -> Vec<StreamId> {
+        self.streams.read().await.iter().map(|(_, v)| v.stream_id()).collect()
+    }
+
+    /// Stop accepting new clients. Connected clients are left alone.
+    pub fn start_drain(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == ServerState::Running {
+            tracing::info!("start draining clients");
+            *state = ServerState::Draining { since: Instant::now() };
+        }
+    }
+
+    pub fn server_state(&self) -> ServerState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        matches!(self.server_state(), ServerState::Draining { .. })
+    }
+
+    /// Stop accepting new remote connections on `port`, its open streams and other ports are left alone.
+    /// `None` when no endpoint listens on `port`.
+    pub async fn drain_port(&self, port: u16) -> Option<PortDrainStatus> {
+        let endpoints: HashSet<EndpointId> = self.endpoints_map
+            .iter()
+            .filter(|endpoint| endpoint.remote_port == port)
+            .map(|endpoint| *endpoint.key())
+            .collect();
+        if endpoints.is_empty() {
+            return None;
+        }
+        if self.draining_ports.insert(port) {
+            tracing::info!(port, "start draining remote port");
+        }
+
+        let streams = self.streams.read().await
+            .values()
+            .filter(|stream| !stream.disabled() && endpoints.contains(&stream.endpoint_id()))
+            .count();
+        Some(PortDrainStatus { port, streams })
+    }
+
+    pub fn is_port_draining(&self, port: u16) -> bool {
+        self.draining_ports.contains(&port)
+    }
+
+    pub async fn status(&self) -> ServerStatus {
+        let (state, drain_elapsed_secs) = match self.server_state() {
+            ServerState::Running => ("running", None),
+            ServerState::Draining { since } => ("draining", Some(since.elapsed().as_secs_f64())),
+        };
+        ServerStatus {
+            state,
+            clients: self.len_clients().await,
+            streams: self.len_streams().await,
+            drain_elapsed_secs,
+            compression: compression::stats(),
+        }
+    }
+
+    /// Connected clients ordered by id.
+    pub async fn client_statuses(&self) -> Vec<ClientStatus> {
+        let mut statuses: Vec<ClientStatus> = self
+            .clients
+            .read()
+            .await
+            .values()
+            .map(|client| ClientStatus {
+                client_id: client.client_id,
+                subject: client.subject().map(str::to_string),
+                labels: client.labels().clone(),
+                remote_ports: client.endpoints().iter().map(|e| e.remote_port).collect(),
+                paused: self.is_client_paused(client.client_id),
+                health: self.client_health(client),
+            })
+            .collect();
+        statuses.sort_by_key(|status| status.client_id.to_string());
+        statuses
+    }
+
+    /// Allocated remote ports ordered by port, with the client using each.
+    pub async fn port_statuses(&self) -> Vec<PortStatus> {
+        let owners: HashMap<u16, ClientId> = self
+            .clients
+            .read()
+            .await
+            .values()
+            .flat_map(|client| client.endpoints().iter().map(move |e| (e.remote_port, client.client_id)))
+            .collect();
+        let held = self.held_ports();
+        let connecting = self.handshake_ports();
+
+        let pools = self.alloc.lock().await;
+        let mut statuses: Vec<PortStatus> = pools
+            .iter()
+            .flat_map(|(pool, alloc)| alloc.allocated().map(move |port| (pool, port)))
+            .map(|(pool, port)| {
+                let (client_id, state) = match (owners.get(&port), connecting.get(&port)) {
+                    (Some(owner), _) => (Some(*owner), "in_use"),
+                    (None, Some(connecting)) => (Some(*connecting), "connecting"),
+                    (None, None) if held.contains(&port) => (None, "held"),
+                    (None, None) => (None, "orphaned"),
+                };
+                PortStatus { port, pool: pool.clone(), client_id, state }
+            })
+            .collect();
+        statuses.sort_by_key(|status| status.port);
+        statuses
+    }
+
+    /// Force an orphaned remote port back into its pool, e.g. one left allocated after a crash.
+    /// Refused with `PortInUse` while a client, a client still in its handshake or a held stream uses the port.
+    pub async fn release_port(&self, port: u16) -> Result<(), PortAllocatorError> {
+        let in_use = self.clients.read().await.values().any(|client| client.endpoints().iter().any(|e| e.remote_port == port));
+        if in_use || self.held_ports().contains(&port) || self.handshake_ports().contains_key(&port) {
+            return Err(PortAllocatorError::PortInUse(port));
+        }
+
+        let mut pools = self.alloc.lock().await;
+        let alloc = pools.values_mut().find(|alloc| alloc.is_allocated(port)).ok_or(PortAllocatorError::PortAlreadyReleased)?;
+        alloc.release_port(port)?;
+        if self.reservations.iter().any(|e| e.value().contains(&port)) {
+            alloc.reserve_port(port);
+        }
+        record_port_gauges(&pools);
+        drop(pools);
+
+        // endpoints left behind by the client that leaked the port
+        self.endpoints_map.retain(|eid, endpoint| {
+            let stale = endpoint.remote_port == port;
+            if stale {
+                self.endpoint_pools.remove(eid);
+            }
+            !stale
+        });
+        self.draining_ports.remove(&port);
+        tracing::warn!(port, "force released orphaned port");
+        Ok(())
+    }
+
+    fn held_ports(&self) -> HashSet<u16> {
+        self.held.lock().unwrap().values().flatten().map(|(_, port)| *port).collect()
+    }
+
+    /// Count the ports of `endpoints` as in use until the guard is dropped, i.e. once the client
+    /// is added or turned away. Ports a client is moved to while binding are followed as well.
+    pub fn track_handshake(self: &Arc<Self>, client_id: ClientId, endpoints: &Endpoints) -> HandshakeGuard {
+        self.handshakes.insert(client_id, endpoints.iter().map(|e| e.id).collect());
+        HandshakeGuard { store: Arc::downgrade(self), client_id }
+    }
+
+    fn handshake_ports(&self) -> HashMap<u16, ClientId> {
+        self.handshakes
+            .iter()
+            .flat_map(|entry| {
+                let client_id = *entry.key();
+                entry.value().iter().filter_map(|eid| self.endpoints_map.get(eid).map(|e| (e.remote_port, client_id))).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn traffic_totals(&self) -> TrafficTotals {
+        TrafficTotals {
+            streams: self.streams_total.load(Ordering::Relaxed),
+            bytes_to_clients: self.bytes_to_clients.load(Ordering::Relaxed),
+            bytes_from_clients: self.bytes_from_clients.load(Ordering::Relaxed),
+        }
+    }
+
+    pub async fn len_streams(&self) -> usize {
+        self.streams.read().await.len()
+    }
+
+    pub async fn len_clients(&self) -> usize {
+        self.clients.read().await.len()
+    }
+
+
+    pub async fn allocate_port(&self, rng: &mut impl Rng) -> Result<u16, PortAllocatorError> {
+        let mut pools = self.alloc.lock().await;
+        let alloc = pools.get_mut(DEFAULT_PORT_POOL).expect("default port pool always exists");
+        let port = match self.rng.lock().await.as_mut() {
+            Some(seeded) => alloc.allocate_port(seeded)?,
+            None => alloc.allocate_port(rng)?,
+        };
+        record_port_gauges(&pools);
+        Ok(port)
+    }
+
+    pub async fn allocate_endpoints(&self, rng: &mut impl Rng, client_claims: EndpointClaims) -> Result<Endpoints, PortAllocatorError> {
+        self.allocate_endpoints_in_pool(rng, None, client_claims).await
+    }
+
+    /// Allocate from the pool named `pool`. Unknown pools fall back to the default pool.
+    pub async fn allocate_endpoints_in_pool(&self, rng: &mut impl Rng, pool: Option<&str>, client_claims: EndpointClaims) -> Result<Endpoints, PortAllocatorError> {
+        self.allocate_endpoints_for(rng, pool, None, client_claims).await
+    }
+
+    /// Same as `allocate_endpoints_in_pool` but reuses the ports reserved for the token `subject` when a state file is set.
+    pub async fn allocate_endpoints_for(&self, rng: &mut impl Rng, pool: Option<&str>, subject: Option<&str>, client_claims: EndpointClaims) -> Result<Endpoints, PortAllocatorError> {
+        self.allocate_endpoints_within(rng, pool, subject, None, client_claims).await
+    }
+
+    /// Same as `allocate_endpoints_for` but only hands out remote ports in `allowed` when it is set.
+    pub async fn allocate_endpoints_within(&self, rng: &mut impl Rng, pool: Option<&str>, subject: Option<&str>, allowed: Option<&[u16]>, client_claims: EndpointClaims) -> Result<Endpoints, PortAllocatorError> {
+        let subject = subject.filter(|_| self.state_file.is_some());
+        let preferred = subject
+            .and_then(|s| self.reservations.get(s).map(|ports| ports.value().clone()))
+            .unwrap_or_default();
+
+        let mut pools = self.alloc.lock().await;
+        let pool = match pool {
+            Some(pool) if pools.contains_key(pool) => pool,
+            Some(pool) => {
+                tracing::warn!(pool = %pool, "unknown port pool, fall back to default pool");
+                DEFAULT_PORT_POOL
+            }
+            None => DEFAULT_PORT_POOL,
+        };
+
+        let alloc = pools.get_mut(pool).expect("default port pool always exists");
+        let endpoints = match self.rng.lock().await.as_mut() {
+            Some(seeded) => alloc.allocate_ports_within(seeded, client_claims, &preferred, allowed)?,
+            None => alloc.allocate_ports_within(rng, client_claims, &preferred, allowed)?,
+        };
+        record_port_gauges(&pools);
+        for endpoint in endpoints.clone().into_iter() {
+            self.endpoint_pools.insert(endpoint.id, pool.to_string());
+            self.endpoints_map.insert(endpoint.id, endpoint);
+        }
+
+        if let Some(subject) = subject {
+            let mut ports: Vec<u16> = endpoints.iter().map(|e| e.remote_port).collect();
+            ports.sort_unstable();
+            ports.dedup();
+            if ports != preferred {
+                self.reservations.insert(subject.to_string(), ports);
+            }
+            self.reservations_used.insert(subject.to_string(), unix_now());
+            self.save_reservations();
+        }
+        Ok(endpoints)
+    }
+
+    /// Hold `port` for the client presenting the returned token, see `allocate_reserved_endpoints`.
+    /// The port returns to its pool when no client presents the token within `ttl`, at most `MAX_RESERVATION_TTL`.
+    pub async fn reserve_port(&self, port: u16, ttl: Duration) -> Result<ReservationToken, PortAllocatorError> {
+        let mut pools = self.alloc.lock().await;
+        let pool = pools
+            .iter_mut()
+            .find_map(|(name, alloc)| alloc.reserve_port(port).then(|| name.clone()))
+            .ok_or(PortAllocatorError::PortUnavailable(port))?;
+        drop(pools);
+
+        let ttl = ttl.min(MAX_RESERVATION_TTL);
+        let token = ReservationToken::generate();
+        self.port_reservations.insert(token.0.clone(), PortReservation { pool, port, expires_at: Instant::now() + ttl });
+        tracing::info!(port, ?ttl, "reserved port");
+        Ok(token)
+    }
+
+    /// Same as `allocate_endpoints_within` but grants the port reserved for `token` from its pool.
+    /// Fails with `ReservationInvalid` when the token is unknown or has expired, or the reserved port is not granted
+    /// e.g. because the claims or `allowed` ask for other ports. The reservation is used up once the endpoints are allocated.
+    pub async fn allocate_reserved_endpoints(&self, rng: &mut impl Rng, token: &str, allowed: Option<&[u16]>, client_claims: EndpointClaims) -> Result<Endpoints, PortAllocatorError> {
+        let (token, reservation) = self.port_reservations.remove(token).ok_or(PortAllocatorError::ReservationInvalid)?;
+        let mut pools = self.alloc.lock().await;
+        let alloc = pools.get_mut(&reservation.pool).ok_or(PortAllocatorError::ReservationInvalid)?;
+        if reservation.expires_at <= Instant::now() {
+            alloc.unreserve_port(reservation.port);
+            return Err(PortAllocatorError::ReservationInvalid);
+        }
+
+        let preferred = [reservation.port];
+        let result = match self.rng.lock().await.as_mut() {
+            Some(seeded) => alloc.allocate_ports_within(seeded, client_claims, &preferred, allowed),
+            None => alloc.allocate_ports_within(rng, client_claims, &preferred, allowed),
+        };
+        let endpoints = match result {
+            Ok(endpoints) if endpoints.iter().any(|e| e.remote_port == reservation.port) => endpoints,
+            Ok(endpoints) => {
+                let mut ports: Vec<u16> = endpoints.iter().map(|e| e.remote_port).collect();
+                ports.sort_unstable();
+                ports.dedup();
+                for port in ports {
+                    let _ = alloc.release_port(port);
+                }
+                alloc.reserve_port(reservation.port);
+                self.port_reservations.insert(token, reservation);
+                return Err(PortAllocatorError::ReservationInvalid);
+            }
+            Err(e) => {
+                // still reserved, the client may try again
+                self.port_reservations.insert(token, reservation);
+                return Err(e);
+            }
+        };
+        record_port_gauges(&pools);
+        for endpoint in endpoints.clone().into_iter() {
+            self.endpoint_pools.insert(endpoint.id, reservation.pool.clone());
+            self.endpoints_map.insert(endpoint.id, endpoint);
+        }
+        Ok(endpoints)
+    }
+
+    /// Return the ports of expired reservations to their pools.
+    pub async fn expire_port_reservations(&self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<String> = self.port_reservations.iter().filter(|e| e.expires_at <= now).map(|e| e.key().clone()).collect();
+        let mut pools = self.alloc.lock().await;
+        let mut count = 0;
+        for token in expired {
+            if let Some((_, reservation)) = self.port_reservations.remove_if(&token, |_, r| r.expires_at <= now) {
+                if let Some(alloc) = pools.get_mut(&reservation.pool) {
+                    alloc.unreserve_port(reservation.port);
+                }
+                tracing::info!(port = reservation.port, "port reservation expired");
+                count += 1;
+            }
+        }
+        if count > 0 {
+            record_port_gauges(&pools);
+        }
+        count
+    }
+
+    /// Forget the reservations of token subjects that no client used for the subject reservation ttl
+    /// and return their ports to the pools. Returns how many subjects were forgotten.
+    pub async fn expire_subject_reservations(&self) -> usize {
+        if self.state_file.is_none() {
+            return 0;
+        }
+        let now = unix_now();
+        let connected: HashSet<String> = self.clients.read().await.values().filter_map(|c| c.subject().map(str::to_string)).collect();
+        let mut changed = false;
+        for subject in connected.iter() {
+            if let Some(mut used) = self.reservations_used.get_mut(subject) {
+                if now.saturating_sub(*used) >= RESERVATION_REFRESH.as_secs() {
+                    *used = now;
+                    changed = true;
+                }
+            }
+        }
+
+        let ttl = self.subject_reservation_ttl.as_secs();
+        let expired: Vec<String> = self.reservations_used
+            .iter()
+            .filter(|e| !connected.contains(e.key()) && now.saturating_sub(*e.value()) >= ttl)
+            .map(|e| e.key().clone())
+            .collect();
+        if !expired.is_empty() {
+            let token_ports: HashSet<u16> = self.port_reservations.iter().map(|e| e.port).collect();
+            let mut pools = self.alloc.lock().await;
+            for subject in expired.iter() {
+                self.reservations_used.remove(subject);
+                if let Some((_, ports)) = self.reservations.remove(subject) {
+                    // a port may still be reserved by a token or by another subject
+                    for port in ports.iter().filter(|p| !token_ports.contains(p) && !self.reservations.iter().any(|e| e.value().contains(p))) {
+                        for alloc in pools.values_mut() {
+                            alloc.unreserve_port(*port);
+                        }
+                    }
+                    tracing::info!(subject = %subject, ?ports, "subject reservation expired");
+                }
+            }
+            record_port_gauges(&pools);
+            changed = true;
+        }
+        if changed {
+            self.save_reservations();
+        }
+        expired.len()
+    }
+
+    pub async fn release_endpoint(&self, eid: EndpointId) -> Result<(), PortAllocatorError> {
+        let remote_port = self.endpoints_map.get(&eid).ok_or(PortAllocatorError::PortOutOfRange)?.remote_port;
+        // the next endpoint on the port starts accepting again
+        self.draining_ports.remove(&remote_port);
+        let pool = self.endpoint_pools.get(&eid).map(|p| p.value().clone()).unwrap_or_else(|| DEFAULT_PORT_POOL.to_string());
+
+        let mut pools = self.alloc.lock().await;
+        match pools.get_mut(&pool) {
+            Some(alloc) => {
+                alloc.release_port(remote_port)?;
+                if self.reservations.iter().any(|e| e.value().contains(&remote_port)) {
+                    alloc.reserve_port(remote_port);
+                }
+                record_port_gauges(&pools);
+                Ok(())
+            }
+            None => Err(PortAllocatorError::PortOutOfRange),
+        }
+    }
+
+    /// Move the endpoints `eids` sharing the remote `port` to another free port of their pool, within `allowed`
+    /// when it is set, e.g. when another process holds `port`. `port` is released once the new port is taken.
+    pub async fn move_endpoints(&self, rng: &mut impl Rng, eids: &[EndpointId], port: u16, allowed: Option<&[u16]>) -> Result<u16, PortAllocatorError> {
+        let pool = eids
+            .first()
+            .and_then(|eid| self.endpoint_pools.get(eid).map(|p| p.value().clone()))
+            .unwrap_or_else(|| DEFAULT_PORT_POOL.to_string());
+
+        let mut pools = self.alloc.lock().await;
+        let alloc = pools.get_mut(&pool).ok_or(PortAllocatorError::PortOutOfRange)?;
+        let moved = match self.rng.lock().await.as_mut() {
+            Some(seeded) => alloc.move_port(seeded, port, allowed)?,
+            None => alloc.move_port(rng, port, allowed)?,
+        };
+        record_port_gauges(&pools);
+        drop(pools);
+
+        for eid in eids {
+            if let Some(mut endpoint) = self.endpoints_map.get_mut(eid) {
+                endpoint.remote_port = moved;
+            }
+        }
+        Ok(moved)
+    }
+
+    pub fn get_remote_addr_by_endpoint_id(&self, eid: EndpointId) -> Option<impl ToSocketAddrs + std::fmt::Debug + Clone> {
+        let endpoint = self.endpoints_map.get(&eid)?;
+
+        Some(format!("0.0.0.0:{}", endpoint.remote_port))
+    }
+
+    pub fn get_remote_port_by_endpoint_id(&self, eid: EndpointId) -> Option<u16> {
+        self.endpoints_map.get(&eid).map(|e| e.remote_port)
+    }
+
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn record_port_gauges(pools: &HashMap<String, PortAllocator>) {
+    // summed over every pool
+    let allocated: usize = pools.values().map(|alloc| alloc.allocated_ports()).sum();
+    let total: usize = pools.values().map(|alloc| alloc.total_ports()).sum();
+    gauge!("ownserver_server.store.ports_allocated", allocated as f64);
+    gauge!("ownserver_server.store.ports_total", total as f64);
+}
+
+#[cfg(test)]
+mod store_port_pool_test {
+    use super::*;
+    use crate::test_support::get_endpoint_claims_single;
+    use rand::thread_rng;
+
+    fn get_store() -> Store {
+        let mut pools = HashMap::new();
+        pools.insert("free".to_string(), 2000..2010);
+        pools.insert("paid".to_string(), 3000..3010);
+        Store::with_port_pools(1000..1010, pools)
+    }
+
+    #[tokio::test]
+    async fn allocate_from_named_pool() -> Result<(), PortAllocatorError> {
+        let mut rng = thread_rng();
+        let store = get_store();
+
+        let free = store.allocate_endpoints_in_pool(&mut rng, Some("free"), get_endpoint_claims_single()).await?;
+        assert!((2000..2010).contains(&free[0].remote_port));
+
+        let paid = store.allocate_endpoints_in_pool(&mut rng, Some("paid"), get_endpoint_claims_single()).await?;
+        assert!((3000..3010).contains(&paid[0].remote_port));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exclude_ports_of_every_pool() -> Result<(), PortAllocatorError> {
+        let mut rng = thread_rng();
+        let store = Store::with_port_pools(1000..1002, HashMap::from([("paid".to_string(), 3000..3002)]))
+            .with_excluded_ports(&[1000, 3001])?;
+
+        for _ in 0..2 {
+            let endpoints = store.allocate_endpoints_in_pool(&mut rng, None, get_endpoint_claims_single()).await?;
+            assert_eq!(endpoints[0].remote_port, 1001);
+            store.release_endpoint(endpoints[0].id).await?;
+        }
+        let paid = store.allocate_endpoints_in_pool(&mut rng, Some("paid"), get_endpoint_claims_single()).await?;
+        assert_eq!(paid[0].remote_port, 3000);
+
+        let err = get_store().with_excluded_ports(&[5000]).err().unwrap();
+        assert_eq!(err, PortAllocatorError::ExcludedPortOutOfRange(5000));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_default_pool() -> Result<(), PortAllocatorError> {
+        let mut rng = thread_rng();
+        let store = get_store();
+
+        let unknown = store.allocate_endpoints_in_pool(&mut rng, Some("unknown"), get_endpoint_claims_single()).await?;
+        assert!((1000..1010).contains(&unknown[0].remote_port));
+
+        let none = store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await?;
+        assert!((1000..1010).contains(&none[0].remote_port));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn release_endpoint_to_its_pool() -> Result<(), PortAllocatorError> {
+        let mut rng = thread_rng();
+        let mut pools = HashMap::new();
+        pools.insert("paid".to_string(), 3000..3001);
+        let store = Store::with_port_pools(1000..1010, pools);
+
+        let paid = store.allocate_endpoints_in_pool(&mut rng, Some("paid"), get_endpoint_claims_single()).await?;
+        assert_eq!(paid[0].remote_port, 3000);
+        store.release_endpoint(paid[0].id).await?;
+
+        let paid = store.allocate_endpoints_in_pool(&mut rng, Some("paid"), get_endpoint_claims_single()).await?;
+        assert_eq!(paid[0].remote_port, 3000);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_client_health_test {
+    use super::*;
+    use std::sync::Arc;
+    use futures::channel::mpsc::UnboundedReceiver;
+    use metrics_util::debugging::{DebugValue, Snapshotter};
+    use warp::ws::Message;
+    use crate::{client::ClientOptions, test_support::{client_with, install_debugging_recorder}};
+
+    fn client(store: Arc<Store>, subject: &str) -> (Client, UnboundedReceiver<Message>) {
+        client_with_labels(store, subject, HashMap::new())
+    }
+
+    fn client_with_labels(store: Arc<Store>, subject: &str, labels: HashMap<String, String>) -> (Client, UnboundedReceiver<Message>) {
+        let options = ClientOptions { subject: Some(subject.to_string()), labels, ..Default::default() };
+        client_with(store, Vec::new(), options)
+    }
+
+    fn health_gauge(labels: &[(&str, &str)]) -> Option<f64> {
+        Snapshotter::current_thread_snapshot()?.into_vec().into_iter()
+            .find(|(key, ..)| {
+                let key_labels: Vec<(&str, &str)> = key.key().labels().map(|label| (label.key(), label.value())).collect();
+                key.key().name() == "ownserver_server.client.health" && key_labels == labels
+            })
+            .and_then(|(.., value)| match value {
+                DebugValue::Gauge(v) => Some(v.into_inner()),
+                _ => None,
+            })
+    }
+
+    async fn health(store: &Store, client_id: ClientId) -> u8 {
+        store.client_statuses().await.into_iter().find(|status| status.client_id == client_id).expect("client is not connected").health
+    }
+
+    #[tokio::test]
+    async fn lower_health_of_degraded_client() -> Result<(), Box<dyn std::error::Error>> {
+        install_debugging_recorder();
+        let store: Arc<Store> = Default::default();
+        let (alice, alice_rx) = client(store.clone(), "alice");
+        let alice_id = alice.client_id;
+        store.add_client(alice).await;
+        assert_eq!(health(&store, alice_id).await, 100);
+        store.cleanup().await;
+        assert_eq!(health_gauge(&[]), Some(100.0));
+
+        // a slow heartbeat
+        let nonce = store.clients.write().await.get_mut(&alice_id).unwrap().heartbeat_mut().ping();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        store.ack_heartbeat(alice_id, nonce).await.expect("heartbeat is not outstanding");
+        let slow = health(&store, alice_id).await;
+        assert!(slow < 100, "health is {}", slow);
+        store.cleanup().await;
+        assert_eq!(health_gauge(&[]), Some(slow as f64));
+
+        // sends fail once the client stops reading
+        drop(alice_rx);
+        for _ in 0..10 {
+            if store.send_to_client(alice_id, ControlPacketV2::Ping).await.is_err() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        let failing = health(&store, alice_id).await;
+        assert!(failing < slow, "health is {}", failing);
+
+        // the same subject connects again
+        let (again, _again_rx) = client(store.clone(), "alice");
+        let again_id = again.client_id;
+        store.add_client(again).await;
+        assert_eq!(health(&store, again_id).await, 90);
+        assert!(health(&store, alice_id).await < failing);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_lowest_health_per_metric_labels() -> Result<(), Box<dyn std::error::Error>> {
+        install_debugging_recorder();
+        let store = Arc::new(Store::default().with_metric_labels(vec!["plan".to_string()])?);
+        let mut clients = Vec::new();
+        for (subject, plan) in [("alice", "pro"), ("bob", "pro"), ("carol", "free")] {
+            let labels = HashMap::from([("plan".to_string(), plan.to_string())]);
+            let (client, rx) = client_with_labels(store.clone(), subject, labels);
+            clients.push((client.client_id, rx));
+            store.add_client(client).await;
+        }
+        let alice_id = clients[0].0;
+        let nonce = store.clients.write().await.get_mut(&alice_id).unwrap().heartbeat_mut().ping();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        store.ack_heartbeat(alice_id, nonce).await.expect("heartbeat is not outstanding");
+
+        store.cleanup().await;
+        let slow = health(&store, alice_id).await as f64;
+        assert_eq!(health_gauge(&[("plan", "pro")]), Some(slow));
+        assert_eq!(health_gauge(&[("plan", "free")]), Some(100.0));
+        let per_client = Snapshotter::current_thread_snapshot().expect("no metrics recorded").into_vec().into_iter()
+            .any(|(key, ..)| key.key().name() == "ownserver_server.client.health" && key.key().labels().any(|label| label.key() == "client_id"));
+        assert!(!per_client);
+
+        // the series of a gone client does not keep its health
+        store.disable_client(alice_id).await;
+        store.cleanup().await;
+        assert_eq!(health_gauge(&[("plan", "pro")]), Some(100.0));
+        for (client_id, _) in &clients {
+            store.disable_client(*client_id).await;
+        }
+        store.cleanup().await;
+        assert_eq!(health_gauge(&[("plan", "free")]), Some(100.0));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_client_id_metric_label() {
+        let err = Store::default().with_metric_labels(vec!["plan".to_string(), "client_id".to_string()]).unwrap_err();
+        assert!(matches!(err, ProxyServerError::InvalidConfig(_)));
+    }
+}
+
+#[cfg(test)]
+mod store_state_file_test {
+    use super::*;
+    use crate::test_support::get_endpoint_claims_single;
+    use rand::thread_rng;
+
+    #[tokio::test]
+    async fn keep_reserved_ports_across_restart() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rng = thread_rng();
+        let path = std::env::temp_dir().join(format!("ownserver-store-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = Store::new(1000..1003).with_state_file(path.clone());
+        let alice = store.allocate_endpoints_for(&mut rng, None, Some("alice"), get_endpoint_claims_single()).await?;
+        let reserved = alice[0].remote_port;
+        drop(store);
+
+        // wait for the debounced write
+        let mut saved = false;
+        for _ in 0..50 {
+            if state::load_reservations(&path).get("alice") == Some(&vec![reserved]) {
+                saved = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(saved);
+
+        let store = Store::new(1000..1003).with_state_file(path.clone());
+        for _ in 0..2 {
+            let other = store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await?;
+            assert_ne!(other[0].remote_port, reserved);
+        }
+        assert!(store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await.is_err());
+
+        let alice = store.allocate_endpoints_for(&mut rng, None, Some("alice"), get_endpoint_claims_single()).await?;
+        assert_eq!(alice[0].remote_port, reserved);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn free_reserved_ports_of_unused_subjects() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rng = thread_rng();
+        let path = std::env::temp_dir().join(format!("ownserver-store-expired-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = Store::new(1000..1001).with_state_file(path.clone());
+        let alice = store.allocate_endpoints_for(&mut rng, None, Some("alice"), get_endpoint_claims_single()).await?;
+        store.release_endpoint(alice[0].id).await?;
+        // used just now
+        assert_eq!(store.expire_subject_reservations().await, 0);
+        assert!(store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await.is_err());
+
+        let store = store.with_subject_reservation_ttl(Duration::ZERO);
+        assert_eq!(store.expire_subject_reservations().await, 1);
+        let other = store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await?;
+        assert_eq!(other[0].remote_port, alice[0].remote_port);
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_duplicate_subject_test {
+    use super::*;
+    use std::sync::Arc;
+    use crate::test_support::{client, next_packet};
+
+    #[tokio::test]
+    async fn reject_second_client_with_same_subject() {
+        let store: Arc<Store> = Default::default();
+        let (first, _first_rx) = client(store.clone());
+        let first_id = first.client_id;
+        assert_eq!(store.claim_subject("alice", first_id, DuplicatePolicy::Reject).await, Ok(()));
+        store.add_client(first).await;
+
+        let second_id = ClientId::new();
+        assert_eq!(store.claim_subject("alice", second_id, DuplicatePolicy::Reject).await, Err(first_id));
+
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 1);
+        assert!(store.send_to_client(first_id, ControlPacketV2::Ping).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn replace_client_with_same_subject() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let (first, mut first_rx) = client(store.clone());
+        let first_id = first.client_id;
+        store.claim_subject("alice", first_id, DuplicatePolicy::Replace).await.unwrap();
+        store.add_client(first).await;
+
+        let (second, _second_rx) = client(store.clone());
+        let second_id = second.client_id;
+        store.claim_subject("alice", second_id, DuplicatePolicy::Replace).await.unwrap();
+        store.add_client(second).await;
+
+        assert_eq!(next_packet(&mut first_rx).await?, ControlPacketV2::Disconnect(CloseReason::AlreadyConnected));
+
+        store.cleanup().await;
+        assert_eq!(store.len_clients().await, 1);
+        assert!(store.send_to_client(first_id, ControlPacketV2::Ping).await.is_err());
+        assert!(store.send_to_client(second_id, ControlPacketV2::Ping).await.is_ok());
+
+        // the replaced client no longer blocks the subject
+        assert_eq!(store.claim_subject("alice", ClientId::new(), DuplicatePolicy::Reject).await, Err(second_id));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_open_latency_test {
+    use super::*;
+    use std::sync::Arc;
+    use crate::test_support::add_udp_stream;
+
+    #[tokio::test]
+    async fn measure_latency_until_client_acknowledges() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let stream_id = add_udp_stream(&store, ClientId::new(), "127.0.0.1:40000".parse()?).await?;
+
+        // slow client
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let latency = store.ack_remote(stream_id).expect("stream is not opening");
+        assert!(latency >= Duration::from_millis(50));
+        assert_eq!(store.ack_remote(stream_id), None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_rate_limit_test {
+    use super::*;
+
+    #[test]
+    fn drop_remote_connections_over_the_rate_limit() {
+        let store = Store::default().with_rate_limiter(ConnectionRateLimiter::new(1.0, 5));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let accepted = (0..20).filter(|_| store.allow_remote_connection(ip)).count();
+        assert_eq!(accepted, 5);
+        assert!(store.allow_remote_connection("192.0.2.2".parse().unwrap()));
+
+        let store = Store::default();
+        assert!((0..20).all(|_| store.allow_remote_connection(ip)));
+    }
+}
+
+#[cfg(test)]
+mod store_shutdown_test {
+    use super::*;
+    use std::sync::Arc;
+    use rand::thread_rng;
+    use crate::test_support::{add_udp_stream, client_with, get_endpoint_claims_single, next_packet};
+
+    #[tokio::test]
+    async fn release_all_ports_and_clear_maps() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rng = thread_rng();
+        let store = Arc::new(Store::new(1000..1002));
+        let endpoints = store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await?;
+        store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await?;
+        assert!(store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await.is_err());
+
+        let (client, mut rx) = client_with(store.clone(), endpoints.clone(), Default::default());
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let peer_addr: SocketAddr = "127.0.0.1:40000".parse()?;
+        let stream_id = add_udp_stream(&store, client_id, peer_addr).await?;
+
+        store.shutdown().await;
+
+        assert_eq!(next_packet(&mut rx).await?, ControlPacketV2::End(stream_id));
+
+        assert_eq!(store.len_clients().await, 0);
+        assert_eq!(store.len_streams().await, 0);
+        assert_eq!(store.find_stream_id_by_addr(&peer_addr).await, None);
+        assert!(store.get_remote_port_by_endpoint_id(endpoints[0].id).is_none());
+
+        // every port is free again
+        store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await?;
+        store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_deterministic_allocation_test {
+    use super::*;
+    use crate::test_support::get_endpoint_claims_single;
+    use rand::{thread_rng, SeedableRng};
+
+    async fn allocate_ports(store: &Store, n: usize) -> Result<Vec<u16>, PortAllocatorError> {
+        let mut rng = thread_rng();
+        let mut ports = Vec::new();
+        for _ in 0..n {
+            let endpoints = store.allocate_endpoints(&mut rng, get_endpoint_claims_single()).await?;
+            ports.push(endpoints[0].remote_port);
+        }
+        Ok(ports)
+    }
+
+    #[tokio::test]
+    async fn allocate_sequentially_with_deterministic_ports() -> Result<(), PortAllocatorError> {
+        let store = Store::new(1000..1010).with_deterministic_ports();
+        assert_eq!(allocate_ports(&store, 3).await?, vec![1000, 1001, 1002]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reproduce_allocation_with_seeded_rng() -> Result<(), PortAllocatorError> {
+        let first = Store::new_with_rng(1000..2000, StdRng::seed_from_u64(42));
+        let second = Store::new_with_rng(1000..2000, StdRng::seed_from_u64(42));
+        assert_eq!(allocate_ports(&first, 5).await?, allocate_ports(&second, 5).await?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_addrs_map_test {
+    use super::*;
+    use std::sync::Arc;
+    use crate::test_support::{add_udp_stream, client, next_packet};
+
+    #[tokio::test]
+    async fn evict_least_recently_used_peer() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default().with_addrs_map_capacity(2));
+        let (client, mut rx) = client(store.clone());
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let first: SocketAddr = "127.0.0.1:40000".parse()?;
+        let second: SocketAddr = "127.0.0.1:40001".parse()?;
+        let third: SocketAddr = "127.0.0.1:40002".parse()?;
+        let first_sid = add_udp_stream(&store, client_id, first).await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let second_sid = add_udp_stream(&store, client_id, second).await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // the first peer sends again, so the second one is the least recently used
+        assert_eq!(store.find_stream_id_by_addr(&first).await, Some(first_sid));
+        let third_sid = add_udp_stream(&store, client_id, third).await?;
+
+        assert_eq!(store.addrs_map.len(), 2);
+        assert_eq!(store.find_stream_id_by_addr(&second).await, None);
+        assert_eq!(store.find_stream_id_by_addr(&first).await, Some(first_sid));
+        assert_eq!(store.find_stream_id_by_addr(&third).await, Some(third_sid));
+
+        assert_eq!(next_packet(&mut rx).await?, ControlPacketV2::End(second_sid));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_peers_touched_by_traffic() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default().with_addrs_map_capacity(2));
+        let first: SocketAddr = "127.0.0.1:40000".parse()?;
+        let second: SocketAddr = "127.0.0.1:40001".parse()?;
+        let third: SocketAddr = "127.0.0.1:40002".parse()?;
+        let client_id = ClientId::new();
+        let first_sid = add_udp_stream(&store, client_id, first).await?;
+        add_udp_stream(&store, client_id, second).await?;
+
+        store.touch_addr(&first);
+        add_udp_stream(&store, client_id, third).await?;
+        assert_eq!(store.find_stream_id_by_addr(&second).await, None);
+        assert_eq!(store.find_stream_id_by_addr(&first).await, Some(first_sid));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forget_peers_of_closed_streams_on_cleanup() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let peer_addr: SocketAddr = "127.0.0.1:40000".parse()?;
+        let stream_id = add_udp_stream(&store, ClientId::new(), peer_addr).await?;
+        assert_eq!(store.addrs_map.len(), 1);
+
+        store.disable_remote(stream_id, CloseCause::PeerClosed).await;
+        store.cleanup().await;
+        assert_eq!(store.addrs_map.len(), 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_reconnect_test {
+    use super::*;
+    use std::sync::Arc;
+    use ownserver_lib::Protocol;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+    use crate::{client::ClientOptions, remote::{tcp::RemoteTcp, SocketTimeouts}, test_support::{client_with, next_packet}};
+
+    fn add_endpoint(store: &Store, remote_port: u16) -> Endpoints {
+        let endpoint = Endpoint { id: EndpointId::new(), protocol: Protocol::TCP, local_port: 25565, remote_port };
+        store.endpoints_map.insert(endpoint.id, endpoint.clone());
+        vec![endpoint]
+    }
+
+    #[tokio::test]
+    async fn resume_stream_when_subject_reconnects() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default().with_reconnect_window(Duration::from_secs(5)));
+        let options = ClientOptions { subject: Some("alice".to_string()), ..Default::default() };
+
+        let endpoints = add_endpoint(&store, 10000);
+        let endpoint_id = endpoints[0].id;
+        let (old, _sent) = client_with(store.clone(), endpoints, options.clone());
+        let old_id = old.client_id;
+        store.add_client(old).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, peer_addr) = listener.accept().await?;
+        let remote = RemoteTcp::new(store.clone(), socket, old_id, endpoint_id, SocketTimeouts::default(), false, false);
+        let stream_id = remote.stream_id;
+        store.add_remote(RemoteStream::RemoteTcp(remote), peer_addr).await;
+
+        // the player keeps sending while the client is away
+        store.close_client(old_id, CloseReason::Abnormal).await;
+        store.cleanup().await;
+        assert!(store.has_held_streams("alice"));
+        peer.write_all(b"hello").await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let endpoints = add_endpoint(&store, 10000);
+        let new_endpoint_id = endpoints[0].id;
+        let (new, mut sent) = client_with(store.clone(), endpoints, options);
+        store.add_client(new).await;
+
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Init(stream_id, new_endpoint_id));
+        assert_eq!(next_packet(&mut sent).await?, ControlPacketV2::Data(stream_id, b"hello".to_vec()));
+
+        store.send_to_remote(stream_id, StreamMessage::Data(b"world".to_vec())).await?;
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(2), peer.read_exact(&mut buf)).await??;
+        assert_eq!(&buf, b"world");
+
+        store.cleanup().await;
+        assert_eq!(store.len_streams().await, 1);
+        assert!(!store.has_held_streams("alice"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_held_stream_after_window() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::default().with_reconnect_window(Duration::from_millis(200)));
+        let options = ClientOptions { subject: Some("alice".to_string()), ..Default::default() };
+        let endpoints = add_endpoint(&store, 10000);
+        let endpoint_id = endpoints[0].id;
+        let (client, _sent) = client_with(store.clone(), endpoints, options);
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, peer_addr) = listener.accept().await?;
+        let remote = RemoteTcp::new(store.clone(), socket, client_id, endpoint_id, SocketTimeouts::default(), false, false);
+        store.add_remote(RemoteStream::RemoteTcp(remote), peer_addr).await;
+
+        store.close_client(client_id, CloseReason::Abnormal).await;
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        store.cleanup().await;
+        assert_eq!(store.len_streams().await, 0);
+        assert!(!store.has_held_streams("alice"));
+
+        // the remote connection is closed once the stream is dropped
+        let mut buf = [0; 1];
+        assert_eq!(tokio::time::timeout(Duration::from_secs(2), peer.read(&mut buf)).await??, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_for_reconnect_only_when_client_is_gone() {
+        let store = Arc::new(Store::default().with_reconnect_window(Duration::from_secs(5)));
+        let options = ClientOptions { subject: Some("alice".to_string()), ..Default::default() };
+        let endpoints = add_endpoint(&store, 10000);
+        let (client, _sent) = client_with(store.clone(), endpoints, options);
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        // an error from a live client closes the stream rather than waiting
+        assert!(!store.waits_for_reconnect(client_id).await);
+        store.close_client(client_id, CloseReason::Abnormal).await;
+        assert!(store.waits_for_reconnect(client_id).await);
+        store.cleanup().await;
+        assert!(store.waits_for_reconnect(client_id).await);
+
+        let store = Store::default();
+        assert!(!store.waits_for_reconnect(client_id).await);
+    }
+
+    #[tokio::test]
+    async fn cleanup_only_previous_client_of_subject() {
+        let store = Arc::new(Store::default().with_reconnect_window(Duration::from_secs(5)));
+        let mut client_ids = Vec::new();
+        for (subject, port) in [("alice", 10000), ("bob", 10001)] {
+            let options = ClientOptions { subject: Some(subject.to_string()), ..Default::default() };
+            let endpoints = add_endpoint(&store, port);
+            let (client, _sent) = client_with(store.clone(), endpoints, options);
+            client_ids.push(client.client_id);
+            store.add_client(client).await;
+        }
+        for client_id in &client_ids {
+            store.close_client(*client_id, CloseReason::Abnormal).await;
+        }
+
+        assert_eq!(store.client_spans.len(), 2);
+        assert_eq!(store.cleanup_subject("alice").await, 1);
+        assert_eq!(store.len_clients().await, 1);
+        assert!(store.clients.read().await.contains_key(&client_ids[1]));
+        // the span is dropped with its client
+        assert!(!store.client_spans.contains_key(&client_ids[0]));
+        assert_eq!(store.cleanup().await, 1);
+        assert_eq!(store.len_clients().await, 0);
+        assert!(store.client_spans.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod store_client_streams_test {
+    use super::*;
+    use std::sync::Arc;
+    use crate::test_support::add_udp_stream;
+
+    #[tokio::test]
+    async fn keep_index_in_step_with_streams() -> Result<(), Box<dyn std::error::Error>> {
+        let store: Arc<Store> = Default::default();
+        let alice = ClientId::new();
+        let bob = ClientId::new();
+        let first = add_udp_stream(&store, alice, "127.0.0.1:40000".parse()?).await?;
+        let second = add_udp_stream(&store, alice, "127.0.0.1:40001".parse()?).await?;
+        let third = add_udp_stream(&store, bob, "127.0.0.1:40002".parse()?).await?;
+
+        assert_eq!(store.streams_for_client(alice), HashSet::from([first, second]));
+        assert_eq!(store.streams_for_client(bob), HashSet::from([third]));
+
+        store.disable_remote(first, CloseCause::PeerClosed).await;
+        store.cleanup().await;
+        assert_eq!(store.streams_for_client(alice), HashSet::from([second]));
+
+        // closing every stream of a client leaves no entry behind
+        store.disable_remote_by_client(alice, CloseCause::ClientDisconnected).await;
+        assert!(store.streams_for_client(alice).is_empty());
+        assert!(!store.streams.read().await.contains_key(&second));
+        assert!(store.streams.read().await.get(&third).map(|s| !s.disabled()).unwrap_or(false));
+        assert!(!store.client_streams.contains_key(&alice));
+        assert_eq!(store.streams_for_client(bob), HashSet::from([third]));
+
+        store.shutdown().await;
+        assert!(store.client_streams.is_empty());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_mirror_test {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+    use crate::test_support::{client, next_packet};
+
+    #[tokio::test]
+    async fn mirror_data_sent_to_clients() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let store = Arc::new(Store::default().with_mirror(TrafficMirror::connect(listener.local_addr()?)));
+        let (mut mirror, _) = listener.accept().await?;
+
+        let (client, mut sent) = client(store.clone());
+        let client_id = client.client_id;
+        store.add_client(client).await;
+
+        let stream_id = StreamId::new();
+        store.send_to_client(client_id, ControlPacketV2::Data(stream_id, b"hello".to_vec())).await?;
+        store.send_to_client(client_id, ControlPacketV2::Heartbeat(1)).await?;
+        store.send_to_client(client_id, ControlPacketV2::Data(stream_id, b"world".to_vec())).await?;
+
+        // the client gets every packet as it would without the mirror
+        for expected in [
+            ControlPacketV2::Data(stream_id, b"hello".to_vec()),
+            ControlPacketV2::Heartbeat(1),
+            ControlPacketV2::Data(stream_id, b"world".to_vec()),
+        ] {
+            assert_eq!(next_packet(&mut sent).await?, expected);
+        }
+
+        // and the mirror a copy of the data only
+        let mut copied = [0; 10];
+        tokio::time::timeout(Duration::from_secs(2), mirror.read_exact(&mut copied)).await??;
+        assert_eq!(&copied, b"helloworld");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_inject_test {
+    use super::*;
+    use std::sync::Arc;
+    use ownserver_lib::{EndpointClaim, Protocol};
+    use rand::thread_rng;
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+    use tokio_util::sync::CancellationToken;
+    use crate::test_support::{next_init, serve_tcp_client};
+
+    #[tokio::test]
+    async fn inject_bytes_into_live_stream() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(Store::new(10092..10093));
+        let claims = vec![EndpointClaim { protocol: Protocol::TCP, local_port: 3000, remote_port: 0 }];
+        let endpoints = store.allocate_endpoints(&mut thread_rng(), claims).await?;
+        let port = endpoints[0].remote_port;
+        let ct = CancellationToken::new();
+        let (_, mut sent) = serve_tcp_client(&store, endpoints, &ct).await?;
+
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await?;
+        let (stream_id, _) = next_init(&mut sent).await?;
+
+        store.inject_to_remote(stream_id, b"injected").await?;
+        let mut buf = [0; 8];
+        tokio::time::timeout(Duration::from_secs(2), peer.read_exact(&mut buf)).await??;
+        assert_eq!(&buf, b"injected");
+
+        let unknown = StreamId::new();
+        assert_eq!(store.inject_to_remote(unknown, b"x").await, Err(ClientStreamError::StreamNotAvailable(unknown)));
+        store.disable_remote(stream_id, CloseCause::LocalClosed).await;
+        assert_eq!(store.inject_to_remote(stream_id, b"x").await, Err(ClientStreamError::StreamDisabled(stream_id)));
+        ct.cancel();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_close_cause_test {
+    use super::*;
+    use metrics_util::debugging::{DebugValue, Snapshotter};
+    use crate::test_support::{add_client, add_udp_stream, install_debugging_recorder};
+
+    fn closed_count(cause: CloseCause) -> u64 {
+        let snapshot = match Snapshotter::current_thread_snapshot() {
+            Some(snapshot) => snapshot,
+            None => return 0,
+        };
+        snapshot.into_vec().into_iter()
+            .filter(|(key, ..)| key.key().name() == "ownserver_server.stream.closed")
+            .filter(|(key, ..)| key.key().labels().any(|label| label.key() == "cause" && label.value() == cause.label()))
+            .map(|(.., value)| match value {
+                DebugValue::Counter(n) => n,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn count_each_stream_by_its_first_cause() -> Result<(), Box<dyn std::error::Error>> {
+        // the recorder keeps metrics per thread, the current thread runtime runs every task here
+        install_debugging_recorder();
+        let store: Arc<Store> = Default::default();
+
+        let (alice, _) = add_client(&store).await;
+        add_udp_stream(&store, alice, "127.0.0.1:40000".parse()?).await?;
+        let refused = add_udp_stream(&store, alice, "127.0.0.1:40001".parse()?).await?;
+        store.close_stream_by_addr(&"127.0.0.1:40000".parse()?).await;
+        assert!(store.send_to_remote(refused, StreamMessage::TunnelRefused).await.is_err());
+        // closing an already closed stream again counts nothing
+        store.disable_remote(refused, CloseCause::Error).await;
+
+        let (bob, _) = add_client(&store).await;
+        add_udp_stream(&store, bob, "127.0.0.1:40002".parse()?).await?;
+        add_udp_stream(&store, bob, "127.0.0.1:40003".parse()?).await?;
+        store.close_client(bob, CloseReason::QuotaExceeded).await;
+
+        let (carol, _) = add_client(&store).await;
+        add_udp_stream(&store, carol, "127.0.0.1:40004".parse()?).await?;
+        store.disable_client(carol).await;
+
+        assert_eq!(closed_count(CloseCause::AdminClosed), 1);
+        assert_eq!(closed_count(CloseCause::LocalClosed), 1);
+        assert_eq!(closed_count(CloseCause::QuotaExceeded), 2);
+        assert_eq!(closed_count(CloseCause::ClientDisconnected), 1);
+        assert_eq!(closed_count(CloseCause::Error), 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_port_gauge_test {
+    use super::*;
+    use metrics_util::debugging::{DebugValue, Snapshotter};
+    use rand::thread_rng;
+    use crate::test_support::install_debugging_recorder;
+
+    fn gauge_value(name: &str) -> Option<f64> {
+        Snapshotter::current_thread_snapshot()?.into_vec().into_iter()
+            .find(|(key, ..)| key.key().name() == name)
+            .and_then(|(.., value)| match value {
+                DebugValue::Gauge(v) => Some(v.into_inner()),
+                _ => None,
+            })
+    }
+
+    #[tokio::test]
+    async fn count_allocated_ports_of_every_pool() -> Result<(), Box<dyn std::error::Error>> {
+        install_debugging_recorder();
+        let mut rng = thread_rng();
+        let mut pools = HashMap::new();
+        pools.insert("paid".to_string(), 3000..3005);
+        let store = Store::with_port_pools(1000..1010, pools).with_excluded_ports(&[1009])?;
+        assert_eq!(gauge_value("ownserver_server.store.ports_total"), Some(14.0));
+        assert_eq!(gauge_value("ownserver_server.store.ports_allocated"), Some(0.0));
+
+        let claims = |local_port| vec![ownserver_lib::EndpointClaim { protocol: ownserver_lib::Protocol::TCP, local_port, remote_port: 0 }];
+        let endpoints = store.allocate_endpoints(&mut rng, claims(25565)).await?;
+        store.allocate_endpoints_in_pool(&mut rng, Some("paid"), claims(25565)).await?;
+        store.allocate_port(&mut rng).await?;
+        assert_eq!(gauge_value("ownserver_server.store.ports_allocated"), Some(3.0));
+
+        store.release_endpoint(endpoints[0].id).await?;
+        assert_eq!(gauge_value("ownserver_server.store.ports_allocated"), Some(2.0));
+        assert_eq!(gauge_value("ownserver_server.store.ports_total"), Some(14.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_gauges_when_port_reservations_expire() -> Result<(), Box<dyn std::error::Error>> {
+        install_debugging_recorder();
+        let store = Store::new(1000..1010).with_deterministic_ports();
+        store.allocate_port(&mut thread_rng()).await?;
+        store.reserve_port(1005, Duration::ZERO).await?;
+        // stale until the pools change again
+        gauge!("ownserver_server.store.ports_allocated", -1.0);
+        gauge!("ownserver_server.store.ports_total", -1.0);
+
+        assert_eq!(store.expire_port_reservations().await, 1);
+        assert_eq!(gauge_value("ownserver_server.store.ports_allocated"), Some(1.0));
+        assert_eq!(gauge_value("ownserver_server.store.ports_total"), Some(10.0));
+        Ok(())
+    }
+}
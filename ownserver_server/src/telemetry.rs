@@ -0,0 +1,174 @@
+use metrics::{
+    describe_counter, describe_gauge, describe_histogram, register_counter, register_gauge,
+    register_histogram, Unit,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MetricInfo {
+    pub name: &'static str,
+    pub kind: MetricKind,
+    pub unit: Unit,
+    /// Labeled metrics are only described, they come into being with their first labels.
+    pub labeled: bool,
+    pub description: &'static str,
+}
+
+const fn counter(name: &'static str, description: &'static str) -> MetricInfo {
+    MetricInfo { name, kind: MetricKind::Counter, unit: Unit::Count, labeled: false, description }
+}
+
+const fn labeled_counter(name: &'static str, unit: Unit, description: &'static str) -> MetricInfo {
+    MetricInfo { name, kind: MetricKind::Counter, unit, labeled: true, description }
+}
+
+const fn gauge(name: &'static str, description: &'static str) -> MetricInfo {
+    MetricInfo { name, kind: MetricKind::Gauge, unit: Unit::Count, labeled: false, description }
+}
+
+const fn labeled_gauge(name: &'static str, description: &'static str) -> MetricInfo {
+    MetricInfo { name, kind: MetricKind::Gauge, unit: Unit::Count, labeled: true, description }
+}
+
+const fn histogram(name: &'static str, unit: Unit, description: &'static str) -> MetricInfo {
+    MetricInfo { name, kind: MetricKind::Histogram, unit, labeled: false, description }
+}
+
+/// Every metric the server emits.
+pub const METRICS: &[MetricInfo] = &[
+    gauge("ownserver_server.store.clients", "The number of Clients at this time."),
+    gauge("ownserver_server.store.streams", "The number of RemoteStreams at this time."),
+    gauge("ownserver_server.store.addrs_map_size", "The number of remote peer addresses remembered at this time."),
+    counter("ownserver_server.store.addrs_map_evicted", "The number of remote peers disconnected because too many peers were remembered."),
+    labeled_counter("ownserver_server.store.bytes_total", Unit::Bytes, "Bytes forwarded per client in either direction, also labeled by the token labels of --metric-labels."),
+    counter("ownserver_server.store.streams_held", "The number of tcp streams held for a client to reconnect."),
+    counter("ownserver_server.store.streams_resumed", "The number of held tcp streams taken over by a reconnected client."),
+    counter("ownserver_server.store.streams_closed_with_client", "The number of open streams closed because their client went away."),
+    gauge("ownserver_server.store.ports_allocated", "The number of remote ports handed out at this time, over every port pool."),
+    gauge("ownserver_server.store.ports_total", "The number of remote ports that can be handed out, over every port pool."),
+    counter("ownserver_server.store.port_exhausted", "The number of clients rejected because no remote port was available."),
+    counter("ownserver_server.store.port_unavailable", "The number of clients rejected because a requested remote port was taken, see --fixed-ports."),
+    counter("ownserver_server.control_server.handle_new_connection", "The number of successfully accepted websocket connections so far."),
+    counter("ownserver_server.control_server.handle_new_connection.bind_error", "The number of handshakes failed because the remote port could not be bound."),
+    counter("ownserver_server.control_server.bind_endpoints.port_in_use", "The number of remote ports held by another process and swapped for another port."),
+    counter("ownserver_server.control_server.handle_new_connection.read_client_hello_error", "The number of handshakes failed reading ClientHello."),
+    counter("ownserver_server.control_server.handle_new_connection.client_hello_too_large", "The number of connections closed because ClientHello was over --max-handshake-size."),
+    counter("ownserver_server.control_server.handle_new_connection.send_server_hello_error", "The number of handshakes failed sending ServerHello."),
+    counter("ownserver_server.control_server.process_client_claims.success", "The number of successful handshakes so far."),
+    counter("ownserver_server.control_server.process_client_claims.service_temporary_unavailable", "The number of handshake error ServiceTemporaryUnavailable so far."),
+    counter("ownserver_server.control_server.process_client_claims.invalid_client_hello", "The number of handshake error InvalidClientHello so far."),
+    counter("ownserver_server.control_server.process_client_claims.reservation_invalid", "The number of clients rejected because their port reservation was unknown or expired."),
+    counter("ownserver_server.control_server.process_client_claims.invalid_jwt", "The number of handshake error InvalidJWT so far."),
+    counter("ownserver_server.control_server.process_client_claims.expired_token", "The number of handshake error ExpiredToken so far."),
+    counter("ownserver_server.control_server.process_client_claims.verifier_unavailable", "The number of handshakes failed because tokens could not be verified."),
+    counter("ownserver_server.control_server.process_client_claims.illegal_host", "The number of handshake error IllegalHost so far."),
+    counter("ownserver_server.control_server.process_client_claims.version_mismatch", "The number of handshake error VersionMismatch so far."),
+    counter("ownserver_server.control_server.process_client_claims.too_many_clients", "The number of clients rejected for reaching the client limit."),
+    counter("ownserver_server.control_server.process_client_claims.already_connected", "The number of clients rejected because the same client is connected."),
+    counter("ownserver_server.control_server.process_client_claims.draining", "The number of clients rejected while the server is draining."),
+    counter("ownserver_server.tls.client_cert_rejected", "The number of TLS handshakes failed because the client certificate was not trusted."),
+    labeled_counter("ownserver_server.compression.bytes_in", Unit::Bytes, "Response body bytes before compression, by coding."),
+    labeled_counter("ownserver_server.compression.bytes_out", Unit::Bytes, "Response body bytes after compression, by coding."),
+    gauge("ownserver_server.compression.ratio", "Compressed bytes over uncompressed bytes of every compressed response so far, lower is better."),
+    counter("ownserver_server.mirror.dropped", "The number of copies not sent to --mirror-addr because it was down or could not keep up."),
+    counter("ownserver_server.audit.dropped", "The number of audit events dropped because the writer could not keep up."),
+    labeled_counter("ownserver_server.client.connected", Unit::Count, "Clients registered, by the token labels of --metric-labels."),
+    counter("ownserver_server.client.quota_exceeded", "The number of clients disconnected for exceeding the traffic quota."),
+    histogram("ownserver_server.client.rtt_ms", Unit::Milliseconds, "Milliseconds until a client answers a heartbeat."),
+    labeled_gauge("ownserver_server.client.health", "Lowest tunnel quality from 0 to 100 among the clients of each label set, lowered by heartbeat rtt, failed sends and reconnects."),
+    counter("ownserver_server.client.data_send_retry", "The number of data packets retried because the client queue was full."),
+    counter("ownserver_server.client.stream_congested", "The number of streams closed because the client queue stayed full."),
+    counter("ownserver_server.client.heartbeat_timeout", "The number of clients disconnected for missing heartbeats."),
+    labeled_counter("ownserver_server.client.packet_rejected", Unit::Count, "The number of client packets dropped because their type is not allowed."),
+    counter("ownserver_server.client.protocol_violation", "The number of clients disconnected for sending too many disallowed packets."),
+    counter("ownserver_server.control.decode_errors", "The number of client packets dropped because they could not be decoded."),
+    counter("ownserver_server.client.session_expired", "The number of clients disconnected at the maximum session duration."),
+    histogram("ownserver_server.remote.open_latency_ms", Unit::Milliseconds, "Milliseconds from accepting a remote connection until the client acknowledges the stream."),
+    counter("ownserver_server.remote.ratelimited", "The number of new remote connections dropped by the per source ip rate limit."),
+    counter("ownserver_server.remote.denied", "The number of new remote connections refused by the access list."),
+    counter("ownserver_server.access.reloaded", "The number of times the access list was reloaded."),
+    counter("ownserver_server.access.reload_failed", "The number of access list reloads that failed and kept the previous list."),
+    counter("ownserver_server.remote.tcp.swawn_remote", "How many times tcp::spawn_remote called."),
+    labeled_counter("ownserver_server.stream.closed", Unit::Count, "Streams torn down, by the cause of the first close: peer_closed, local_closed, idle_timeout, quota_exceeded, client_disconnected, admin_closed or error."),
+    counter("ownserver_server.remote.tcp.read_timeout", "The number of remote tcp streams closed by read timeout."),
+    counter("ownserver_server.remote.tcp.write_timeout", "The number of remote tcp streams closed by write timeout."),
+    counter("ownserver_server.remote.tcp.write_error", "The number of remote tcp streams closed by a failed write."),
+    counter("ownserver_server.remote.tcp.banner_error", "The number of remote tcp streams closed because the banner could not be sent."),
+    counter("ownserver_server.remote.tcp.throttled", "The number of remote tcp reads delayed by --max-msg-rate."),
+    labeled_counter("ownserver_server.remote.accept_errors", Unit::Count, "Failed accepts of remote tcp connections by error kind, e.g. `too_many_open_files`."),
+    labeled_counter("ownserver_server.remote.tcp.sniffed", Unit::Count, "Remote tcp connections by the protocol told from their first bytes."),
+    counter("ownserver_server.remote.udp.msg_ratelimited", "Udp datagrams dropped by --max-msg-rate."),
+    labeled_counter("ownserver_server.remote.udp.oversized", Unit::Count, "Udp datagrams dropped for exceeding --max-udp-payload, by the side that sent them."),
+    labeled_counter("ownserver_server.remote.udp.near_limit", Unit::Count, "Udp datagrams of at least 90% of --max-udp-payload, by the side that sent them."),
+    counter("ownserver_server.remote.udp.swawn_remote", "How many times udp::spawn_remote called."),
+];
+
+/// Describe every metric and register the unlabeled ones, so they are exported with HELP text from the start.
+/// Does nothing much when no recorder is installed.
+pub fn init_metrics() {
+    for metric in METRICS {
+        let (name, unit, description) = (metric.name, metric.unit, metric.description);
+        match metric.kind {
+            MetricKind::Counter => describe_counter!(name, unit, description),
+            MetricKind::Gauge => describe_gauge!(name, unit, description),
+            MetricKind::Histogram => describe_histogram!(name, unit, description),
+        }
+        if metric.labeled {
+            continue;
+        }
+        match metric.kind {
+            MetricKind::Counter => {
+                register_counter!(name);
+            }
+            MetricKind::Gauge => {
+                register_gauge!(name);
+            }
+            MetricKind::Histogram => {
+                register_histogram!(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod telemetry_test {
+    use super::*;
+    use std::collections::HashSet;
+    use metrics_util::debugging::Snapshotter;
+    use crate::test_support::install_debugging_recorder;
+
+    #[test]
+    fn register_described_metrics() {
+        // the recorder keeps metrics per thread, each test runs on its own thread
+        install_debugging_recorder();
+        init_metrics();
+
+        let snapshot = Snapshotter::current_thread_snapshot().expect("no recorder installed");
+        let registered: Vec<_> = snapshot.into_vec().into_iter()
+            .map(|(key, unit, description, _)| (key.key().name().to_string(), unit, description.map(|d| d.to_string())))
+            .collect();
+
+        for metric in METRICS.iter().filter(|metric| !metric.labeled) {
+            let found = registered.iter().find(|(name, ..)| name == metric.name);
+            assert_eq!(
+                found,
+                Some(&(metric.name.to_string(), Some(metric.unit), Some(metric.description.to_string()))),
+                "{} is not registered with its description",
+                metric.name,
+            );
+        }
+        assert!(registered.iter().all(|(name, ..)| !METRICS.iter().any(|m| m.labeled && m.name == name)));
+    }
+
+    #[test]
+    fn describe_each_metric_once() {
+        let names: HashSet<_> = METRICS.iter().map(|metric| metric.name).collect();
+        assert_eq!(names.len(), METRICS.len());
+    }
+}
@@ -0,0 +1,167 @@
+//! Fixtures shared by the unit tests.
+use std::{convert::Infallible, error::Error, fmt, io, net::SocketAddr, sync::{Arc, Once}, time::Duration};
+
+use bytes::BytesMut;
+use futures::{channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender}, Sink, StreamExt};
+use metrics_util::debugging::DebuggingRecorder;
+use ownserver_lib::{ClientHelloV2, ClientId, ControlPacketV2, ControlPacketV2Codec, EndpointClaim, EndpointClaims, EndpointId, Endpoints, Protocol, StreamId, CLIENT_HELLO_VERSION};
+use rand::thread_rng;
+use tokio::net::UdpSocket;
+use tokio_util::{codec::{Decoder, Encoder}, sync::CancellationToken};
+use warp::{test::RequestBuilder, ws::Message};
+
+use crate::{admin::AdminTokens, client::ClientOptions, remote::{self, stream::RemoteStream, udp::RemoteUdp, SocketOptions, SocketTimeouts}, Client, Store};
+
+/// Install the per thread `DebuggingRecorder` the metric tests take their snapshots from.
+/// The first call installs it for the whole test binary and fails when another recorder is installed.
+pub fn install_debugging_recorder() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| DebuggingRecorder::per_thread().install().expect("another metrics recorder is installed"));
+}
+
+/// Full admin token of `with_admin_token`.
+pub const ADMIN_TOKEN: &str = "s3cret";
+
+/// `store` with `ADMIN_TOKEN`, which the admin endpoints that change the server need.
+pub fn with_admin_token(store: Store) -> Store {
+    store.with_admin_tokens(AdminTokens::new(Some(ADMIN_TOKEN.to_string()), None))
+}
+
+/// `GET path` authorized by `ADMIN_TOKEN`.
+pub fn admin_get(path: &str) -> RequestBuilder {
+    warp::test::request()
+        .path(path)
+        .header("authorization", format!("Bearer {}", ADMIN_TOKEN))
+}
+
+/// `POST path` authorized by `ADMIN_TOKEN`.
+pub fn admin_post(path: &str) -> RequestBuilder {
+    warp::test::request()
+        .method("POST")
+        .path(path)
+        .header("authorization", format!("Bearer {}", ADMIN_TOKEN))
+}
+
+/// A single tcp claim of local port 25565 for any remote port.
+pub fn get_endpoint_claims_single() -> EndpointClaims {
+    vec![EndpointClaim {
+        protocol: Protocol::TCP,
+        local_port: 25565,
+        remote_port: 0,
+    }]
+}
+
+/// Serialized hello of the current version, with `token` and asking for `endpoint_claims`.
+pub fn client_hello_with_token(token: &str, endpoint_claims: EndpointClaims) -> Vec<u8> {
+    serde_json::to_vec(&ClientHelloV2 {
+        version: CLIENT_HELLO_VERSION,
+        token: token.to_string(),
+        endpoint_claims,
+        capabilities: Vec::new(),
+        reservation: None,
+    })
+    .unwrap_or_default()
+}
+
+/// `packet` as a client sends it.
+pub fn encode(packet: ControlPacketV2) -> Result<Message, Infallible> {
+    let mut bytes = BytesMut::new();
+    ControlPacketV2Codec::new().encode(packet, &mut bytes).unwrap();
+    Ok(Message::binary(bytes.to_vec()))
+}
+
+/// Next packet sent to the client, failing after 2 seconds.
+pub async fn next_packet(sent: &mut UnboundedReceiver<Message>) -> Result<ControlPacketV2, Box<dyn Error>> {
+    let message = tokio::time::timeout(Duration::from_secs(2), sent.next()).await?.expect("client sent nothing");
+    let mut bytes = BytesMut::from(&message.into_bytes()[..]);
+    Ok(ControlPacketV2Codec::new().decode(&mut bytes)?.expect("empty packet"))
+}
+
+/// Stream and endpoint of the `Init` the client is sent next.
+pub async fn next_init(sent: &mut UnboundedReceiver<Message>) -> Result<(StreamId, EndpointId), Box<dyn Error>> {
+    match next_packet(sent).await? {
+        ControlPacketV2::Init(stream_id, endpoint_id) => Ok((stream_id, endpoint_id)),
+        packet => panic!("expected init, got {:?}", packet),
+    }
+}
+
+/// Client without endpoints that never sends anything, with what it is sent.
+pub fn client(store: Arc<Store>) -> (Client, UnboundedReceiver<Message>) {
+    client_with(store, Vec::new(), Default::default())
+}
+
+/// Client of `endpoints` that never sends anything, with what it is sent.
+pub fn client_with(store: Arc<Store>, endpoints: Endpoints, options: ClientOptions) -> (Client, UnboundedReceiver<Message>) {
+    let (sink, sent) = unbounded::<Message>();
+    (client_over(store, endpoints, sink, options), sent)
+}
+
+/// Client of `endpoints` that is sent messages through `sink` and never sends anything.
+pub fn client_over<Si>(store: Arc<Store>, endpoints: Endpoints, sink: Si, options: ClientOptions) -> Client
+where
+    Si: Sink<Message> + Send + 'static,
+    Si::Error: fmt::Debug,
+{
+    let stream = futures::stream::pending::<Result<Message, Infallible>>();
+    Client::with_transport(store, ClientId::new(), endpoints, sink, stream, options)
+}
+
+/// Client of `endpoints` with the sender of what it sends and what it is sent.
+pub fn client_with_incoming(
+    store: Arc<Store>,
+    endpoints: Endpoints,
+    options: ClientOptions,
+) -> (Client, UnboundedSender<Result<Message, Infallible>>, UnboundedReceiver<Message>) {
+    let (sink, sent) = unbounded::<Message>();
+    let (incoming, stream) = unbounded::<Result<Message, Infallible>>();
+    (Client::with_transport(store, ClientId::new(), endpoints, sink, stream, options), incoming, sent)
+}
+
+/// Register a client without endpoints, with what it is sent.
+pub async fn add_client(store: &Arc<Store>) -> (ClientId, UnboundedReceiver<Message>) {
+    let (client, sent) = client(store.clone());
+    let client_id = client.client_id;
+    store.add_client(client).await;
+    (client_id, sent)
+}
+
+/// Register a client of tcp `endpoints` that never sends anything, their remote ports served until `ct` is cancelled.
+pub async fn serve_tcp_client(store: &Arc<Store>, endpoints: Endpoints, ct: &CancellationToken) -> io::Result<(ClientId, UnboundedReceiver<Message>)> {
+    let (client, sent) = client_with(store.clone(), endpoints.clone(), Default::default());
+    let client_id = client.client_id;
+    for endpoint in &endpoints {
+        let listeners = remote::tcp::bind_remote(store.clone(), client_id, endpoint.id, false, remote::DEFAULT_BACKLOG).await?;
+        remote::tcp::serve_remote(store.clone(), listeners, client_id, endpoint.id, SocketTimeouts::default(), SocketOptions::default(), ct.clone());
+    }
+    store.add_client(client).await;
+    Ok((client_id, sent))
+}
+
+/// Client registered by `add_tcp_client`.
+pub struct TcpClient {
+    pub client_id: ClientId,
+    pub endpoint_id: EndpointId,
+    /// messages from the client
+    pub incoming: UnboundedSender<Result<Message, Infallible>>,
+    /// messages to the client
+    pub sent: UnboundedReceiver<Message>,
+}
+
+/// Register a client of the endpoint allocated for `get_endpoint_claims_single`, its remote port not served.
+pub async fn add_tcp_client(store: &Arc<Store>, options: ClientOptions) -> Result<TcpClient, Box<dyn Error>> {
+    let endpoints = store.allocate_endpoints_for(&mut thread_rng(), None, None, get_endpoint_claims_single()).await?;
+    let endpoint_id = endpoints[0].id;
+    let (client, incoming, sent) = client_with_incoming(store.clone(), endpoints, options);
+    let client_id = client.client_id;
+    store.add_client(client).await;
+    Ok(TcpClient { client_id, endpoint_id, incoming, sent })
+}
+
+/// Register a udp stream of `client_id` from `peer_addr`, of a new endpoint.
+pub async fn add_udp_stream(store: &Arc<Store>, client_id: ClientId, peer_addr: SocketAddr) -> io::Result<StreamId> {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let remote = RemoteUdp::new(store.clone(), socket, peer_addr, client_id, EndpointId::new());
+    let stream_id = remote.stream_id;
+    store.add_remote(RemoteStream::RemoteUdp(remote), peer_addr).await;
+    Ok(stream_id)
+}
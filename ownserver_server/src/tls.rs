@@ -0,0 +1,230 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use hyper::{server::conn::Http, service::{service_fn, Service}, Body, Request, Response};
+use metrics::increment_counter;
+use ownserver::tls::{load_certs, load_private_key, load_roots};
+pub use ownserver::tls::TlsError;
+use rustls::server::{AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier};
+use rustls::{Certificate, DistinguishedName, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::accept::accept_with_backoff;
+
+/// Who a trusted client certificate names: the common name and, when present, the organizational unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// replaces the token subject
+    pub subject: String,
+    /// replaces the token tier
+    pub tier: Option<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let subject = cert.subject();
+        let common_name = subject.iter_common_name().next()?.as_str().ok()?;
+        let unit = subject.iter_organizational_unit().next().and_then(|ou| ou.as_str().ok());
+        Some(Self {
+            subject: common_name.to_string(),
+            tier: unit.map(str::to_string),
+        })
+    }
+}
+
+/// How long a client may take to finish the TLS handshake before we drop it.
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Inserted into every request served by `serve`.
+#[derive(Debug, Clone)]
+pub struct TlsPeer {
+    pub addr: SocketAddr,
+    /// `None` unless the server requires client certificates
+    pub identity: Option<ClientIdentity>,
+}
+
+/// Serve with the certificate and key in the pem files `cert` and `key`.
+/// With `client_ca`, clients must present a certificate signed by a CA of that pem file.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Arc<ServerConfig>, TlsError> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(path) => {
+            let verifier = LoggingClientCertVerifier {
+                inner: AllowAnyAuthenticatedClient::new(load_roots(path)?),
+            };
+            builder.with_client_cert_verifier(Arc::new(verifier))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_private_key(key)?)?;
+    Ok(Arc::new(config))
+}
+
+// rustls only tells the client why it was turned away
+struct LoggingClientCertVerifier {
+    inner: AllowAnyAuthenticatedClient,
+}
+
+impl ClientCertVerifier for LoggingClientCertVerifier {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.inner.verify_client_cert(end_entity, intermediates, now).map_err(|e| {
+            let subject = X509Certificate::from_der(&end_entity.0)
+                .map(|(_, cert)| cert.subject().to_string())
+                .unwrap_or_else(|_| "<unparsable>".to_string());
+            tracing::warn!(%subject, "rejected client certificate: {}", e);
+            increment_counter!("ownserver_server.tls.client_cert_rejected");
+            e
+        })
+    }
+}
+
+/// Accept TLS connections on `addr` and serve them with `service`, e.g. `warp::service(routes)`.
+pub async fn serve<S>(service: S, addr: SocketAddr, config: Arc<ServerConfig>)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("failed to bind tls control port: {:?}", e);
+            return;
+        }
+    };
+    let acceptor = TlsAcceptor::from(config);
+
+    loop {
+        let (socket, peer_addr) = accept_with_backoff(&listener, |e| tracing::error!("failed to accept tls connection: {:?}", e)).await;
+
+        tokio::spawn(
+            serve_connection(acceptor.clone(), socket, peer_addr, service.clone())
+                .instrument(tracing::info_span!("handle_tls")),
+        );
+    }
+}
+
+/// Handshake on one accepted connection and serve it with `service`, also used by `listener::serve`.
+pub(crate) async fn serve_connection<S, T>(acceptor: TlsAcceptor, socket: T, peer_addr: SocketAddr, mut service: S)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            tracing::info!(%peer_addr, "tls handshake failed: {:?}", e);
+            return;
+        }
+        Err(_) => {
+            tracing::info!(%peer_addr, "tls handshake timed out");
+            return;
+        }
+    };
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| ClientIdentity::from_der(&cert.0));
+    let peer = TlsPeer { addr: peer_addr, identity };
+
+    let service = service_fn(move |mut request: Request<Body>| {
+        request.extensions_mut().insert(peer.clone());
+        service.call(request)
+    });
+    if let Err(e) = Http::new().serve_connection(stream, service).with_upgrades().await {
+        tracing::info!(%peer_addr, "tls connection closed: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tls_test {
+    use super::*;
+    use ownserver::tls::{client_config, TlsOptions};
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use warp::Filter;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    async fn whoami(port: u16, cert: &str, key: &str) -> std::io::Result<String> {
+        let config = client_config(&TlsOptions {
+            server_ca: Some(testdata("mtls_ca.pem")),
+            client_cert: Some((testdata(cert), testdata(key))),
+        })
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let socket = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut stream = tokio_rustls::TlsConnector::from(config)
+            .connect(rustls::ServerName::try_from("localhost").expect("valid name"), socket)
+            .await?;
+
+        stream.write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[test]
+    fn read_identity_from_certificate() -> Result<(), TlsError> {
+        let cert = load_certs(&testdata("mtls_client.pem"))?.remove(0);
+        assert_eq!(
+            ClientIdentity::from_der(&cert.0),
+            Some(ClientIdentity { subject: "alice".to_string(), tier: Some("paid".to_string()) })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_only_trusted_client_certificates() -> Result<(), Box<dyn std::error::Error>> {
+        let port = 10064;
+        let config = server_config(&testdata("mtls_server.pem"), &testdata("mtls_server_key.pem"), Some(&testdata("mtls_ca.pem")))?;
+        let routes = warp::path("whoami").and(warp::ext::optional::<TlsPeer>()).map(|peer: Option<TlsPeer>| {
+            peer.and_then(|peer| peer.identity).map(|identity| identity.subject).unwrap_or_default()
+        });
+        tokio::spawn(serve(warp::service(routes), ([127, 0, 0, 1], port).into(), config));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let response = whoami(port, "mtls_client.pem", "mtls_client_key.pem").await?;
+        assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+        assert!(response.ends_with("alice"), "unexpected response: {}", response);
+
+        // the server aborts the handshake once it has seen the certificate
+        assert!(whoami(port, "mtls_untrusted.pem", "mtls_untrusted_key.pem").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drop_clients_stalling_the_handshake() -> Result<(), Box<dyn std::error::Error>> {
+        let config = server_config(&testdata("mtls_server.pem"), &testdata("mtls_server_key.pem"), None)?;
+        let routes = warp::any().map(|| "");
+        let (mut client, server) = tokio::io::duplex(1024);
+        let started = tokio::time::Instant::now();
+        serve_connection(TlsAcceptor::from(config), server, ([127, 0, 0, 1], 0).into(), warp::service(routes)).await;
+        assert!(started.elapsed() >= TLS_HANDSHAKE_TIMEOUT);
+
+        // the server side is gone
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await?, 0);
+        Ok(())
+    }
+}
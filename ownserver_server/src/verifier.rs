@@ -0,0 +1,577 @@
+use std::{collections::HashMap, fmt, time::{Duration, Instant}};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use jsonwebtoken::{decode, decode_header, errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use ownserver_auth::decode_jwt;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+/// What a verified token allows its client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenClaims {
+    /// recorded in the audit log and used to keep remote ports across reconnects
+    pub subject: Option<String>,
+    /// port pool the remote ports are allocated from, the default pool when `None`
+    pub tier: Option<String>,
+    /// unix time in seconds the token expires at, the handshake is rejected afterwards
+    pub expires_at: Option<u64>,
+    /// remote ports the client may be given, any port of its pool when `None`
+    pub allowed_ports: Option<Vec<u16>>,
+    /// metadata of the tunnel e.g.) account id and plan, recorded in the audit log and the admin listing
+    pub labels: HashMap<String, String>,
+}
+
+impl TokenClaims {
+    /// `now` is unix time in seconds.
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum VerifyError {
+    #[error("Token is invalid.")]
+    Invalid,
+
+    #[error("Token has expired.")]
+    Expired,
+
+    #[error("Token was issued for a different host.")]
+    IllegalHost,
+
+    #[error("Token could not be verified: {0}")]
+    Unavailable(String),
+}
+
+/// Checks the token of every new client. Set by `Store::with_token_verifier`, `HmacVerifier` otherwise.
+#[async_trait]
+pub trait TokenVerifier: fmt::Debug + Send + Sync {
+    async fn verify(&self, token: &str) -> Result<TokenClaims, VerifyError>;
+}
+
+/// Labels of a token beyond this many are dropped, see `bounded_labels`.
+pub const MAX_LABELS: usize = 8;
+const MAX_LABEL_KEY_LEN: usize = 32;
+const MAX_LABEL_VALUE_LEN: usize = 64;
+
+/// Accepts jwts signed with `secret` and issued for `host`.
+/// `sub`, `tier`, `exp`, `ports` and `labels` claims of the payload are read into `TokenClaims`.
+#[derive(Debug, Clone)]
+pub struct HmacVerifier {
+    secret: String,
+    host: String,
+}
+
+impl HmacVerifier {
+    pub fn new(secret: impl Into<String>, host: impl Into<String>) -> Self {
+        Self { secret: secret.into(), host: host.into() }
+    }
+}
+
+#[async_trait]
+impl TokenVerifier for HmacVerifier {
+    async fn verify(&self, token: &str) -> Result<TokenClaims, VerifyError> {
+        match decode_jwt(&self.secret, token).map(|c| c.host == self.host) {
+            Ok(true) => {}
+            Ok(false) => return Err(VerifyError::IllegalHost),
+            Err(e) => {
+                tracing::info!("failed to parse client jwt: {:?}", e);
+                return Err(VerifyError::Invalid);
+            }
+        }
+
+        let ExtraClaims { tier, sub, exp, ports, labels } = read_extra_claims(token).unwrap_or_default();
+        Ok(TokenClaims {
+            subject: sub,
+            tier,
+            expires_at: exp,
+            allowed_ports: ports,
+            labels: bounded_labels(labels.unwrap_or_default()),
+        })
+    }
+}
+
+/// Accepts every token, even an empty one, for local development. Never use it on a public server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoAuthVerifier;
+
+#[async_trait]
+impl TokenVerifier for NoAuthVerifier {
+    async fn verify(&self, _token: &str) -> Result<TokenClaims, VerifyError> {
+        Ok(TokenClaims::default())
+    }
+}
+
+/// A fetched JWKS is used for this long before it is fetched again.
+pub const JWKS_MAX_AGE: Duration = Duration::from_secs(3600);
+/// A token of an unknown `kid` fetches the JWKS again at most this often.
+pub const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts RS256 and ES256 jwts signed by a public key, checking `exp`, `nbf` and, when set, `aud`.
+/// `sub`, `tier`, `ports` and `labels` claims are read into `TokenClaims` like `HmacVerifier` does.
+pub struct JwtVerifier {
+    keys: Keys,
+    audience: Option<String>,
+}
+
+enum Keys {
+    Static(DecodingKey<'static>, Algorithm),
+    Jwks {
+        url: String,
+        client: reqwest::Client,
+        cache: RwLock<JwksCache>,
+        min_refresh_interval: Duration,
+    },
+}
+
+#[derive(Default)]
+struct JwksCache {
+    keys: HashMap<String, (DecodingKey<'static>, Algorithm)>,
+    fetched_at: Option<Instant>,
+}
+
+impl JwksCache {
+    fn is_stale(&self) -> bool {
+        self.fetched_at.map(|at| at.elapsed() >= JWKS_MAX_AGE).unwrap_or(true)
+    }
+
+    fn may_refresh(&self, min_refresh_interval: Duration) -> bool {
+        self.fetched_at.map(|at| at.elapsed() >= min_refresh_interval).unwrap_or(true)
+    }
+}
+
+impl JwtVerifier {
+    /// Verify with a pem encoded RSA or P-256 public key, whatever `kid` the token names.
+    pub fn with_public_key(pem: &[u8]) -> Result<Self, jsonwebtoken::errors::Error> {
+        let keys = match DecodingKey::from_rsa_pem(pem) {
+            Ok(key) => Keys::Static(key.into_static(), Algorithm::RS256),
+            Err(_) => Keys::Static(DecodingKey::from_ec_pem(pem)?.into_static(), Algorithm::ES256),
+        };
+        Ok(Self { keys, audience: None })
+    }
+
+    /// Verify with the key of the token's `kid` in the JWKS at `url`.
+    /// The set is fetched on first use and again when a token names a `kid` it doesn't have, for key rotation.
+    pub fn with_jwks_url(url: impl Into<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(JWKS_FETCH_TIMEOUT)
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new());
+        let keys = Keys::Jwks {
+            url: url.into(),
+            client,
+            cache: RwLock::new(JwksCache::default()),
+            min_refresh_interval: JWKS_MIN_REFRESH_INTERVAL,
+        };
+        Self { keys, audience: None }
+    }
+
+    /// Reject tokens whose `aud` claim doesn't contain `audience`.
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// How often an unknown `kid` may fetch the JWKS again. Does nothing for a static public key.
+    pub fn with_min_refresh_interval(mut self, interval: Duration) -> Self {
+        if let Keys::Jwks { ref mut min_refresh_interval, .. } = self.keys {
+            *min_refresh_interval = interval;
+        }
+        self
+    }
+
+    fn decode(&self, token: &str, key: &DecodingKey, algorithm: Algorithm) -> Result<TokenClaims, VerifyError> {
+        let mut validation = Validation::new(algorithm);
+        validation.validate_nbf = true;
+        if let Some(ref audience) = self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        match decode::<ExtraClaims>(token, key, &validation) {
+            Ok(data) => {
+                let ExtraClaims { tier, sub, exp, ports, labels } = data.claims;
+                Ok(TokenClaims {
+                    subject: sub,
+                    tier,
+                    expires_at: exp,
+                    allowed_ports: ports,
+                    labels: bounded_labels(labels.unwrap_or_default()),
+                })
+            }
+            Err(e) => match e.kind() {
+                ErrorKind::ExpiredSignature => Err(VerifyError::Expired),
+                _ => {
+                    tracing::info!("failed to verify client jwt: {:?}", e);
+                    Err(VerifyError::Invalid)
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Debug for JwtVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys = match self.keys {
+            Keys::Static(_, algorithm) => format!("{:?} public key", algorithm),
+            Keys::Jwks { ref url, .. } => format!("jwks at {}", url),
+        };
+        f.debug_struct("JwtVerifier").field("keys", &keys).field("audience", &self.audience).finish()
+    }
+}
+
+#[async_trait]
+impl TokenVerifier for JwtVerifier {
+    async fn verify(&self, token: &str) -> Result<TokenClaims, VerifyError> {
+        let (url, client, cache, min_refresh_interval) = match self.keys {
+            Keys::Static(ref key, algorithm) => return self.decode(token, key, algorithm),
+            Keys::Jwks { ref url, ref client, ref cache, min_refresh_interval } => (url, client, cache, min_refresh_interval),
+        };
+
+        let kid = match decode_header(token) {
+            Ok(header) => match header.kid {
+                Some(kid) => kid,
+                None => {
+                    tracing::info!("client jwt names no kid");
+                    return Err(VerifyError::Invalid);
+                }
+            },
+            Err(e) => {
+                tracing::info!("failed to parse client jwt: {:?}", e);
+                return Err(VerifyError::Invalid);
+            }
+        };
+
+        {
+            let cache = cache.read().await;
+            if !cache.is_stale() {
+                if let Some((key, algorithm)) = cache.keys.get(&kid) {
+                    return self.decode(token, key, *algorithm);
+                }
+            }
+        }
+
+        let mut cache = cache.write().await;
+        // another token may have refreshed the set while waiting for the lock
+        if cache.is_stale() || (!cache.keys.contains_key(&kid) && cache.may_refresh(min_refresh_interval)) {
+            match fetch_jwks(client, url).await {
+                Ok(keys) => {
+                    tracing::info!("fetched {} keys from jwks {}", keys.len(), url);
+                    cache.keys = keys;
+                }
+                Err(e) => tracing::warn!("failed to fetch jwks {}: {:?}", url, e),
+            }
+            cache.fetched_at = Some(Instant::now());
+        }
+
+        match cache.keys.get(&kid) {
+            Some((key, algorithm)) => self.decode(token, key, *algorithm),
+            None if cache.keys.is_empty() => Err(VerifyError::Unavailable(format!("no keys fetched from jwks {}", url))),
+            None => {
+                tracing::info!("client jwt names unknown kid {}", kid);
+                Err(VerifyError::Invalid)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+impl Jwk {
+    // `None` for keys of other types and keys without a `kid`, no token could pick them
+    fn into_key(self) -> Option<(String, (DecodingKey<'static>, Algorithm))> {
+        let kid = self.kid?;
+        let key = match (self.kty.as_str(), self.crv.as_deref()) {
+            ("RSA", _) => {
+                let (n, e) = (self.n?, self.e?);
+                URL_SAFE_NO_PAD.decode(&n).ok()?;
+                URL_SAFE_NO_PAD.decode(&e).ok()?;
+                (DecodingKey::from_rsa_components(&n, &e).into_static(), Algorithm::RS256)
+            }
+            ("EC", Some("P-256")) => {
+                let (x, y) = (URL_SAFE_NO_PAD.decode(self.x?).ok()?, URL_SAFE_NO_PAD.decode(self.y?).ok()?);
+                if x.len() != 32 || y.len() != 32 {
+                    return None;
+                }
+                // uncompressed point
+                let point: Vec<u8> = [&[0x04][..], &x, &y].concat();
+                (DecodingKey::from_ec_der(&point).into_static(), Algorithm::ES256)
+            }
+            _ => return None,
+        };
+        Some((kid, key))
+    }
+}
+
+async fn fetch_jwks(client: &reqwest::Client, url: &str) -> Result<HashMap<String, (DecodingKey<'static>, Algorithm)>, reqwest::Error> {
+    let jwks: Jwks = client.get(url).send().await?.error_for_status()?.json().await?;
+    Ok(jwks.keys.into_iter().filter_map(Jwk::into_key).collect())
+}
+
+#[derive(Deserialize, Default)]
+struct ExtraClaims {
+    #[serde(default)]
+    tier: Option<String>,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    exp: Option<u64>,
+    #[serde(default)]
+    ports: Option<Vec<u16>>,
+    // values other than strings are dropped rather than failing the other claims
+    #[serde(default)]
+    labels: Option<HashMap<String, serde_json::Value>>,
+}
+
+/// Keep at most `MAX_LABELS` string labels with short `[A-Za-z0-9_]` keys and short values,
+/// so that a token can't blow up log lines or metric series. Keys are kept in sorted order.
+pub fn bounded_labels(labels: HashMap<String, serde_json::Value>) -> HashMap<String, String> {
+    let mut labels: Vec<(String, String)> = labels
+        .into_iter()
+        .filter_map(|(key, value)| match value {
+            serde_json::Value::String(value) => Some((key, value)),
+            _ => None,
+        })
+        .filter(|(key, value)| {
+            !key.is_empty()
+                && key.len() <= MAX_LABEL_KEY_LEN
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && value.len() <= MAX_LABEL_VALUE_LEN
+        })
+        .collect();
+    labels.sort();
+    if labels.len() > MAX_LABELS {
+        tracing::info!("token has {} labels, keep the first {}", labels.len(), MAX_LABELS);
+        labels.truncate(MAX_LABELS);
+    }
+    labels.into_iter().collect()
+}
+
+/// Read optional claims from a jwt whose signature has already been verified.
+fn read_extra_claims(token: &str) -> Option<ExtraClaims> {
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    serde_json::from_slice::<ExtraClaims>(&payload).ok()
+}
+
+#[cfg(test)]
+mod verifier_test {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+    use ownserver_auth::make_jwt;
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex};
+    use warp::Filter;
+
+    fn read_tier(token: &str) -> Option<String> {
+        read_extra_claims(token)?.tier
+    }
+
+    #[test]
+    fn read_tier_from_jwt_payload() {
+        let token = format!("header.{}.signature", URL_SAFE_NO_PAD.encode(r#"{"host":"foohost.test.local","tier":"paid"}"#));
+        assert_eq!(read_tier(&token), Some("paid".to_string()));
+    }
+
+    #[test]
+    fn read_no_tier_from_jwt_payload() {
+        let token = format!("header.{}.signature", URL_SAFE_NO_PAD.encode(r#"{"host":"foohost.test.local"}"#));
+        assert_eq!(read_tier(&token), None);
+        assert_eq!(read_tier("invalid jwt"), None);
+    }
+
+    #[test]
+    fn read_subject_from_jwt_payload() {
+        let token = format!("header.{}.signature", URL_SAFE_NO_PAD.encode(r#"{"host":"foohost.test.local","sub":"alice"}"#));
+        assert_eq!(read_extra_claims(&token).and_then(|c| c.sub), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn read_bounded_labels_from_jwt_payload() {
+        let payload = r#"{"host":"foohost.test.local","tier":"paid","labels":{"account_id":"1234","plan":"pro","seats":3,"bad key":"x"}}"#;
+        let token = format!("header.{}.signature", URL_SAFE_NO_PAD.encode(payload));
+        let claims = read_extra_claims(&token).expect("claims are readable");
+        assert_eq!(claims.tier, Some("paid".to_string()));
+        assert_eq!(
+            bounded_labels(claims.labels.unwrap_or_default()),
+            HashMap::from([("account_id".to_string(), "1234".to_string()), ("plan".to_string(), "pro".to_string())])
+        );
+
+        let many = (0..20).map(|i| (format!("k{:02}", i), serde_json::Value::from("v"))).collect();
+        let labels = bounded_labels(many);
+        assert_eq!(labels.len(), MAX_LABELS);
+        assert!(labels.contains_key("k00") && !labels.contains_key("k19"));
+    }
+
+    #[test]
+    fn read_allowed_ports_from_jwt_payload() {
+        let token = format!("header.{}.signature", URL_SAFE_NO_PAD.encode(r#"{"host":"foohost.test.local","ports":[25565,25566]}"#));
+        assert_eq!(read_extra_claims(&token).and_then(|c| c.ports), Some(vec![25565, 25566]));
+    }
+
+    #[tokio::test]
+    async fn verify_jwt_for_host() -> Result<(), Box<dyn std::error::Error>> {
+        let verifier = HmacVerifier::new("supersecret", "foohost.test.local");
+
+        let token = make_jwt("supersecret", ChronoDuration::minutes(10), "foohost.test.local".to_string())?;
+        let claims = verifier.verify(&token).await?;
+        assert_eq!(claims.tier, None);
+        assert!(claims.expires_at.is_some());
+
+        let token = make_jwt("supersecret", ChronoDuration::minutes(10), "other.test.local".to_string())?;
+        assert_eq!(verifier.verify(&token).await, Err(VerifyError::IllegalHost));
+        assert_eq!(verifier.verify("invalid jwt").await, Err(VerifyError::Invalid));
+        Ok(())
+    }
+
+    #[test]
+    fn expire_at_expires_at() {
+        let claims = TokenClaims { expires_at: Some(1000), ..Default::default() };
+        assert!(!claims.is_expired(999));
+        assert!(claims.is_expired(1000));
+        assert!(!TokenClaims::default().is_expired(u64::MAX));
+    }
+
+    fn unix_now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    fn sign(algorithm: Algorithm, kid: Option<&str>, private_pem: &[u8], claims: serde_json::Value) -> String {
+        let mut header = jsonwebtoken::Header::new(algorithm);
+        header.kid = kid.map(str::to_string);
+        let key = match algorithm {
+            Algorithm::ES256 => jsonwebtoken::EncodingKey::from_ec_pem(private_pem),
+            _ => jsonwebtoken::EncodingKey::from_rsa_pem(private_pem),
+        };
+        jsonwebtoken::encode(&header, &claims, &key.expect("failed to read private key")).expect("failed to sign jwt")
+    }
+
+    const RSA1_PRIVATE: &[u8] = include_bytes!("../testdata/rsa1_private.pem");
+    const RSA2_PRIVATE: &[u8] = include_bytes!("../testdata/rsa2_private.pem");
+    const EC_PRIVATE: &[u8] = include_bytes!("../testdata/ec_private.pem");
+
+    #[tokio::test]
+    async fn verify_valid_tokens_of_static_public_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let claims = serde_json::json!({"sub": "alice", "tier": "paid", "aud": "ownserver", "exp": unix_now() + 600, "nbf": unix_now() - 10});
+
+        let verifier = JwtVerifier::with_public_key(include_bytes!("../testdata/rsa1_public.pem"))?.with_audience("ownserver");
+        let verified = verifier.verify(&sign(Algorithm::RS256, None, RSA1_PRIVATE, claims.clone())).await?;
+        assert_eq!(verified.subject, Some("alice".to_string()));
+        assert_eq!(verified.tier, Some("paid".to_string()));
+
+        let verifier = JwtVerifier::with_public_key(include_bytes!("../testdata/ec_public.pem"))?.with_audience("ownserver");
+        let verified = verifier.verify(&sign(Algorithm::ES256, None, EC_PRIVATE, claims.clone())).await?;
+        assert_eq!(verified.subject, Some("alice".to_string()));
+
+        // signed by another key
+        assert_eq!(verifier.verify(&sign(Algorithm::RS256, None, RSA1_PRIVATE, claims)).await, Err(VerifyError::Invalid));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_expired_and_immature_tokens() -> Result<(), Box<dyn std::error::Error>> {
+        let verifier = JwtVerifier::with_public_key(include_bytes!("../testdata/ec_public.pem"))?;
+
+        let token = sign(Algorithm::ES256, None, EC_PRIVATE, serde_json::json!({"sub": "alice", "exp": unix_now() - 3600}));
+        assert_eq!(verifier.verify(&token).await, Err(VerifyError::Expired));
+
+        let token = sign(Algorithm::ES256, None, EC_PRIVATE, serde_json::json!({"sub": "alice", "exp": unix_now() + 7200, "nbf": unix_now() + 3600}));
+        assert_eq!(verifier.verify(&token).await, Err(VerifyError::Invalid));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_tokens_for_other_audience() -> Result<(), Box<dyn std::error::Error>> {
+        let verifier = JwtVerifier::with_public_key(include_bytes!("../testdata/rsa1_public.pem"))?.with_audience("ownserver");
+
+        let token = sign(Algorithm::RS256, None, RSA1_PRIVATE, serde_json::json!({"sub": "alice", "aud": "someone-else", "exp": unix_now() + 600}));
+        assert_eq!(verifier.verify(&token).await, Err(VerifyError::Invalid));
+
+        let token = sign(Algorithm::RS256, None, RSA1_PRIVATE, serde_json::json!({"sub": "alice", "aud": ["someone-else", "ownserver"], "exp": unix_now() + 600}));
+        assert!(verifier.verify(&token).await.is_ok());
+        Ok(())
+    }
+
+    // serves `keys` as a jwks, counting fetches
+    async fn launch_jwks_server(keys: Arc<Mutex<Vec<serde_json::Value>>>, fetches: Arc<AtomicUsize>) -> String {
+        let route = warp::path!("jwks.json").map(move || {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            warp::reply::json(&serde_json::json!({ "keys": *keys.lock().unwrap() }))
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/jwks.json", addr)
+    }
+
+    #[tokio::test]
+    async fn refresh_jwks_on_unknown_kid() -> Result<(), Box<dyn std::error::Error>> {
+        let jwk = |data: &str| serde_json::from_str::<serde_json::Value>(data).expect("invalid jwk");
+        let keys = Arc::new(Mutex::new(vec![
+            jwk(include_str!("../testdata/rsa1_jwk.json")),
+            jwk(include_str!("../testdata/ec_jwk.json")),
+        ]));
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let url = launch_jwks_server(keys.clone(), fetches.clone()).await;
+        let verifier = JwtVerifier::with_jwks_url(url).with_min_refresh_interval(Duration::ZERO);
+        let claims = serde_json::json!({"sub": "alice", "exp": unix_now() + 600});
+
+        assert!(verifier.verify(&sign(Algorithm::RS256, Some("key-1"), RSA1_PRIVATE, claims.clone())).await.is_ok());
+        assert!(verifier.verify(&sign(Algorithm::ES256, Some("key-ec"), EC_PRIVATE, claims.clone())).await.is_ok());
+        // known kids are served from the cache
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // the issuer rotates to key-2
+        keys.lock().unwrap()[0] = jwk(include_str!("../testdata/rsa2_jwk.json"));
+        assert!(verifier.verify(&sign(Algorithm::RS256, Some("key-2"), RSA2_PRIVATE, claims.clone())).await.is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // a kid missing after refreshing is rejected, as is a token claiming a kid it wasn't signed by
+        assert_eq!(verifier.verify(&sign(Algorithm::RS256, Some("key-3"), RSA1_PRIVATE, claims.clone())).await, Err(VerifyError::Invalid));
+        assert_eq!(verifier.verify(&sign(Algorithm::RS256, Some("key-2"), RSA1_PRIVATE, claims.clone())).await, Err(VerifyError::Invalid));
+        assert_eq!(verifier.verify(&sign(Algorithm::RS256, None, RSA2_PRIVATE, claims)).await, Err(VerifyError::Invalid));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_jwks_refresh_of_unknown_kids() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = Arc::new(Mutex::new(vec![serde_json::from_str(include_str!("../testdata/rsa1_jwk.json"))?]));
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let verifier = JwtVerifier::with_jwks_url(launch_jwks_server(keys, fetches.clone()).await);
+        let claims = serde_json::json!({"sub": "alice", "exp": unix_now() + 600});
+
+        assert!(verifier.verify(&sign(Algorithm::RS256, Some("key-1"), RSA1_PRIVATE, claims.clone())).await.is_ok());
+        for _ in 0..3 {
+            assert_eq!(verifier.verify(&sign(Algorithm::RS256, Some("key-2"), RSA2_PRIVATE, claims.clone())).await, Err(VerifyError::Invalid));
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_unreachable_jwks_as_unavailable() {
+        let verifier = JwtVerifier::with_jwks_url("http://127.0.0.1:9/jwks.json");
+        let token = sign(Algorithm::RS256, Some("key-1"), RSA1_PRIVATE, serde_json::json!({"sub": "alice", "exp": unix_now() + 600}));
+        assert!(matches!(verifier.verify(&token).await, Err(VerifyError::Unavailable(_))));
+    }
+}
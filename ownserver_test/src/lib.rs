@@ -1,15 +1,17 @@
 use std::sync::Arc;
-use once_cell::sync::OnceCell;
 
 use ownserver_auth::build_routes;
 use ownserver_server::{
     proxy_server,
     Config,
+    ConfigBuilder,
     Store,
 };
 use ownserver_lib::{EndpointClaim, EndpointClaims, Protocol};
 use ownserver::{
+    config::ClientConfig,
     proxy_client::{self, ClientInfo},
+    transport::Transport,
     Store as ClientStore,
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -56,9 +58,8 @@ macro_rules! assert_udp_socket_bytes_matches {
     };
 }
 
-pub static CONFIG: OnceCell<Config> = OnceCell::new();
-
 pub const CONTROL_PORT: u16 = 5000;
+pub const H2_CONTROL_PORT: u16 = ownserver_lib::DEFAULT_H2_CONTROL_PORT;
 pub const LOCAL_PORT: u16 = 3000;
 pub const REMOTE_PORT_START: u16 = 4500;
 pub const REMOTE_PORT_END: u16 = 4599;
@@ -98,32 +99,32 @@ pub async fn launch_token_server(token_port: u16) -> TokenServer {
 }
 
 
+/// Config of the proxy server of e2e tests, IPv4 only.
+pub fn proxy_config(control_port: u16, remote_port_start: u16, remote_port_end: u16) -> ConfigBuilder {
+    Config::builder()
+        .control_port(control_port)
+        .h2_control_port(H2_CONTROL_PORT)
+        .token_secret("supersecret")
+        .host("127.0.0.1")
+        .port_range(remote_port_start..remote_port_end)
+        .periodic_cleanup_interval(2 << 30)
+        .periodic_ping_interval(2 << 30)
+}
+
 pub async fn launch_proxy_server(
     control_port: u16,
     remote_port_start: u16,
     remote_port_end: u16
 ) -> Result<ProxyServer, Box<dyn std::error::Error>> {
+    launch_proxy_server_with(proxy_config(control_port, remote_port_start, remote_port_end).build()?).await
+}
 
-    let config = CONFIG.get_or_init(||
-        Config {
-            control_port,
-            token_secret: "supersecret".to_string(),
-            host: "127.0.0.1".to_string(),
-            remote_port_start,
-            remote_port_end,
-            periodic_cleanup_interval: 2 << 30,
-            periodic_ping_interval: 2 << 30,
-        }
-    );
-
+pub async fn launch_proxy_server_with(config: Config) -> Result<ProxyServer, Box<dyn std::error::Error>> {
     let store = Arc::new(Store::new(config.remote_port_start..config.remote_port_end));
 
     let store_ = store.clone();
     tokio::spawn(async move {
-        proxy_server::run(
-            &CONFIG,
-            store_,
-        )
+        proxy_server::run_with_config(Arc::new(config), store_)
         .await.join_next().await;
     });
 
@@ -150,15 +151,34 @@ pub mod tcp {
         control_port: u16,
         endpoint_claims: EndpointClaims,
     ) -> Result<ProxyClient, Box<dyn std::error::Error>> {
+        launch_proxy_client_with_transport(control_port, Transport::WebSocket, endpoint_claims).await
+    }
+
+    pub async fn launch_proxy_client_with_transport(
+        control_port: u16,
+        transport: Transport,
+        endpoint_claims: EndpointClaims,
+    ) -> Result<ProxyClient, Box<dyn std::error::Error>> {
+        let config = ClientConfig::builder()
+            .control_port(control_port)
+            .token_server("http://127.0.0.1:8888/v0/request_token")
+            .transport(transport)
+            .endpoints(endpoint_claims)
+            .build();
+        launch_proxy_client_with_config(config).await
+    }
+
+    pub async fn launch_proxy_client_with_config(config: ClientConfig) -> Result<ProxyClient, Box<dyn std::error::Error>> {
         let client_store: Arc<ClientStore> = Default::default();
         let cancellation_token = CancellationToken::new();
     
-        let (client_info, mut set) =
-                proxy_client::run(client_store, control_port, "http://127.0.0.1:8888/v0/request_token", cancellation_token.clone(), endpoint_claims)
+        let mut handle =
+                proxy_client::run_with_config(client_store, config, cancellation_token.clone())
                     .await
                     .expect("failed to launch proxy_client");
+        let client_info = handle.client_info().clone();
         tokio::spawn(async move {
-            while let Some(res) = set.join_next().await {
+            while let Some(res) = handle.join_next().await {
                 let _ = res.unwrap();
             }
         });
@@ -241,6 +261,119 @@ pub mod tcp {
     }
 
 
+    /// Behaves like `launch_local_server`, but a connection that starts with `stall` is never read again.
+    pub async fn launch_local_server_stalling(local_port: u16) -> LocalServer {
+        let local_server = async move {
+            let listener = TcpListener::bind(format!("127.0.0.1:{}", local_port))
+                .await
+                .unwrap();
+    
+            loop {
+                let (mut socket, _) = listener.accept().await.expect("No connections to accept");
+    
+                tokio::spawn(async move {
+                    loop {
+                        let mut buf = [0; 4 * 1024];
+                        let n = socket
+                            .read(&mut buf)
+                            .await
+                            .expect("failed to read data from socket");
+                        if n == 0 {
+                            return;
+                        }
+                        if buf[..n].starts_with(b"stall") {
+                            // keep the socket open without reading from it
+                            std::future::pending::<()>().await;
+                        }
+    
+                        let mut msg = b"hello, ".to_vec();
+                        msg.append(&mut buf[..n].to_vec());
+                        socket
+                            .write_all(&msg)
+                            .await
+                            .expect("failed to write packet data to local tcp socket");
+                    }
+                });
+            }
+        };
+        tokio::spawn(local_server);
+    
+        wait!();
+        LocalServer {}
+    }
+
+
+    /// Answers `hello, ` and everything read once the peer half-closes the connection, then closes it.
+    pub async fn launch_local_server_half_closing(local_port: u16) -> LocalServer {
+        let local_server = async move {
+            let listener = TcpListener::bind(format!("127.0.0.1:{}", local_port))
+                .await
+                .unwrap();
+
+            loop {
+                let (mut socket, _) = listener.accept().await.expect("No connections to accept");
+
+                tokio::spawn(async move {
+                    let mut msg = b"hello, ".to_vec();
+                    socket
+                        .read_to_end(&mut msg)
+                        .await
+                        .expect("failed to read data from socket");
+                    socket
+                        .write_all(&msg)
+                        .await
+                        .expect("failed to write packet data to local tcp socket");
+                });
+            }
+        };
+        tokio::spawn(local_server);
+
+        wait!();
+        LocalServer {}
+    }
+
+    /// Behaves like `launch_local_server`, but a connection that sends `reset` is reset without an answer.
+    pub async fn launch_local_server_resetting(local_port: u16) -> LocalServer {
+        let local_server = async move {
+            let listener = TcpListener::bind(format!("127.0.0.1:{}", local_port))
+                .await
+                .unwrap();
+    
+            loop {
+                let (mut socket, _) = listener.accept().await.expect("No connections to accept");
+    
+                tokio::spawn(async move {
+                    loop {
+                        let mut buf = [0; 4 * 1024];
+                        let n = socket
+                            .read(&mut buf)
+                            .await
+                            .expect("failed to read data from socket");
+                        if n == 0 {
+                            return;
+                        }
+                        if buf[..n].starts_with(b"reset") {
+                            // closing with zero linger sends RST instead of FIN
+                            socket.set_linger(Some(std::time::Duration::ZERO)).expect("failed to set linger");
+                            return;
+                        }
+    
+                        let mut msg = b"hello, ".to_vec();
+                        msg.append(&mut buf[..n].to_vec());
+                        socket
+                            .write_all(&msg)
+                            .await
+                            .expect("failed to write packet data to local tcp socket");
+                    }
+                });
+            }
+        };
+        tokio::spawn(local_server);
+    
+        wait!();
+        LocalServer {}
+    }
+
     pub async fn with_proxy<T>(endpoint_claims: EndpointClaims, test_func: impl FnOnce(TokenServer, ProxyServer, ProxyClient) -> T)
         where
         T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
@@ -256,6 +389,60 @@ pub mod tcp {
         test_func(token_server, proxy_server, proxy_client).await.expect("failed to call test_func");
     }
 
+    /// Same as `with_proxy` with a proxy server accepting remote connections over IPv6 as well.
+    pub async fn with_proxy_ipv6<T>(endpoint_claims: EndpointClaims, test_func: impl FnOnce(TokenServer, ProxyServer, ProxyClient) -> T)
+        where
+        T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
+    {
+        let token_server = launch_token_server(TOKEN_PORT).await;
+        wait!();
+
+        let config = proxy_config(CONTROL_PORT, REMOTE_PORT_START, REMOTE_PORT_END).enable_ipv6(true).build().expect("invalid proxy config");
+        let proxy_server = launch_proxy_server_with(config).await.expect("failed to launch proxy server");
+        wait!();
+
+        let proxy_client = launch_proxy_client(CONTROL_PORT, endpoint_claims).await.expect("failed to launch proxy client");
+
+        test_func(token_server, proxy_server, proxy_client).await.expect("failed to call test_func");
+    }
+
+    /// Same as `with_proxy` with a proxy client running in `--loopback` mode.
+    pub async fn with_proxy_loopback<T>(endpoint_claims: EndpointClaims, test_func: impl FnOnce(TokenServer, ProxyServer, ProxyClient) -> T)
+        where
+        T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
+    {
+        let token_server = launch_token_server(TOKEN_PORT).await;
+        wait!();
+
+        let proxy_server = launch_proxy_server(CONTROL_PORT, REMOTE_PORT_START, REMOTE_PORT_END).await.expect("failed to launch proxy server");
+        wait!();
+
+        let config = ClientConfig::builder()
+            .control_port(CONTROL_PORT)
+            .token_server("http://127.0.0.1:8888/v0/request_token")
+            .endpoints(endpoint_claims)
+            .loopback(true)
+            .build();
+        let proxy_client = launch_proxy_client_with_config(config).await.expect("failed to launch proxy client");
+
+        test_func(token_server, proxy_server, proxy_client).await.expect("failed to call test_func");
+    }
+
+    pub async fn with_proxy_h2<T>(endpoint_claims: EndpointClaims, test_func: impl FnOnce(TokenServer, ProxyServer, ProxyClient) -> T)
+        where
+        T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
+    {
+        let token_server = launch_token_server(TOKEN_PORT).await;
+        wait!();
+
+        let proxy_server = launch_proxy_server(CONTROL_PORT, REMOTE_PORT_START, REMOTE_PORT_END).await.expect("failed to launch proxy server");
+        wait!();
+
+        let proxy_client = launch_proxy_client_with_transport(H2_CONTROL_PORT, Transport::H2, endpoint_claims).await.expect("failed to launch proxy client");
+
+        test_func(token_server, proxy_server, proxy_client).await.expect("failed to call test_func");
+    }
+
     pub async fn with_local_server<T>(local_port: u16, test_func: impl FnOnce(LocalServer) -> T)
         where
         T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
@@ -266,6 +453,36 @@ pub mod tcp {
         test_func(local_server).await.expect("failed to call test_func");
     }
 
+    pub async fn with_local_server_stalling<T>(local_port: u16, test_func: impl FnOnce(LocalServer) -> T)
+        where
+        T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
+    {
+        let local_server = launch_local_server_stalling(local_port).await;
+        wait!();
+
+        test_func(local_server).await.expect("failed to call test_func");
+    }
+
+    pub async fn with_local_server_resetting<T>(local_port: u16, test_func: impl FnOnce(LocalServer) -> T)
+        where
+        T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
+    {
+        let local_server = launch_local_server_resetting(local_port).await;
+        wait!();
+
+        test_func(local_server).await.expect("failed to call test_func");
+    }
+
+    pub async fn with_local_server_half_closing<T>(local_port: u16, test_func: impl FnOnce(LocalServer) -> T)
+        where
+        T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
+    {
+        let local_server = launch_local_server_half_closing(local_port).await;
+        wait!();
+
+        test_func(local_server).await.expect("failed to call test_func");
+    }
+
     pub async fn with_local_server_echoback<T>(local_port: u16, test_func: impl FnOnce(LocalServer) -> T)
         where
         T: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send,
@@ -295,12 +512,13 @@ pub mod udp {
         let client_store: Arc<ClientStore> = Default::default();
         let cancellation_token = CancellationToken::new();
     
-        let (client_info, mut set) =
+        let mut handle =
             proxy_client::run(client_store, control_port, "http://127.0.0.1:8888/v0/request_token", cancellation_token.clone(), endpoint_claims)
                 .await
                 .expect("failed to launch proxy_client");
+        let client_info = handle.client_info().clone();
         tokio::spawn(async move {
-            while let Some(res) = set.join_next().await {
+            while let Some(res) = handle.join_next().await {
                 let _ = res.unwrap();
             }
         });
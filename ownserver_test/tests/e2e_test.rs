@@ -8,7 +8,7 @@ use ownserver_lib::{EndpointClaim, Protocol};
 #[cfg(test)]
 mod e2e_tcp_test {
     use super::*;
-    use ownserver_test::{tcp::{with_proxy, with_local_server, get_endpoint_claims_single, with_local_server_echoback}, assert_tcp_socket_bytes_matches, LOCAL_PORT};
+    use ownserver_test::{tcp::{with_proxy, with_proxy_h2, with_proxy_ipv6, with_proxy_loopback, with_local_server, get_endpoint_claims_single, with_local_server_echoback, with_local_server_half_closing, with_local_server_resetting, with_local_server_stalling}, assert_tcp_socket_bytes_matches, LOCAL_PORT};
 
 
     #[tokio::test]
@@ -35,6 +35,60 @@ mod e2e_tcp_test {
         Ok(())
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn return_remote_port_allocated_by_server(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = get_endpoint_claims_single(LOCAL_PORT);
+        with_proxy(endpoint_claims, |_token_server, proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addrs = client_info.remote_addrs();
+            assert_eq!(remote_addrs.len(), 1);
+            assert_eq!(remote_addrs[0].host, client_info.public_host);
+            assert_eq!(remote_addrs[0].local_port, LOCAL_PORT);
+            assert_eq!(Some(remote_addrs[0].port), proxy_server.store.get_remote_port_by_endpoint_id(client_info.endpoints[0].id));
+            wait!();
+
+            // players reach the local server at the returned address
+            let remote_addr = remote_addrs[0].to_string();
+            with_local_server(LOCAL_PORT, |_local_server| async move {
+                let mut remote = TcpStream::connect(remote_addr)
+                    .await?;
+                remote.write_all(b"foobar".as_ref()).await?;
+                assert_tcp_socket_bytes_matches!(&mut remote, b"hello, foobar");
+
+                Ok(())
+            }).await;
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn forward_remote_traffic_to_local_over_h2(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = get_endpoint_claims_single(LOCAL_PORT);
+        with_proxy_h2(endpoint_claims, |_token_server, _proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addr = format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port);
+            wait!();
+
+            with_local_server(LOCAL_PORT, |_local_server| async move {
+                let mut remote = TcpStream::connect(remote_addr)
+                    .await?;
+                remote.write_all(b"foobar".as_ref()).await?;
+                assert_tcp_socket_bytes_matches!(&mut remote, b"hello, foobar");
+
+                Ok(())
+            }).await;
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
+
     #[tokio::test]
     #[serial]
     async fn forward_multiple_remote_traffic_to_local() -> Result<(), Box<dyn std::error::Error>> {
@@ -130,6 +184,99 @@ mod e2e_tcp_test {
         Ok(())
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn close_remote_when_local_connection_resets() -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = get_endpoint_claims_single(LOCAL_PORT);
+        with_proxy(endpoint_claims, |_token_server, _proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addr = format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port);
+            wait!();
+
+            with_local_server_resetting(LOCAL_PORT, |_local_server| async move {
+                let mut remote = TcpStream::connect(remote_addr).await?;
+                remote.write_all(b"foobar".as_ref()).await?;
+                assert_tcp_socket_bytes_matches!(&mut remote, b"hello, foobar");
+
+                // the remote peer is closed right away instead of at its idle timeout
+                remote.write_all(b"reset".as_ref()).await?;
+                let mut buf = [0; 1];
+                let n = tokio::time::timeout(std::time::Duration::from_secs(2), remote.read(&mut buf)).await??;
+                assert_eq!(n, 0);
+
+                Ok(())
+            }).await;
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn forward_half_close_in_both_directions() -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = get_endpoint_claims_single(LOCAL_PORT);
+        with_proxy(endpoint_claims, |_token_server, _proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addr = format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port);
+            wait!();
+
+            with_local_server_half_closing(LOCAL_PORT, |_local_server| async move {
+                let mut remote = TcpStream::connect(remote_addr).await?;
+                remote.write_all(b"foobar".as_ref()).await?;
+                // the local service answers only after it reads EOF, then closes its side too
+                remote.shutdown().await?;
+
+                let mut received = Vec::new();
+                tokio::time::timeout(std::time::Duration::from_secs(5), remote.read_to_end(&mut received)).await??;
+                assert_eq!(received, b"hello, foobar");
+
+                Ok(())
+            }).await;
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn close_remote_streams_when_client_disconnects() -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = get_endpoint_claims_single(LOCAL_PORT);
+        with_proxy(endpoint_claims, |_token_server, proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addr = format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port);
+            wait!();
+
+            with_local_server(LOCAL_PORT, |_local_server| async move {
+                let mut remote = TcpStream::connect(remote_addr.clone()).await?;
+                let mut remote2 = TcpStream::connect(remote_addr).await?;
+                wait!();
+
+                remote.write_all(b"foobar".as_ref()).await?;
+                assert_tcp_socket_bytes_matches!(&mut remote, b"hello, foobar");
+                remote2.write_all(b"fugapiyo".as_ref()).await?;
+                assert_tcp_socket_bytes_matches!(&mut remote2, b"hello, fugapiyo");
+                assert_eq!(proxy_server.store.len_streams().await, 2);
+
+                proxy_client.cancellation_token.cancel();
+
+                // both remote peers see the connection close without waiting for cleanup
+                let mut buf = [0; 1];
+                for remote in [&mut remote, &mut remote2] {
+                    let n = tokio::time::timeout(std::time::Duration::from_secs(2), remote.read(&mut buf)).await??;
+                    assert_eq!(n, 0);
+                }
+                assert_eq!(proxy_server.store.len_streams().await, 0);
+
+                Ok(())
+            }).await;
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
+
     #[tokio::test]
     #[serial]
     async fn remove_disabled_client_streams() -> Result<(), Box<dyn std::error::Error>> {
@@ -157,9 +304,9 @@ mod e2e_tcp_test {
                 cancellation_token.cancel();
                 wait!();
 
-                // client and stream remains in store
+                // streams are dropped with the client, the client remains in store
                 assert_eq!(store.len_clients().await, 1);
-                assert_eq!(store.len_streams().await, 1);
+                assert_eq!(store.len_streams().await, 0);
 
                 // need to call cleanup
                 store.cleanup().await;
@@ -282,6 +429,58 @@ mod e2e_tcp_test {
         Ok(())
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn forward_interleaved_traffic_of_multiple_mappings(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = vec![
+            EndpointClaim {
+                protocol: Protocol::TCP,
+                local_port: LOCAL_PORT + 1,
+                remote_port: 0,
+            },
+            EndpointClaim {
+                protocol: Protocol::TCP,
+                local_port: LOCAL_PORT + 2,
+                remote_port: 0,
+            },
+        ];
+        with_proxy(endpoint_claims, |_token_server, _proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addr0 = format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port);
+            let remote_addr1 = format!("{}:{}", client_info.host, client_info.endpoints[1].remote_port);
+            let local_port0 = client_info.endpoints[0].local_port;
+            let local_port1 = client_info.endpoints[1].local_port;
+            wait!();
+
+            with_local_server(local_port0, |_local_server0: ownserver_test::LocalServer| async move {
+                with_local_server(local_port1, |_local_server1: ownserver_test::LocalServer| async move {
+                    let mut remote0 = TcpStream::connect(remote_addr0)
+                        .await
+                        .expect("Failed to connect to remote port");
+                    let mut remote1 = TcpStream::connect(remote_addr1)
+                        .await
+                        .expect("Failed to connect to remote port");
+                    wait!();
+
+                    for i in 0..3 {
+                        let m0 = format!("{}: {}", local_port0, i);
+                        let m1 = format!("{}: {}", local_port1, i);
+                        remote0.write_all(m0.as_bytes()).await?;
+                        remote1.write_all(m1.as_bytes()).await?;
+
+                        assert_tcp_socket_bytes_matches!(&mut remote1, format!("hello, {}", m1).as_bytes());
+                        assert_tcp_socket_bytes_matches!(&mut remote0, format!("hello, {}", m0).as_bytes());
+                    }
+                    Ok(())
+                }).await;
+                Ok(())
+            }).await;
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
 
     #[tokio::test]
     #[serial]
@@ -328,6 +527,89 @@ mod e2e_tcp_test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    #[serial]
+    async fn forward_traffic_while_another_stream_is_stalled(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = get_endpoint_claims_single(LOCAL_PORT);
+        with_proxy(endpoint_claims, |_token_server, _proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addr = format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port);
+            wait!();
+
+            with_local_server_stalling(LOCAL_PORT, |_local_server| async move {
+                let mut stalled = TcpStream::connect(remote_addr.clone())
+                    .await?;
+                stalled.write_all(b"stall".as_ref()).await?;
+                wait!();
+
+                // far more than the stream window, local server never reads it
+                tokio::spawn(async move {
+                    let payload = vec![0u8; 4 * 1024 * 1024];
+                    let _ = stalled.write_all(&payload).await;
+                });
+                wait!();
+
+                let mut remote = TcpStream::connect(remote_addr)
+                    .await?;
+                remote.write_all(b"foobar".as_ref()).await?;
+                assert_tcp_socket_bytes_matches!(&mut remote, b"hello, foobar");
+
+                Ok(())
+            }).await;
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn forward_remote_traffic_over_ipv6(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = get_endpoint_claims_single(LOCAL_PORT);
+        with_proxy_ipv6(endpoint_claims, |_token_server, _proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addr = format!("[::1]:{}", client_info.endpoints[0].remote_port);
+            wait!();
+
+            with_local_server(LOCAL_PORT, |_local_server| async move {
+                let mut remote = TcpStream::connect(remote_addr)
+                    .await?;
+                remote.write_all(b"foobar".as_ref()).await?;
+                assert_tcp_socket_bytes_matches!(&mut remote, b"hello, foobar");
+
+                Ok(())
+            }).await;
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn echo_remote_traffic_in_loopback_mode(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let endpoint_claims = get_endpoint_claims_single(LOCAL_PORT);
+        with_proxy_loopback(endpoint_claims, |_token_server, _proxy_server, proxy_client| async move {
+            let client_info = proxy_client.client_info;
+            let remote_addr = format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port);
+            wait!();
+
+            let mut remote = TcpStream::connect(remote_addr)
+                .await?;
+            remote.write_all(b"foobar".as_ref()).await?;
+            assert_tcp_socket_bytes_matches!(&mut remote, b"foobar");
+            remote.write_all(b"hello".as_ref()).await?;
+            assert_tcp_socket_bytes_matches!(&mut remote, b"hello");
+
+            Ok(())
+        }).await;
+
+        Ok(())
+    }
 }
 
 
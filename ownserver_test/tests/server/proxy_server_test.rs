@@ -18,7 +18,7 @@ use bytes::BytesMut;
 
 #[cfg(test)]
 mod server_tcp_test {
-    use ownserver_lib::{EndpointClaim, Protocol, ControlPacketV2, ControlPacketV2Codec};
+    use ownserver_lib::{ids::{set_id_generator, SequentialIds}, EndpointClaim, Protocol, ControlPacketV2, ControlPacketV2Codec, StreamId};
 
     use super::*;
     static CONFIG: OnceCell<Config> = OnceCell::new();
@@ -63,15 +63,14 @@ mod server_tcp_test {
     > {
 
         let config = CONFIG.get_or_init(||
-            Config {
-                control_port: 5000,
-                token_secret: "supersecret".to_string(),
-                host: "127.0.0.1".to_string(),
-                remote_port_start: 4000,
-                remote_port_end: 4099,
-                periodic_cleanup_interval: 2 << 30,
-                periodic_ping_interval: 2 << 30,
-            }
+            Config::builder()
+                .token_secret("supersecret")
+                .host("127.0.0.1")
+                .port_range(4000..4099)
+                .periodic_cleanup_interval(2 << 30)
+                .periodic_ping_interval(2 << 30)
+                .build()
+                .expect("invalid config")
         );
 
         let store = Arc::new(Store::new(config.remote_port_start..config.remote_port_end));
@@ -163,25 +162,22 @@ mod server_tcp_test {
     #[tokio::test]
     #[serial]
     async fn forward_multiple_remote_traffic_to_client() -> Result<(), Box<dyn std::error::Error>> {
-        let (websoket, store, client_info) = launch_proxy_server(CONTROL_PORT).await?;
+        let (websoket, _store, client_info) = launch_proxy_server(CONTROL_PORT).await?;
         let (mut _raw_client_ws_sink, mut raw_client_ws_stream) = websoket.split();
+        // only the remote streams take ids from here on
+        let _ids = set_id_generator(SequentialIds::default());
 
         let mut remote1 = TcpStream::connect(format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port))
             .await
             .expect("Failed to connect to remote port");
         wait!();
-        let stream_id1 = store.get_stream_ids().await[0];
+        let stream_id1 = StreamId::from_u128(1);
 
         let mut remote2 = TcpStream::connect(format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port))
             .await
             .expect("Failed to connect to remote port");
         wait!();
-        let stream_id2 = store.get_stream_ids().await
-            .into_iter()
-            .find(|sid| sid != &stream_id1)
-            .unwrap();
-
-        assert_ne!(stream_id1, stream_id2);
+        let stream_id2 = StreamId::from_u128(2);
 
         remote1
             .write_all(b"some bytes 1")
@@ -215,25 +211,22 @@ mod server_tcp_test {
     #[tokio::test]
     #[serial]
     async fn forward_client_traffic_to_multiple_remote() -> Result<(), Box<dyn std::error::Error>> {
-        let (websoket, store, client_info) = launch_proxy_server(CONTROL_PORT).await?;
+        let (websoket, _store, client_info) = launch_proxy_server(CONTROL_PORT).await?;
         let (mut raw_client_ws_sink, mut _raw_client_ws_stream) = websoket.split();
+        // only the remote streams take ids from here on
+        let _ids = set_id_generator(SequentialIds::default());
 
         let mut remote1 = TcpStream::connect(format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port))
             .await
             .expect("Failed to connect to remote port");
         wait!();
-        let stream_id1 = store.get_stream_ids().await[0];
+        let stream_id1 = StreamId::from_u128(1);
 
         let mut remote2 = TcpStream::connect(format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port))
             .await
             .expect("Failed to connect to remote port");
         wait!();
-        let stream_id2 = store.get_stream_ids().await
-            .into_iter()
-            .find(|sid| sid != &stream_id1)
-            .unwrap();
-
-        assert_ne!(stream_id1, stream_id2);
+        let stream_id2 = StreamId::from_u128(2);
 
         let mut codec = ControlPacketV2Codec::new();
         let mut bytes = BytesMut::new();
@@ -260,7 +253,7 @@ mod server_tcp_test {
 #[cfg(test)]
 mod server_udp_test {
 
-    use ownserver_lib::{EndpointClaim, Protocol, ControlPacketV2Codec, ControlPacketV2};
+    use ownserver_lib::{ids::{set_id_generator, SequentialIds}, EndpointClaim, Protocol, ControlPacketV2Codec, ControlPacketV2, StreamId};
 
     use super::*;
     static CONFIG: OnceCell<Config> = OnceCell::new();
@@ -304,15 +297,14 @@ mod server_udp_test {
         Box<dyn std::error::Error>,
     > {
         let config = CONFIG.get_or_init(||
-            Config {
-                control_port: 5000,
-                token_secret: "supersecret".to_string(),
-                host: "127.0.0.1".to_string(),
-                remote_port_start: 4100,
-                remote_port_end: 4199,
-                periodic_cleanup_interval: 2 << 30,
-                periodic_ping_interval: 2 << 30,
-            }
+            Config::builder()
+                .token_secret("supersecret")
+                .host("127.0.0.1")
+                .port_range(4100..4199)
+                .periodic_cleanup_interval(2 << 30)
+                .periodic_ping_interval(2 << 30)
+                .build()
+                .expect("invalid config")
         );
         let store = Arc::new(Store::new(config.remote_port_start..config.remote_port_end));
 
@@ -407,8 +399,10 @@ mod server_udp_test {
     #[tokio::test]
     #[serial]
     async fn forward_multiple_remote_traffic_to_client() -> Result<(), Box<dyn std::error::Error>> {
-        let (websoket, store, client_info) = launch_proxy_server(CONTROL_PORT).await?;
+        let (websoket, _store, client_info) = launch_proxy_server(CONTROL_PORT).await?;
         let (mut _raw_client_ws_sink, mut raw_client_ws_stream) = websoket.split();
+        // only the remote streams take ids from here on
+        let _ids = set_id_generator(SequentialIds::default());
 
         let remote1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
         remote1.connect(format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port)).await.unwrap();
@@ -418,7 +412,7 @@ mod server_udp_test {
             .await
             .expect("failed to send client hello");
         wait!();
-        let stream_id1 = store.get_stream_ids().await[0];
+        let stream_id1 = StreamId::from_u128(1);
 
         let remote2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
         remote2.connect(format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port)).await.unwrap();
@@ -428,10 +422,7 @@ mod server_udp_test {
             .await
             .expect("failed to send client hello");
         wait!();
-        let stream_id2 = store.get_stream_ids().await
-            .into_iter()
-            .find(|sid| sid != &stream_id1)
-            .unwrap();
+        let stream_id2 = StreamId::from_u128(2);
 
 
         assert_control_packet_matches!(
@@ -456,8 +447,10 @@ mod server_udp_test {
     #[tokio::test]
     #[serial]
     async fn forward_client_traffic_to_multiple_remote() -> Result<(), Box<dyn std::error::Error>> {
-        let (websoket, store, client_info) = launch_proxy_server(CONTROL_PORT).await?;
+        let (websoket, _store, client_info) = launch_proxy_server(CONTROL_PORT).await?;
         let (mut raw_client_ws_sink, mut _raw_client_ws_stream) = websoket.split();
+        // only the remote streams take ids from here on
+        let _ids = set_id_generator(SequentialIds::default());
 
         let remote1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
         remote1.connect(format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port)).await.unwrap();
@@ -467,7 +460,7 @@ mod server_udp_test {
             .await
             .expect("failed to send client hello");
         wait!();
-        let stream_id1 = store.get_stream_ids().await[0];
+        let stream_id1 = StreamId::from_u128(1);
 
         let remote2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
         remote2.connect(format!("{}:{}", client_info.host, client_info.endpoints[0].remote_port)).await.unwrap();
@@ -477,10 +470,7 @@ mod server_udp_test {
             .await
             .expect("failed to send client hello");
         wait!();
-        let stream_id2 = store.get_stream_ids().await
-            .into_iter()
-            .find(|sid| sid != &stream_id1)
-            .unwrap();
+        let stream_id2 = StreamId::from_u128(2);
 
         let mut codec = ControlPacketV2Codec::new();
         let mut bytes = BytesMut::new();